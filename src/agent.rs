@@ -0,0 +1,257 @@
+use serde_json::{json, Value};
+use url::Url;
+use ws::{Builder, CloseCode, Handshake, Message, Request, Response, Result, Sender};
+
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::{debug, error, info, warn};
+
+use crate::condition::{Condition, Facts};
+
+/// Rule changing the traffic of an agent, set by the controller.
+pub enum AgentRule {
+    /// Messages matching the condition aren't forwarded.
+    Drop(Condition),
+}
+
+impl AgentRule {
+    /// Parses `drop:<condition>`.
+    pub fn parse(rule: &str) -> std::result::Result<Self, String> {
+        let drop = rule.strip_prefix("drop:")
+            .ok_or_else(|| format!("Agent rule {} is not a drop rule", rule))?;
+        Condition::parse(drop).map(AgentRule::Drop).map_err(|e| format!("Invalid agent rule {}: {}", rule, e))
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            AgentRule::Drop(condition) => format!("drop:{}", condition),
+        }
+    }
+
+    pub fn drops(&self, facts: &Facts) -> bool {
+        match self {
+            AgentRule::Drop(condition) => condition.matches(facts),
+        }
+    }
+}
+
+/// Server for a controller, a ws-proxy elsewhere attached to this one. Controllers must
+/// authenticate with the token as a bearer token, then they receive each record of the
+/// capture as a text message with a single JSON line and can replace the rules.
+#[derive(Clone)]
+pub struct Agent {
+    broadcaster: Sender,
+    rules: Arc<Mutex<Vec<AgentRule>>>,
+}
+
+impl Agent {
+    /// Starts listening on all interfaces. The greeting is sent to every controller
+    /// which connects, followed by the current rules.
+    pub fn start(port: u16, token: Vec<u8>, greeting: Value) -> std::result::Result<Self, String> {
+        let (broadcaster_tx, broadcaster_rx) = mpsc::channel();
+        let rules = Arc::new(Mutex::new(vec![]));
+        let shared = rules.clone();
+
+        thread::spawn(move || {
+            let ws = Builder::new().build(|out: Sender| Controller {
+                out,
+                token: token.clone(),
+                greeting: greeting.clone(),
+                rules: shared.clone(),
+            });
+            let ws = match ws {
+                Ok(ws) => ws.bind(SocketAddr::from(([0,0,0,0], port))),
+                Err(e) => Err(e)
+            };
+
+            let ws = match ws {
+                Ok(ws) => ws,
+                Err(e) => {
+                    broadcaster_tx.send(Err(e.to_string())).unwrap();
+                    return;
+                }
+            };
+
+            broadcaster_tx.send(Ok(ws.broadcaster())).unwrap();
+            if let Err(e) = ws.run() {
+                error!("Error: {}", e);
+            }
+        });
+
+        let broadcaster = broadcaster_rx.recv()
+            .map_err(|e| e.to_string())??;
+        info!("Controllers can attach to port {}", port);
+        Ok(Agent { broadcaster, rules })
+    }
+
+    pub fn publish(&self, record: &Value) {
+        self.broadcaster.broadcast(Message::text(record.to_string())).unwrap_or_else(|e| {
+            error!("Error: {}", e);
+        })
+    }
+
+    /// Name of the rule dropping the message, if there is one.
+    pub fn drops(&self, facts: &Facts) -> Option<String> {
+        self.rules.lock().unwrap().iter()
+            .find(|rule| rule.drops(facts))
+            .map(AgentRule::name)
+    }
+}
+
+struct Controller {
+    out: Sender,
+    token: Vec<u8>,
+    greeting: Value,
+    rules: Arc<Mutex<Vec<AgentRule>>>,
+}
+
+impl Controller {
+    fn rules(&self) -> Value {
+        let names: Vec<String> = self.rules.lock().unwrap().iter().map(AgentRule::name).collect();
+        json!({ "event": "rules", "rules": names })
+    }
+
+    /// Replaces all rules with those of the update, unless one of them is invalid.
+    fn update(&self, update: &str) -> std::result::Result<(), String> {
+        let update: Value = serde_json::from_str(update).map_err(|e| format!("Update is not JSON: {}", e))?;
+        let rules = update["rules"].as_array().ok_or("Update has no list of rules")?
+            .iter()
+            .map(|rule| AgentRule::parse(rule.as_str().unwrap_or_default()))
+            .collect::<std::result::Result<Vec<AgentRule>, String>>()?;
+        *self.rules.lock().unwrap() = rules;
+        Ok(())
+    }
+}
+
+impl ws::Handler for Controller {
+    fn on_request(&mut self, request: &Request) -> Result<Response> {
+        let expected = [b"Bearer ".as_slice(), &self.token].concat();
+        let authorized = request.header("authorization")
+            .map(|value| constant_time_eq(value, &expected))
+            .unwrap_or(false);
+        if !authorized {
+            warn!("Controller {} is rejected, its token is wrong", self.out.connection_id());
+            return Ok(Response::new(401, "Unauthorized", b"Agent token is required".to_vec()));
+        }
+        Response::from_request(request)
+    }
+
+    fn on_open(&mut self, h: Handshake) -> Result<()> {
+        info!("Controller {} attached from {:?}", self.out.connection_id(), h.peer_addr);
+        self.out.send(Message::text(self.greeting.to_string()))?;
+        self.out.send(Message::text(self.rules().to_string()))
+    }
+
+    fn on_message(&mut self, message: Message) -> Result<()> {
+        let update = match message {
+            Message::Text(update) => update,
+            Message::Binary(_) => return Ok(()),
+        };
+        match self.update(&update) {
+            Ok(()) => {
+                let rules = self.rules();
+                println!("Agent rules were set by controller {}: {}", self.out.connection_id(), rules["rules"]);
+                // Every controller sees the rules in effect
+                self.out.broadcast(Message::text(rules.to_string()))
+            },
+            Err(e) => self.out.send(Message::text(json!({ "event": "error", "error": e }).to_string())),
+        }
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        debug!("Controller {} detached: {:?}", self.out.connection_id(), code);
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+/// Attaches to the agent at the url and passes every record it sends to the callback
+/// until the agent goes away. The rules are set first, then every line of the standard
+/// input updates them: a rule is added, `clear` removes all of them.
+pub fn attach<F>(url: &Url, token: &[u8], rules: Vec<String>, on_record: F) -> std::result::Result<(), String>
+    where F: FnMut(Value) + 'static
+{
+    for rule in rules.iter() {
+        AgentRule::parse(rule)?;
+    }
+    let mut on_record = Some(on_record);
+    let mut initial = Some(rules.clone());
+    let mut ws = Builder::new()
+        .build(|out: Sender| Attached {
+            out,
+            token: token.to_vec(),
+            rules: initial.take().unwrap_or_default(),
+            on_record: Box::new(on_record.take().expect("Agent is attached once")),
+        })
+        .map_err(|e| e.to_string())?;
+    ws.connect(url.clone()).map_err(|e| e.to_string())?;
+
+    let agent = ws.broadcaster();
+    thread::spawn(move || {
+        let mut rules = rules;
+        for line in io::stdin().lock().lines().map_while(std::result::Result::ok) {
+            match line.trim() {
+                "" => continue,
+                "clear" => rules.clear(),
+                rule => match AgentRule::parse(rule) {
+                    Ok(_) => rules.push(rule.to_string()),
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                }
+            }
+            if agent.send(json!({ "rules": rules }).to_string()).is_err() {
+                return;
+            }
+        }
+    });
+    ws.run().map(|_| ()).map_err(|e| e.to_string())
+}
+
+struct Attached {
+    out: Sender,
+    token: Vec<u8>,
+    rules: Vec<String>,
+    on_record: Box<dyn FnMut(Value)>,
+}
+
+impl ws::Handler for Attached {
+    fn build_request(&mut self, url: &Url) -> Result<Request> {
+        let mut request = Request::from_url(url)?;
+        request.headers_mut().push(("Authorization".to_string(), [b"Bearer ".as_slice(), &self.token].concat()));
+        Ok(request)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if !self.rules.is_empty() {
+            self.out.send(json!({ "rules": self.rules }).to_string())?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, message: Message) -> Result<()> {
+        if let Message::Text(text) = message {
+            match serde_json::from_str::<Value>(&text) {
+                Ok(record) => (self.on_record)(record),
+                Err(e) => warn!("Agent sent a record which is not JSON: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        println!("Agent closed the connection: {:?} {}", code, reason);
+        self.out.shutdown().unwrap_or_else(|e| error!("Error: {}", e));
+    }
+
+    fn on_error(&mut self, e: ws::Error) {
+        println!("Agent connection failed: {}", e);
+        self.out.shutdown().unwrap_or_else(|e| error!("Error: {}", e));
+    }
+}
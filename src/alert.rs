@@ -0,0 +1,259 @@
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use url::Url;
+use ws::Message;
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::clock::Clock;
+use crate::closecodes::Leg;
+use crate::condition::{Condition, Facts};
+use crate::logqueue::LogFile;
+use crate::session::{self, MessageId};
+use crate::track;
+
+/// Pattern of messages looking like errors, for the `error` rule without a pattern.
+pub(crate) const ERROR_PATTERN: &str = r#"(?i)"(error|errors|fault)"\s*:|\berror\b"#;
+/// Longest wait of a webhook to connect, take the request or answer it.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Condition to alert on.
+#[derive(Clone)]
+pub enum AlertRule {
+    /// Tracked value goes above the threshold.
+    Above(String, JsonPath, f64),
+    /// Tracked value goes below the threshold.
+    Below(String, JsonPath, f64),
+    /// No messages from the leg, or from both legs, for the duration.
+    Silence(Option<Leg>, Duration),
+    /// Message looking like an error appears.
+    Error(Condition),
+}
+
+impl AlertRule {
+    /// Parses `<jsonpath>><number>`, `<jsonpath><<number>`,
+    /// `silence:[<client|server>:]<seconds>` or `error[:<condition>]`.
+    pub fn parse(rule: &str) -> std::result::Result<Self, String> {
+        if let Some(silence) = rule.strip_prefix("silence:") {
+            let (leg, seconds) = match silence.find(':') {
+                Some(index) => (Some(Leg::parse(&silence[..index])?), &silence[index + 1..]),
+                None => (None, silence),
+            };
+            let seconds = seconds.parse::<f64>().ok().filter(|seconds| *seconds > 0.0)
+                .ok_or_else(|| format!("Invalid duration of silence in alert {}", rule))?;
+            return Ok(AlertRule::Silence(leg, Duration::from_secs_f64(seconds)));
+        }
+        if rule == "error" {
+            return Ok(AlertRule::Error(Condition::parse(ERROR_PATTERN).unwrap()));
+        }
+        if let Some(condition) = rule.strip_prefix("error:") {
+            return Condition::parse(condition)
+                .map(AlertRule::Error)
+                .map_err(|e| format!("{} in alert {}", e, rule));
+        }
+
+        let index = rule.rfind(['<', '>'])
+            .ok_or_else(|| format!("Alert {} is neither a threshold, silence nor error rule", rule))?;
+        let expression = rule[..index].trim().to_string();
+        let path = track::parse(&expression)?;
+        let threshold = rule[index + 1..].trim().parse::<f64>()
+            .map_err(|e| format!("Invalid threshold in alert {}: {}", rule, e))?;
+        Ok(match &rule[index..index + 1] {
+            ">" => AlertRule::Above(expression, path, threshold),
+            _ => AlertRule::Below(expression, path, threshold),
+        })
+    }
+
+    fn name(&self) -> String {
+        match self {
+            AlertRule::Above(expression, _, threshold) => format!("{} > {}", expression, threshold),
+            AlertRule::Below(expression, _, threshold) => format!("{} < {}", expression, threshold),
+            AlertRule::Silence(Some(leg), duration) => format!("silence of {} for {:?}", leg, duration),
+            AlertRule::Silence(None, duration) => format!("silence for {:?}", duration),
+            AlertRule::Error(condition) if condition.to_string() == ERROR_PATTERN => "error".to_string(),
+            AlertRule::Error(condition) => format!("error matching {}", condition),
+        }
+    }
+}
+
+/// Raises alerts as console warnings, annotations in the capture and optionally webhook calls.
+/// Threshold and silence alerts are raised once when the condition starts to hold.
+pub struct Alerts {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    rules: Vec<AlertRule>,
+    firing: Mutex<Vec<bool>>,
+    /// Time of the last message from each leg.
+    last_message: Mutex<[Option<Instant>; 2]>,
+    capture: LogFile,
+    webhook: Option<Url>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Alerts {
+    /// Starts watching for silence in a background thread if there are silence rules.
+    pub fn start(rules: Vec<AlertRule>, capture: LogFile, webhook: Option<Url>, clock: Arc<dyn Clock>) -> Self {
+        let watch = rules.iter().any(|rule| matches!(rule, AlertRule::Silence(..)));
+        let inner = Arc::new(Inner {
+            firing: Mutex::new(vec![false; rules.len()]),
+            last_message: Mutex::new([None, None]),
+            rules,
+            capture,
+            webhook,
+            clock,
+        });
+
+        if watch {
+            let (watched, clock) = (Arc::downgrade(&inner), inner.clock.clone());
+            thread::spawn(move || watch_silence(watched, clock));
+        }
+        Alerts { inner }
+    }
+
+    pub fn inspect(&self, id: MessageId, facts: &Facts) {
+        let (inner, from) = (&self.inner, facts.from);
+        inner.last_message.lock().unwrap()[from as usize] = Some(inner.clock.now());
+
+        let (text, json) = match facts.message {
+            Message::Text(text) => (Cow::from(text), serde_json::from_str::<Value>(text).ok()),
+            Message::Binary(data) => (Cow::from(format!("<{} bytes>", data.len())), None),
+        };
+        for (index, rule) in inner.rules.iter().enumerate() {
+            match rule {
+                AlertRule::Above(_, path, threshold) | AlertRule::Below(_, path, threshold) => {
+                    let values = match &json {
+                        Some(json) => numbers(path, json),
+                        None => continue,
+                    };
+                    if values.is_empty() {
+                        continue;
+                    }
+                    let holds = values.iter().any(|value| match rule {
+                        AlertRule::Above(..) => value > threshold,
+                        _ => value < threshold,
+                    });
+                    let details = format!("{} in message {} from {} is {:?}", rule.name(), id, from, values);
+                    inner.transition(index, holds, Some(id), details);
+                },
+                AlertRule::Silence(leg, _) => {
+                    if leg.is_none() || *leg == Some(from) {
+                        inner.firing.lock().unwrap()[index] = false;
+                    }
+                },
+                AlertRule::Error(condition) => {
+                    if condition.matches(facts) {
+                        inner.raise(rule, Some(id), format!("message {} from {}: {}", id, from, text));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// Raises the alert when the condition starts to hold.
+    fn transition(&self, index: usize, holds: bool, message: Option<MessageId>, details: String) {
+        let was_firing = std::mem::replace(&mut self.firing.lock().unwrap()[index], holds);
+        if holds && !was_firing {
+            self.raise(&self.rules[index], message, details);
+        }
+    }
+
+    fn raise(&self, rule: &AlertRule, message: Option<MessageId>, details: String) {
+        println!("Alert: {}: {}", rule.name(), details);
+
+        let record = session::alert_record(&rule.name(), message, &details);
+        self.capture.write(format!("{}\n", record));
+        if let Some(webhook) = &self.webhook {
+            let webhook = webhook.clone();
+            thread::spawn(move || {
+                if let Err(e) = post(&webhook, &record.to_string()) {
+                    error!("Error: alert webhook {} failed: {}", webhook, e);
+                }
+            });
+        }
+    }
+}
+
+fn watch_silence(inner: Weak<Inner>, clock: Arc<dyn Clock>) {
+    loop {
+        clock.sleep(Duration::from_millis(250));
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        // Silence is counted only after there was some traffic
+        let last = *inner.last_message.lock().unwrap();
+        for (index, rule) in inner.rules.iter().enumerate() {
+            if let AlertRule::Silence(leg, duration) = rule {
+                let last = match leg {
+                    Some(leg) => last[*leg as usize],
+                    None => last.iter().flatten().max().copied(),
+                };
+                let silent = match last {
+                    Some(last) => clock.now() - last,
+                    None => continue,
+                };
+                if silent >= *duration {
+                    let details = format!("no messages for {:.1}s", silent.as_secs_f64());
+                    inner.transition(index, true, None, details);
+                }
+            }
+        }
+    }
+}
+
+fn numbers(path: &JsonPath, json: &Value) -> Vec<f64> {
+    path.query(json).all().into_iter()
+        .filter_map(|value| match value {
+            Value::Number(number) => number.as_f64(),
+            Value::String(text) => text.trim().parse().ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Posts a JSON body to a plain http:// URL.
+pub(crate) fn post(url: &Url, body: &str) -> std::io::Result<()> {
+    if url.scheme() != "http" {
+        return Err(std::io::Error::other("only http:// webhooks are supported"));
+    }
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path = format!("{}?{}", path, query);
+    }
+
+    // An address which doesn't answer mustn't hold the caller for the timeout of the system
+    let mut stream = Err(std::io::Error::other(format!("{} has no address", host)));
+    for address in url.socket_addrs(|| Some(80))? {
+        stream = TcpStream::connect_timeout(&address, POST_TIMEOUT);
+        if stream.is_ok() {
+            break;
+        }
+    }
+    let mut stream = stream?;
+    stream.set_read_timeout(Some(POST_TIMEOUT))?;
+    stream.set_write_timeout(Some(POST_TIMEOUT))?;
+    write!(stream, "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{}", path, host, port, body.len(), body)?;
+
+    let mut status = [0; 12];
+    stream.read_exact(&mut status)?;
+    let status = String::from_utf8_lossy(&status[9..12]).to_string();
+    if !status.starts_with('2') {
+        return Err(std::io::Error::other(format!("status {}", status)));
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use regex::{Captures, Regex};
+use serde_json::Value;
+use sha2::Sha256;
+use ws::Message;
+
+/// Replaces personal data in what the proxy records with pseudonyms.
+/// Pseudonyms are derived from the original values with HMAC-SHA256, so the same value
+/// gets the same pseudonym across the whole session (or across sessions sharing the key),
+/// while the original can't be recovered without the key.
+///
+/// Forwarded messages are never modified, only their logs, capture and observer copies.
+pub struct Anonymizer {
+    key: Vec<u8>,
+    fields: Vec<String>,
+    emails: Option<Regex>,
+    ips: Option<Regex>,
+}
+
+impl Anonymizer {
+    /// Parses comma-separated rules: `email` and `ip` pseudonymize such values found
+    /// in any text, any other rule is a name of JSON fields to pseudonymize wherever they are.
+    /// Without a key, a random one is generated.
+    pub fn parse(rules: &str, key: Option<Vec<u8>>) -> std::result::Result<Self, String> {
+        let key = key.unwrap_or_else(|| {
+            let mut key = vec![0; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            key
+        });
+
+        let (mut emails, mut ips, mut fields) = (false, false, vec![]);
+        for rule in rules.split(',').map(str::trim) {
+            match rule {
+                "" => return Err(format!("Empty anonymization rule in {}", rules)),
+                "email" => emails = true,
+                "ip" => ips = true,
+                field => fields.push(field.to_string()),
+            }
+        }
+
+        Ok(Anonymizer {
+            key,
+            fields,
+            emails: Some(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}")
+                .filter(|_| emails)
+                .map(|pattern| Regex::new(pattern).unwrap()),
+            ips: Some(r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b")
+                .filter(|_| ips)
+                .map(|pattern| Regex::new(pattern).unwrap()),
+        })
+    }
+
+    pub fn message(&self, message: &Message) -> Message {
+        match message {
+            Message::Text(text) => Message::text(self.json_or_text(text)),
+            Message::Binary(_) => message.clone(),
+        }
+    }
+
+    /// Pseudonymizes a JSON document, falling back to plain text rules if it isn't one.
+    pub fn json_or_text(&self, text: &str) -> String {
+        match serde_json::from_str::<Value>(text) {
+            Ok(mut value) if value.is_object() || value.is_array() => {
+                self.value(&mut value);
+                value.to_string()
+            },
+            _ => self.text(text)
+        }
+    }
+
+    pub fn value(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                for (name, value) in object.iter_mut() {
+                    if self.fields.iter().any(|field| field == name) {
+                        *value = self.field(value);
+                    } else {
+                        self.value(value);
+                    }
+                }
+            },
+            Value::Array(values) => values.iter_mut().for_each(|value| self.value(value)),
+            Value::String(text) => *text = self.text(text),
+            _ => {}
+        }
+    }
+
+    pub fn text(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        if let Some(emails) = &self.emails {
+            text = emails.replace_all(&text, |email: &Captures| {
+                format!("user-{}@anonymized.invalid", hex::encode(&self.digest("email", &email[0])[..5]))
+            }).into_owned();
+        }
+        if let Some(ips) = &self.ips {
+            text = ips.replace_all(&text, |ip: &Captures| {
+                let digest = self.digest("ip", &ip[0]);
+                format!("10.{}.{}.{}", digest[0], digest[1], digest[2])
+            }).into_owned();
+        }
+        text
+    }
+
+    /// Pseudonym of a field value of the same type, so that the document stays valid for consumers.
+    fn field(&self, value: &Value) -> Value {
+        let digest = self.digest("field", &value.to_string());
+        match value {
+            Value::Null => Value::Null,
+            Value::Number(_) => {
+                let mut number = [0; 4];
+                number.copy_from_slice(&digest[..4]);
+                Value::from(u32::from_be_bytes(number))
+            },
+            Value::String(_) => Value::String(format!("anon-{}", hex::encode(&digest[..6]))),
+            _ => Value::String(format!("anon-{}", hex::encode(&digest[..6]))),
+        }
+    }
+
+    fn digest(&self, kind: &str, original: &str) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(kind.as_bytes());
+        mac.update(b":");
+        mac.update(original.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn anonymizer(rules: &str) -> Anonymizer {
+        Anonymizer::parse(rules, Some(b"key".to_vec())).unwrap()
+    }
+
+    #[test]
+    fn fields_get_pseudonyms_of_their_type() {
+        let anonymizer = anonymizer("user,account");
+        let anonymized = anonymizer.json_or_text(
+            "{\"user\":\"alice\",\"account\":42,\"items\":[{\"user\":\"bob\"}],\"type\":\"order\",\"note\":null}");
+        let value: Value = serde_json::from_str(&anonymized).unwrap();
+        assert!(value["user"].as_str().unwrap().starts_with("anon-"));
+        assert!(value["account"].is_u64());
+        assert_ne!(value["items"][0]["user"], value["user"]);
+        assert_eq!((&value["type"], &value["note"]), (&Value::from("order"), &Value::Null));
+        // The same value gets the same pseudonym, unless the key differs
+        assert_eq!(anonymizer.json_or_text("{\"user\":\"alice\"}"), format!("{{\"user\":{}}}", value["user"]));
+        let other = Anonymizer::parse("user", Some(b"other".to_vec())).unwrap();
+        assert_ne!(other.json_or_text("{\"user\":\"alice\"}"), anonymizer.json_or_text("{\"user\":\"alice\"}"));
+    }
+
+    #[test]
+    fn emails_and_addresses_are_replaced_in_any_text() {
+        let anonymizer = anonymizer("email, ip");
+        let text = anonymizer.text("alice@example.com logged in from 192.168.1.20, not 999.1.1.1");
+        let pattern = Regex::new(
+            r"^user-[0-9a-f]{10}@anonymized\.invalid logged in from 10\.\d+\.\d+\.\d+, not 999\.1\.1\.1$").unwrap();
+        assert!(pattern.is_match(&text), "{}", text);
+        assert_eq!(anonymizer.text("alice@example.com"), anonymizer.text("alice@example.com"));
+        // Strings in JSON are texts too, binary messages are left alone
+        assert!(anonymizer.json_or_text("[\"alice@example.com\"]").contains("@anonymized.invalid"));
+        assert_eq!(anonymizer.message(&Message::binary(b"alice@example.com".to_vec())),
+            Message::binary(b"alice@example.com".to_vec()));
+        assert_eq!(Anonymizer::parse("email,,ip", None).err().unwrap(), "Empty anonymization rule in email,,ip");
+    }
+}
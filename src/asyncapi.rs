@@ -0,0 +1,121 @@
+use serde_json::{json, Map, Value};
+use url::Url;
+use ws::Message;
+
+use std::collections::BTreeMap;
+
+use crate::bundle::Recorded;
+use crate::closecodes::Leg;
+use crate::schema::{self, Shape};
+
+/// Version of the AsyncAPI specification of exported documents.
+pub const VERSION: &str = "2.6.0";
+
+/// Examples kept for every message.
+const EXAMPLES: usize = 3;
+
+/// Messages of one kind sent by one side.
+#[derive(Default)]
+struct Observed {
+    count: usize,
+    shape: Shape,
+    examples: Vec<Value>,
+}
+
+/// Name of a message in the document, like `client-subscribe` or `server-text`:
+/// the side sending it and the kind of JSON messages.
+pub fn message_name(from: Leg, message: &Message) -> String {
+    let kind = match message {
+        Message::Binary(_) => "binary".to_string(),
+        Message::Text(text) => match serde_json::from_str::<Value>(text) {
+            Ok(value) => schema::kind(&value).unwrap_or_else(|| "json".to_string()),
+            Err(_) => "text".to_string(),
+        },
+    };
+    format!("{}-{}", from, kind)
+}
+
+/// Payload of a message as it is described by the schemas.
+pub fn payload(message: &Message) -> Value {
+    match message {
+        Message::Text(text) => serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.clone())),
+        Message::Binary(data) => Value::String(format!("<{} bytes>", data.len())),
+    }
+}
+
+/// Describes the observed traffic as an AsyncAPI document: the upstream as the server,
+/// its path as the channel, where clients publish and subscribe to the messages seen,
+/// each with the schema of its payload inferred from all of them and a few examples.
+pub fn export(messages: &[Recorded], upstream: Option<&Url>, session: &str) -> Value {
+    let mut observed: BTreeMap<String, (Leg, Observed)> = BTreeMap::new();
+    for recorded in messages.iter() {
+        let (_, observed) = observed.entry(message_name(recorded.from, &recorded.message))
+            .or_insert_with(|| (recorded.from, Observed::default()));
+        observed.count += 1;
+        // Binary messages are only counted
+        if let Message::Text(_) = &recorded.message {
+            let payload = payload(&recorded.message);
+            observed.shape.add(&payload);
+            if observed.examples.len() < EXAMPLES && !observed.examples.contains(&payload) {
+                observed.examples.push(payload);
+            }
+        }
+    }
+
+    let mut components = Map::new();
+    let mut published = vec![];
+    let mut subscribed = vec![];
+    for (name, (from, observed)) in observed.iter() {
+        let payload = match observed.examples.is_empty() {
+            true => json!({ "type": "string", "format": "binary" }),
+            false => observed.shape.schema(),
+        };
+        let examples: Vec<Value> = observed.examples.iter()
+            .map(|example| json!({ "payload": example }))
+            .collect();
+        components.insert(name.clone(), json!({
+            "name": name,
+            "summary": format!("Sent by the {}, seen {} times", from, observed.count),
+            "payload": payload,
+            "examples": examples,
+        }));
+
+        let reference = json!({ "$ref": format!("#/components/messages/{}", name) });
+        match from {
+            // The document describes the upstream, so clients publish to it
+            Leg::Client => published.push(reference),
+            Leg::Server => subscribed.push(reference),
+        }
+    }
+
+    let mut channel = Map::new();
+    if !published.is_empty() {
+        channel.insert("publish".to_string(), json!({ "message": { "oneOf": published } }));
+    }
+    if !subscribed.is_empty() {
+        channel.insert("subscribe".to_string(), json!({ "message": { "oneOf": subscribed } }));
+    }
+    let (channel_name, host, servers) = match upstream {
+        Some(upstream) => {
+            let host = match upstream.port() {
+                Some(port) => format!("{}:{}", upstream.host_str().unwrap_or_default(), port),
+                None => upstream.host_str().unwrap_or_default().to_string(),
+            };
+            let servers = json!({ "upstream": { "url": host, "protocol": upstream.scheme() } });
+            (upstream.path().to_string(), host, servers)
+        },
+        None => ("/".to_string(), "WebSocket".to_string(), json!({})),
+    };
+
+    json!({
+        "asyncapi": VERSION,
+        "info": {
+            "title": format!("{} API", host),
+            "version": format!("observed-{}", session),
+            "description": format!("Inferred by ws-proxy from {} messages of session {}", messages.len(), session),
+        },
+        "servers": servers,
+        "channels": { channel_name: channel },
+        "components": { "messages": components },
+    })
+}
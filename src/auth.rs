@@ -0,0 +1,338 @@
+use url::Url;
+use chrono::{DateTime, Utc};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use std::any::Any;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub type AuthError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Longest wait for a provider to give credentials, unless it tells otherwise.
+pub const CREDENTIALS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Credentials which are attached to the upstream handshake,
+/// either as extra request headers or as extra query parameters of the url.
+#[derive(Clone, Debug)]
+pub enum HeadersOrQuery {
+    Headers(Vec<(String, String)>),
+    Query(Vec<(String, String)>),
+}
+
+impl HeadersOrQuery {
+    /// Returns the url to connect to and the headers to add to the handshake request.
+    pub fn apply(self, url: &Url) -> (Url, Vec<(String, String)>) {
+        match self {
+            HeadersOrQuery::Headers(headers) => (url.clone(), headers),
+            HeadersOrQuery::Query(pairs) => {
+                let mut url = url.clone();
+                if !pairs.is_empty() {
+                    url.query_pairs_mut().extend_pairs(pairs);
+                }
+                (url, vec![])
+            }
+        }
+    }
+}
+
+/// Source of credentials for the upstream leg.
+/// It is asked right before every connection to the server,
+/// so implementations may fetch or refresh tokens here.
+///
+/// The future is polled with `futures::executor::block_on` on a thread of its own, away
+/// from the event loop of the proxy and outside of any tokio runtime: a provider doing its
+/// IO with tokio (or reqwest, hyper and the like) has to enter a runtime it owns, e.g. with
+/// `Handle::block_on` or by spawning onto it and awaiting the join handle. A provider which
+/// panics or doesn't answer within its `timeout` fails the attempt with the auth diagnosis,
+/// and the attempt is made again after the backoff delay, while the late provider is left
+/// to finish on its thread.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    async fn credentials(&self, upstream: &Url) -> Result<HeadersOrQuery, AuthError>;
+
+    /// Longest wait for the credentials of one attempt.
+    fn timeout(&self) -> Duration {
+        CREDENTIALS_TIMEOUT
+    }
+}
+
+/// Asks the provider for credentials on a thread of its own, within its timeout.
+pub(crate) fn obtain(provider: Arc<dyn AuthProvider>, upstream: &Url) -> Result<HeadersOrQuery, String> {
+    let timeout = provider.timeout();
+    let (answer_tx, answer_rx) = mpsc::channel();
+    let url = upstream.clone();
+    thread::Builder::new()
+        .name("auth".to_string())
+        .spawn(move || {
+            let answer = panic::catch_unwind(AssertUnwindSafe(|| {
+                futures::executor::block_on(provider.credentials(&url))
+            }));
+            answer_tx.send(answer).ok();
+        })
+        .map_err(|e| format!("can't start asking for credentials: {}", e))?;
+    match answer_rx.recv_timeout(timeout) {
+        Ok(Ok(credentials)) => credentials.map_err(|e| format!("failed to obtain credentials: {}", e)),
+        Ok(Err(panic)) => Err(format!("the credentials provider panicked: {}", panic_message(&*panic))),
+        Err(_) => Err(format!("no credentials were given within {:?}", timeout)),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (_, Some(message)) => message,
+        _ => "no message",
+    }
+}
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const PRESIGN_EXPIRES_SECONDS: u32 = 300;
+
+/// AWS service which is behind the upstream url.
+/// API Gateway WebSocket APIs accept presigned query parameters,
+/// AppSync real-time endpoints expect a base64-encoded signed header set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AwsService {
+    ExecuteApi,
+    AppSync,
+}
+
+impl AwsService {
+    fn signing_name(self) -> &'static str {
+        match self {
+            AwsService::ExecuteApi => "execute-api",
+            AwsService::AppSync => "appsync",
+        }
+    }
+}
+
+pub struct AwsCredentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    pub fn from_env() -> std::result::Result<Self, String> {
+        let access_key = env::var("AWS_ACCESS_KEY_ID")
+            .map_err(|_| "AWS_ACCESS_KEY_ID is not set".to_string())?;
+        let secret_key = env::var("AWS_SECRET_ACCESS_KEY")
+            .map_err(|_| "AWS_SECRET_ACCESS_KEY is not set".to_string())?;
+        let session_token = env::var("AWS_SESSION_TOKEN").ok()
+            .filter(|token| !token.is_empty());
+
+        Ok(AwsCredentials { access_key, secret_key, session_token })
+    }
+}
+
+/// Signer of the upstream handshake with AWS Signature Version 4.
+pub struct SigV4 {
+    region: String,
+    service: AwsService,
+    credentials: AwsCredentials,
+}
+
+impl SigV4 {
+    /// Parses `<region>[:<service>]`, where service is `execute-api` (default) or `appsync`.
+    /// Credentials are taken from the standard AWS environment variables.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let mut parts = spec.splitn(2, ':');
+        let region = parts.next().unwrap_or("").to_string();
+        if region.is_empty() {
+            return Err(format!("Region is missing in {}", spec));
+        }
+
+        let service = match parts.next() {
+            None | Some("execute-api") => AwsService::ExecuteApi,
+            Some("appsync") => AwsService::AppSync,
+            Some(other) => return Err(format!("Unsupported AWS service {}", other)),
+        };
+
+        Ok(SigV4 {
+            region,
+            service,
+            credentials: AwsCredentials::from_env()?,
+        })
+    }
+
+    /// WebSocket subprotocol which the signed endpoint requires, if any.
+    pub fn subprotocol(&self) -> Option<&'static str> {
+        match self.service {
+            AwsService::ExecuteApi => None,
+            AwsService::AppSync => Some("graphql-ws"),
+        }
+    }
+
+    /// Returns authentication query parameters to be appended to the url.
+    /// The signature is valid only for a short time, so it must be computed right before connecting.
+    pub fn sign(&self, url: &Url, now: DateTime<Utc>) -> Vec<(String, String)> {
+        match self.service {
+            AwsService::ExecuteApi => self.presign(url, now),
+            AwsService::AppSync => self.sign_appsync(url, now),
+        }
+    }
+
+    fn presign(&self, url: &Url, now: DateTime<Utc>) -> Vec<(String, String)> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = self.scope(now);
+
+        let mut added: Vec<(String, String)> = vec![
+            ("X-Amz-Algorithm".into(), ALGORITHM.into()),
+            ("X-Amz-Credential".into(), format!("{}/{}", self.credentials.access_key, scope)),
+            ("X-Amz-Date".into(), amz_date.clone()),
+            ("X-Amz-Expires".into(), PRESIGN_EXPIRES_SECONDS.to_string()),
+            ("X-Amz-SignedHeaders".into(), "host".into()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            added.push(("X-Amz-Security-Token".into(), token.clone()));
+        }
+
+        let mut query: Vec<(String, String)> = url.query_pairs()
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect();
+        query.extend(added.iter().cloned());
+
+        let canonical_request = format!("GET\n{}\n{}\nhost:{}\n\nhost\n{}",
+            canonical_uri(url.path()), canonical_query(&query), host_header(url), sha256_hex(b""));
+
+        let signature = self.signature(&amz_date, &scope, &canonical_request, now);
+        added.push(("X-Amz-Signature".into(), signature));
+        added
+    }
+
+    /// AppSync real-time endpoints authorize the handshake with headers,
+    /// signed as if they were sent with a `POST /graphql/connect` to the GraphQL api host.
+    fn sign_appsync(&self, url: &Url, now: DateTime<Utc>) -> Vec<(String, String)> {
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let scope = self.scope(now);
+        let host = host_header(url).replace("appsync-realtime-api", "appsync-api");
+        let body = b"{}";
+
+        let mut headers: Vec<(String, String)> = vec![
+            ("accept".into(), "application/json, text/javascript".into()),
+            ("content-encoding".into(), "amz-1.0".into()),
+            ("content-type".into(), "application/json; charset=UTF-8".into()),
+            ("host".into(), host),
+            ("x-amz-date".into(), amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token".into(), token.clone()));
+        }
+
+        let signed_headers = headers.iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers.iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        let canonical_request = format!("POST\n{}\n\n{}\n{}\n{}",
+            canonical_uri("/graphql/connect"), canonical_headers, signed_headers, sha256_hex(body));
+
+        let signature = self.signature(&amz_date, &scope, &canonical_request, now);
+        let authorization = format!("{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.credentials.access_key, scope, signed_headers, signature);
+
+        let mut header = serde_json::Map::new();
+        for (name, value) in headers {
+            header.insert(name, serde_json::Value::String(value));
+        }
+        header.insert("Authorization".into(), serde_json::Value::String(authorization));
+        let header = serde_json::Value::Object(header).to_string();
+
+        vec![
+            ("header".into(), BASE64.encode(header)),
+            ("payload".into(), BASE64.encode(body)),
+        ]
+    }
+
+    fn scope(&self, now: DateTime<Utc>) -> String {
+        format!("{}/{}/{}/aws4_request",
+            now.format("%Y%m%d"), self.region, self.service.signing_name())
+    }
+
+    fn signature(&self, amz_date: &str, scope: &str, canonical_request: &str, now: DateTime<Utc>) -> String {
+        let string_to_sign = format!("{}\n{}\n{}\n{}",
+            ALGORITHM, amz_date, scope, sha256_hex(canonical_request.as_bytes()));
+
+        let secret = format!("AWS4{}", self.credentials.secret_key);
+        let key = hmac(secret.as_bytes(), now.format("%Y%m%d").to_string().as_bytes());
+        let key = hmac(&key, self.region.as_bytes());
+        let key = hmac(&key, self.service.signing_name().as_bytes());
+        let key = hmac(&key, b"aws4_request");
+
+        hex::encode(hmac(&key, string_to_sign.as_bytes()))
+    }
+}
+
+#[async_trait]
+impl AuthProvider for SigV4 {
+    async fn credentials(&self, upstream: &Url) -> Result<HeadersOrQuery, AuthError> {
+        Ok(HeadersOrQuery::Query(self.sign(upstream, Utc::now())))
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn host_header(url: &Url) -> String {
+    let host = url.host_str().unwrap_or("");
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Path segments are encoded once more on top of the encoding already present in the url,
+/// as required for every service except S3.
+fn canonical_uri(path: &str) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.split('/')
+        .map(uri_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn canonical_query(pairs: &[(String, String)]) -> String {
+    let mut encoded: Vec<(String, String)> = pairs.iter()
+        .map(|(k, v)| (uri_encode(k), uri_encode(v)))
+        .collect();
+    encoded.sort();
+
+    encoded.iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn uri_encode(raw: &str) -> String {
+    let mut encoded = String::with_capacity(raw.len());
+    for byte in raw.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            },
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
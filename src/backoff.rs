@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+/// Delays between attempts to connect to the server again after its connection was lost:
+/// doubling from the first one up to the longest, each cut by a random part of its half,
+/// so that proxies restarted along with a server don't all come back at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    pub first: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { first: Duration::from_millis(500), max: Duration::from_secs(30) }
+    }
+}
+
+impl Backoff {
+    /// Parses `<first>[,<longest>]` in seconds, like `0.5,30`.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        let seconds = |value: &str| value.trim().parse::<f64>().ok()
+            .filter(|seconds| *seconds > 0.0)
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .ok_or_else(|| format!("Invalid reconnection delays {}, expected like 0.5,30", value));
+        let (first, max) = match value.split_once(',') {
+            Some((first, max)) => (seconds(first)?, seconds(max)?),
+            None => (seconds(value)?, Backoff::default().max),
+        };
+        if max < first {
+            return Err(format!("The longest reconnection delay is shorter than the first one in {}", value));
+        }
+        Ok(Backoff { first, max })
+    }
+
+    /// Delay before the attempt, counted from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self.first.saturating_mul(2u32.saturating_pow(attempt)).min(self.max);
+        ceiling / 2 + ceiling.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_are_parsed() {
+        let longest = Duration::from_secs(30);
+        assert_eq!(Backoff::parse("0.5,30"), Ok(Backoff { first: Duration::from_millis(500), max: longest }));
+        assert_eq!(Backoff::parse("2"), Ok(Backoff { first: Duration::from_secs(2), max: Duration::from_secs(30) }));
+        assert!(Backoff::parse("5,1").is_err());
+        for invalid in ["0", "-1", "nan", "inf", "1e30", "1,1e30", "soon"] {
+            assert!(Backoff::parse(invalid).is_err(), "{} is parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_longest() {
+        let backoff = Backoff { first: Duration::from_secs(1), max: Duration::from_secs(8) };
+        for (attempt, ceiling) in [(0, 1), (1, 2), (2, 4), (3, 8), (10, 8), (u32::MAX, 8)] {
+            let delay = backoff.delay(attempt);
+            let ceiling = Duration::from_secs(ceiling);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "attempt {} waits {:?}", attempt, delay);
+        }
+    }
+}
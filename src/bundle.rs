@@ -0,0 +1,311 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde_json::Value;
+use ws::{Builder, CloseCode, Handshake, Message, Result, Sender};
+use ws::util::Token;
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use crate::clock::Clock;
+use crate::closecodes::Leg;
+use crate::encryption::Encryption;
+use crate::manifest::MANIFEST;
+use crate::normalize::Normalization;
+use crate::overhead::OVERHEAD;
+use crate::replay::Timing;
+use crate::session;
+
+/// Summary of the session, generated while bundling.
+pub const REPORT: &str = "report.txt";
+
+/// Packs a session directory into a single gzipped tar archive, which contains
+/// the capture, the index with handshakes, the command line, the overhead measured
+/// and a report.
+pub fn create(session_dir: &Path, output: &Path) -> std::result::Result<(), String> {
+    let id = session_dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| format!("{} is not a session directory", session_dir.display()))?;
+    let index = session_dir.join(session::INDEX);
+    if !index.is_file() && !Encryption::path(&index).is_file() {
+        return Err(format!("{} is not a session directory", session_dir.display()));
+    }
+
+    let file = File::create(output).map_err(|e| format!("Can't create {}: {}", output.display(), e))?;
+    let mut archive = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    for name in [session::INDEX, session::CAPTURE, session::CONFIG, session::CLOSE_CODES, MANIFEST, OVERHEAD].iter() {
+        let path = session_dir.join(name);
+        for path in [Encryption::path(&path), path].iter() {
+            if path.is_file() {
+                let name = path.file_name().unwrap().to_string_lossy();
+                archive.append_path_with_name(path, format!("{}/{}", id, name))
+                    .map_err(|e| format!("Can't add {}: {}", path.display(), e))?;
+            }
+        }
+    }
+
+    let report = report(&id, session_dir);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(report.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp() as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, format!("{}/{}", id, REPORT), report.as_bytes())
+        .map_err(|e| format!("Can't add the report: {}", e))?;
+
+    archive.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .map_err(|e| format!("Can't write {}: {}", output.display(), e))?;
+    Ok(())
+}
+
+fn report(id: &str, session_dir: &Path) -> String {
+    let records = |name: &str| -> Vec<Value> {
+        fs::read_to_string(session_dir.join(name)).unwrap_or_default()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    };
+    let index = records(session::INDEX);
+    let capture = records(session::CAPTURE);
+
+    let count = |records: &[Value], key: &str, value: &str| records.iter()
+        .filter(|record| record[key] == value)
+        .count();
+    let session = index.iter().find(|record| record["event"] == "session");
+    let field = |name: &str| session
+        .and_then(|record| record[name].as_str())
+        .unwrap_or("unknown")
+        .to_string();
+
+    let mut report = format!("Session {}\nUpstream: {}\nStarted: {}\n\
+        Client connections: {}\nMessages: {} from clients, {} from the server\n\n",
+        id, field("upstream"), field("time"),
+        index.iter().filter(|record| record["event"] == "open" && record["role"] == "client").count(),
+        count(&capture, "from", "client"), count(&capture, "from", "server"));
+    report.push_str(&fs::read_to_string(session_dir.join(session::CLOSE_CODES))
+        .unwrap_or_else(|_| "Close codes: not recorded".to_string()));
+    report.push('\n');
+    report
+}
+
+/// A forwarded message restored from a capture.
+pub struct Recorded {
+    /// Message id from the capture, missing in captures older than message ids.
+    pub id: Option<String>,
+    pub time: Option<DateTime<Utc>>,
+    /// Hop of the proxy in a chain which captured the message, set with --hop.
+    pub hop: Option<String>,
+    pub from: Leg,
+    pub message: Message,
+}
+
+/// Contents of a bundle needed to replay it.
+pub struct Bundle {
+    pub id: String,
+    pub report: String,
+    pub messages: Vec<Recorded>,
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> std::result::Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Can't open {}: {}", path.display(), e))?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+        let mut bundle = Bundle { id: String::new(), report: String::new(), messages: vec![] };
+        let entries = archive.entries().map_err(|e| format!("{} is not a bundle: {}", path.display(), e))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|e| format!("{} is damaged: {}", path.display(), e))?;
+            let name = entry.path().map_err(|e| e.to_string())?.into_owned();
+
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|e| format!("{} is damaged: {}", path.display(), e))?;
+            match name.file_name().and_then(|name| name.to_str()) {
+                Some(session::CAPTURE) => bundle.messages = captured(&contents),
+                Some(REPORT) => bundle.report = contents,
+                _ => {}
+            }
+            if bundle.id.is_empty() {
+                if let Some(id) = name.iter().next() {
+                    bundle.id = id.to_string_lossy().into_owned();
+                }
+            }
+        }
+
+        Ok(bundle)
+    }
+}
+
+/// Forwarded messages of a capture, other events are skipped.
+pub fn captured(capture: &str) -> Vec<Recorded> {
+    capture.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter_map(|record| recorded(&record))
+        .collect()
+}
+
+/// Forwarded message of a capture record, if it is one.
+pub fn recorded(record: &Value) -> Option<Recorded> {
+    let from = Leg::parse(record["from"].as_str()?).ok()?;
+    let data = record["data"].as_str()?;
+    let message = match record["type"].as_str()? {
+        "text" => Message::text(data),
+        "binary" => Message::binary(BASE64.decode(data).ok()?),
+        _ => return None
+    };
+    let id = record["id"].as_str().map(String::from);
+    let time = record["time"].as_str()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.with_timezone(&Utc));
+    let hop = record["hop"].as_str().map(String::from);
+    Some(Recorded { id, time, hop, from, message })
+}
+
+/// Messages of the server which followed a message of a client.
+pub(crate) struct Step {
+    pub expected: Option<Recorded>,
+    pub replies: Vec<Recorded>,
+}
+
+pub(crate) fn steps(messages: Vec<Recorded>) -> Vec<Step> {
+    let mut steps = vec![Step { expected: None, replies: vec![] }];
+    for recorded in messages {
+        match recorded.from {
+            Leg::Client => steps.push(Step { expected: Some(recorded), replies: vec![] }),
+            Leg::Server => steps.last_mut().unwrap().replies.push(recorded),
+        }
+    }
+    steps
+}
+
+/// Replays the bundle to every client connected to the port: messages of the server
+/// sent before the first message of a client are sent right away, the following ones
+/// after each message received from the client, step by step as they were captured.
+/// With timing other than max speed messages of a step are sent with their captured gaps
+/// on the clock.
+pub fn serve(bundle: Bundle, port: u16, timing: Timing, normalization: Normalization,
+             clock: Arc<dyn Clock>) -> std::result::Result<(), String> {
+    let steps = Arc::new(steps(bundle.messages));
+    info!("Replaying bundle {} in {} steps", bundle.id, steps.len());
+
+    let ws = Builder::new()
+        .build(|out: Sender| Replay {
+            out,
+            steps: steps.clone(),
+            position: 0,
+            timing,
+            clock: clock.clone(),
+            pending: VecDeque::new(),
+            normalization: normalization.clone(),
+        })
+        .map_err(|e| e.to_string())?;
+    match ws.listen(SocketAddr::from(([127,0,0,1], port))) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string())
+    }
+}
+
+struct Replay {
+    out: Sender,
+    steps: Arc<Vec<Step>>,
+    position: usize,
+    timing: Timing,
+    clock: Arc<dyn Clock>,
+    /// Messages of the current step waiting for their gaps, in order.
+    pending: VecDeque<Message>,
+    /// Form messages of the client are compared with the captured ones in.
+    normalization: Normalization,
+}
+
+impl Replay {
+    fn play(&mut self) {
+        // Messages of the previous step still waiting are due once the client goes on
+        for message in self.pending.drain(..) {
+            self.out.send(message).unwrap_or_else(|e| {
+                error!("Error: {}", e);
+            });
+        }
+        let step = &self.steps[self.position];
+        let mut previous = step.expected.as_ref();
+        let mut due = Duration::ZERO;
+        for reply in step.replies.iter() {
+            due += self.timing.gap(previous, reply);
+            previous = Some(reply);
+            if due.is_zero() {
+                self.out.send(reply.message.clone()).unwrap_or_else(|e| {
+                    error!("Error: {}", e);
+                });
+                continue;
+            }
+            self.pending.push_back(reply.message.clone());
+            self.clock.wake(&self.out, due, Token(self.position)).unwrap_or_else(|e| {
+                error!("Error: {}", e);
+            });
+        }
+        println!("[connection id: {}] step {}/{}: {} messages of the server sent",
+            self.out.connection_id(), self.position, self.steps.len() - 1, step.replies.len());
+        self.position += 1;
+    }
+}
+
+impl ws::Handler for Replay {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.play();
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if self.position >= self.steps.len() {
+            println!("[connection id: {}] the bundle is over, message is not answered: {}",
+                self.out.connection_id(), msg);
+            return Ok(());
+        }
+
+        if let Some(expected) = &self.steps[self.position].expected {
+            let differs = match (&expected.message, &msg) {
+                (Message::Text(expected), Message::Text(received)) =>
+                    self.normalization.text(expected) != self.normalization.text(received),
+                (expected, received) => expected != received,
+            };
+            if differs {
+                warn!("Message differs from the captured one");
+                println!("[connection id: {}] expected {}: {}\n[connection id: {}] received: {}",
+                    self.out.connection_id(), expected.id.as_deref().unwrap_or("message"), expected.message,
+                    self.out.connection_id(), msg);
+            }
+        }
+        self.play();
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        // Timeouts of steps played to the end already are stale
+        if event == Token(self.position.wrapping_sub(1)) {
+            if let Some(message) = self.pending.pop_front() {
+                self.out.send(message)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        println!("[connection id: {}] closed with {:?} at step {}/{}",
+            self.out.connection_id(), code, self.position.saturating_sub(1), self.steps.len() - 1);
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        error!("Error: {}", err);
+    }
+}
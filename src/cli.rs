@@ -0,0 +1,832 @@
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use url::Url;
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ws_proxy::alert::AlertRule;
+use ws_proxy::backoff::Backoff;
+use ws_proxy::closecodes::Leg;
+use ws_proxy::condition::Condition;
+use ws_proxy::deflate::DeflatePlan;
+use ws_proxy::gaps::Gaps;
+use ws_proxy::fault::FaultPlan;
+use ws_proxy::flood::FloodPlan;
+use ws_proxy::interleave::InterleavePlan;
+use ws_proxy::latency::Latency;
+use ws_proxy::logqueue::{LogFormat, LogNames, SyncPolicy};
+use ws_proxy::memory::{self, Shedding};
+use ws_proxy::normalize::NormalizeRule;
+use ws_proxy::overhead::Overhead;
+use ws_proxy::probe::ProbeResponse;
+use ws_proxy::profile::Profile;
+use ws_proxy::replay::Timing;
+use ws_proxy::retention;
+use ws_proxy::sampling::Rate;
+use ws_proxy::scaffold::Preset;
+use ws_proxy::segments::SplitRule;
+use ws_proxy::shutdown::ShutdownPlan;
+use ws_proxy::tags::{self, TagRule};
+use ws_proxy::throttle::ThrottlePlan;
+use ws_proxy::track;
+use ws_proxy::tunnel::Framing;
+use ws_proxy::upstreamloss::LossNotice;
+use ws_proxy::upstreamqueue::Overflow;
+
+/// This is a proxy, which dumps all messages passing through specified port.
+///
+/// Without a command the arguments are those of run, like ws-proxy <server-url> <proxy-port>.
+#[derive(Parser)]
+#[command(name = "ws-proxy", version, arg_required_else_help = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Proxy a server, dumping all messages passing through the proxy port
+    #[command(after_long_help = RUN_HELP)]
+    Run(Box<RunArgs>),
+    /// Start the proxy with the command line saved in a snapshot
+    ///
+    /// The snapshot is a file written with --state, which keeps being saved into.
+    Restore {
+        snapshot: PathBuf,
+    },
+    /// Ask for the settings and start the proxy with them
+    ///
+    /// wizard asks for the server url, the port, authentication and what to log, prints
+    /// the equivalent command line to be reused, and starts the proxy with it if asked to.
+    Wizard,
+    /// Check the proxy on this machine using a built-in echo server on the loopback interface
+    ///
+    /// Without --bench it only verifies that every message comes back through the proxy
+    /// unchanged and in order. With --bench it also measures round-trip latency and
+    /// throughput with and without the proxy, to show at which message rate the proxy
+    /// itself becomes the bottleneck. Log files are written to a temporary directory.
+    Selftest(SelftestArgs),
+    /// Diagnose why a server can't be proxied, before starting the proxy
+    ///
+    /// doctor resolves the host of the url, connects to it, makes the TLS handshake for
+    /// wss:// showing the certificate, and tries the WebSocket handshake as the proxy does,
+    /// with the Origin and User-Agent of a browser and offering common subprotocols. It
+    /// prints the outcome and timing of every check and what the failures likely mean.
+    /// It exits with 1 when the handshake of the proxy fails.
+    Doctor(DoctorArgs),
+    /// Stress test a server, to see how it or a gateway in front of it copes with abuse
+    #[command(subcommand)]
+    Stress(Stress),
+    /// Pack a session into a single file to be handed over
+    ///
+    /// bundle packs the session directory (a session id or a path) with the capture,
+    /// handshakes, command line and a report into a tar.gz.
+    Bundle {
+        /// Session id or directory
+        session: String,
+        /// Bundle to write, <session id>.bundle.tar.gz by default
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Play the server of a bundle to any client
+    ///
+    /// serve-bundle replays the bundle on the given port: the captured messages of the
+    /// server are sent step by step, after each message received from the client,
+    /// as fast as possible or with the gaps between them as captured, like replay.
+    /// Messages of the client differing from the captured ones are printed, compared
+    /// as JSON when they are and normalized with --normalize.
+    ServeBundle {
+        bundle: PathBuf,
+        port: u16,
+        /// Pace of the messages of the server
+        #[arg(long, value_name = "original|max-speed|scale=N", default_value = "max-speed",
+            value_parser = Timing::parse)]
+        timing: Timing,
+        /// Canonicalize messages before comparing them, like numbers,whitespace or quote:strip=$.ts
+        #[arg(long, value_name = "[TYPE:]STEP,...", value_parser = NormalizeRule::parse)]
+        normalize: Vec<NormalizeRule>,
+    },
+    /// Replay the messages of clients against a live server and compare its replies
+    ///
+    /// replay sends the captured messages of clients to the server, and its replies are
+    /// compared with the captured ones, as JSON where possible, with values at --ignore
+    /// expressions (like $..timestamp) tolerated. Every divergence is reported with the
+    /// ids of the messages, and the exit code is 1. With --serve <port> no server is needed:
+    /// the captured messages of the server are played to every client connecting to the
+    /// port instead, step by step after each message of the client, like serve-bundle.
+    /// Messages are sent as fast as the steps allow, --timing original keeps the gaps
+    /// between them as captured and --timing scale=<factor> multiplies the gaps by a factor
+    /// up to 1000, like scale=0.5 for twice as fast.
+    ///
+    /// --normalize canonicalizes messages before they are compared, for all messages
+    /// or those of a type (the type field of JSON, or event, op and the like) with
+    /// <type>:<steps>. Steps are numbers, writing 1.0 and 1e0 as 1, or numbers=<decimals>
+    /// rounding them, whitespace, trimming strings and collapsing whitespace in them,
+    /// and strip=<jsonpath>, removing the fields. Keys are compared sorted anyway.
+    Replay(ReplayArgs),
+    /// Write a script of the test server from a capture
+    ///
+    /// learn writes a script for --test-script from a captured session: a rule for every
+    /// kind of request, where values differing between requests of the kind become variables.
+    Learn {
+        /// Bundle or capture
+        source: PathBuf,
+        /// Script to write, printed by default
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Describe the traffic of a session as an AsyncAPI document
+    ///
+    /// asyncapi writes the document in YAML, or in JSON if the output ends with .json:
+    /// the upstream is the server and its path is the channel, messages are told apart
+    /// by the side sending them and their type field (or event, op, method, action, kind),
+    /// with payload schemas inferred from all of them and examples.
+    Asyncapi {
+        /// Session id, directory or capture
+        session: String,
+        /// Document to write, printed by default
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Check a recorded session against an AsyncAPI 2 document
+    ///
+    /// validate checks the messages of a session as --contract does while the proxy runs,
+    /// printing every violation. The exit code is 1 if there are any.
+    Validate {
+        /// Session id, directory or capture
+        session: String,
+        /// AsyncAPI 2 document in YAML or JSON
+        #[arg(long)]
+        contract: PathBuf,
+    },
+    /// Compare recorded sessions
+    #[command(subcommand)]
+    Analyze(Analyze),
+    /// Align the captures of chained proxies and report the delays between hops
+    ///
+    /// merge aligns the captures of all hops, given from the client side to the server side,
+    /// by hashes of the messages and reports how long they took between neighbouring hops,
+    /// with --output every message with its ids and times at all hops. Clocks of the hosts
+    /// are trusted, their skew adds to the delays.
+    Merge {
+        /// Sessions, directories or captures from the client side to the server side
+        #[arg(num_args = 2.., required = true)]
+        sessions: Vec<String>,
+        /// Merged capture to write
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Control a proxy started with --agent-port from here
+    ///
+    /// attach records every record of the agent's capture into a local session and shows it
+    /// like --console does. Rules like drop:server:"type":"ping" typed in, or given with
+    /// --rule, are applied by the agent: matching messages are captured but not forwarded.
+    /// clear removes all rules.
+    Attach {
+        /// ws:// or wss:// url of the agent port
+        url: Url,
+        /// File with the token of the agent
+        #[arg(long)]
+        agent_token: PathBuf,
+        /// Rule applied by the agent, like drop:server:<regex> or drop:expr:<expression>
+        #[arg(long)]
+        rule: Vec<String>,
+    },
+    /// Add or remove tags of captured messages
+    ///
+    /// tag changes tags of messages by their ids, the changes are appended to the capture.
+    Tag {
+        /// Session id, directory or capture
+        session: String,
+        /// Ids of the messages, like c3:1842
+        #[arg(required = true)]
+        messages: Vec<String>,
+        /// Tag to add
+        #[arg(long, value_parser = tag)]
+        add: Vec<String>,
+        /// Tag to remove
+        #[arg(long, value_parser = tag, required_unless_present = "add")]
+        remove: Vec<String>,
+    },
+    /// List captured messages with their tags
+    ///
+    /// grep lists the messages matching the condition, having all tags given with --tag
+    /// and none of --without.
+    Grep {
+        /// Session id, directory or capture
+        session: String,
+        /// Condition on the messages listed, a regex or expr:<expression>
+        #[arg(value_parser = Condition::parse)]
+        condition: Option<Condition>,
+        /// Tag the messages must have
+        #[arg(long, value_parser = tag)]
+        tag: Vec<String>,
+        /// Tag the messages must not have
+        #[arg(long, value_parser = tag)]
+        without: Vec<String>,
+        /// Saved view to show the messages through
+        #[arg(long)]
+        view: Option<String>,
+    },
+    /// Browse one capture, or two side by side, in the terminal
+    ///
+    /// inspect opens a capture with a pane of the selected message below. With two captures,
+    /// like of a failing run and a passing one, they are shown side by side and the selection
+    /// of one follows the other, Tab swaps which one is scrolled: by default to the message
+    /// with the same number, with --by to the first message from the same side with the same
+    /// value of the JSONPath, like $.id, and with --offset to the message closest in time since
+    /// the start, with the second run shifted by the seconds. Counterparts with the same data
+    /// are highlighted green, differing ones red, compared as JSON when they are and
+    /// normalized with --normalize like by replay.
+    Inspect {
+        /// Sessions, directories or captures
+        #[arg(num_args = 1..=2, required = true)]
+        sessions: Vec<String>,
+        /// JSONPath of the values messages are matched by
+        #[arg(long, value_parser = jsonpath, conflicts_with = "offset")]
+        by: Option<String>,
+        /// Seconds the second run is shifted by, matching messages by time
+        #[arg(long, allow_negative_numbers = true)]
+        offset: Option<f64>,
+        /// Canonicalize messages before comparing them, like numbers,whitespace or quote:strip=$.ts
+        #[arg(long, value_name = "[TYPE:]STEP,...", value_parser = NormalizeRule::parse)]
+        normalize: Vec<NormalizeRule>,
+    },
+    /// Save, list or delete views
+    ///
+    /// Views are saved ways of looking at messages, kept in ws-proxy.views.yaml: which ones
+    /// are shown (matching the filter, from the side, with the tags), what is highlighted in
+    /// them and which JSON fields are shown instead of whole messages.
+    #[command(subcommand)]
+    View(ViewCommand),
+    /// Run a capture through the hooks of a rhai script
+    ///
+    /// The script is one for run --script: captured messages pass its on_client_message(msg)
+    /// and on_server_message(msg) hooks in order, which drop, replace or make up messages as
+    /// they would live. Once all have passed, report() of the script is called if it defines
+    /// one, with the state the hooks kept in this. The messages left are written with --output
+    /// as a new capture, or printed if nothing is reported, and the report is printed.
+    Process {
+        /// Session id, directory or capture
+        session: String,
+        /// Rhai script with message hooks
+        #[arg(long)]
+        script: PathBuf,
+        /// Capture to write
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Scaffold a working setup for a protocol
+    ///
+    /// init writes a script of the test server playing a scenario of the protocol, a process
+    /// script, views saved into ws-proxy.views.yaml, and ws-proxy.state.json with the command
+    /// line using them with --label, --tag and --alert rules, started with
+    /// restore ws-proxy.state.json. Existing files are overwritten only with --force.
+    Init {
+        /// json, graphql-ws or socket.io
+        #[arg(value_parser = Preset::parse, default_value = "json")]
+        preset: Preset,
+        /// Directory to write into
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+        /// Port of the proxy
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// Server to redirect to instead of the test server
+        #[arg(long)]
+        server: Option<String>,
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
+    },
+    /// List or check decoder and message processor plugins
+    ///
+    /// list shows the manifests of the plugins found, verify loads them as the proxy does,
+    /// checking their digests and interfaces. The exit code is 1 if any is invalid.
+    Plugins {
+        action: PluginsAction,
+        /// Directory of the plugins
+        #[arg(long, default_value = ws_proxy::plugins::PLUGINS)]
+        dir: PathBuf,
+    },
+    /// Check that the files of a session are complete and unmodified
+    ///
+    /// verify checks the files against manifest.json, written by a proxy started with
+    /// --sign-key, with the same key.
+    Verify {
+        /// Session id or directory
+        session: String,
+        /// File with the key the manifest was signed with
+        #[arg(long)]
+        sign_key: PathBuf,
+    },
+    /// Salvage the complete records of a damaged capture
+    ///
+    /// repair keeps all complete records and drops the torn ones. Without --output the
+    /// capture is repaired in place, keeping the damaged file next to it.
+    Repair {
+        capture: PathBuf,
+        /// Repaired capture to write
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+#[command(args_override_self = true)]
+pub struct RunArgs {
+    /// <server-url> <proxy-port>, or only <proxy-port> with --with-test-server
+    #[arg(value_name = "PARAMETERS", num_args = 0..=2)]
+    pub parameters: Vec<String>,
+    /// File with the parameters and flags, TOML or YAML
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Built-in flags for a protocol: graphql-ws, socketio, signalr or binance-ws
+    #[arg(long, value_name = "NAME", value_parser = Profile::parse)]
+    pub profile: Option<Profile>,
+    /// Redirect to a built-in server on a random local port
+    #[arg(long)]
+    pub with_test_server: bool,
+    /// Script of the test server, it echoes messages without one
+    #[arg(long, value_name = "FILE", requires = "with_test_server")]
+    pub test_script: Option<String>,
+    /// Proxy a Server-Sent Events endpoint at an http(s):// url instead of websockets
+    #[arg(long, conflicts_with = "with_test_server")]
+    pub sse: bool,
+    /// Tunnel a tcp:// or tls:// server instead of websockets, cutting the bytes into messages
+    #[arg(long, value_name = "FRAMING", value_parser = Framing::parse, conflicts_with_all = ["with_test_server", "sse"])]
+    pub tunnel: Option<Framing>,
+    /// Seconds before connecting to the server again, doubled up to the longest, like 0.5,30
+    #[arg(long, value_name = "FIRST[,LONGEST]", value_parser = Backoff::parse, conflicts_with = "no_reconnect")]
+    pub reconnect: Option<Backoff>,
+    /// Don't connect to the server again until a new client comes
+    #[arg(long)]
+    pub no_reconnect: bool,
+    /// Close clients when the connection to the server is lost, telling them why in the close
+    /// frame or also in a final JSON message
+    #[arg(long, value_name = "close|json", value_parser = LossNotice::parse, conflicts_with = "reconnect")]
+    pub on_upstream_loss: Option<LossNotice>,
+    /// Messages of clients held while the server isn't connected, like 1MB, 0 drops them
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    pub upstream_queue: Option<usize>,
+    /// What to do with messages beyond --upstream-queue, close by default with --strict-passthrough
+    #[arg(long, value_name = "drop-newest|drop-oldest|close", value_parser = Overflow::parse)]
+    pub upstream_overflow: Option<Overflow>,
+    /// Send matching messages of clients, like subscriptions, to the server again after a reconnect
+    #[arg(long, value_name = "CONDITION", value_parser = Condition::parse, conflicts_with = "on_upstream_loss")]
+    pub resubscribe: Vec<Condition>,
+    /// Sign the connection to the server with AWS Signature Version 4
+    #[arg(long, value_name = "REGION[:SERVICE]")]
+    pub aws_sigv4: Option<String>,
+    /// Subprotocol offered to the server and accepted from clients offering it
+    #[arg(long, value_name = "PROTOCOL")]
+    pub subprotocol: Vec<String>,
+
+    /// Verify that messages are forwarded unchanged and in order
+    #[arg(long)]
+    pub self_check: bool,
+    /// Refuse everything changing the traffic and stop at the first changed message
+    #[arg(long)]
+    pub strict_passthrough: bool,
+    /// Print memory usage every given number of seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub stats: Option<u64>,
+    /// Memory for buffered messages and log entries, like 64MB
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    pub max_memory: Option<usize>,
+    /// What to do beyond --max-memory
+    #[arg(long, value_name = "drop|close", value_parser = Shedding::parse)]
+    pub shed: Option<Shedding>,
+    /// Share of client connections captured, like 1/10
+    #[arg(long, value_name = "N/M", value_parser = Rate::parse)]
+    pub sample_connections: Option<Rate>,
+    /// Let :pause and :resume stop reading from clients, through a TCP relay
+    #[arg(long)]
+    pub pausable: bool,
+    /// Hold matching messages until they are forwarded, edited or dropped in the terminal
+    #[arg(long, value_name = "CONDITION", value_parser = Condition::parse)]
+    pub intercept: Vec<Condition>,
+    /// Answer plain HTTP requests on the proxy port with a status page or 426 Upgrade Required
+    #[arg(long, value_name = "status|426", value_parser = ProbeResponse::parse)]
+    pub probe_response: Option<ProbeResponse>,
+
+    /// Label of the session
+    #[arg(long, value_name = "KEY=VALUE", value_parser = label)]
+    pub label: Vec<(String, String)>,
+    /// End connections of a side in an unusual way
+    #[arg(long, value_name = "CLIENT|SERVER:SEQUENCE[@N]", value_parser = ShutdownPlan::parse)]
+    pub shutdown: Vec<ShutdownPlan>,
+    /// Send messages toward a side in two fragments with frames between them
+    #[arg(long, value_name = "CLIENT|SERVER:FRAME[,FRAME]...", value_parser = InterleavePlan::parse)]
+    pub interleave: Vec<InterleavePlan>,
+    /// Send binary messages to every connection of a side
+    #[arg(long, value_name = "CLIENT|SERVER:ZEROS|RANDOM:SIZE[@PER-SECOND]", value_parser = FloodPlan::parse)]
+    pub flood: Vec<FloodPlan>,
+    /// Drop, duplicate or corrupt forwarded messages with the probabilities
+    #[arg(long, value_name = "[CLIENT|SERVER:]FAULT=P[,FAULT=P]...", value_parser = FaultPlan::parse)]
+    pub fault: Vec<FaultPlan>,
+    /// Seed of the faults, to repeat those of a run
+    #[arg(long, value_name = "N", requires = "fault")]
+    pub fault_seed: Option<u64>,
+    /// Forward the messages of a direction later, by a fixed delay or one drawn from a range
+    #[arg(long, value_name = "CLIENT->SERVER|SERVER->CLIENT=DELAY[..DELAY]", value_parser = Latency::parse)]
+    pub delay: Vec<Latency>,
+    /// Limit the bandwidth of the link of each client, in a direction or in both
+    #[arg(long, value_name = "[CLIENT->SERVER|SERVER->CLIENT=]RATE", value_parser = ThrottlePlan::parse)]
+    pub throttle: Vec<ThrottlePlan>,
+    /// Negotiate permessage-deflate with a side, compressing and decompressing in between
+    #[arg(long, value_name = "CLIENT|SERVER[:PARAMETER[,PARAMETER]...]", value_parser = DeflatePlan::parse)]
+    pub deflate: Vec<DeflatePlan>,
+    /// Tell clients about traffic changed by the proxy with synthetic messages
+    #[arg(long)]
+    pub notify_clients: bool,
+
+    /// Port on all interfaces where websocket clients watch the traffic
+    #[arg(long, value_name = "PORT")]
+    pub observer_port: Option<u16>,
+    /// Port where a controller started with attach connects
+    #[arg(long, value_name = "PORT", requires = "agent_token")]
+    pub agent_port: Option<u16>,
+    /// File with the token the controller must present
+    #[arg(long, value_name = "FILE")]
+    pub agent_token: Option<PathBuf>,
+    /// Port on localhost for a browser devtools extension
+    #[arg(long, value_name = "PORT")]
+    pub devtools_port: Option<u16>,
+    /// Port on localhost of an HTTP API controlling the running proxy, and of its web UI
+    #[arg(long, value_name = "PORT")]
+    pub control_port: Option<u16>,
+    /// Port on all interfaces serving Prometheus metrics at /metrics
+    #[arg(long, value_name = "PORT")]
+    pub prometheus_port: Option<u16>,
+    /// Export spans of forwarded messages to an OpenTelemetry collector at the http:// url
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<Url>,
+
+    /// Encrypt logs, the capture and the index for the age recipient
+    #[arg(long, value_name = "AGE-RECIPIENT")]
+    pub encrypt_logs: Vec<String>,
+    /// Remove sessions older than the age or beyond the total size
+    #[arg(long, value_name = "AGE|SIZE")]
+    pub retain: Vec<String>,
+    /// Sign a manifest of the session files with the key from the file
+    #[arg(long, value_name = "FILE")]
+    pub sign_key: Option<PathBuf>,
+    /// Replace personal data with pseudonyms in logs: email, ip or JSON field names
+    #[arg(long, value_name = "RULE[,RULE]...")]
+    pub anonymize: Option<String>,
+    /// File with the key pseudonyms are derived with, random by default
+    #[arg(long, value_name = "FILE", requires = "anonymize")]
+    pub anonymize_key: Option<PathBuf>,
+    /// Cut larger payloads in logs and the capture
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    pub log_max_payload: Option<usize>,
+    /// Keep the full payloads cut by --log-max-payload in the blobs directory
+    #[arg(long, requires = "log_max_payload")]
+    pub log_blobs: bool,
+
+    /// Pretty-print JSON messages
+    #[arg(long)]
+    pub pretty_jsons: bool,
+    /// Detect the kind of every text message and pretty-print it accordingly
+    #[arg(long)]
+    pub pretty: bool,
+    /// Log messages matching the regex with the renderer
+    #[arg(long, value_name = "REGEX=RENDERER")]
+    pub render: Vec<String>,
+    /// Indent XML messages
+    #[arg(long)]
+    pub pretty_xml: bool,
+    /// Log only the values of the XPath expression for XML messages
+    #[arg(long, value_name = "EXPR")]
+    pub xpath: Vec<String>,
+
+    /// Write numbers found by the JSONPath into track.csv
+    #[arg(long, value_name = "JSONPATH", value_parser = jsonpath)]
+    pub track: Vec<String>,
+    /// Warn when the rule starts to hold
+    #[arg(long, value_name = "RULE", value_parser = AlertRule::parse)]
+    pub alert: Vec<AlertRule>,
+    /// Post every alert as JSON to the url
+    #[arg(long, value_name = "URL")]
+    pub alert_webhook: Option<Url>,
+    /// Capture pings, pongs and close frames along with the messages
+    #[arg(long)]
+    pub capture_frames: bool,
+    /// Append a digest of the traffic to digest.txt every interval, like 5m
+    #[arg(long, value_name = "INTERVAL", value_parser = retention::parse_interval)]
+    pub digest_every: Option<Duration>,
+    /// Post every digest as JSON to the url
+    #[arg(long, value_name = "URL", requires = "digest_every")]
+    pub digest_webhook: Option<Url>,
+    /// Append a snapshot of the metrics to metrics.jsonl every interval, like 1m
+    #[arg(long, value_name = "INTERVAL", value_parser = retention::parse_interval)]
+    pub metrics_every: Option<Duration>,
+    /// Record the time spent on messages in each stage of the proxy, all or a share like 1/100
+    #[arg(long, value_name = "all|N/M", value_parser = Overhead::parse_rate)]
+    pub overhead: Option<Rate>,
+    /// Tag matching messages when they are captured
+    #[arg(long, value_name = "TAG=CONDITION", value_parser = TagRule::parse)]
+    pub tag: Vec<TagRule>,
+    /// Start a new capture segment at the session boundary
+    #[arg(long, value_name = "message=CONDITION|close=CODE", value_parser = SplitRule::parse)]
+    pub split_on: Vec<SplitRule>,
+    /// Print messages through the saved view
+    #[arg(long, value_name = "NAME")]
+    pub view: Option<String>,
+    /// Record directions without messages for longer than the seconds
+    #[arg(long, value_name = "[CLIENT:|SERVER:]SECONDS", value_parser = Gaps::parse)]
+    pub gap: Option<(Option<Leg>, Duration)>,
+    /// Keep the runtime state in the file and load it at start
+    #[arg(long, value_name = "FILE")]
+    pub state: Option<PathBuf>,
+    /// When logs and the capture are forced to the disk
+    #[arg(long, value_name = "never|always|SECONDS", value_parser = SyncPolicy::parse)]
+    pub fsync: Option<SyncPolicy>,
+    /// Format of the client and server logs, text by default
+    #[arg(long, value_name = "text|ndjson", value_parser = LogFormat::parse)]
+    pub format: Option<LogFormat>,
+    /// Rotate the client and server logs when they reach the size
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    pub max_log_size: Option<usize>,
+    /// Rotated logs kept with --max-log-size, 5 by default
+    #[arg(long, value_name = "N", requires = "max_log_size")]
+    pub max_log_files: Option<usize>,
+    /// Log each client into its own ws-proxy.client-<id>.log
+    #[arg(long)]
+    pub log_per_connection: bool,
+    /// Directory of the client and server logs, created if missing
+    #[arg(long, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+    /// Name of the client and server logs with {session}, {role} and {id}
+    #[arg(long, value_name = "TEMPLATE", value_parser = LogNames::parse_template, conflicts_with = "log_per_connection")]
+    pub log_name: Option<String>,
+    /// Keep logs in memory, written only on SIGUSR1
+    #[arg(long)]
+    pub no_files: bool,
+    /// Memory for logs with --no-files, 64MB by default
+    #[arg(long, value_name = "SIZE", value_parser = memory::parse_size)]
+    pub no_files_limit: Option<usize>,
+    /// Print every message as a line instead of writing logs
+    #[arg(long)]
+    pub console: bool,
+    /// Seconds between rollups of --console, 10 by default
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub console_summary: Option<u64>,
+    /// Show the live traffic in a terminal UI instead of printing it
+    #[arg(long, conflicts_with_all = ["console", "console_summary"])]
+    pub tui: bool,
+    /// Validate messages against an AsyncAPI 2 document
+    #[arg(long, value_name = "FILE")]
+    pub contract: Option<PathBuf>,
+    /// Name of this proxy in a chain, annotated in the capture
+    #[arg(long, value_name = "NAME")]
+    pub hop: Option<String>,
+    /// Directory of decoder and message processor plugins to load, none are loaded without it
+    #[arg(long, value_name = "DIR")]
+    pub plugins: Option<PathBuf>,
+    /// Rhai script with on_client_message(msg) and on_server_message(msg) hooks modifying,
+    /// dropping and making up messages
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
+    /// Certificate chain in PEM serving wss:// to clients
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key of --tls-cert in PEM
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// CA bundle in PEM verifying a wss:// server instead of the system ones
+    #[arg(long, value_name = "FILE")]
+    pub upstream_ca: Option<PathBuf>,
+    /// Client certificate in PEM for servers requiring mutual TLS
+    #[arg(long, value_name = "FILE", requires = "upstream_key")]
+    pub upstream_cert: Option<PathBuf>,
+    /// Private key of --upstream-cert in PEM
+    #[arg(long, value_name = "FILE", requires = "upstream_cert")]
+    pub upstream_key: Option<PathBuf>,
+    /// Skip verification of the server certificate and host name
+    #[arg(long)]
+    pub insecure: bool,
+}
+
+#[derive(Args)]
+pub struct SelftestArgs {
+    /// Measure latency and throughput with and without the proxy
+    #[arg(long)]
+    pub bench: bool,
+    /// Messages sent
+    #[arg(long, value_name = "COUNT", default_value_t = 10_000)]
+    pub messages: usize,
+    /// Size of the messages
+    #[arg(long, value_name = "BYTES", default_value_t = 128)]
+    pub size: usize,
+    /// Pretty-print JSON messages in the logs
+    #[arg(long)]
+    pub pretty_jsons: bool,
+}
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    pub url: Url,
+    /// Header added to the handshakes
+    #[arg(long, value_name = "NAME:VALUE", value_parser = header)]
+    pub header: Vec<(String, String)>,
+    /// Subprotocol offered in the handshakes
+    #[arg(long, value_name = "PROTOCOL")]
+    pub protocol: Vec<String>,
+    /// CA bundle in PEM verifying a wss:// server instead of the system ones
+    #[arg(long, value_name = "FILE")]
+    pub upstream_ca: Option<PathBuf>,
+    /// Client certificate in PEM for servers requiring mutual TLS
+    #[arg(long, value_name = "FILE", requires = "upstream_key")]
+    pub upstream_cert: Option<PathBuf>,
+    /// Private key of --upstream-cert in PEM
+    #[arg(long, value_name = "FILE", requires = "upstream_cert")]
+    pub upstream_key: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Stress {
+    /// Trickle upgrade requests over many connections, like a slowloris attack
+    ///
+    /// The handshake test opens many TCP connections and trickles the upgrade request over
+    /// each of them one byte per interval, never completing it. Meanwhile, a regular handshake
+    /// is attempted every second to check whether other clients are still served. After the
+    /// duration it reports how many connections were accepted, closed or answered by the
+    /// server and how regular handshakes fared. Only ws:// urls are supported.
+    Handshake {
+        server_url: Url,
+        /// Connections opened
+        #[arg(long, value_name = "N", default_value_t = 200)]
+        connections: usize,
+        /// Milliseconds between bytes
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        interval: u64,
+        /// Seconds the test runs
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        duration: u64,
+    },
+}
+
+#[derive(Args)]
+pub struct ReplayArgs {
+    /// Bundle, capture or session
+    pub source: PathBuf,
+    #[arg(required_unless_present = "serve", conflicts_with = "serve")]
+    pub server_url: Option<Url>,
+    /// Play the server to clients connecting to the port instead
+    #[arg(long, value_name = "PORT")]
+    pub serve: Option<u16>,
+    /// JSONPath of values which may differ, like $..timestamp
+    #[arg(long, value_name = "JSONPATH")]
+    pub ignore: Vec<String>,
+    /// Canonicalize messages before comparing them, like numbers,whitespace or quote:strip=$.ts
+    #[arg(long, value_name = "[TYPE:]STEP,...", value_parser = NormalizeRule::parse)]
+    pub normalize: Vec<NormalizeRule>,
+    /// Seconds to wait for the replies after each message
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    pub timeout: u64,
+    /// Pace of the replayed messages
+    #[arg(long, value_name = "original|max-speed|scale=N", default_value = "max-speed",
+        value_parser = Timing::parse)]
+    pub timing: Timing,
+    /// Report to write
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum Analyze {
+    /// Compare the message types of two sessions
+    ///
+    /// drift reports message types which appeared or are gone, e.g. since last week, and
+    /// fields of the others which were added, removed, changed their types or stopped being
+    /// always present. The exit code is 1 then.
+    Drift {
+        /// Older session, directory or capture
+        old: String,
+        /// Newer session, directory or capture
+        new: String,
+    },
+    /// Chart the metrics of a session
+    ///
+    /// metrics prints the snapshots written with run --metrics-every: messages of each
+    /// side, bytes and open client connections since the previous snapshot, with a bar
+    /// of the messages. --csv writes the series as CSV for a spreadsheet instead.
+    Metrics {
+        /// Session id or directory
+        session: String,
+        /// Series of one connection, 0 for the server
+        #[arg(long, value_name = "ID")]
+        connection: Option<u32>,
+        /// Write CSV instead of a chart
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Chart the latency of requests by message type
+    ///
+    /// latency pairs every client message with the server message answering it: the first
+    /// one repeating its id, given in a field like id, requestId or correlationId, or else
+    /// the next one. For each message type of the requests it prints the number answered
+    /// and lost, percentiles of the latency and a heatmap of how the latencies spread over
+    /// buckets from 1 ms to 2.5 s, the slowest types first. A running session can be analyzed.
+    Latency {
+        /// Session id, directory or capture
+        session: String,
+        /// Field of JSON messages correlating responses with requests, instead of the usual ones
+        #[arg(long, value_name = "FIELD")]
+        correlate: Vec<String>,
+    },
+    /// Break down the time the proxy spent on messages by stage
+    ///
+    /// overhead prints the measurements written with run --overhead: for each stage a message
+    /// goes through in the proxy, decode, rules, script, log and send, percentiles of the time
+    /// spent in it and its share of all the time spent, then the same for whole messages.
+    Overhead {
+        /// Session id or directory
+        session: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ViewCommand {
+    /// Save a view, replacing one of the same name
+    Save {
+        name: String,
+        /// Condition on the messages shown
+        #[arg(long, value_name = "CONDITION")]
+        filter: Option<String>,
+        /// Side of the messages shown
+        #[arg(long, value_name = "client|server")]
+        from: Option<String>,
+        /// Tag the messages shown have
+        #[arg(long)]
+        tag: Vec<String>,
+        /// Pattern highlighted in the messages, or condition on the messages highlighted
+        #[arg(long, value_name = "CONDITION")]
+        highlight: Vec<String>,
+        /// JSON field shown instead of whole messages
+        #[arg(long, value_name = "JSONPATH")]
+        project: Vec<String>,
+    },
+    /// List the saved views
+    List,
+    /// Delete a saved view
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum PluginsAction {
+    List,
+    Verify,
+}
+
+/// The arguments with run inserted when no command is given, as the proxy was started
+/// before it had commands.
+pub fn arguments<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    let command = match args.get(1).and_then(|arg| arg.to_str()) {
+        Some(arg) => matches!(arg, "-h" | "--help" | "-V" | "--version" | "help")
+            || Cli::command().find_subcommand(arg).is_some(),
+        None => true,
+    };
+    if !command {
+        args.insert(1, OsString::from("run"));
+    }
+    args
+}
+
+fn label(value: &str) -> std::result::Result<(String, String), String> {
+    value.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Label {} must be in form key=value", value))
+}
+
+fn header(value: &str) -> std::result::Result<(String, String), String> {
+    value.split_once(':')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("Header {} must be in form name:value", value))
+}
+
+fn jsonpath(value: &str) -> std::result::Result<String, String> {
+    track::parse(value).map(|_| value.to_string())
+}
+
+fn tag(value: &str) -> std::result::Result<String, String> {
+    match tags::valid(value) {
+        true => Ok(value.to_string()),
+        false => Err(format!("Tag {} is invalid, tags are letters, digits, - and _", value)),
+    }
+}
+
+const RUN_HELP: &str =
+    "The only two parameters are a port number to listen and a websocket url\
+    \nto redirect messages to. If a message comes from the <server-url>, it is directed\
+    \nto every client connected to the debug proxy. Looping is forbidden.\n\
+    \nWhat the flags do in detail, and the commands typed into the terminal while the proxy\
+    \nruns, are described in docs/run.md: https://github.com/kirillt/ws-proxy/blob/master/docs/run.md";
@@ -0,0 +1,135 @@
+use chrono::{DateTime, Utc};
+use ws::Sender;
+use ws::util::Token;
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of time for features measuring or waiting for it,
+/// so that they can run on simulated time as well.
+pub trait Clock: Send + Sync {
+    /// Monotonic time for measuring intervals.
+    fn now(&self) -> Instant;
+    /// Wall-clock time for records.
+    fn utc(&self) -> DateTime<Utc>;
+    fn sleep(&self, duration: Duration);
+    /// Has the handler of the connection get the timeout event once the delay passes.
+    fn wake(&self, out: &Sender, delay: Duration, event: Token) -> std::result::Result<(), String>;
+}
+
+/// Real time of the machine.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+
+    fn wake(&self, out: &Sender, delay: Duration, event: Token) -> std::result::Result<(), String> {
+        out.timeout(delay.as_millis() as u64, event).map_err(|e| e.to_string())
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// Time which passes only when it is advanced. With a manual clock, sleeping waits
+/// until someone else advances it far enough; with an instant clock, sleeping itself
+/// advances the time, so that waits take no real time at all. Handlers are woken up
+/// by the event loop once the time passes their delays, in the order of their ends.
+pub struct SimulatedClock {
+    start: Instant,
+    start_utc: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+    advanced: Condvar,
+    /// Handlers to wake up with the elapsed time they are due at.
+    wakes: Mutex<Vec<(Duration, Sender, Token)>>,
+    instant: bool,
+}
+
+impl SimulatedClock {
+    pub fn manual() -> Self {
+        SimulatedClock::new(false)
+    }
+
+    pub fn instant() -> Self {
+        SimulatedClock::new(true)
+    }
+
+    fn new(instant: bool) -> Self {
+        SimulatedClock {
+            start: Instant::now(),
+            start_utc: Utc::now(),
+            elapsed: Mutex::new(Duration::default()),
+            advanced: Condvar::new(),
+            wakes: Mutex::new(Vec::new()),
+            instant,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let elapsed = {
+            let mut elapsed = self.elapsed.lock().unwrap();
+            *elapsed += duration;
+            *elapsed
+        };
+        self.advanced.notify_all();
+        let mut due: Vec<(Duration, Sender, Token)> = {
+            let mut wakes = self.wakes.lock().unwrap();
+            let (due, later) = wakes.drain(..).partition(|(at, _, _)| *at <= elapsed);
+            *wakes = later;
+            due
+        };
+        // Stable, so that handlers due at once are woken up in the order they asked
+        due.sort_by_key(|(at, _, _)| *at);
+        for (_, out, event) in due {
+            out.timeout(0, event).ok();
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        self.start_utc + chrono::Duration::from_std(self.elapsed()).unwrap_or_else(|_| chrono::Duration::zero())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        if self.instant {
+            self.advance(duration);
+            return;
+        }
+
+        let mut elapsed = self.elapsed.lock().unwrap();
+        let until = *elapsed + duration;
+        while *elapsed < until {
+            elapsed = self.advanced.wait(elapsed).unwrap();
+        }
+    }
+
+    fn wake(&self, out: &Sender, delay: Duration, event: Token) -> std::result::Result<(), String> {
+        let at = self.elapsed() + delay;
+        self.wakes.lock().unwrap().push((at, out.clone(), event));
+        match self.instant {
+            true => self.advance(delay),
+            false => self.advance(Duration::ZERO),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Side of the proxy where a connection was closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Leg {
+    Client,
+    Server,
+}
+
+/// Who started the closing handshake.
+/// `Peer` also covers connections which were dropped without any close frame (1006).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Initiator {
+    Peer,
+    Proxy,
+}
+
+impl Leg {
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "client" => Ok(Leg::Client),
+            "server" => Ok(Leg::Server),
+            _ => Err(format!("Unknown side {}, expected client or server", value)),
+        }
+    }
+}
+
+impl Initiator {
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "peer" => Ok(Initiator::Peer),
+            "proxy" => Ok(Initiator::Proxy),
+            _ => Err(format!("Unknown initiator {}, expected peer or proxy", value)),
+        }
+    }
+}
+
+impl fmt::Display for Leg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Leg::Client => "client",
+            Leg::Server => "server",
+        })
+    }
+}
+
+impl fmt::Display for Initiator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Initiator::Peer => "peer",
+            Initiator::Proxy => "proxy",
+        })
+    }
+}
+
+/// Counts of close codes observed during a run, per leg and initiator.
+#[derive(Default)]
+pub struct CloseStats {
+    counts: BTreeMap<(Leg, u16, Initiator), u64>,
+}
+
+impl CloseStats {
+    pub fn new() -> Self {
+        CloseStats::default()
+    }
+
+    pub fn record(&mut self, leg: Leg, code: u16, initiator: Initiator) {
+        self.add(leg, code, initiator, 1);
+    }
+
+    pub fn add(&mut self, leg: Leg, code: u16, initiator: Initiator, count: u64) {
+        *self.counts.entry((leg, code, initiator)).or_insert(0) += count;
+    }
+
+    pub fn counts(&self) -> impl Iterator<Item = (Leg, u16, Initiator, u64)> + '_ {
+        self.counts.iter().map(|((leg, code, initiator), count)| (*leg, *code, *initiator, *count))
+    }
+
+    pub fn report(&self) -> String {
+        if self.counts.is_empty() {
+            return "Close codes: no connections were closed".to_string();
+        }
+
+        let mut report = String::from("Close codes:");
+        for ((leg, code, initiator), count) in self.counts.iter() {
+            report.push_str(&format!("\n  {:<8}{:>6} {:<24} closed by {:<6}{:>8}",
+                leg, code, describe(*code), initiator, count));
+        }
+        report
+    }
+}
+
+/// Meaning of a close code according to RFC 6455 and the IANA registry.
+pub fn describe(code: u16) -> &'static str {
+    match code {
+        1000 => "normal closure",
+        1001 => "going away",
+        1002 => "protocol error",
+        1003 => "unsupported data",
+        1005 => "no status received",
+        1006 => "abnormal closure",
+        1007 => "invalid payload data",
+        1008 => "policy violation",
+        1009 => "message too big",
+        1010 => "mandatory extension",
+        1011 => "internal error",
+        1012 => "service restart",
+        1013 => "try again later",
+        1014 => "bad gateway",
+        1015 => "TLS handshake failure",
+        3000..=3999 => "registered by library",
+        4000..=4999 => "private use",
+        _ => "unknown",
+    }
+}
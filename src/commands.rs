@@ -0,0 +1,667 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use url::Url;
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use log::error;
+
+use crate::agent;
+use crate::asyncapi;
+use crate::bundle::{self, Bundle, Recorded};
+use crate::clock;
+use crate::condition::{Condition, Facts};
+use crate::console::{self, Console};
+use crate::contract::Contract;
+use crate::drift::{self, Inventory};
+use crate::encryption::Sink;
+use crate::heatmap::{self, Heatmap};
+use crate::hooks::Hooks;
+use crate::hops::{self, Hop};
+use crate::inspect::{self, Side, Sync};
+use crate::learn;
+use crate::manifest;
+use crate::metrics;
+use crate::normalize::{Normalization, NormalizeRule};
+use crate::overhead;
+use crate::plugins::{self, Decoder, Kind};
+use crate::process::Processing;
+use crate::profile::Profile;
+use crate::repair;
+use crate::replay::{self, Comparison, Timing};
+use crate::scaffold::{self, Preset};
+use crate::session::{self, MessageId, Session};
+use crate::snapshot::Snapshot;
+use crate::storage::Storage;
+use crate::tags;
+use crate::track;
+use crate::views::{self, View};
+use crate::wasm::Processor;
+use crate::wizard;
+
+/// Outcome of a command: failed checks exit with 1, errors with 255.
+pub type Outcome = std::result::Result<ExitCode, String>;
+
+/// Where a replay sends the messages of clients.
+pub enum ReplayTarget {
+    /// The server at the url, whose answers are compared with those captured.
+    Server(Url),
+    /// Clients connecting to the port, which are answered with the captured messages.
+    Serve(u16),
+}
+
+/// Repairs a damaged capture into the output, or in place keeping the original aside.
+pub fn repair_capture(capture: PathBuf, output: Option<PathBuf>) -> Outcome {
+    // Without --output the capture is repaired in place, keeping the damaged original
+    let (source, output) = match output {
+        Some(output) => (capture, output),
+        None => {
+            let mut damaged = capture.as_os_str().to_owned();
+            damaged.push(".damaged");
+            let damaged = PathBuf::from(damaged);
+            std::fs::rename(&capture, &damaged)
+                .map_err(|e| format!("Can't move {} aside: {}", capture.display(), e))?;
+            println!("Damaged capture is kept as {}", damaged.display());
+            (damaged, capture)
+        }
+    };
+
+    let repaired = repair::repair(&source, &output).map_err(|e| format!("Failed to repair: {}", e))?;
+    println!("Repaired {}: {} records kept, {} salvaged, {} damaged lines ({} bytes) dropped",
+        output.display(), repaired.kept, repaired.salvaged, repaired.dropped, repaired.dropped_bytes);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Bundles the session into a file to be shared.
+pub fn create_bundle(session: &str, output: Option<PathBuf>) -> Outcome {
+    let dir = session_dir(session);
+    let id = dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| session.to_string());
+    let output = output.unwrap_or_else(|| PathBuf::from(format!("{}.bundle.tar.gz", id)));
+
+    bundle::create(&dir, &output).map_err(|e| format!("Failed to create bundle: {}", e))?;
+    println!("Session {} is bundled into {}", id, output.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Verifies the signed manifest of the session, failing if a file is missing or modified.
+pub fn verify_session(session: &str, key: &Path) -> Outcome {
+    let key = manifest::load_key(key)?;
+    let problems = manifest::verify(&session_dir(session), &key)
+        .map_err(|e| format!("Failed to verify session: {}", e))?;
+    if problems.is_empty() {
+        println!("OK: session {} is complete and unmodified", session);
+        return Ok(ExitCode::SUCCESS);
+    }
+    for problem in problems {
+        println!("FAILED: {}", problem);
+    }
+    Ok(ExitCode::from(255))
+}
+
+/// Directory of a session given by its id or path.
+fn session_dir(session: &str) -> PathBuf {
+    if Path::new(session).is_dir() {
+        Path::new(session).to_path_buf()
+    } else {
+        Path::new(session::WORKSPACE).join(session)
+    }
+}
+
+/// Answers clients connecting to the port with the server messages of the bundle.
+pub fn serve_bundle(path: &Path, port: u16, timing: Timing, normalize: Vec<NormalizeRule>) -> Outcome {
+    let bundle = Bundle::open(path).map_err(|e| format!("Failed to open bundle: {}", e))?;
+    println!("{}", bundle.report);
+    println!("Replaying {} messages on port {}", bundle.messages.len(), port);
+
+    bundle::serve(bundle, port, timing, Normalization::new(normalize), clock::system())
+        .map_err(|e| format!("Failed to serve bundle: {}", e))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Replays the capture of a session, a capture file or a bundle, failing if the server diverges.
+pub fn replay_session(source: &Path, target: ReplayTarget, ignore: &[String], normalize: Vec<NormalizeRule>,
+                      timeout: Duration, timing: Timing, output: Option<PathBuf>) -> Outcome {
+    let normalization = Normalization::new(normalize);
+    let comparison = Comparison::new(ignore, normalization.clone())?;
+    let (source, id) = match source.to_str() {
+        Some(session) if !source.is_file() => {
+            let (capture, id, _) = session_capture(session);
+            (capture, id)
+        },
+        _ => (source.to_path_buf(), source.display().to_string()),
+    };
+    let messages = load_messages(&source)?;
+    let server_url = match target {
+        ReplayTarget::Server(server_url) => server_url,
+        ReplayTarget::Serve(port) => {
+            println!("Replaying {} messages on port {}", messages.len(), port);
+            let bundle = Bundle { id, report: String::new(), messages };
+            bundle::serve(bundle, port, timing, normalization, clock::system())
+                .map_err(|e| format!("Failed to replay: {}", e))?;
+            return Ok(ExitCode::SUCCESS);
+        },
+    };
+    println!("Replaying {} messages against {}", messages.len(), server_url);
+
+    let report = replay::replay(messages, &server_url, comparison, timeout, timing, clock::system())
+        .map_err(|e| format!("Failed to replay: {}", e))?;
+    println!("{}", report);
+    if let Some(output) = output {
+        std::fs::write(&output, format!("{}\n", report)).unwrap_or_else(|e| {
+            error!("Error: {}", e);
+            println!("Failed to write report {}", output.display());
+        });
+    }
+    match report.divergences.is_empty() {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::from(1)),
+    }
+}
+
+/// Learns a script of the test server from a capture or a bundle.
+pub fn learn_script(path: &Path, output: Option<PathBuf>) -> Outcome {
+    let script = learn::learn(load_messages(path)?);
+    match output {
+        Some(output) => {
+            std::fs::write(&output, script)
+                .map_err(|e| format!("Failed to write script {}: {}", output.display(), e))?;
+            println!("Script for --test-script written to {}", output.display());
+        },
+        None => print!("{}", script)
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Exports an AsyncAPI document of the messages of the session, in YAML unless the output is .json.
+pub fn export_asyncapi(session: &str, output: Option<PathBuf>) -> Outcome {
+    let (capture, id, upstream) = session_capture(session);
+    let document = asyncapi::export(&load_messages(&capture)?, upstream.as_ref(), &id);
+    let json = output.as_ref()
+        .map(|output| output.extension().map(|extension| extension == "json").unwrap_or(false))
+        .unwrap_or(false);
+    let text = if json {
+        serde_json::to_string_pretty(&document).map_err(|e| e.to_string())
+    } else {
+        serde_yaml::to_string(&document).map_err(|e| e.to_string())
+    };
+    let text = text.map_err(|e| format!("Failed to write the document: {}", e))?;
+    match output {
+        Some(output) => {
+            std::fs::write(&output, text).map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+            println!("AsyncAPI document written to {}", output.display());
+        },
+        None => print!("{}", text)
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Checks the messages of the session against the contract, failing at a violation.
+pub fn validate_session(session: &str, contract: &Path) -> Outcome {
+    let contract = Contract::load(contract)?;
+
+    let (capture, _, upstream) = session_capture(session);
+    let path = upstream.as_ref().map(|upstream| upstream.path()).unwrap_or("/");
+    let (validator, violation) = contract.validator(path);
+    let mut violations = 0;
+    if let Some(violation) = violation {
+        println!("VIOLATION: {}", violation);
+        violations += 1;
+    }
+    let messages = load_messages(&capture)?;
+    for recorded in messages.iter() {
+        if let Some(violation) = validator.check(recorded.from, &recorded.message) {
+            println!("VIOLATION: {} from {}: {}", recorded.id.as_deref().unwrap_or("-"), recorded.from, violation);
+            violations += 1;
+        }
+    }
+    if violations > 0 {
+        println!("{} of {} messages checked violate the contract", violations, messages.len());
+        return Ok(ExitCode::from(1));
+    }
+    println!("OK: all {} messages follow the contract", messages.len());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Compares the kinds of messages of two sessions, failing if they drifted apart.
+pub fn analyze_drift(old: &str, new: &str) -> Outcome {
+    let (old_capture, old_id, _) = session_capture(old);
+    let (new_capture, new_id, _) = session_capture(new);
+    let changes = drift::compare(
+        &Inventory::of(&load_messages(&old_capture)?),
+        &Inventory::of(&load_messages(&new_capture)?));
+
+    if changes.is_empty() {
+        println!("No drift between {} and {}", old_id, new_id);
+        return Ok(ExitCode::SUCCESS);
+    }
+    println!("Drift from {} to {}:", old_id, new_id);
+    for change in changes.iter() {
+        println!("{}", change);
+    }
+    Ok(ExitCode::from(1))
+}
+
+/// Prints the metrics of the session as a chart or as CSV.
+pub fn analyze_metrics(session: &str, connection: Option<u32>, csv: bool) -> Outcome {
+    let snapshots = metrics::load(&session_dir(session))?;
+    if csv {
+        print!("{}", metrics::csv(&snapshots, connection));
+    } else if snapshots.is_empty() {
+        println!("No metrics were written in session {}", session);
+    } else {
+        print!("{}", metrics::chart(&snapshots, connection));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints a heatmap of the latency of requests by their type.
+pub fn analyze_latency(session: &str, correlate: Vec<String>) -> Outcome {
+    let (capture, id, _) = session_capture(session);
+    // Sessions of a profile pair responses by its fields first
+    let fields = match correlate.is_empty() {
+        true => session_profile(&capture).map(|profile| profile.correlation_fields()).unwrap_or_default().iter()
+            .chain(heatmap::CORRELATION_FIELDS.iter())
+            .map(|field| field.to_string())
+            .collect(),
+        false => correlate,
+    };
+    let heatmap = Heatmap::of(&load_messages(&capture)?, &fields);
+    if heatmap.is_empty() {
+        println!("There are no client messages in session {}", id);
+    } else {
+        print!("{}", heatmap.render());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints the time messages of the session spent in each stage of the proxy.
+pub fn analyze_overhead(session: &str) -> Outcome {
+    let records = overhead::load(&session_dir(session))?;
+    if records.is_empty() {
+        println!("No messages were measured in session {}", session);
+    } else {
+        print!("{}", overhead::table(&records));
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Merges the captures of the hops of a chain of proxies and prints the latency between them.
+pub fn merge_hops(sessions: &[String], output: Option<PathBuf>) -> Outcome {
+    let hops = sessions.iter()
+        .map(|session| {
+            let (capture, id, _) = session_capture(session);
+            let messages = load_messages(&capture)?;
+            // Captures of proxies started with --hop are named after it
+            let name = messages.iter().find_map(|recorded| recorded.hop.clone()).unwrap_or(id);
+            Ok(Hop { name, messages })
+        })
+        .collect::<std::result::Result<Vec<Hop>, String>>()?;
+    let (merged, latencies) = hops::merge(&hops);
+
+    let partial = merged.iter()
+        .filter(|record| record["hops"].as_array().map(Vec::len) != Some(hops.len()))
+        .count();
+    if let Some(output) = output {
+        let text: String = merged.iter().map(|record| format!("{}\n", record)).collect();
+        std::fs::write(&output, text).map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+        println!("{} merged messages written to {}", merged.len(), output.display());
+    }
+    for latency in latencies.iter() {
+        println!("{}", latency);
+    }
+    if partial > 0 {
+        println!("{} of {} messages are missing at some hops", partial, merged.len());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Shows the traffic of a remote agent, recorded into a local session, and sends it rules typed.
+pub fn attach_agent(url: Url, token: &Path, rules: Vec<String>) -> Outcome {
+    let token = manifest::load_key(token)?;
+    println!("Attaching to agent {}", url);
+    println!("Type a rule like drop:server:<regex> to add it, or clear to remove all rules");
+
+    let console = Console::start(console::DEFAULT_SUMMARY);
+    let agent_url = url.to_string();
+    // The remote capture is recorded into a local session, so that it can be inspected like any other
+    let mut local: Option<(Session, Sink)> = None;
+    let attached = agent::attach(&url, &token, rules, move |record| match record["event"].as_str() {
+        Some("session") => {
+            let remote = record["session"].as_str().unwrap_or_default();
+            let upstream = record["upstream"].as_str().unwrap_or_default();
+            let proxy_port = record["proxy_port"].as_u64().unwrap_or_default() as u16;
+            let storage = Storage::Disk(None);
+            let started = Session::start(Path::new(session::WORKSPACE), Utc::now(), proxy_port, &storage)
+                .map_err(|e| format!("Failed to create session directory in {}: {}", session::WORKSPACE, e))
+                .and_then(|session| match storage.open(&session.capture_path()) {
+                    Ok(capture) => Ok((session, capture)),
+                    Err(e) => Err(format!("Failed to create file {}: {}", session.capture_path().display(), e)),
+                });
+            // The traffic is still shown, only not recorded
+            let (mut session, capture) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    println!("{}, session {} is not recorded", e, remote);
+                    return;
+                },
+            };
+            println!("Attached to session {} proxying to {}, recorded in {}", remote, upstream, session.dir().display());
+
+            let labels = vec![("agent".to_string(), agent_url.clone()), ("agent_session".to_string(), remote.to_string())];
+            let record = session::session_record(session.id(), upstream, proxy_port, &labels, record["hop"].as_str());
+            session.record(record);
+            local = Some((session, capture));
+        },
+        Some("rules") => println!("Agent rules: {}", record["rules"]),
+        Some("error") => println!("Agent rejected the rules: {}", record["error"]),
+        event => {
+            if let Some(recorded) = bundle::recorded(&record) {
+                let id = MessageId {
+                    connection_id: record["connection_id"].as_u64().unwrap_or_default() as u32,
+                    sequence: record["sequence"].as_u64().unwrap_or_default(),
+                };
+                console.message(id, recorded.from, &recorded.message);
+            } else if event == Some("provenance") {
+                println!("Agent {}: {} {}", record["action"].as_str().unwrap_or_default(),
+                    record["original"].as_str().unwrap_or("-"), record["rule"].as_str().unwrap_or_default());
+            }
+            if let Some((_, capture)) = &mut local {
+                writeln!(capture, "{}", record).unwrap_or_else(|e| error!("Error: {}", e));
+            }
+        }
+    });
+    attached.map_err(|e| format!("Failed to attach to agent {}: {}", url, e))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Adds and removes tags of captured messages.
+pub fn tag_messages(session: &str, messages: &[String], added: &[String], removed: &[String]) -> Outcome {
+    let (capture, _, _) = session_capture(session);
+    let text = std::fs::read_to_string(&capture)
+        .map_err(|e| format!("Failed to read {}: {}", capture.display(), e))?;
+    let known: HashSet<String> = tags::tagged(&text).iter()
+        .filter_map(|(record, _)| record["id"].as_str().map(String::from))
+        .collect();
+    if let Some(unknown) = messages.iter().find(|id| !known.contains(*id)) {
+        return Err(format!("Message {} is not in {}", unknown, capture.display()));
+    }
+
+    // Tags are appended, the captured records stay as they were
+    let records: String = messages.iter()
+        .map(|id| format!("{}\n", session::tag_record(id, added, removed)))
+        .collect();
+    std::fs::OpenOptions::new().append(true).open(&capture)
+        .and_then(|mut file| file.write_all(records.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", capture.display(), e))?;
+    println!("Tagged {} messages in {}", messages.len(), capture.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Shows the captures of sessions side by side.
+pub fn inspect_captures(sessions: &[String], by: Option<String>, offset: Option<f64>,
+                    normalize: Vec<NormalizeRule>) -> Outcome {
+    let sync = match (by, offset) {
+        (Some(expression), _) => {
+            let path = track::parse(&expression)?;
+            Sync::Key(expression, path)
+        },
+        (None, Some(offset)) => Sync::Offset(offset),
+        (None, None) => Sync::Order,
+    };
+
+    let sides = sessions.iter()
+        .map(|session| {
+            let (capture, _, _) = session_capture(session);
+            let text = std::fs::read_to_string(&capture)
+                .map_err(|e| format!("Failed to read {}: {}", capture.display(), e))?;
+            Ok(Side::load(session, &text))
+        })
+        .collect::<std::result::Result<Vec<Side>, String>>()?;
+    inspect::run(sides, sync, Normalization::new(normalize))?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Prints the captured messages with the tags and matching the condition, as the view shows them.
+pub fn grep_messages(session: &str, condition: Option<Condition>, with: &[String], without: &[String],
+    view: Option<String>) -> Outcome {
+    let view = view.map(|name| views::find(Path::new(views::VIEWS), &name)).transpose()?;
+
+    let (capture, _, _) = session_capture(session);
+    let text = std::fs::read_to_string(&capture)
+        .map_err(|e| format!("Failed to read {}: {}", capture.display(), e))?;
+    for (record, tags) in tags::tagged(&text) {
+        let Recorded { from, message, .. } = match bundle::recorded(&record) {
+            Some(recorded) => recorded,
+            None => continue,
+        };
+        let connection_id = record["connection_id"].as_u64().unwrap_or_default() as u32;
+        let facts = Facts { connection_id, ..Facts::of(from, &message) };
+        let shown = with.iter().all(|tag| tags.contains(tag))
+            && !without.iter().any(|tag| tags.contains(tag))
+            && condition.as_ref().map(|condition| condition.matches(&facts)).unwrap_or(true);
+        if !shown {
+            continue;
+        }
+        let tags: Vec<String> = tags.into_iter().collect();
+        let data = match &view {
+            Some(view) => match view.show(&facts, &tags) {
+                Some(data) => data,
+                None => continue,
+            },
+            None => record["data"].as_str().unwrap_or_default().to_string(),
+        };
+        println!("{} {} [{}] {}", record["id"].as_str().unwrap_or("-"), from, tags.join(","), data);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Writes a scenario, a script, views and a state to start the proxy with for the preset.
+pub fn init_scaffold(preset: Preset, dir: &Path, port: u16, server: Option<String>, force: bool) -> Outcome {
+    let scaffold = scaffold::scaffold(preset, port, server.as_deref());
+    let files = [&scaffold.scenario, &scaffold.script];
+    let state = dir.join(scaffold::STATE);
+    let existing: Vec<PathBuf> = files.iter().map(|(name, _)| dir.join(name))
+        .chain([state.clone()])
+        .filter(|path| path.exists())
+        .collect();
+    if !existing.is_empty() && !force {
+        let existing: Vec<String> = existing.iter().map(|path| path.display().to_string()).collect();
+        return Err(format!("{} exist, overwrite them with --force", existing.join(", ")));
+    }
+
+    std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    for (name, text) in files {
+        std::fs::write(dir.join(name), text)
+            .map_err(|e| format!("Failed to write {}: {}", dir.join(name).display(), e))?;
+        println!("Written {}", dir.join(name).display());
+    }
+
+    // Views are added to those already saved, the preset's replace ones of the same names
+    let views_path = dir.join(views::VIEWS);
+    let mut saved = views::load(&views_path)?;
+    let names: Vec<String> = scaffold.views.keys().cloned().collect();
+    saved.extend(scaffold.views);
+    views::save(&views_path, &saved)?;
+    println!("Saved views {} into {}", names.join(", "), views_path.display());
+
+    let snapshot = Snapshot {
+        args: scaffold.args,
+        session: String::new(),
+        labels: vec![],
+        close_codes: vec![],
+        undelivered: vec![],
+    };
+    snapshot.save(&state).map_err(|e| format!("Failed to write {}: {}", state.display(), e))?;
+    println!("Written {}: {}", state.display(), wizard::command_line(&snapshot.args));
+
+    println!("\nStart the proxy in {} with: ws-proxy restore {}", dir.display(), scaffold::STATE);
+    println!("and connect a {} client to ws://localhost:{}, then look at the session with:", preset.name(), port);
+    println!("  ws-proxy grep <session> --view {}", names.first().map(String::as_str).unwrap_or_default());
+    println!("  ws-proxy process <session> --script {}", scaffold.script.0);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Lists the plugins in the directory, or verifies that they load.
+pub fn manage_plugins(dir: &Path, verify: bool) -> Outcome {
+    let found = plugins::discover(dir)?;
+    if found.is_empty() {
+        println!("No plugins in {}", dir.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut invalid = 0;
+    for (path, manifest) in found {
+        let checked = match verify {
+            // Loading checks the exported interface as well
+            true => manifest.and_then(|manifest| match manifest.kind {
+                Kind::Dylib => Decoder::load(manifest).map(|decoder| decoder.manifest),
+                Kind::Wasm => Processor::load(manifest).map(|processor| processor.manifest),
+            }),
+            false => manifest,
+        };
+        match checked {
+            Ok(manifest) if verify => println!("OK: {}", manifest),
+            Ok(manifest) => println!("{}: {}", path.display(), manifest),
+            Err(e) => {
+                println!("INVALID: {}: {}", path.display(), e);
+                invalid += 1;
+            }
+        }
+    }
+    match invalid {
+        0 => Ok(ExitCode::SUCCESS),
+        _ => Ok(ExitCode::from(1)),
+    }
+}
+
+/// Runs the capture of the session through the hooks of the script, then reports.
+pub fn process_capture(session: &str, script: &Path, output: Option<PathBuf>) -> Outcome {
+    let hooks = Hooks::load(script)?;
+
+    let (capture, _, upstream) = session_capture(session);
+    let records = |text: String| -> Vec<Value> {
+        text.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+    };
+    let captured = std::fs::read_to_string(&capture)
+        .map_err(|e| format!("Failed to read {}: {}", capture.display(), e))?;
+    // Hooks see the handshakes of connections when the capture is in the directory of its session
+    let index = capture.parent().and_then(|dir| std::fs::read_to_string(dir.join(session::INDEX)).ok());
+    let upstream = upstream.map(|upstream| upstream.to_string()).unwrap_or_default();
+    let processing = Processing::new(&records(index.unwrap_or_default()), &upstream);
+    let processed = processing.run(&hooks, records(captured));
+    let report = hooks.report()?;
+
+    let text: String = processed.iter().map(|record| format!("{}\n", record)).collect();
+    match output {
+        Some(output) => {
+            std::fs::write(&output, text).map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+            let messages = processed.iter().filter(|record| record["event"] == "message").count();
+            println!("{} messages written to {}", messages, output.display());
+        },
+        // Without a report the processed capture itself is the result
+        None if report.is_none() => print!("{}", text),
+        None => {}
+    }
+    if let Some(report) = report {
+        print!("{}", report);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Lists the views saved in the working directory.
+pub fn list_views() -> Outcome {
+    let path = Path::new(views::VIEWS);
+    let saved = views::load(path)?;
+    if saved.is_empty() {
+        println!("No views are saved in {}", path.display());
+    }
+    for (name, view) in saved.iter() {
+        println!("{}: {}", name, view);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Deletes the saved view.
+pub fn delete_view(name: &str) -> Outcome {
+    let path = Path::new(views::VIEWS);
+    let mut saved = views::load(path)?;
+    if saved.remove(name).is_none() {
+        return Err(format!("There is no view {} in {}", name, path.display()));
+    }
+    views::save(path, &saved)?;
+    println!("View {} is deleted", name);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Saves the view, replacing one of the same name.
+pub fn save_view(name: &str, filter: Option<String>, from: Option<String>, tags: Vec<String>,
+                 highlight: Vec<String>, project: Vec<String>) -> Outcome {
+    let path = Path::new(views::VIEWS);
+    let mut saved = views::load(path)?;
+    let mut view = serde_json::Map::new();
+    if let Some(filter) = filter {
+        view.insert("filter".to_string(), json!(filter));
+    }
+    if let Some(from) = from {
+        view.insert("from".to_string(), json!(from));
+    }
+    for (field, values) in [("tags", tags), ("highlight", highlight), ("project", project)] {
+        if !values.is_empty() {
+            view.insert(field.to_string(), json!(values));
+        }
+    }
+    let view = Value::Object(view);
+    View::compile(name, &view)?;
+    saved.insert(name.to_string(), view);
+    views::save(path, &saved)?;
+    println!("View {} is saved in {}", name, path.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Capture of a session given by its id or path, or a capture file itself,
+/// with the session id and the upstream from the index.
+fn session_capture(session: &str) -> (PathBuf, String, Option<Url>) {
+    let (dir, capture) = if Path::new(session).is_file() {
+        let capture = PathBuf::from(session);
+        (capture.parent().map(Path::to_path_buf).unwrap_or_default(), capture)
+    } else {
+        let dir = session_dir(session);
+        let capture = dir.join(session::CAPTURE);
+        (dir, capture)
+    };
+    let record = std::fs::read_to_string(dir.join(session::INDEX)).unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .find(|record| record["event"] == "session");
+    let upstream = record.as_ref()
+        .and_then(|record| record["upstream"].as_str())
+        .and_then(|upstream| Url::parse(upstream).ok());
+    let id = record.as_ref()
+        .and_then(|record| record["session"].as_str().map(String::from))
+        .or_else(|| dir.file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_default();
+    (capture, id, upstream)
+}
+
+/// Profile the proxy of a capture was run with, from the config of its session.
+fn session_profile(capture: &Path) -> Option<Profile> {
+    let config = std::fs::read_to_string(capture.parent()?.join(session::CONFIG)).ok()?;
+    let args: Vec<String> = serde_json::from_str::<Value>(&config).ok()?["args"].as_array()?.iter()
+        .filter_map(|arg| arg.as_str().map(String::from))
+        .collect();
+    Profile::of(&args)
+}
+
+/// Messages of a capture, or of a bundle if the file is not a capture.
+fn load_messages(path: &Path) -> std::result::Result<Vec<Recorded>, String> {
+    let messages = if path.extension().map(|extension| extension == "jsonl").unwrap_or(false) {
+        std::fs::read_to_string(path).map(|capture| bundle::captured(&capture))
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))
+    } else {
+        Bundle::open(path).map(|bundle| bundle.messages)
+    };
+    messages.map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
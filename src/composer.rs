@@ -0,0 +1,293 @@
+use regex::Regex;
+use serde_json::{json, Value};
+use url::Url;
+use ws::Message;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::closecodes::Leg;
+use crate::testserver::{fill, VARIABLE};
+
+/// File keeping templates of messages besides the built-in ones, next to the sessions.
+pub const TEMPLATES: &str = "ws-proxy.templates.yaml";
+/// File keeping the messages sent from the terminal, one JSON object per line.
+pub const HISTORY: &str = "ws-proxy.history";
+
+/// Messages of the history listed and kept in memory.
+const HISTORY_KEPT: usize = 100;
+
+/// Built-in templates: protocol, name, side the message is sent to and its text.
+const BUILT_IN: &[(&str, &str, Leg, &str)] = &[
+    ("graphql-ws", "init", Leg::Server, r#"{"type":"connection_init","payload":{}}"#),
+    ("graphql-ws", "subscribe", Leg::Server, r#"{"id":"{id}","type":"subscribe","payload":{"query":"{query}"}}"#),
+    ("graphql-ws", "complete", Leg::Server, r#"{"id":"{id}","type":"complete"}"#),
+    ("graphql-ws", "ping", Leg::Server, r#"{"type":"ping"}"#),
+    ("graphql-ws", "next", Leg::Client, r#"{"id":"{id}","type":"next","payload":{"data":{data}}}"#),
+    ("stomp", "connect", Leg::Server, "CONNECT\naccept-version:1.2\nhost:{host}\n\n\0"),
+    ("stomp", "subscribe", Leg::Server, "SUBSCRIBE\nid:{id}\ndestination:{destination}\n\n\0"),
+    ("stomp", "send", Leg::Server, "SEND\ndestination:{destination}\ncontent-type:text/plain\n\n{body}\0"),
+    ("stomp", "unsubscribe", Leg::Server, "UNSUBSCRIBE\nid:{id}\n\n\0"),
+    ("stomp", "disconnect", Leg::Server, "DISCONNECT\nreceipt:{id}\n\n\0"),
+    ("stomp", "message", Leg::Client,
+        "MESSAGE\nsubscription:{subscription}\nmessage-id:{id}\ndestination:{destination}\n\n{body}\0"),
+    ("socketio", "event", Leg::Server, r#"42["{event}",{data}]"#),
+    ("socketio", "ping", Leg::Client, "2"),
+    ("socketio", "pong", Leg::Server, "3"),
+    ("signalr", "handshake", Leg::Server, "{\"protocol\":\"json\",\"version\":1}\x1e"),
+    ("signalr", "invoke", Leg::Server,
+        "{\"type\":1,\"invocationId\":\"{id}\",\"target\":\"{target}\",\"arguments\":[{arguments}]}\x1e"),
+    ("signalr", "ping", Leg::Server, "{\"type\":6}\x1e"),
+    ("binance-ws", "subscribe", Leg::Server, r#"{"method":"SUBSCRIBE","params":["{stream}"],"id":{id}}"#),
+    ("binance-ws", "unsubscribe", Leg::Server, r#"{"method":"UNSUBSCRIBE","params":["{stream}"],"id":{id}}"#),
+    ("binance-ws", "list", Leg::Server, r#"{"method":"LIST_SUBSCRIPTIONS","id":{id}}"#),
+];
+
+/// Message with `{name}` variables, sent with `:compose`.
+struct Template {
+    protocol: String,
+    name: String,
+    to: Leg,
+    text: String,
+}
+
+/// Composes well-formed messages of the protocol spoken through the proxy from templates,
+/// for `:compose` of the palette, and keeps the history of the messages sent from the terminal.
+///
+/// The protocol is detected from the subprotocol negotiated with the server, the path or host
+/// of the server, or the first messages of clients. Templates are built in for graphql-ws,
+/// STOMP, Socket.IO, SignalR and Binance, more are read anew from the templates file on every
+/// use: a map of protocols to maps of names to `{to: client|server, text: ...}`. Values of
+/// variables are remembered for the next messages, and `{id}` without one counts up.
+#[derive(Clone)]
+pub struct Composer {
+    templates: PathBuf,
+    history_path: PathBuf,
+    inner: Arc<Mutex<State>>,
+}
+
+struct State {
+    protocol: Option<String>,
+    variables: BTreeMap<String, String>,
+    next_id: u64,
+    history: VecDeque<(Leg, String)>,
+}
+
+impl Composer {
+    /// Composer for the server at the url, with the history kept in the file so far.
+    pub fn new(templates: &Path, history_path: &Path, upstream: &Url) -> Self {
+        let history = fs::read_to_string(history_path).unwrap_or_default().lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|entry| Some((Leg::parse(entry["to"].as_str()?).ok()?, entry["text"].as_str()?.to_string())))
+            .collect::<Vec<_>>();
+        let history = history[history.len().saturating_sub(HISTORY_KEPT)..].iter().cloned().collect();
+        let mut variables = BTreeMap::new();
+        if let Some(host) = upstream.host_str() {
+            variables.insert("host".to_string(), host.to_string());
+        }
+        let protocol = upstream.host_str()
+            .filter(|host| host.contains("binance"))
+            .map(|_| "binance-ws".to_string());
+        let state = State { protocol, variables, next_id: 1, history };
+        Composer {
+            templates: templates.to_path_buf(),
+            history_path: history_path.to_path_buf(),
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Detects the protocol from the handshake with the server.
+    pub fn opened(&self, subprotocol: Option<&str>, resource: &str) {
+        let detected = match subprotocol {
+            Some("graphql-transport-ws") => Some("graphql-ws"),
+            Some(subprotocol) if subprotocol.contains("stomp") => Some("stomp"),
+            _ if resource.starts_with("/socket.io/") => Some("socketio"),
+            _ => None,
+        };
+        if let Some(detected) = detected {
+            self.inner.lock().unwrap().protocol = Some(detected.to_string());
+        }
+    }
+
+    /// Detects the protocol from a message, unless it is known already.
+    pub fn message(&self, from: Leg, message: &Message) {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Binary(_) => return,
+        };
+        let mut state = self.inner.lock().unwrap();
+        if state.protocol.is_some() {
+            return;
+        }
+        let detected = match from {
+            Leg::Client if text.starts_with("CONNECT\n") || text.starts_with("STOMP\n") => Some("stomp"),
+            Leg::Client if text.starts_with(r#"{"type":"connection_init""#) => Some("graphql-ws"),
+            Leg::Client if text.starts_with(r#"{"protocol":"json""#) => Some("signalr"),
+            Leg::Server if text.starts_with(r#"0{"sid":"#) => Some("socketio"),
+            _ => None,
+        };
+        state.protocol = detected.map(String::from);
+    }
+
+    /// Templates with the side they are sent to, those of the detected protocol first.
+    pub fn list(&self) -> std::result::Result<String, String> {
+        let templates = self.load()?;
+        let protocol = self.inner.lock().unwrap().protocol.clone();
+        let mut listed = match &protocol {
+            Some(protocol) => format!("Detected protocol {}\n", protocol),
+            None => "No protocol is detected yet, templates are named <protocol>/<name>\n".to_string(),
+        };
+        let (detected, others): (Vec<&Template>, Vec<&Template>) = templates.iter()
+            .partition(|template| Some(&template.protocol) == protocol.as_ref());
+        for template in detected.into_iter().chain(others) {
+            listed.push_str(&format!("{:<24} to {:<6}  {}\n", format!("{}/{}", template.protocol, template.name),
+                template.to, visible(&template.text)));
+        }
+        Ok(listed.trim_end().to_string())
+    }
+
+    /// Message made from the template, named `<name>` within the detected protocol or
+    /// `<protocol>/<name>`, and `<variable>=<value>` assignments, with the side it is sent to.
+    pub fn compose(&self, argument: &str) -> std::result::Result<(Leg, String), String> {
+        let (name, assignments) = argument.split_once(char::is_whitespace).unwrap_or((argument, ""));
+        if name.is_empty() {
+            return Err("Command is :compose <template> [<variable>=<value> ...]".to_string());
+        }
+        let assignments = parse_assignments(assignments)?;
+        let templates = self.load()?;
+        let mut state = self.inner.lock().unwrap();
+        let template = match name.split_once('/') {
+            Some((protocol, name)) => templates.iter()
+                .find(|template| template.protocol == protocol && template.name == name),
+            None => templates.iter()
+                .find(|template| Some(&template.protocol) == state.protocol.as_ref() && template.name == name)
+                .or_else(|| templates.iter().find(|template| template.name == name)),
+        };
+        let template = template.ok_or_else(|| format!("There is no template {}, :templates lists them", name))?;
+
+        let variable = Regex::new(VARIABLE).unwrap();
+        let names: Vec<&str> = variable.captures_iter(&template.text)
+            .map(|found| found.get(1).unwrap().as_str())
+            .collect();
+        state.variables.extend(assignments);
+        let counted = names.contains(&"id") && !state.variables.contains_key("id");
+        let missing: Vec<&str> = names.iter().copied()
+            .filter(|name| *name != "id" && !state.variables.contains_key(*name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("Template {}/{} needs {}, given like {}=<value>", template.protocol,
+                template.name, missing.join(", "), missing[0]));
+        }
+        let id = state.next_id.to_string();
+        let text = fill(&template.text, |name| match (name, counted) {
+            ("id", true) => Some(id.clone()),
+            (name, _) => state.variables.get(name).cloned(),
+        });
+        if counted {
+            state.next_id += 1;
+        }
+        Ok((template.to, text))
+    }
+
+    /// Keeps a message sent from the terminal in the history.
+    pub fn sent(&self, to: Leg, text: &str) {
+        let mut state = self.inner.lock().unwrap();
+        if state.history.len() == HISTORY_KEPT {
+            state.history.pop_front();
+        }
+        state.history.push_back((to, text.to_string()));
+        let entry = json!({ "to": to.to_string(), "text": text });
+        let written = OpenOptions::new().create(true).append(true).open(&self.history_path)
+            .and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = written {
+            println!("Can't write the history {}: {}", self.history_path.display(), e);
+        }
+    }
+
+    /// Messages of the history, numbered for `:again`.
+    pub fn history(&self) -> String {
+        let state = self.inner.lock().unwrap();
+        if state.history.is_empty() {
+            return "No messages were sent yet".to_string();
+        }
+        state.history.iter().enumerate()
+            .map(|(index, (to, text))| format!("{:>3} to {:<6}  {}", index + 1, to, visible(text)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Message of the history by its number.
+    pub fn again(&self, number: &str) -> std::result::Result<(Leg, String), String> {
+        let state = self.inner.lock().unwrap();
+        number.parse::<usize>().ok()
+            .and_then(|number| state.history.get(number.wrapping_sub(1)))
+            .cloned()
+            .ok_or_else(|| format!("There is no message {} in the history, :history lists them", number))
+    }
+
+    /// Built-in templates, replaced by those of the file with the same protocol and name.
+    fn load(&self) -> std::result::Result<Vec<Template>, String> {
+        let mut templates: Vec<Template> = BUILT_IN.iter()
+            .map(|(protocol, name, to, text)| Template {
+                protocol: protocol.to_string(),
+                name: name.to_string(),
+                to: *to,
+                text: text.to_string(),
+            })
+            .collect();
+        let path = &self.templates;
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(templates),
+            Err(e) => return Err(format!("Can't read templates {}: {}", path.display(), e)),
+        };
+        let saved = match serde_yaml::from_str::<Value>(&text) {
+            Ok(Value::Object(saved)) => saved,
+            Ok(Value::Null) => return Ok(templates),
+            Ok(_) => return Err(format!("Templates {} must be a map of protocols to templates", path.display())),
+            Err(e) => return Err(format!("Templates {} are not YAML: {}", path.display(), e)),
+        };
+        for (protocol, named) in saved.iter() {
+            for (name, template) in named.as_object().into_iter().flatten() {
+                let invalid = || format!("Template {}/{} in {} needs to: client|server and text",
+                    protocol, name, path.display());
+                let to = template["to"].as_str().and_then(|to| Leg::parse(to).ok()).ok_or_else(invalid)?;
+                let text = template["text"].as_str().ok_or_else(invalid)?.to_string();
+                templates.retain(|kept| kept.protocol != *protocol || kept.name != *name);
+                templates.push(Template { protocol: protocol.clone(), name: name.clone(), to, text });
+            }
+        }
+        Ok(templates)
+    }
+}
+
+/// Text on one line, with line breaks and other control characters escaped.
+fn visible(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { c.escape_debug().to_string() } else { c.to_string() })
+        .collect()
+}
+
+/// Parses `<name>=<value>` separated by spaces, values with spaces in double quotes.
+fn parse_assignments(text: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let mut assignments = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=')
+            .ok_or_else(|| format!("Variable {} has no value, given like {}=<value>", rest, rest))?;
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("Variable {} isn't given like <name>=<value>", name));
+        }
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"')
+                .ok_or_else(|| format!("Value of {} has no closing quote", name))?,
+            None => after.split_once(char::is_whitespace).unwrap_or((after, "")),
+        };
+        assignments.push((name.to_string(), value.to_string()));
+        rest = after.trim_start();
+    }
+    Ok(assignments)
+}
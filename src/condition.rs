@@ -0,0 +1,160 @@
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use ws::Message;
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::closecodes::Leg;
+use crate::expr::Expression;
+
+/// Condition on a message, the same for every rule matching messages: either
+/// `[client:|server:]<regex>` matching text messages, or `expr:<expression>`
+/// holding for the facts of a message, like `expr:from == "server" && payload.type == "error"`.
+#[derive(Clone)]
+pub enum Condition {
+    Pattern { leg: Option<Leg>, pattern: Regex },
+    Expression(Expression),
+}
+
+/// What is known about a message when conditions are checked.
+pub struct Facts<'a> {
+    pub from: Leg,
+    pub message: &'a Message,
+    pub connection_id: u32,
+    /// Handshake headers of the connection the message came on, with lowercase names.
+    pub headers: &'a [(String, String)],
+    /// Time since the previous message on the connection, or since it was opened.
+    pub gap: Duration,
+    /// Time since the connection was opened.
+    pub age: Duration,
+    /// Messages received on the connection, this one included.
+    pub messages: u64,
+    pub environment: &'a Environment,
+}
+
+/// Variables of the connection a message came on, for rules depending on who is connected.
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    /// Fingerprint of the client, empty for the connection to the server.
+    pub client: String,
+    /// Path of the handshake request, without the query.
+    pub path: String,
+    /// Parameters of the query of the handshake request.
+    pub query: Vec<(String, String)>,
+    /// Server the messages of the connection are forwarded to or come from.
+    pub upstream: String,
+    /// Labels of the session.
+    pub labels: Vec<(String, String)>,
+}
+
+static NO_ENVIRONMENT: Environment = Environment {
+    client: String::new(),
+    path: String::new(),
+    query: Vec::new(),
+    upstream: String::new(),
+    labels: Vec::new(),
+};
+
+impl Environment {
+    /// Environment of a connection requesting the resource, a path with an optional query.
+    pub fn new(client: String, resource: &str, upstream: String, labels: Vec<(String, String)>) -> Self {
+        let (path, query) = resource.split_once('?').unwrap_or((resource, ""));
+        let query = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        Environment { client, path: path.to_string(), query, upstream, labels }
+    }
+
+    /// Environment of a connection nothing is known about.
+    pub fn none() -> &'static Self {
+        &NO_ENVIRONMENT
+    }
+}
+
+impl<'a> Facts<'a> {
+    /// Facts of a message nothing else is known about.
+    pub fn of(from: Leg, message: &'a Message) -> Self {
+        Facts { from, message, connection_id: 0, headers: &[], gap: Duration::ZERO, age: Duration::ZERO,
+            messages: 0, environment: Environment::none() }
+    }
+
+    /// Variables of expressions: `from`, `text`, `binary`, `size`, `payload` (the text
+    /// parsed as JSON, null if it isn't), `connection`, `headers`, `gap_ms`, `age_ms`,
+    /// `messages`, and those of the environment: `client`, `path`, `query`, `upstream`
+    /// and `labels`.
+    fn variables(&self) -> Map<String, Value> {
+        let (text, binary) = match self.message {
+            Message::Text(text) => (text.as_str(), false),
+            Message::Binary(_) => ("", true),
+        };
+        let payload = serde_json::from_str::<Value>(text).unwrap_or(Value::Null);
+        let variables = json!({
+            "from": self.from.to_string(),
+            "text": text,
+            "binary": binary,
+            "size": self.message.len(),
+            "payload": payload,
+            "connection": self.connection_id,
+            "headers": pairs(self.headers),
+            "gap_ms": self.gap.as_millis() as u64,
+            "age_ms": self.age.as_millis() as u64,
+            "messages": self.messages,
+            "client": self.environment.client,
+            "path": self.environment.path,
+            "query": pairs(&self.environment.query),
+            "upstream": self.environment.upstream,
+            "labels": pairs(&self.environment.labels),
+        });
+        match variables {
+            Value::Object(variables) => variables,
+            _ => unreachable!("variables are an object"),
+        }
+    }
+}
+
+/// Object of names and values, the last value of a name given several times wins.
+fn pairs(pairs: &[(String, String)]) -> Map<String, Value> {
+    pairs.iter()
+        .map(|(name, value)| (name.clone(), Value::String(value.clone())))
+        .collect()
+}
+
+impl Condition {
+    /// Parses `[<client|server>:]<regex>` or `expr:<expression>`.
+    pub fn parse(condition: &str) -> std::result::Result<Self, String> {
+        if let Some(expression) = condition.strip_prefix("expr:") {
+            return Expression::parse(expression).map(Condition::Expression)
+                .map_err(|e| format!("Invalid expression {}: {}", expression, e));
+        }
+        let (leg, pattern) = match condition.split_once(':') {
+            Some((leg, pattern)) if Leg::parse(leg).is_ok() => (Leg::parse(leg).ok(), pattern),
+            _ => (None, condition),
+        };
+        let pattern = Regex::new(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+        Ok(Condition::Pattern { leg, pattern })
+    }
+
+    /// Whether the message matches, patterns never match binary messages
+    /// and expressions failing to evaluate don't hold.
+    pub fn matches(&self, facts: &Facts) -> bool {
+        match self {
+            Condition::Pattern { leg, pattern } => {
+                let text = match facts.message {
+                    Message::Text(text) => text,
+                    Message::Binary(_) => return false,
+                };
+                (leg.is_none() || *leg == Some(facts.from)) && pattern.is_match(text)
+            },
+            Condition::Expression(expression) => expression.holds(&facts.variables()),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Pattern { leg: Some(leg), pattern } => write!(f, "{}:{}", leg, pattern),
+            Condition::Pattern { leg: None, pattern } => write!(f, "{}", pattern),
+            Condition::Expression(expression) => write!(f, "expr:{}", expression),
+        }
+    }
+}
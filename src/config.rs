@@ -0,0 +1,68 @@
+use serde_json::Value;
+
+use std::fs;
+use std::path::Path;
+
+/// Command line kept in a TOML or YAML file. Keys are the flags without the dashes,
+/// besides `upstream` and `port`, the parameters of the proxy:
+///
+/// ```toml
+/// upstream = "wss://example.com/socket"
+/// port = 8080
+/// pretty = true
+/// log-max-payload = "64KB"
+/// label = ["ticket=ABC-123", "env=staging"]
+/// ```
+///
+/// A flag without a value is given with `true` and left out with `false`, a list
+/// repeats the flag for each item.
+pub struct Config {
+    pub upstream: Option<String>,
+    pub port: Option<String>,
+    pub flags: Vec<String>,
+}
+
+impl Config {
+    /// Loads a `.toml` file, anything else is read as YAML, which JSON is as well.
+    pub fn load(path: &Path) -> std::result::Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read config {}: {}", path.display(), e))?;
+        let document: Value = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&text)
+                .map_err(|e| format!("Config {} is not TOML: {}", path.display(), e))?,
+            _ => serde_yaml::from_str(&text)
+                .map_err(|e| format!("Config {} is not YAML or JSON: {}", path.display(), e))?,
+        };
+        let entries = match document {
+            Value::Object(entries) => entries,
+            Value::Null => Default::default(),
+            _ => return Err(format!("Config {} is not a table of flags", path.display())),
+        };
+
+        let mut config = Config { upstream: None, port: None, flags: vec![] };
+        for (key, value) in entries {
+            match key.as_str() {
+                "upstream" => config.upstream = Some(scalar(&key, &value)?),
+                "port" => config.port = Some(scalar(&key, &value)?),
+                "config" => return Err(format!("Config {} can't include another config", path.display())),
+                _ => match value {
+                    Value::Bool(true) => config.flags.push(format!("--{}", key)),
+                    Value::Bool(false) | Value::Null => {},
+                    Value::Array(items) => for item in items {
+                        config.flags.extend([format!("--{}", key), scalar(&key, &item)?]);
+                    },
+                    value => config.flags.extend([format!("--{}", key), scalar(&key, &value)?]),
+                },
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn scalar(key: &str, value: &Value) -> std::result::Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        _ => Err(format!("Value of {} must be a string or a number, not {}", key, value)),
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::Local;
+use serde_json::Value;
+use ws::Message;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::closecodes::Leg;
+use crate::heatmap;
+use crate::memory::format_size;
+use crate::session::MessageId;
+
+/// Fields naming the kind of a JSON message, the first one found is shown.
+const TYPE_FIELDS: [&str; 6] = ["type", "event", "op", "method", "action", "kind"];
+
+/// Number of message types listed in a rollup.
+const TOP_TYPES: usize = 5;
+
+/// Interval of rollups printed unless another one is given.
+pub const DEFAULT_SUMMARY: Duration = Duration::from_secs(10);
+
+/// Prints one compact line per message and periodic rollups of the traffic,
+/// like `top` for WebSockets.
+pub struct Console {
+    inner: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Time and type of the last client message, which the next server message answers.
+    last_request: Option<(Instant, String)>,
+    rollup: Rollup,
+}
+
+#[derive(Default)]
+struct Rollup {
+    messages: [u64; 2],
+    bytes: [u64; 2],
+    types: BTreeMap<String, u64>,
+    latencies: Vec<Duration>,
+    /// Latencies by the type of the request.
+    type_latencies: BTreeMap<String, Vec<Duration>>,
+}
+
+impl Console {
+    /// Starts printing rollups every interval in a background thread.
+    pub fn start(interval: Duration) -> Self {
+        let inner = Arc::new(Mutex::new(State::default()));
+        let state = Arc::downgrade(&inner);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let rollup = match state.upgrade() {
+                Some(state) => std::mem::take(&mut state.lock().unwrap().rollup),
+                None => return,
+            };
+            println!("{}", rollup.report(interval));
+        });
+        Console { inner }
+    }
+
+    pub fn message(&self, id: MessageId, from: Leg, message: &Message) {
+        let mut state = self.inner.lock().unwrap();
+        let (kind, message_type) = match message {
+            Message::Text(text) => ("text", message_type(text)),
+            Message::Binary(_) => ("binary", None),
+        };
+        let type_name = message_type.clone().unwrap_or_else(|| kind.to_string());
+        let latency = match from {
+            Leg::Client => {
+                state.last_request = Some((Instant::now(), type_name.clone()));
+                None
+            },
+            Leg::Server => state.last_request.take().map(|(request, request_type)| (request.elapsed(), request_type)),
+        };
+
+        let rollup = &mut state.rollup;
+        rollup.messages[from as usize] += 1;
+        rollup.bytes[from as usize] += message.len() as u64;
+        *rollup.types.entry(type_name).or_insert(0) += 1;
+        let latency = latency.map(|(latency, request_type)| {
+            rollup.type_latencies.entry(request_type).or_default().push(latency);
+            latency
+        });
+        rollup.latencies.extend(latency);
+
+        let direction = match from {
+            Leg::Client => "client >",
+            Leg::Server => "server <",
+        };
+        println!("{} {:<10} {} {:<6} {:<24} {:>9} {}",
+            Local::now().format("%H:%M:%S%.3f"),
+            id.to_string(),
+            direction,
+            kind,
+            message_type.unwrap_or_else(|| "-".to_string()),
+            format_size(message.len()),
+            latency.map(|latency| format!("+{}ms", latency.as_millis())).unwrap_or_default());
+    }
+}
+
+impl Rollup {
+    fn report(&self, interval: Duration) -> String {
+        let seconds = interval.as_secs_f64();
+        let mut report = format!("--- last {:.0}s: client {} msgs ({}, {:.1}/s), server {} msgs ({}, {:.1}/s)",
+            seconds,
+            self.messages[Leg::Client as usize], format_size(self.bytes[Leg::Client as usize] as usize),
+            self.messages[Leg::Client as usize] as f64 / seconds,
+            self.messages[Leg::Server as usize], format_size(self.bytes[Leg::Server as usize] as usize),
+            self.messages[Leg::Server as usize] as f64 / seconds);
+
+        if !self.latencies.is_empty() {
+            let total: Duration = self.latencies.iter().sum();
+            let max = self.latencies.iter().max().copied().unwrap_or_default();
+            report.push_str(&format!(", latency avg {}ms max {}ms",
+                (total / self.latencies.len() as u32).as_millis(), max.as_millis()));
+        }
+
+        let mut types: Vec<(&String, &u64)> = self.types.iter().collect();
+        types.sort_by(|a, b| b.1.cmp(a.1));
+        if !types.is_empty() {
+            let top: Vec<String> = types.iter()
+                .take(TOP_TYPES)
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect();
+            report.push_str(&format!("\n    top types: {}", top.join(", ")));
+        }
+
+        let mut slowest: Vec<(&String, Duration)> = self.type_latencies.iter()
+            .filter_map(|(name, latencies)| {
+                let mut sorted = latencies.clone();
+                sorted.sort();
+                heatmap::percentile(&sorted, 90).map(|p90| (name, p90))
+            })
+            .collect();
+        slowest.sort_by_key(|(_, p90)| std::cmp::Reverse(*p90));
+        if !slowest.is_empty() {
+            let top: Vec<String> = slowest.iter()
+                .take(TOP_TYPES)
+                .map(|(name, p90)| format!("{} {}", name, heatmap::format_latency(*p90)))
+                .collect();
+            report.push_str(&format!("\n    slowest types by p90: {}", top.join(", ")));
+        }
+        report
+    }
+}
+
+pub(crate) fn message_type(text: &str) -> Option<String> {
+    let value = serde_json::from_str::<Value>(text).ok()?;
+    TYPE_FIELDS.iter()
+        .filter_map(|field| value.get(field))
+        .find_map(|found| match found {
+            Value::String(name) => Some(name.clone()),
+            Value::Number(number) => Some(number.to_string()),
+            _ => None,
+        })
+}
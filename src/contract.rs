@@ -0,0 +1,148 @@
+use jsonschema::JSONSchema;
+use regex::Regex;
+use serde_json::Value;
+use ws::Message;
+
+use std::fs;
+use std::path::Path;
+
+use crate::asyncapi;
+use crate::closecodes::Leg;
+
+/// Nesting of references followed when they are inlined, deeper ones are cycles.
+const MAX_REFERENCES: usize = 32;
+
+/// AsyncAPI 2 document which the traffic is expected to follow.
+pub struct Contract {
+    document: Value,
+}
+
+/// Messages allowed in a channel of the contract, in both directions.
+pub struct Validator {
+    /// Messages clients publish, with their payload schemas.
+    publish: Vec<(String, Option<JSONSchema>)>,
+    /// Messages clients subscribe to.
+    subscribe: Vec<(String, Option<JSONSchema>)>,
+}
+
+impl Contract {
+    /// Loads a document in YAML or JSON.
+    pub fn load(path: &Path) -> std::result::Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read contract {}: {}", path.display(), e))?;
+        let document: Value = serde_yaml::from_str(&text)
+            .map_err(|e| format!("Contract {} is not YAML or JSON: {}", path.display(), e))?;
+        match document["asyncapi"].as_str() {
+            Some(version) if version.starts_with("2.") => Ok(Contract { document }),
+            Some(version) => Err(format!("AsyncAPI {} of {} is not supported, only 2.x is", version, path.display())),
+            None => Err(format!("{} is not an AsyncAPI document", path.display())),
+        }
+    }
+
+    /// Validator of the channel with the path, like `/v1/stream`, where `{parameters}`
+    /// match any segment. An unknown channel is a violation, and then messages
+    /// of all channels are allowed.
+    pub fn validator(&self, path: &str) -> (Validator, Option<String>) {
+        let channels = match self.document["channels"].as_object() {
+            Some(channels) => channels,
+            None => return (Validator { publish: vec![], subscribe: vec![] }, Some("Contract has no channels".to_string())),
+        };
+        let path = path.trim_matches('/');
+        let found = channels.iter().find(|(name, _)| channel_pattern(name).is_match(path));
+
+        let (selected, violation): (Vec<&Value>, _) = match found {
+            Some((_, channel)) => (vec![channel], None),
+            None => (channels.values().collect(), Some(format!("channel /{} is not in the contract", path))),
+        };
+        let messages = |operation: &str| -> Vec<(String, Option<JSONSchema>)> {
+            selected.iter()
+                .flat_map(|channel| self.messages(&channel[operation]["message"]))
+                .map(|message| {
+                    let payload = self.inline(&message["payload"], 0);
+                    let name = message["name"].as_str().or_else(|| message["title"].as_str())
+                        .unwrap_or("message").to_string();
+                    let schema = match payload {
+                        Value::Null => None,
+                        payload => JSONSchema::compile(&payload).ok(),
+                    };
+                    (name, schema)
+                })
+                .collect()
+        };
+        (Validator { publish: messages("publish"), subscribe: messages("subscribe") }, violation)
+    }
+
+    /// Messages of an operation, which is a message or oneOf a list of them.
+    fn messages(&self, message: &Value) -> Vec<Value> {
+        let message = self.inline(message, 0);
+        match message["oneOf"].as_array() {
+            Some(messages) => messages.iter().map(|message| self.inline(message, 0)).collect(),
+            None if message.is_null() => vec![],
+            None => vec![message],
+        }
+    }
+
+    /// The value with all local references replaced by what they refer to.
+    fn inline(&self, value: &Value, depth: usize) -> Value {
+        if depth > MAX_REFERENCES {
+            return Value::Bool(true);
+        }
+        match value {
+            Value::Object(object) => match object.get("$ref").and_then(Value::as_str) {
+                Some(reference) => match reference.strip_prefix('#').and_then(|pointer| self.document.pointer(pointer)) {
+                    Some(target) => self.inline(target, depth + 1),
+                    // References to other documents aren't followed, anything is allowed instead
+                    None => Value::Bool(true),
+                },
+                None => Value::Object(object.iter()
+                    .map(|(key, value)| (key.clone(), self.inline(value, depth)))
+                    .collect()),
+            },
+            Value::Array(array) => Value::Array(array.iter().map(|value| self.inline(value, depth)).collect()),
+            value => value.clone(),
+        }
+    }
+}
+
+fn channel_pattern(name: &str) -> Regex {
+    let parameter = Regex::new(r"\\\{[^}]*\\\}").unwrap();
+    let escaped = regex::escape(name.trim_matches('/'));
+    Regex::new(&format!("^{}$", parameter.replace_all(&escaped, "[^/]+"))).unwrap()
+}
+
+impl Validator {
+    /// Describes how the message violates the contract, if it does: it must match
+    /// one of the messages the contract allows in its direction.
+    pub fn check(&self, from: Leg, message: &Message) -> Option<String> {
+        let allowed = match from {
+            Leg::Client => &self.publish,
+            Leg::Server => &self.subscribe,
+        };
+        if allowed.is_empty() {
+            return Some(format!("no messages from {} are allowed", from));
+        }
+
+        let payload = asyncapi::payload(message);
+        let mut problems = vec![];
+        for (name, schema) in allowed.iter() {
+            let schema = match schema {
+                Some(schema) => schema,
+                None => return None,
+            };
+            match schema.validate(&payload) {
+                Ok(()) => return None,
+                Err(errors) => {
+                    let errors: Vec<String> = errors
+                        .map(|error| match error.instance_path.to_string().as_str() {
+                            "" => error.to_string(),
+                            path => format!("{}: {}", path, error),
+                        })
+                        .take(3)
+                        .collect();
+                    problems.push(format!("not {} ({})", name, errors.join("; ")));
+                }
+            }
+        }
+        Some(problems.join(", "))
+    }
+}
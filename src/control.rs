@@ -0,0 +1,345 @@
+use serde_json::{json, Value};
+use ws::util::Token;
+use ws::{Message, Sender};
+
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+use crate::closecodes::Leg;
+use crate::palette::Setting;
+use crate::render::{Renderer, Renderers};
+use crate::sse;
+use crate::webui::{self, Viewers};
+
+/// Largest body of a request, the message to send.
+const MAX_BODY: u64 = 1 << 20;
+/// How long a request waits for the proxy to carry it out, and for the client to send it.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Change of the traffic requested over HTTP, carried out by the event loop of the proxy.
+pub enum Operation {
+    /// Message to the server, or to one client or all of them.
+    Send { to: Leg, connection: Option<u32>, message: Message },
+    /// Closes the connection of a client.
+    Disconnect { connection: u32, code: u16, reason: String },
+    /// Changes a setting of a client, as `:on` does, or only reads them without one.
+    Settings { connection: u32, setting: Option<Setting> },
+}
+
+impl Operation {
+    /// Whether the operation changes what is forwarded, which strict passthrough forbids.
+    fn changes_traffic(&self) -> bool {
+        match self {
+            Operation::Settings { setting, .. } => setting.as_ref().is_some_and(Setting::changes_traffic),
+            Operation::Send { .. } | Operation::Disconnect { .. } => true,
+        }
+    }
+}
+
+/// Status and JSON body of a response.
+pub type Answer = (u16, Value);
+
+/// Response to a request.
+enum Reply {
+    Answer(Answer),
+    Page,
+    /// Handshake of a page of the UI streaming the events, with its key.
+    Events(String),
+}
+
+/// Open connection of the proxy, as listed by the API.
+struct Registered {
+    out: Sender,
+    details: Value,
+}
+
+type Registry = Arc<Mutex<BTreeMap<u32, Registered>>>;
+
+/// HTTP server on localhost controlling the running proxy, given with --control-port:
+///
+/// ```text
+/// GET    /connections                   open connections
+/// POST   /send?to=client|server[&connection=<id>]
+///                                       body as a message, binary with application/octet-stream
+/// DELETE /connections/<id>[?code=<code>&reason=<text>]
+///                                       closes the connection of a client
+/// GET    /connections/<id>/settings     {"connection": <id>, "settings": [<setting>...]} given with :on
+/// POST   /connections/<id>/settings     body as a setting after :on <id>, like delay 500 or log debug
+/// DELETE /connections/<id>/settings     the client gets the settings of the proxy again
+/// GET    /options                       {"pretty": [<renderer>...]}
+/// PUT    /options                       replaces the renderers messages are printed with
+/// GET    /                              web UI of the live traffic
+/// GET    /events                        WebSocket of the events the UI shows
+/// ```
+///
+/// Sending, disconnecting and settings of clients are handed to the event loop, which is woken
+/// up with a timeout of an open connection and takes them with `next`.
+pub struct Control {
+    connections: Registry,
+    viewers: Viewers,
+    operations: mpsc::Receiver<(Operation, mpsc::Sender<Answer>)>,
+}
+
+impl Control {
+    /// Starts listening on the loopback interface. With strict passthrough sending, disconnecting
+    /// and settings changing the traffic are refused.
+    pub fn start(port: u16, renderers: Arc<RwLock<Renderers>>, token: Token, strict: bool)
+        -> std::result::Result<Self, String> {
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let listener = TcpListener::bind(address).map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+        let connections: Registry = Arc::new(Mutex::new(BTreeMap::new()));
+        let (operations_tx, operations) = mpsc::channel();
+        let viewers = Viewers::default();
+        let server = Server {
+            connections: connections.clone(),
+            viewers: viewers.clone(),
+            renderers,
+            operations: operations_tx,
+            token,
+            strict,
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => server.serve(stream),
+                    Err(e) => error!("Error: {}", e),
+                }
+            }
+        });
+        info!("Control API is listening on {}", address);
+        Ok(Control { connections, viewers, operations })
+    }
+
+    /// Lists an opened connection.
+    pub fn opened(&self, connection_id: u32, out: Sender, details: Value) {
+        self.viewers.publish(&json!({ "event": "opened", "connection": details }));
+        self.connections.lock().unwrap().insert(connection_id, Registered { out, details });
+    }
+
+    pub fn closed(&self, connection_id: u32) {
+        if self.connections.lock().unwrap().remove(&connection_id).is_some() {
+            self.viewers.publish(&json!({ "event": "closed", "connection_id": connection_id }));
+        }
+    }
+
+    /// Shows a message record in the UI, in the tabs of the given clients.
+    pub fn message(&self, record: &Value, clients: &[u32]) {
+        let mut event = record.clone();
+        event["connections"] = json!(clients);
+        self.viewers.publish(&event);
+    }
+
+    /// Next operation to be carried out by the event loop, with the sender of its answer.
+    pub fn next(&self) -> Option<(Operation, mpsc::Sender<Answer>)> {
+        self.operations.try_recv().ok()
+    }
+}
+
+/// The listening side of the API, answering requests one by one.
+struct Server {
+    connections: Registry,
+    viewers: Viewers,
+    renderers: Arc<RwLock<Renderers>>,
+    operations: mpsc::Sender<(Operation, mpsc::Sender<Answer>)>,
+    token: Token,
+    strict: bool,
+}
+
+impl Server {
+    fn serve(&self, stream: TcpStream) {
+        stream.set_read_timeout(Some(TIMEOUT)).ok();
+        let reply = self.read(&stream).unwrap_or_else(|e| Reply::Answer((400, json!({ "error": e.to_string() }))));
+        let (status, kind, body) = match reply {
+            Reply::Answer((status, body)) => (status, "application/json", format!("{}\n", body)),
+            Reply::Page => (200, "text/html; charset=utf-8", webui::PAGE.to_string()),
+            Reply::Events(key) => {
+                if let Err(e) = self.viewers.connect(stream, &key) {
+                    debug!("Web UI is not connected: {}", e);
+                }
+                return;
+            },
+        };
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Service Unavailable",
+        };
+        let response = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n{}", status, reason, kind, body.len(), body);
+        if let Err(e) = (&stream).write_all(response.as_bytes()) {
+            debug!("Control response is not sent: {}", e);
+        }
+    }
+
+    fn read(&self, stream: &TcpStream) -> io::Result<Reply> {
+        let mut reader = BufReader::new(stream);
+        let (request_line, headers) = sse::read_head(&mut reader)?;
+        // Browsers tell the origin of pages, which could reach localhost from anywhere but the UI
+        let host = sse::header(&headers, "host").unwrap_or_default();
+        if let Some(origin) = sse::header(&headers, "origin").filter(|origin| !own(origin, &host)) {
+            let refused = format!("Requests of web pages like {} are refused", origin);
+            return Ok(Reply::Answer((403, json!({ "error": refused }))));
+        }
+        let length = sse::header(&headers, "content-length")
+            .and_then(|length| length.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        if length > MAX_BODY {
+            return Ok(Reply::Answer((400, json!({ "error": format!("Body is larger than {} bytes", MAX_BODY) }))));
+        }
+        let mut body = vec![];
+        reader.take(length).read_to_end(&mut body)?;
+        let binary = sse::header(&headers, "content-type")
+            .map(|kind| kind.trim().starts_with("application/octet-stream"))
+            .unwrap_or(false);
+
+        let (method, target) = match request_line.split(' ').collect::<Vec<&str>>().as_slice() {
+            [method, target, _] => (method.to_string(), target.to_string()),
+            _ => return Ok(Reply::Answer((400, json!({ "error": "Bad request" })))),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+        let query: BTreeMap<String, String> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        debug!("Control request {} {}", method, target);
+        match (method.as_str(), path) {
+            ("GET", "/") => Ok(Reply::Page),
+            ("GET", "/events") => Ok(match sse::header(&headers, "sec-websocket-key") {
+                Some(key) => Reply::Events(key),
+                None => Reply::Answer((400, json!({ "error": "Events are streamed over a WebSocket" }))),
+            }),
+            _ => Ok(Reply::Answer(self.answer(&method, path, &query, body, binary))),
+        }
+    }
+
+    fn answer(&self, method: &str, path: &str, query: &BTreeMap<String, String>, body: Vec<u8>, binary: bool) -> Answer {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["connections"]) => {
+                let connections: Vec<Value> = self.connections.lock().unwrap().values()
+                    .map(|registered| registered.details.clone())
+                    .collect();
+                (200, json!({ "connections": connections }))
+            },
+            ("POST", ["send"]) => {
+                let to = match query.get("to").map(|to| Leg::parse(to)) {
+                    Some(Ok(to)) => to,
+                    Some(Err(e)) => return (400, json!({ "error": e })),
+                    None => return (400, json!({ "error": "The side to send to is missing, ?to=client or ?to=server" })),
+                };
+                let connection = match query.get("connection").map(|id| id.parse::<u32>()) {
+                    Some(Ok(connection)) if to == Leg::Client => Some(connection),
+                    Some(Ok(_)) => return (400, json!({ "error": "Only messages to clients go to a connection" })),
+                    Some(Err(_)) => return (400, json!({ "error": "Connection id is invalid" })),
+                    None => None,
+                };
+                let message = match binary {
+                    true => Message::Binary(body),
+                    false => match String::from_utf8(body) {
+                        Ok(text) => Message::Text(text),
+                        Err(_) => return (400, json!({ "error": "Body is not UTF-8, send binary messages \
+                            as application/octet-stream" })),
+                    },
+                };
+                self.act(Operation::Send { to, connection, message })
+            },
+            ("DELETE", ["connections", id]) => {
+                let connection = match id.parse::<u32>() {
+                    Ok(connection) => connection,
+                    Err(_) => return (400, json!({ "error": "Connection id is invalid" })),
+                };
+                let code = match query.get("code").map(|code| code.parse::<u16>()) {
+                    Some(Ok(code)) => code,
+                    Some(Err(_)) => return (400, json!({ "error": "Close code is invalid" })),
+                    None => 1000,
+                };
+                let reason = query.get("reason").cloned().unwrap_or_default();
+                self.act(Operation::Disconnect { connection, code, reason })
+            },
+            (_, ["connections", id, "settings"]) => {
+                let connection = match id.parse::<u32>() {
+                    Ok(connection) => connection,
+                    Err(_) => return (400, json!({ "error": "Connection id is invalid" })),
+                };
+                let setting = match method {
+                    "GET" => None,
+                    "POST" => match String::from_utf8(body).map_err(|_| "Setting is not UTF-8".to_string())
+                        .and_then(|setting| Setting::parse(&setting)) {
+                        Ok(setting) => Some(setting),
+                        Err(e) => return (400, json!({ "error": e })),
+                    },
+                    "DELETE" => Some(Setting::Reset),
+                    _ => return (405, json!({ "error": format!("{} is not allowed on {}", method, path) })),
+                };
+                self.act(Operation::Settings { connection, setting })
+            },
+            ("GET", ["options"]) => (200, self.options()),
+            ("PUT", ["options"]) => match self.set_options(&body) {
+                Ok(()) => (200, self.options()),
+                Err(e) => (400, json!({ "error": e })),
+            },
+            (_, ["connections"]) | (_, ["send"]) | (_, ["connections", _]) | (_, ["options"]) => {
+                (405, json!({ "error": format!("{} is not allowed on {}", method, path) }))
+            },
+            _ => (404, json!({ "error": format!("There is no {}", path) })),
+        }
+    }
+
+    /// Hands the operation to the event loop and waits for it to be carried out.
+    fn act(&self, operation: Operation) -> Answer {
+        if self.strict && operation.changes_traffic() {
+            return (403, json!({ "error": "Changing the traffic is forbidden by --strict-passthrough" }));
+        }
+        let waker = match self.connections.lock().unwrap().values().next() {
+            Some(registered) => registered.out.clone(),
+            None => return (409, json!({ "error": "No connection is open" })),
+        };
+        let (answer_tx, answer) = mpsc::channel();
+        if self.operations.send((operation, answer_tx)).is_err() {
+            return (503, json!({ "error": "The proxy is stopped" }));
+        }
+        waker.timeout(0, self.token).ok();
+        answer.recv_timeout(TIMEOUT)
+            .unwrap_or_else(|_| (503, json!({ "error": "The proxy didn't carry the request out in time" })))
+    }
+
+    fn options(&self) -> Value {
+        let renderers = self.renderers.read().unwrap();
+        let pretty: Vec<String> = renderers.enabled().iter().map(Renderer::to_string).collect();
+        json!({ "pretty": pretty })
+    }
+
+    /// Replaces the enabled renderers with those of `{"pretty": [...]}`, unless one is unknown.
+    fn set_options(&self, body: &[u8]) -> std::result::Result<(), String> {
+        let options: Value = serde_json::from_slice(body).map_err(|e| format!("Options are not JSON: {}", e))?;
+        let pretty = options["pretty"].as_array().ok_or("Options have no list of renderers in pretty")?;
+        let enabled = pretty.iter()
+            .map(|name| name.as_str().ok_or_else(|| format!("Renderer {} is not a name", name)))
+            .map(|name| name.and_then(Renderer::parse))
+            .collect::<std::result::Result<Vec<Renderer>, String>>()?;
+        let mut renderers = self.renderers.write().unwrap();
+        for renderer in renderers.enabled().to_vec() {
+            renderers.disable(renderer);
+        }
+        for renderer in enabled {
+            renderers.enable(renderer);
+        }
+        let names: Vec<String> = renderers.enabled().iter().map(Renderer::to_string).collect();
+        info!("Messages are printed with renderers {:?}", names);
+        Ok(())
+    }
+}
+
+/// Whether the origin is the page of the UI, served from a loopback name rather than
+/// one a web page could make resolve to localhost.
+fn own(origin: &str, host: &str) -> bool {
+    let name = host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host);
+    ["localhost", "127.0.0.1"].contains(&name) && origin == format!("http://{}", host)
+}
@@ -0,0 +1,257 @@
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use ws::{Frame, OpCode};
+
+use crate::closecodes::Leg;
+
+const EXTENSION: &str = "permessage-deflate";
+/// Tail of a block flushed with sync, which RFC 7692 leaves out of messages.
+const TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+/// Window of the decompressors, which inflates messages compressed with any smaller one.
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// permessage-deflate negotiated with one side of the proxy, given with --deflate.
+/// Messages are inflated as they come from the side and deflated as they go to it,
+/// so each side gets the compression agreed with it whatever the other one has.
+#[derive(Clone, Copy, Debug)]
+pub struct DeflatePlan {
+    pub leg: Leg,
+    /// Largest window the proxy and the side compress with, from 9 to 15.
+    pub window_bits: u8,
+    /// Whether the proxy and the side start every message with an empty window.
+    pub no_context_takeover: bool,
+}
+
+impl DeflatePlan {
+    /// Parses `<client|server>[:<parameter>[,...]]` with parameters `window_bits=<9-15>`
+    /// and `no_context_takeover`, like `client` or `server:window_bits=10,no_context_takeover`.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (leg, parameters) = spec.split_once(':').unwrap_or((spec, ""));
+        let mut plan = DeflatePlan { leg: Leg::parse(leg)?, window_bits: MAX_WINDOW_BITS, no_context_takeover: false };
+        for parameter in parameters.split(',').filter(|parameter| !parameter.is_empty()) {
+            match parameter.split_once('=') {
+                Some(("window_bits", bits)) => {
+                    plan.window_bits = bits.parse::<u8>().ok()
+                        .filter(|bits| (9..=MAX_WINDOW_BITS).contains(bits))
+                        .ok_or_else(|| format!("Window bits {} in {} are not from 9 to 15", bits, spec))?;
+                },
+                None if parameter == "no_context_takeover" => plan.no_context_takeover = true,
+                _ => return Err(format!("Unknown parameter {} in {}, expected window_bits=<9-15> \
+                    or no_context_takeover", parameter, spec)),
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Extension offered to the server in the upgrade request.
+    pub fn offer(&self) -> String {
+        let mut offer = EXTENSION.to_string();
+        match self.window_bits < MAX_WINDOW_BITS {
+            true => offer.push_str(&format!("; client_max_window_bits={0}; server_max_window_bits={0}",
+                self.window_bits)),
+            false => offer.push_str("; client_max_window_bits"),
+        }
+        if self.no_context_takeover {
+            offer.push_str("; client_no_context_takeover; server_no_context_takeover");
+        }
+        offer
+    }
+
+    /// Accepts the first offer of a client the proxy can comply with, returning
+    /// the compression of the connection and the extension of the response.
+    pub fn accept(&self, offers: &[&str]) -> Option<(Deflate, String)> {
+        offers.iter().find_map(|offer| self.accept_offer(offer))
+    }
+
+    fn accept_offer(&self, offer: &str) -> Option<(Deflate, String)> {
+        let parameters = extension_parameters(offer)?;
+        let (mut compress_bits, mut server_bits_offered) = (self.window_bits, false);
+        let mut client_bits = None;
+        let (mut reset_compress, mut client_reset) = (self.no_context_takeover, self.no_context_takeover);
+        for (name, value) in parameters {
+            match (name, value) {
+                ("server_no_context_takeover", None) => reset_compress = true,
+                ("client_no_context_takeover", None) => client_reset = true,
+                ("server_max_window_bits", Some(bits)) => {
+                    compress_bits = compress_bits.min(window_bits(bits)?);
+                    server_bits_offered = true;
+                },
+                ("client_max_window_bits", bits) => {
+                    let bits = bits.map(window_bits).unwrap_or(Some(MAX_WINDOW_BITS))?;
+                    client_bits = Some(bits.min(self.window_bits));
+                },
+                // Parameters the proxy doesn't know decline the offer
+                _ => return None,
+            }
+        }
+
+        let mut response = EXTENSION.to_string();
+        if server_bits_offered || compress_bits < MAX_WINDOW_BITS {
+            response.push_str(&format!("; server_max_window_bits={}", compress_bits));
+        }
+        if reset_compress {
+            response.push_str("; server_no_context_takeover");
+        }
+        if client_reset {
+            response.push_str("; client_no_context_takeover");
+        }
+        // Clients not offering the parameter compress with any window, which is inflated too
+        if let Some(bits) = client_bits.filter(|bits| *bits < MAX_WINDOW_BITS) {
+            response.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        Some((Deflate::new(compress_bits, reset_compress), response))
+    }
+
+    /// Compression agreed by the server in the response to the offer, none if it
+    /// didn't accept it. Parameters the offer didn't allow fail the connection.
+    pub fn accepted(&self, extensions: &[&str]) -> std::result::Result<Option<Deflate>, String> {
+        let accepted = match extensions.iter().find(|extension| extension_name(extension) == EXTENSION) {
+            Some(accepted) => accepted,
+            None => return Ok(None),
+        };
+        let parameters = extension_parameters(accepted)
+            .ok_or_else(|| format!("Server accepted {} with repeated parameters", accepted))?;
+        let (mut compress_bits, mut reset_compress) = (self.window_bits, self.no_context_takeover);
+        for (name, value) in parameters {
+            match (name, value) {
+                ("server_no_context_takeover", None) => {},
+                ("client_no_context_takeover", None) => reset_compress = true,
+                ("server_max_window_bits", Some(bits)) if window_bits(bits).is_some() => {},
+                ("client_max_window_bits", Some(bits)) => {
+                    compress_bits = compress_bits.min(window_bits(bits)
+                        .ok_or_else(|| format!("Server accepted {} with invalid window bits {}", EXTENSION, bits))?);
+                },
+                _ => return Err(format!("Server accepted {} with unexpected parameter {}", EXTENSION, name)),
+            }
+        }
+        Ok(Some(Deflate::new(compress_bits, reset_compress)))
+    }
+}
+
+/// Name of an extension in a Sec-WebSocket-Extensions header.
+fn extension_name(extension: &str) -> &str {
+    extension.split(';').next().unwrap_or_default().trim()
+}
+
+/// Parameters of a permessage-deflate extension, none if it's another one or
+/// repeats a parameter, which RFC 7692 forbids.
+fn extension_parameters(extension: &str) -> Option<Vec<(&str, Option<&str>)>> {
+    let mut parts = extension.split(';').map(str::trim);
+    if parts.next() != Some(EXTENSION) {
+        return None;
+    }
+    let mut parameters: Vec<(&str, Option<&str>)> = vec![];
+    for part in parts {
+        let (name, value) = match part.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (part, None),
+        };
+        if parameters.iter().any(|(seen, _)| *seen == name) {
+            return None;
+        }
+        parameters.push((name, value));
+    }
+    Some(parameters)
+}
+
+/// Window bits the proxy can compress with, from 9 to 15.
+fn window_bits(bits: &str) -> Option<u8> {
+    bits.parse::<u8>().ok().filter(|bits| (9..=MAX_WINDOW_BITS).contains(bits))
+}
+
+/// Compression of the messages of one connection.
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// Whether every sent message starts with an empty window.
+    reset_compress: bool,
+    /// Compressed fragments of a message being received.
+    fragments: Vec<Frame>,
+}
+
+impl Deflate {
+    fn new(window_bits: u8, reset_compress: bool) -> Self {
+        Deflate {
+            compress: Compress::new_with_window_bits(Compression::default(), false, window_bits),
+            decompress: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            reset_compress,
+            fragments: vec![],
+        }
+    }
+
+    /// Inflates a compressed message, collecting its fragments until the last one.
+    /// Other frames pass unchanged.
+    pub fn incoming(&mut self, mut frame: Frame) -> std::result::Result<Option<Frame>, String> {
+        if frame.is_control() || (self.fragments.is_empty() && !frame.has_rsv1()) {
+            return Ok(Some(frame));
+        }
+        frame.set_rsv1(false);
+        if !frame.is_final() {
+            self.fragments.push(frame);
+            return Ok(None);
+        }
+        let (opcode, mut compressed) = match self.fragments.is_empty() {
+            true => (frame.opcode(), frame.into_data()),
+            false => {
+                let opcode = self.fragments[0].opcode();
+                let mut compressed: Vec<u8> = self.fragments.drain(..).flat_map(Frame::into_data).collect();
+                compressed.extend(frame.into_data());
+                (opcode, compressed)
+            },
+        };
+        compressed.extend_from_slice(&TAIL);
+        let inflated = self.inflate(&compressed)?;
+        Ok(Some(Frame::message(inflated, opcode, true)))
+    }
+
+    /// Deflates a whole message. Fragments of messages, which the proxy sends only when
+    /// interleaving frames, and control frames go uncompressed.
+    pub fn outgoing(&mut self, mut frame: Frame) -> std::result::Result<Frame, String> {
+        if !matches!(frame.opcode(), OpCode::Text | OpCode::Binary) || !frame.is_final() {
+            return Ok(frame);
+        }
+        let mut compressed = self.deflate(frame.payload())?;
+        compressed.truncate(compressed.len() - TAIL.len());
+        if self.reset_compress {
+            self.compress.reset();
+        }
+        frame.set_rsv1(true);
+        *frame.payload_mut() = compressed;
+        Ok(frame)
+    }
+
+    fn deflate(&mut self, data: &[u8]) -> std::result::Result<Vec<u8>, String> {
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|e| format!("Failed to compress a message: {}", e))?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // The flush is complete once the output has room left
+            if consumed == data.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            output.reserve(output.capacity().max(64));
+        }
+    }
+
+    fn inflate(&mut self, data: &[u8]) -> std::result::Result<Vec<u8>, String> {
+        let mut output = Vec::with_capacity(data.len() * 2 + 64);
+        let start = self.decompress.total_in();
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self.decompress.decompress_vec(&data[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| format!("Failed to decompress a message: {}", e))?;
+            let consumed = (self.decompress.total_in() - start) as usize;
+            // A message ending the stream leaves the next one to a fresh one
+            if status == Status::StreamEnd {
+                self.decompress.reset(false);
+                return Ok(output);
+            }
+            if consumed == data.len() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            output.reserve(output.capacity().max(64));
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use serde_json::{json, Value};
+use ws::{Builder, CloseCode, Handshake, Message, Request, Response, Result, Sender};
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::{debug, error, info, warn};
+
+/// Header of a client handshake naming the browser tab which opened the connection.
+/// The extension adds it to WebSocket requests of the tabs it inspects.
+pub const TAB_HEADER: &str = "X-WS-Proxy-Tab";
+
+/// Version of the protocol, sent in the greeting and in every event.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Events kept for extensions subscribing after the traffic started.
+const HISTORY: usize = 1000;
+
+/// Origins of browser extensions, other web pages must not read the traffic.
+const EXTENSION_ORIGINS: [&str; 3] = ["chrome-extension://", "moz-extension://", "safari-web-extension://"];
+
+/// Endpoint on localhost for a browser devtools extension showing the traffic in the Network panel.
+///
+/// Every WebSocket message in either direction is one JSON object with a `type`. The extension
+/// sends `{"type":"subscribe","tab":"<id>"}` to receive the events of connections opened by that
+/// tab, or of all connections without a tab. It gets the recent events first, then live ones:
+/// `connection` when a client connects, `frame` for every message, `sent` by the page or
+/// `received` by it, and `closed`. Connections are mapped to tabs by the X-WS-Proxy-Tab header.
+#[derive(Clone)]
+pub struct DevTools {
+    inner: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    session: String,
+    subscribers: HashMap<u32, (Sender, Option<String>)>,
+    /// Tab of every client connection seen, if it has one.
+    tabs: HashMap<u32, Option<String>>,
+    history: VecDeque<Value>,
+}
+
+impl DevTools {
+    /// Starts listening on the loopback interface only.
+    pub fn start(port: u16, session: &str) -> std::result::Result<Self, String> {
+        let inner = Arc::new(Mutex::new(State { session: session.to_string(), ..State::default() }));
+        let shared = inner.clone();
+        let (started_tx, started_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let ws = match Builder::new().build(|out: Sender| Extension { out, inner: shared.clone() }) {
+                Ok(ws) => ws.bind(SocketAddr::from(([127,0,0,1], port))),
+                Err(e) => Err(e)
+            };
+
+            let ws = match ws {
+                Ok(ws) => ws,
+                Err(e) => {
+                    started_tx.send(Err(e.to_string())).unwrap();
+                    return;
+                }
+            };
+
+            started_tx.send(Ok(())).unwrap();
+            if let Err(e) = ws.run() {
+                error!("Error: {}", e);
+            }
+        });
+
+        started_rx.recv().map_err(|e| e.to_string())??;
+        info!("Devtools extensions can connect to localhost:{}", port);
+        Ok(DevTools { inner })
+    }
+
+    /// Maps a client connection to its tab, given the open record of the index.
+    pub fn opened(&self, connection_id: u32, tab: Option<String>, record: &Value) {
+        let mut state = self.inner.lock().unwrap();
+        state.tabs.insert(connection_id, tab.clone());
+        state.publish(json!({
+            "v": PROTOCOL_VERSION,
+            "type": "connection",
+            "tab": tab,
+            "connection": connection_id,
+            "url": record["resource"],
+            "headers": record["request_headers"],
+            "time": record["time"],
+        }));
+    }
+
+    /// Shows a message record of the client connection, forwarded in either direction.
+    pub fn frame(&self, connection_id: u32, record: &Value) {
+        let mut state = self.inner.lock().unwrap();
+        let tab = state.tab(connection_id);
+        let direction = match record["from"].as_str() {
+            Some("client") => "sent",
+            _ => "received",
+        };
+        state.publish(json!({
+            "v": PROTOCOL_VERSION,
+            "type": "frame",
+            "tab": tab,
+            "connection": connection_id,
+            "id": record["id"],
+            "direction": direction,
+            "opcode": record["type"],
+            "data": record["data"],
+            "time": record["time"],
+        }));
+    }
+
+    /// Shows the close record of a client connection.
+    pub fn closed(&self, connection_id: u32, record: &Value) {
+        let mut state = self.inner.lock().unwrap();
+        let tab = state.tab(connection_id);
+        state.publish(json!({
+            "v": PROTOCOL_VERSION,
+            "type": "closed",
+            "tab": tab,
+            "connection": connection_id,
+            "code": record["code"],
+            "reason": record["reason"],
+            "time": record["time"],
+        }));
+    }
+}
+
+impl State {
+    fn tab(&self, connection_id: u32) -> Option<String> {
+        self.tabs.get(&connection_id).cloned().flatten()
+    }
+
+    fn publish(&mut self, event: Value) {
+        for (sender, filter) in self.subscribers.values() {
+            if shown(filter, &event) {
+                sender.send(Message::text(event.to_string())).unwrap_or_else(|e| error!("Error: {}", e));
+            }
+        }
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(event);
+    }
+}
+
+fn shown(filter: &Option<String>, event: &Value) -> bool {
+    match filter {
+        Some(tab) => event["tab"].as_str() == Some(tab),
+        None => true,
+    }
+}
+
+struct Extension {
+    out: Sender,
+    inner: Arc<Mutex<State>>,
+}
+
+impl Extension {
+    fn subscribe(&self, tab: Option<String>) {
+        let mut state = self.inner.lock().unwrap();
+        for event in state.history.iter().filter(|event| shown(&tab, event)) {
+            self.out.send(Message::text(event.to_string())).unwrap_or_else(|e| error!("Error: {}", e));
+        }
+        debug!("Devtools extension {} subscribed to tab {:?}", self.out.connection_id(), tab);
+        state.subscribers.insert(self.out.connection_id(), (self.out.clone(), tab));
+    }
+
+    fn error(&self, error: &str) {
+        self.out.send(Message::text(json!({ "v": PROTOCOL_VERSION, "type": "error", "error": error }).to_string()))
+            .unwrap_or_else(|e| error!("Error: {}", e));
+    }
+}
+
+impl ws::Handler for Extension {
+    fn on_request(&mut self, request: &Request) -> Result<Response> {
+        // Browsers send an origin, any web page could connect to localhost otherwise
+        let origin = request.header("origin").map(|origin| String::from_utf8_lossy(origin).into_owned());
+        if let Some(origin) = origin.filter(|origin| !EXTENSION_ORIGINS.iter().any(|prefix| origin.starts_with(prefix))) {
+            warn!("Devtools connection from {} is rejected, only extensions may connect", origin);
+            return Ok(Response::new(403, "Forbidden", b"Only browser extensions may connect".to_vec()));
+        }
+        Response::from_request(request)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        info!("Devtools extension {} connected", self.out.connection_id());
+        let session = self.inner.lock().unwrap().session.clone();
+        self.out.send(Message::text(json!({
+            "v": PROTOCOL_VERSION,
+            "type": "hello",
+            "session": session,
+        }).to_string()))
+    }
+
+    fn on_message(&mut self, message: Message) -> Result<()> {
+        let request = match message {
+            Message::Text(text) => serde_json::from_str::<Value>(&text).unwrap_or_default(),
+            Message::Binary(_) => Value::Null,
+        };
+        match request["type"].as_str() {
+            Some("subscribe") => self.subscribe(match &request["tab"] {
+                Value::String(tab) => Some(tab.clone()),
+                Value::Number(tab) => Some(tab.to_string()),
+                _ => None,
+            }),
+            Some(other) => self.error(&format!("unknown request {}", other)),
+            None => self.error("requests must be JSON objects with a type"),
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        debug!("Devtools extension {} disconnected: {:?}", self.out.connection_id(), code);
+        self.inner.lock().unwrap().subscribers.remove(&self.out.connection_id());
+    }
+}
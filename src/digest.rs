@@ -0,0 +1,242 @@
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde_json::{json, Map, Value};
+use url::Url;
+use ws::Message;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::alert::{self, ERROR_PATTERN};
+use crate::clock::Clock;
+use crate::closecodes::{self, Leg};
+use crate::console;
+use crate::logqueue::LogFile;
+use crate::memory::format_size;
+
+/// Digests of the traffic, appended to the session directory.
+pub const DIGEST: &str = "digest.txt";
+
+/// Message types and clients listed in a digest.
+const TOP: usize = 5;
+
+/// Writes a rollup of the traffic every interval, so that an unattended capture
+/// can be read in the morning before the raw logs: messages by type, errors,
+/// latencies of replies and the clients sending the most.
+pub struct Digests {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    interval: Duration,
+    file: LogFile,
+    webhook: Option<Url>,
+    clock: Arc<dyn Clock>,
+    errors: Regex,
+    state: Mutex<State>,
+}
+
+struct State {
+    since: DateTime<Utc>,
+    last_request: Option<Instant>,
+    period: Period,
+}
+
+#[derive(Default)]
+struct Period {
+    messages: [u64; 2],
+    types: BTreeMap<String, u64>,
+    error_messages: u64,
+    /// Connections closed with codes other than normal closure or going away.
+    error_closes: BTreeMap<u16, u64>,
+    latencies: Vec<Duration>,
+    /// Messages and bytes sent by each client.
+    talkers: HashMap<u32, (u64, u64)>,
+}
+
+impl Digests {
+    /// Starts writing digests every interval in a background thread.
+    pub fn start(interval: Duration, file: LogFile, webhook: Option<Url>, clock: Arc<dyn Clock>) -> Self {
+        let state = State { since: clock.utc(), last_request: None, period: Period::default() };
+        let inner = Arc::new(Inner {
+            interval,
+            file,
+            webhook,
+            clock: clock.clone(),
+            errors: Regex::new(ERROR_PATTERN).unwrap(),
+            state: Mutex::new(state),
+        });
+        let watched = Arc::downgrade(&inner);
+        thread::spawn(move || watch(watched, clock));
+        Digests { inner }
+    }
+
+    pub fn message(&self, connection_id: u32, from: Leg, message: &Message) {
+        let inner = &self.inner;
+        let mut state = inner.state.lock().unwrap();
+        let latency = match from {
+            Leg::Client => {
+                state.last_request = Some(inner.clock.now());
+                None
+            },
+            Leg::Server => state.last_request.take().map(|request| inner.clock.now() - request),
+        };
+
+        let period = &mut state.period;
+        period.messages[from as usize] += 1;
+        let message_type = match message {
+            Message::Text(text) => {
+                if inner.errors.is_match(text) {
+                    period.error_messages += 1;
+                }
+                console::message_type(text).unwrap_or_else(|| "text".to_string())
+            },
+            Message::Binary(_) => "binary".to_string(),
+        };
+        *period.types.entry(message_type).or_insert(0) += 1;
+        period.latencies.extend(latency);
+        if from == Leg::Client {
+            let talker = period.talkers.entry(connection_id).or_insert((0, 0));
+            talker.0 += 1;
+            talker.1 += message.len() as u64;
+        }
+    }
+
+    pub fn closed(&self, code: u16) {
+        if code != 1000 && code != 1001 {
+            *self.inner.state.lock().unwrap().period.error_closes.entry(code).or_insert(0) += 1;
+        }
+    }
+}
+
+impl Drop for Digests {
+    /// The traffic since the last digest gets one as well.
+    fn drop(&mut self) {
+        self.inner.digest();
+    }
+}
+
+impl Inner {
+    fn digest(&self) {
+        let (since, period) = {
+            let mut state = self.state.lock().unwrap();
+            let since = std::mem::replace(&mut state.since, self.clock.utc());
+            (since, std::mem::take(&mut state.period))
+        };
+        let record = period.record(since, self.clock.utc());
+        self.file.write(format!("{}\n", report(&record)));
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = alert::post(webhook, &record.to_string()) {
+                error!("Error: digest webhook {} failed: {}", webhook, e);
+            }
+        }
+    }
+}
+
+fn watch(inner: Weak<Inner>, clock: Arc<dyn Clock>) {
+    let interval = match inner.upgrade() {
+        Some(inner) => inner.interval,
+        None => return,
+    };
+    loop {
+        clock.sleep(interval);
+        match inner.upgrade() {
+            Some(inner) => inner.digest(),
+            None => return,
+        }
+    }
+}
+
+impl Period {
+    fn record(&self, since: DateTime<Utc>, until: DateTime<Utc>) -> Value {
+        let mut types: Vec<(&String, &u64)> = self.types.iter().collect();
+        types.sort_by(|a, b| b.1.cmp(a.1));
+        let types: Vec<Value> = types.into_iter().take(TOP)
+            .map(|(name, count)| json!({ "type": name, "count": count }))
+            .collect();
+
+        let mut talkers: Vec<(&u32, &(u64, u64))> = self.talkers.iter().collect();
+        talkers.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(b.0)));
+        let talkers: Vec<Value> = talkers.into_iter().take(TOP)
+            .map(|(connection_id, (messages, bytes))| json!({
+                "connection_id": connection_id,
+                "messages": messages,
+                "bytes": bytes,
+            }))
+            .collect();
+
+        let mut latencies = self.latencies.clone();
+        latencies.sort();
+        let percentile = |share: f64| latencies.get(((latencies.len() as f64 * share).ceil() as usize).saturating_sub(1))
+            .map(|latency| latency.as_millis() as u64);
+        let closes: Map<String, Value> = self.error_closes.iter()
+            .map(|(code, count)| (code.to_string(), json!(count)))
+            .collect();
+
+        json!({
+            "event": "digest",
+            "since": since.to_rfc3339(),
+            "until": until.to_rfc3339(),
+            "messages": {
+                "client": self.messages[Leg::Client as usize],
+                "server": self.messages[Leg::Server as usize],
+            },
+            "types": types,
+            "errors": {
+                "messages": self.error_messages,
+                "closes": closes,
+            },
+            "latency_ms": {
+                "replies": latencies.len(),
+                "p50": percentile(0.5),
+                "p90": percentile(0.9),
+                "p99": percentile(0.99),
+            },
+            "top_talkers": talkers,
+        })
+    }
+}
+
+/// Digest as a few lines of text.
+fn report(record: &Value) -> String {
+    let mut report = format!("=== {} - {}\nmessages: client {}, server {}",
+        record["since"].as_str().unwrap_or_default(), record["until"].as_str().unwrap_or_default(),
+        record["messages"]["client"], record["messages"]["server"]);
+
+    let types: Vec<String> = record["types"].as_array().into_iter().flatten()
+        .map(|found| format!("{} {}", found["type"].as_str().unwrap_or_default(), found["count"]))
+        .collect();
+    if !types.is_empty() {
+        report.push_str(&format!("\ntop types: {}", types.join(", ")));
+    }
+
+    let closes: Vec<String> = record["errors"]["closes"].as_object().into_iter().flatten()
+        .map(|(code, count)| {
+            let code = code.parse().unwrap_or_default();
+            format!("{} {} x{}", code, closecodes::describe(code), count)
+        })
+        .collect();
+    report.push_str(&format!("\nerrors: {} error messages", record["errors"]["messages"]));
+    if !closes.is_empty() {
+        report.push_str(&format!(", closes {}", closes.join(", ")));
+    }
+
+    let latency = &record["latency_ms"];
+    if latency["replies"].as_u64().unwrap_or_default() > 0 {
+        report.push_str(&format!("\nlatency: p50 {}ms, p90 {}ms, p99 {}ms of {} replies",
+            latency["p50"], latency["p90"], latency["p99"], latency["replies"]));
+    }
+
+    let talkers: Vec<String> = record["top_talkers"].as_array().into_iter().flatten()
+        .map(|talker| format!("connection {} {} msgs ({})", talker["connection_id"], talker["messages"],
+            format_size(talker["bytes"].as_u64().unwrap_or_default() as usize)))
+        .collect();
+    if !talkers.is_empty() {
+        report.push_str(&format!("\ntop talkers: {}", talkers.join(", ")));
+    }
+    report
+}
@@ -0,0 +1,460 @@
+use openssl::nid::Nid;
+use openssl::ssl::{SslConnector, SslStream, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509VerifyResult};
+use url::Url;
+use ws::{Request, Response};
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use ws_proxy::tls;
+
+use crate::cli::DoctorArgs;
+use crate::Outcome;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest response head read before giving up on a server.
+const MAX_HEAD: usize = 16 * 1024;
+/// Subprotocols offered all at once, to see whether the server insists on one.
+const COMMON_PROTOCOLS: &[&str] = &["graphql-transport-ws", "graphql-ws", "mqtt", "wamp.2.json", "v12.stomp", "ocpp1.6"];
+const BROWSER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+/// Close frame with code 1000 and a zero mask, as clients have to mask their frames.
+const CLOSE_FRAME: &[u8] = &[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8];
+
+pub fn run(args: DoctorArgs) -> Outcome {
+    let secure = match args.url.scheme() {
+        "ws" => false,
+        "wss" => true,
+        scheme => return Err(format!("Unsupported scheme {}, expected ws or wss", scheme)),
+    };
+    let host = args.url.host_str().ok_or_else(|| format!("There is no host in {}", args.url))?.to_string();
+    let port = args.url.port_or_known_default().unwrap_or(80);
+    let connector = match secure {
+        true => {
+            let identity = args.upstream_cert.as_deref().zip(args.upstream_key.as_deref());
+            Some(tls::connector(args.upstream_ca.as_deref(), identity, false)?)
+        },
+        false => None,
+    };
+    let doctor = Doctor { url: &args.url, host: &host, connector: connector.as_ref(), headers: &args.header };
+
+    println!("Checking {}", args.url);
+    let addresses = match doctor.resolve(port) {
+        Some(addresses) => addresses,
+        None => return Ok(ExitCode::from(1)),
+    };
+    let (address, stream) = match doctor.connect(&addresses, true) {
+        Some(connected) => connected,
+        None => return Ok(ExitCode::from(1)),
+    };
+    let (stream, trusted) = match doctor.secure(stream, true) {
+        Some(secured) => secured,
+        None => return Ok(ExitCode::from(1)),
+    };
+
+    let mut attempts = vec![];
+    let mut stream = Some(stream);
+    for variant in VARIANTS {
+        // The first attempt reuses the connection checked above, the others get their own
+        let stream = match stream.take() {
+            Some(stream) => Some(stream),
+            None => doctor.reconnect(address),
+        };
+        let upgrade = match stream {
+            Some(mut stream) => doctor.upgrade(&mut stream, variant, &args.protocol),
+            None => Upgrade::Unanswered("the connection failed".to_string()),
+        };
+        report(&format!("Handshake {}", variant.name), &upgrade.outcome(), None, &upgrade.describe());
+        attempts.push((variant, upgrade));
+    }
+
+    println!();
+    diagnose(&args.url, trusted, &attempts);
+    match attempts[0].1 {
+        Upgrade::Accepted { .. } if trusted => Ok(ExitCode::SUCCESS),
+        _ => Ok(ExitCode::from(1)),
+    }
+}
+
+/// Way of making the upgrade request, tried one after another to tell what the server wants.
+struct Variant {
+    name: &'static str,
+    /// Origin and User-Agent of a browser.
+    browser: bool,
+    /// COMMON_PROTOCOLS offered along with the given ones.
+    protocols: bool,
+}
+
+const VARIANTS: &[Variant] = &[
+    Variant { name: "as the proxy", browser: false, protocols: false },
+    Variant { name: "with browser headers", browser: true, protocols: false },
+    Variant { name: "offering subprotocols", browser: false, protocols: true },
+];
+
+/// How the server answered an upgrade request.
+enum Upgrade {
+    Accepted { protocol: Option<String>, extensions: Option<String>, server: Option<String> },
+    /// 101 with a Sec-WebSocket-Accept not matching the key, as some broken middleboxes send.
+    WrongAccept,
+    Refused { status: u16, reason: String, location: Option<String>, body: String },
+    Unanswered(String),
+}
+
+impl Upgrade {
+    fn outcome(&self) -> String {
+        match self {
+            Upgrade::Accepted { .. } => "ok".to_string(),
+            Upgrade::WrongAccept => "101?".to_string(),
+            Upgrade::Refused { status, .. } => status.to_string(),
+            Upgrade::Unanswered(_) => "failed".to_string(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Upgrade::Accepted { protocol, extensions, server } => {
+                let mut details = vec![format!("subprotocol {}", protocol.as_deref().unwrap_or("none"))];
+                if let Some(extensions) = extensions {
+                    details.push(format!("extensions {}", extensions));
+                }
+                if let Some(server) = server {
+                    details.push(format!("server {}", server));
+                }
+                details.join(", ")
+            },
+            Upgrade::WrongAccept => "101 with a Sec-WebSocket-Accept not matching the key".to_string(),
+            Upgrade::Refused { reason, location, body, .. } => {
+                let mut description = reason.clone();
+                if let Some(location) = location {
+                    description.push_str(&format!(", to {}", location));
+                }
+                if !body.is_empty() {
+                    description.push_str(&format!(": {}", body));
+                }
+                description
+            },
+            Upgrade::Unanswered(error) => error.clone(),
+        }
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<SslStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+struct Doctor<'a> {
+    url: &'a Url,
+    host: &'a str,
+    connector: Option<&'a SslConnector>,
+    headers: &'a [(String, String)],
+}
+
+impl Doctor<'_> {
+    fn resolve(&self, port: u16) -> Option<Vec<SocketAddr>> {
+        let started = Instant::now();
+        match (self.host, port).to_socket_addrs() {
+            Ok(addresses) => {
+                let addresses: Vec<SocketAddr> = addresses.collect();
+                let listed: Vec<String> = addresses.iter().map(|address| address.ip().to_string()).collect();
+                report("DNS", "ok", Some(started.elapsed()), &listed.join(", "));
+                Some(addresses)
+            },
+            Err(e) => {
+                report("DNS", "failed", Some(started.elapsed()), &e.to_string());
+                hint(&format!("{} doesn't resolve: check the spelling, or whether it's only known \
+                    inside a VPN or a cluster", self.host));
+                None
+            },
+        }
+    }
+
+    /// Connects to the first address which accepts, reporting the others which didn't.
+    fn connect(&self, addresses: &[SocketAddr], verbose: bool) -> Option<(SocketAddr, TcpStream)> {
+        let mut errors = vec![];
+        for address in addresses {
+            let started = Instant::now();
+            match TcpStream::connect_timeout(address, TIMEOUT) {
+                Ok(stream) => {
+                    if verbose {
+                        report("TCP", "ok", Some(started.elapsed()), &address.to_string());
+                    }
+                    return Some((*address, stream));
+                },
+                Err(e) => {
+                    if verbose {
+                        report("TCP", "failed", Some(started.elapsed()), &format!("{}: {}", address, e));
+                    }
+                    errors.push(e.kind());
+                },
+            }
+        }
+        if verbose {
+            if errors.contains(&ErrorKind::ConnectionRefused) {
+                hint("Nothing listens on the port: check the port, and whether the server is up");
+            } else if errors.contains(&ErrorKind::TimedOut) {
+                hint("The connection timed out: a firewall may drop it, or the host is down");
+            }
+        }
+        None
+    }
+
+    fn reconnect(&self, address: SocketAddr) -> Option<Stream> {
+        let (_, stream) = self.connect(&[address], false)?;
+        self.secure(stream, false).map(|(stream, _)| stream)
+    }
+
+    /// Makes the TLS handshake for wss://, reporting the certificate of the server, and
+    /// tells whether the certificate is trusted. Verification problems are reported rather
+    /// than failing, to look further.
+    fn secure(&self, stream: TcpStream, verbose: bool) -> Option<(Stream, bool)> {
+        let _ = stream.set_read_timeout(Some(TIMEOUT));
+        let _ = stream.set_write_timeout(Some(TIMEOUT));
+        let connector = match self.connector {
+            Some(connector) => connector,
+            None => return Some((Stream::Plain(stream), true)),
+        };
+        let started = Instant::now();
+        let mut configuration = match connector.configure() {
+            Ok(configuration) => configuration,
+            Err(e) => {
+                report("TLS", "failed", None, &e.to_string());
+                return None;
+            },
+        };
+        configuration.set_verify(SslVerifyMode::NONE);
+        let stream = match configuration.connect(self.host, stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                if verbose {
+                    let error = e.to_string();
+                    report("TLS", "failed", Some(started.elapsed()), &error);
+                    if error.contains("wrong version number") || error.contains("packet length too long") {
+                        let mut plain = self.url.clone();
+                        let _ = plain.set_scheme("ws");
+                        hint(&format!("The server doesn't speak TLS on the port, try {}", plain));
+                    } else if error.contains("alert") && error.contains("certificate") {
+                        hint("The server may want a client certificate, give one with --upstream-cert \
+                            and --upstream-key");
+                    }
+                }
+                return None;
+            },
+        };
+        let verified = stream.ssl().verify_result();
+        if verbose {
+            let ssl = stream.ssl();
+            let mut details = vec![ssl.version_str().to_string()];
+            if let Some(cipher) = ssl.current_cipher() {
+                details.push(cipher.name().to_string());
+            }
+            match verified == X509VerifyResult::OK {
+                true => report("TLS", "ok", Some(started.elapsed()), &details.join(", ")),
+                false => report("TLS", "untrusted", Some(started.elapsed()), verified.error_string()),
+            }
+            if let Some(certificate) = ssl.peer_certificate() {
+                report("Certificate", "", None, &format!("{}, issued by {}, valid until {}",
+                    common_name(certificate.subject_name()), common_name(certificate.issuer_name()),
+                    certificate.not_after()));
+            }
+            if verified != X509VerifyResult::OK {
+                hint("The proxy refuses this certificate: give the CA which issued it with --upstream-ca, \
+                    or run the proxy with --insecure while testing");
+            }
+        }
+        Some((Stream::Tls(Box::new(stream)), verified == X509VerifyResult::OK))
+    }
+
+    fn upgrade(&self, stream: &mut Stream, variant: &Variant, protocols: &[String]) -> Upgrade {
+        let mut request = match Request::from_url(self.url) {
+            Ok(request) => request,
+            Err(e) => return Upgrade::Unanswered(e.to_string()),
+        };
+        for protocol in protocols {
+            request.add_protocol(protocol);
+        }
+        if variant.protocols {
+            for protocol in COMMON_PROTOCOLS {
+                request.add_protocol(protocol);
+            }
+        }
+        let headers = request.headers_mut();
+        if variant.browser {
+            let origin = match self.connector {
+                Some(_) => format!("https://{}", self.host),
+                None => format!("http://{}", self.host),
+            };
+            headers.push(("Origin".to_string(), origin.into_bytes()));
+            headers.push(("User-Agent".to_string(), BROWSER_AGENT.as_bytes().to_vec()));
+        }
+        for (name, value) in self.headers {
+            headers.push((name.clone(), value.clone().into_bytes()));
+        }
+
+        let mut bytes = vec![];
+        if let Err(e) = request.format(&mut bytes).map_err(|e| e.to_string())
+            .and_then(|_| stream.write_all(&bytes).map_err(|e| e.to_string())) {
+            return Upgrade::Unanswered(format!("can't send the request: {}", e));
+        }
+        let (head, rest) = match read_head(stream) {
+            Ok(read) => read,
+            Err(e) => return Upgrade::Unanswered(e),
+        };
+        let response = match Response::parse(&head) {
+            Ok(Some(response)) => response,
+            _ => return Upgrade::Unanswered(format!("the answer isn't HTTP: {}", excerpt(&head))),
+        };
+
+        let header = |name: &str| response.headers().iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| String::from_utf8_lossy(value).into_owned());
+        if response.status() != 101 {
+            return Upgrade::Refused {
+                status: response.status(),
+                reason: response.reason().to_string(),
+                location: header("location"),
+                body: excerpt(&rest),
+            };
+        }
+        let accepted = match (request.hashed_key(), response.key()) {
+            (Ok(expected), Ok(key)) => expected.as_bytes() == key.as_slice(),
+            _ => false,
+        };
+        if !accepted {
+            return Upgrade::WrongAccept;
+        }
+        let _ = stream.write_all(CLOSE_FRAME);
+        Upgrade::Accepted {
+            protocol: header("sec-websocket-protocol"),
+            extensions: header("sec-websocket-extensions"),
+            server: header("server"),
+        }
+    }
+}
+
+/// Reads up to the end of the response head, returning the head and what came after it.
+fn read_head(stream: &mut Stream) -> std::result::Result<(Vec<u8>, Vec<u8>), String> {
+    let mut buffer = vec![];
+    let mut chunk = [0; 4096];
+    loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = buffer.split_off(end + 4);
+            return Ok((buffer, rest));
+        }
+        if buffer.len() > MAX_HEAD {
+            return Err(format!("the answer has no end of headers within {} bytes", MAX_HEAD));
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) if buffer.is_empty() => return Err("the server closed the connection without answering".to_string()),
+            Ok(0) => return Err(format!("the server closed the connection mid-answer: {}", excerpt(&buffer))),
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                return Err(format!("no answer within {} s", TIMEOUT.as_secs())),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// First line of the bytes, shortened, to show what a server said.
+fn excerpt(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let line = text.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+    match line.char_indices().nth(80) {
+        Some((at, _)) => format!("{}...", &line[..at]),
+        None => line.to_string(),
+    }
+}
+
+fn common_name(name: &X509NameRef) -> String {
+    name.entries_by_nid(Nid::COMMONNAME).next()
+        .and_then(|entry| entry.data().to_string().ok())
+        .unwrap_or_else(|| "an unnamed subject".to_string())
+}
+
+fn report(check: &str, outcome: &str, took: Option<Duration>, details: &str) {
+    let took = took.map(|took| format!("{:.1} ms", took.as_secs_f64() * 1000.0)).unwrap_or_default();
+    println!("{:<33} {:<9} {:>9}  {}", check, outcome, took, details);
+}
+
+fn hint(text: &str) {
+    println!("  -> {}", text);
+}
+
+/// Explains the handshakes: what the server wants when only some of them succeed,
+/// and what a refusal of the proxy's one usually means otherwise.
+fn diagnose(url: &Url, trusted: bool, attempts: &[(&Variant, Upgrade)]) {
+    let refused = match &attempts[0].1 {
+        Upgrade::Accepted { .. } if !trusted => {
+            println!("The server accepts WebSocket connections, but the proxy won't trust its certificate.");
+            return;
+        },
+        Upgrade::Accepted { .. } => {
+            println!("The server accepts WebSocket connections, start the proxy with ws-proxy run {} <proxy-port>", url);
+            return;
+        },
+        refused => refused,
+    };
+    for (variant, upgrade) in &attempts[1..] {
+        if let Upgrade::Accepted { protocol, .. } = upgrade {
+            match (variant.browser, protocol) {
+                (true, _) => println!("The server only accepts handshakes with the Origin or User-Agent of a browser. \
+                    The proxy doesn't send them, so it may need to be allowed by the server."),
+                (_, Some(protocol)) => println!("The server only accepts handshakes offering a subprotocol, \
+                    and chose {}. Clients of the proxy have to ask for it.", protocol),
+                (_, None) => println!("The server accepted a handshake offering subprotocols without choosing one, \
+                    it may need one of them anyway."),
+            }
+            return;
+        }
+    }
+    let diagnosis = match refused {
+        Upgrade::Refused { status: 301..=308, location, .. } => format!("The server redirects to {}, \
+            which may be the url to proxy: WebSocket clients don't follow redirects.",
+            location.as_deref().unwrap_or("an unknown location")),
+        Upgrade::Refused { status: 200, .. } => "The server answered as a plain web page: the path is likely wrong, \
+            or a proxy in front of it doesn't pass the upgrade on.".to_string(),
+        Upgrade::Refused { status: 400, .. } => "The server rejected the handshake as malformed: it may expect \
+            a specific path, query or header.".to_string(),
+        Upgrade::Refused { status: 401 | 403, .. } => "The server wants credentials: pass them in the url, with \
+            --header, or run the proxy with its authentication options.".to_string(),
+        Upgrade::Refused { status: 404, .. } => "There is no WebSocket endpoint at this path.".to_string(),
+        Upgrade::Refused { status: 426, .. } =>
+            "The server wants another WebSocket version or a subprotocol.".to_string(),
+        Upgrade::Refused { status: 500..=599, .. } => "The server or a gateway in front of it failed: check its logs, \
+            or whether the gateway passes WebSocket upgrades on.".to_string(),
+        Upgrade::Refused { status, .. } => format!("The server refused the handshake with {}.", status),
+        Upgrade::WrongAccept => "The server accepted with a wrong Sec-WebSocket-Accept: something between rewrites \
+            the handshake, and clients will refuse it.".to_string(),
+        Upgrade::Unanswered(_) => "The server accepts connections but doesn't answer upgrade requests: it may not \
+            be an HTTP server, or want TLS (wss://).".to_string(),
+        Upgrade::Accepted { .. } => unreachable!("accepted handshakes are diagnosed above"),
+    };
+    println!("{}", diagnosis);
+}
@@ -0,0 +1,99 @@
+use ws::Message;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::asyncapi;
+use crate::bundle::Recorded;
+use crate::schema::{Field, Shape};
+
+/// Message types of a capture with the shapes of their payloads.
+#[derive(Default)]
+pub struct Inventory {
+    types: BTreeMap<String, (usize, Shape)>,
+}
+
+impl Inventory {
+    /// Message types are named like in AsyncAPI documents, by the side and the type field.
+    pub fn of(messages: &[Recorded]) -> Self {
+        let mut inventory = Inventory::default();
+        for recorded in messages.iter() {
+            let (count, shape) = inventory.types
+                .entry(asyncapi::message_name(recorded.from, &recorded.message))
+                .or_default();
+            *count += 1;
+            if let Message::Text(_) = &recorded.message {
+                shape.add(&asyncapi::payload(&recorded.message));
+            }
+        }
+        inventory
+    }
+}
+
+/// Difference between the inventories of two captures.
+pub enum Change {
+    Added(String, usize),
+    Removed(String, usize),
+    FieldAdded(String, String, Field),
+    FieldRemoved(String, String, Field),
+    FieldChanged(String, String, Field, Field),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(name, count) => write!(f, "+ {}: new message type, seen {} times", name, count),
+            Change::Removed(name, count) => write!(f, "- {}: message type is gone, was seen {} times", name, count),
+            Change::FieldAdded(name, path, field) => write!(f, "+ {} {}: new field, {}", name, path, describe(field)),
+            Change::FieldRemoved(name, path, field) => write!(f, "- {} {}: field is gone, was {}", name, path, describe(field)),
+            Change::FieldChanged(name, path, old, new) => write!(f, "~ {} {}: was {}, now {}",
+                name, path, describe(old), describe(new)),
+        }
+    }
+}
+
+fn describe(field: &Field) -> String {
+    let types = match field.types.is_empty() {
+        true => "empty".to_string(),
+        false => field.types.join(" or "),
+    };
+    match field.optional {
+        true => format!("{}, optional", types),
+        false => types,
+    }
+}
+
+/// Message types added and removed between the captures, and fields added, removed,
+/// or changed in types or in being always present in the types both have.
+pub fn compare(old: &Inventory, new: &Inventory) -> Vec<Change> {
+    let mut changes = vec![];
+    for (name, (count, _)) in old.types.iter() {
+        if !new.types.contains_key(name) {
+            changes.push(Change::Removed(name.clone(), *count));
+        }
+    }
+    for (name, (count, shape)) in new.types.iter() {
+        let old_shape = match old.types.get(name) {
+            Some((_, old_shape)) => old_shape,
+            None => {
+                changes.push(Change::Added(name.clone(), *count));
+                continue;
+            }
+        };
+
+        let (mut old_fields, new_fields) = (old_shape.fields(), shape.fields());
+        for (path, field) in new_fields {
+            match old_fields.remove(&path) {
+                None => changes.push(Change::FieldAdded(name.clone(), path, field)),
+                Some(old_field) if old_field != field => {
+                    changes.push(Change::FieldChanged(name.clone(), path, old_field, field))
+                },
+                Some(_) => {}
+            }
+        }
+        for (path, field) in old_fields {
+            changes.push(Change::FieldRemoved(name.clone(), path, field));
+        }
+    }
+    changes
+}
@@ -0,0 +1,152 @@
+use age::stream::StreamWriter;
+use age::x25519::Recipient;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use crate::storage::MemoryFile;
+
+/// Encryption of files at rest with age, for the given X25519 recipients (`age1...`).
+/// Only the holders of the matching identities can read them, e.g. with `age -d -i key.txt`.
+#[derive(Clone)]
+pub struct Encryption {
+    recipients: Vec<Recipient>,
+}
+
+impl Encryption {
+    pub fn parse(recipients: &[String]) -> std::result::Result<Self, String> {
+        let recipients = recipients.iter()
+            .map(|recipient| recipient.parse::<Recipient>()
+                .map_err(|e| format!("Invalid age recipient {}: {}", recipient, e)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Encryption { recipients })
+    }
+
+    /// Path of the encrypted file written instead of the given one.
+    pub fn path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".age");
+        PathBuf::from(name)
+    }
+
+    fn wrap(&self, file: File) -> io::Result<StreamWriter<File>> {
+        let recipients = self.recipients.iter().map(|recipient| recipient as &dyn age::Recipient);
+        age::Encryptor::with_recipients(recipients)
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .wrap_output(file)
+    }
+}
+
+/// Opens a file to append to, or creates its encrypted counterpart
+/// when the encryption is given. Encrypted files can't be appended to.
+pub fn open(path: &Path, encryption: Option<&Encryption>) -> io::Result<Sink> {
+    match encryption {
+        None => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(Sink::Plain(file))
+        },
+        Some(encryption) => {
+            let file = File::create(Encryption::path(path))?;
+            Ok(Sink::Encrypted(Some(encryption.wrap(file)?)))
+        }
+    }
+}
+
+/// File being written, encrypted, plain or kept in memory.
+/// An encrypted file is complete only after the sink is dropped.
+pub enum Sink {
+    Plain(File),
+    Encrypted(Option<StreamWriter<File>>),
+    Memory(MemoryFile),
+}
+
+impl Sink {
+    /// Forces written data to the disk. Encrypted data is only flushed,
+    /// its last chunk is written when the stream is finished.
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(file) => file.sync_data(),
+            _ => self.flush(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(file) => file.write(buf),
+            Sink::Encrypted(Some(stream)) => stream.write(buf),
+            Sink::Encrypted(None) => Err(io::Error::other("Encrypted file is already finished")),
+            Sink::Memory(file) => {
+                file.append(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(file) => file.flush(),
+            Sink::Encrypted(Some(stream)) => stream.flush(),
+            Sink::Encrypted(None) | Sink::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Sink::Encrypted(stream) = self {
+            if let Some(Err(e)) = stream.take().map(|stream| stream.finish()) {
+                error!("Error: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use age::x25519::Identity;
+
+    use std::io::Read;
+
+    #[test]
+    fn files_are_readable_with_the_identity_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let encryption = Encryption::parse(&[identity.to_public().to_string()]).unwrap();
+        let path = dir.path().join("capture.jsonl");
+        {
+            let mut sink = open(&path, Some(&encryption)).unwrap();
+            sink.write_all(b"{\"event\":\"open\"}\n").unwrap();
+            sink.sync().unwrap();
+        }
+        assert!(!path.exists());
+
+        let decrypt = |identity: &Identity| -> Option<String> {
+            let encrypted = File::open(Encryption::path(&path)).unwrap();
+            let decryptor = age::Decryptor::new(encrypted).unwrap();
+            let mut plain = String::new();
+            decryptor.decrypt(std::iter::once(identity as &dyn age::Identity)).ok()?.read_to_string(&mut plain).ok()?;
+            Some(plain)
+        };
+        assert_eq!(decrypt(&identity).as_deref(), Some("{\"event\":\"open\"}\n"));
+        assert_eq!(decrypt(&Identity::generate()), None);
+    }
+
+    #[test]
+    fn plain_files_are_appended_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.jsonl");
+        for line in ["first\n", "second\n"] {
+            open(&path, None).unwrap().write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        let invalid = Encryption::parse(&["age1invalid".to_string()]).err().unwrap();
+        assert!(invalid.starts_with("Invalid age recipient age1invalid: "), "{}", invalid);
+    }
+}
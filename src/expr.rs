@@ -0,0 +1,576 @@
+use regex::Regex;
+use serde_json::{json, Map, Number, Value};
+
+use std::fmt;
+
+/// Expression in a subset of CEL, the Common Expression Language, evaluated over JSON
+/// values: literals, variables, fields (`payload.type`), indexes (`headers["origin"]`),
+/// `!`, `-`, arithmetic, comparisons, `in`, `&&`, `||`, `? :`, the functions `size`,
+/// `has`, `int`, `double` and `string`, and the methods `contains`, `startsWith`,
+/// `endsWith`, `matches`, `lowerAscii` and `size`.
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Literal(Value),
+    List(Vec<Node>),
+    Variable(String),
+    Field(Box<Node>, String),
+    Index(Box<Node>, Box<Node>),
+    Call(String, Vec<Node>),
+    Method(Box<Node>, String, Vec<Node>),
+    /// `matches` with a literal pattern, compiled once.
+    Matches(Box<Node>, Regex),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(Box<Node>, Operator, Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Conditional(Box<Node>, Box<Node>, Box<Node>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    In,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Value),
+    Text(String),
+    Name(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 22] = ["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "+", "-", "*", "/", "%",
+    "(", ")", "[", "]", ".", ",", "?", ":"];
+
+impl Expression {
+    pub fn parse(source: &str) -> std::result::Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, position: 0 };
+        let root = parser.conditional()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("Unexpected {}", describe(token)));
+        }
+        Ok(Expression { source: source.to_string(), root })
+    }
+
+    /// Value of the expression with the variables, an error if a field is missing
+    /// or the types don't fit.
+    pub fn evaluate(&self, variables: &Map<String, Value>) -> std::result::Result<Value, String> {
+        evaluate(&self.root, variables)
+    }
+
+    /// Whether the expression is true with the variables, errors count as false.
+    pub fn holds(&self, variables: &Map<String, Value>) -> bool {
+        self.evaluate(variables).map(|value| value == Value::Bool(true)).unwrap_or(false)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn tokenize(source: &str) -> std::result::Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        if c.is_whitespace() {
+            index += 1;
+        } else if c.is_ascii_digit() {
+            let start = index;
+            while index < chars.len() && (chars[index].is_ascii_digit() || chars[index] == '.'
+                && chars.get(index + 1).map(char::is_ascii_digit).unwrap_or(false)) {
+                index += 1;
+            }
+            let literal: String = chars[start..index].iter().collect();
+            let number = match literal.parse::<i64>() {
+                Ok(number) => json!(number),
+                Err(_) => literal.parse::<f64>().ok().and_then(Number::from_f64).map(Value::Number)
+                    .ok_or_else(|| format!("Invalid number {}", literal))?,
+            };
+            tokens.push(Token::Number(number));
+        } else if c == '"' || c == '\'' {
+            let mut text = String::new();
+            index += 1;
+            loop {
+                match chars.get(index) {
+                    None => return Err("Unterminated string".to_string()),
+                    Some(&quote) if quote == c => break,
+                    Some('\\') => {
+                        index += 1;
+                        text.push(match chars.get(index) {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some(&escaped) => escaped,
+                            None => return Err("Unterminated string".to_string()),
+                        });
+                    },
+                    Some(&other) => text.push(other),
+                }
+                index += 1;
+            }
+            index += 1;
+            tokens.push(Token::Text(text));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = index;
+            while index < chars.len() && (chars[index].is_alphanumeric() || chars[index] == '_') {
+                index += 1;
+            }
+            tokens.push(Token::Name(chars[start..index].iter().collect()));
+        } else {
+            let rest: String = chars[index..chars.len().min(index + 2)].iter().collect();
+            let symbol = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("Unexpected {}", c))?;
+            index += symbol.len();
+            tokens.push(Token::Symbol(symbol));
+        }
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(number) => number.to_string(),
+        Token::Text(text) => format!("{:?}", text),
+        Token::Name(name) => name.clone(),
+        Token::Symbol(symbol) => symbol.to_string(),
+    }
+}
+
+/// Recursive descent parser, one method per level of precedence.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, symbol: &str) -> std::result::Result<(), String> {
+        if self.eat(symbol) {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => Err(format!("Expected {} instead of {}", symbol, describe(token))),
+            None => Err(format!("Expected {} at the end", symbol)),
+        }
+    }
+
+    fn conditional(&mut self) -> std::result::Result<Node, String> {
+        let condition = self.or()?;
+        if !self.eat("?") {
+            return Ok(condition);
+        }
+        let then = self.conditional()?;
+        self.expect(":")?;
+        let otherwise = self.conditional()?;
+        Ok(Node::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn or(&mut self) -> std::result::Result<Node, String> {
+        let mut node = self.and()?;
+        while self.eat("||") {
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> std::result::Result<Node, String> {
+        let mut node = self.relation()?;
+        while self.eat("&&") {
+            node = Node::And(Box::new(node), Box::new(self.relation()?));
+        }
+        Ok(node)
+    }
+
+    fn relation(&mut self) -> std::result::Result<Node, String> {
+        let node = self.sum()?;
+        let operator = match self.peek() {
+            Some(Token::Symbol("==")) => Operator::Equal,
+            Some(Token::Symbol("!=")) => Operator::NotEqual,
+            Some(Token::Symbol("<")) => Operator::Less,
+            Some(Token::Symbol("<=")) => Operator::LessOrEqual,
+            Some(Token::Symbol(">")) => Operator::Greater,
+            Some(Token::Symbol(">=")) => Operator::GreaterOrEqual,
+            Some(Token::Name(name)) if name == "in" => Operator::In,
+            _ => return Ok(node),
+        };
+        self.position += 1;
+        Ok(Node::Binary(Box::new(node), operator, Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> std::result::Result<Node, String> {
+        let mut node = self.product()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("+")) => Operator::Add,
+                Some(Token::Symbol("-")) => Operator::Subtract,
+                _ => return Ok(node),
+            };
+            self.position += 1;
+            node = Node::Binary(Box::new(node), operator, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> std::result::Result<Node, String> {
+        let mut node = self.unary()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol("*")) => Operator::Multiply,
+                Some(Token::Symbol("/")) => Operator::Divide,
+                Some(Token::Symbol("%")) => Operator::Remainder,
+                _ => return Ok(node),
+            };
+            self.position += 1;
+            node = Node::Binary(Box::new(node), operator, Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> std::result::Result<Node, String> {
+        if self.eat("!") {
+            return Ok(Node::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        self.postfix()
+    }
+
+    fn postfix(&mut self) -> std::result::Result<Node, String> {
+        let mut node = self.primary()?;
+        loop {
+            if self.eat(".") {
+                let name = match self.tokens.get(self.position).cloned() {
+                    Some(Token::Name(name)) => name,
+                    _ => return Err("Expected a field or method name after .".to_string()),
+                };
+                self.position += 1;
+                if !self.eat("(") {
+                    node = Node::Field(Box::new(node), name);
+                    continue;
+                }
+                let arguments = self.arguments(")")?;
+                node = match (name.as_str(), arguments.as_slice()) {
+                    ("matches", [Node::Literal(Value::String(pattern))]) => {
+                        let pattern = Regex::new(pattern).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+                        Node::Matches(Box::new(node), pattern)
+                    },
+                    _ => Node::Method(Box::new(node), name, arguments),
+                };
+            } else if self.eat("[") {
+                let index = self.conditional()?;
+                self.expect("]")?;
+                node = Node::Index(Box::new(node), Box::new(index));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> std::result::Result<Node, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("Expression ends too early")?;
+        self.position += 1;
+        match token {
+            Token::Number(number) => Ok(Node::Literal(number)),
+            Token::Text(text) => Ok(Node::Literal(Value::String(text))),
+            Token::Name(name) => match name.as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                _ if self.eat("(") => Ok(Node::Call(name, self.arguments(")")?)),
+                _ => Ok(Node::Variable(name)),
+            },
+            Token::Symbol("(") => {
+                let node = self.conditional()?;
+                self.expect(")")?;
+                Ok(node)
+            },
+            Token::Symbol("[") => Ok(Node::List(self.arguments("]")?)),
+            Token::Symbol(symbol) => Err(format!("Unexpected {}", symbol)),
+        }
+    }
+
+    fn arguments(&mut self, end: &str) -> std::result::Result<Vec<Node>, String> {
+        let mut arguments = vec![];
+        if self.eat(end) {
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.conditional()?);
+            if self.eat(end) {
+                return Ok(arguments);
+            }
+            self.expect(",")?;
+        }
+    }
+}
+
+fn evaluate(node: &Node, variables: &Map<String, Value>) -> std::result::Result<Value, String> {
+    match node {
+        Node::Literal(value) => Ok(value.clone()),
+        Node::List(items) => items.iter().map(|item| evaluate(item, variables)).collect::<std::result::Result<_, _>>()
+            .map(Value::Array),
+        Node::Variable(name) => variables.get(name).cloned().ok_or_else(|| format!("Unknown variable {}", name)),
+        Node::Field(target, name) => match evaluate(target, variables)? {
+            Value::Object(mut object) => object.remove(name).ok_or_else(|| format!("No field {}", name)),
+            other => Err(format!("{} has no field {}", type_name(&other), name)),
+        },
+        Node::Index(target, index) => {
+            let (target, index) = (evaluate(target, variables)?, evaluate(index, variables)?);
+            let found = match (&target, &index) {
+                (Value::Object(object), Value::String(key)) => object.get(key),
+                (Value::Array(items), Value::Number(position)) => position.as_u64().and_then(|at| items.get(at as usize)),
+                _ => return Err(format!("{} can't be indexed by {}", type_name(&target), type_name(&index))),
+            };
+            found.cloned().ok_or_else(|| format!("No element {}", index))
+        },
+        Node::Call(name, arguments) => call(name, arguments, variables),
+        Node::Method(target, name, arguments) => {
+            let target = evaluate(target, variables)?;
+            let arguments = arguments.iter().map(|argument| evaluate(argument, variables))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            method(&target, name, &arguments)
+        },
+        Node::Matches(target, pattern) => match evaluate(target, variables)? {
+            Value::String(text) => Ok(Value::Bool(pattern.is_match(&text))),
+            other => Err(format!("{} can't be matched", type_name(&other))),
+        },
+        Node::Not(operand) => Ok(Value::Bool(!boolean(&evaluate(operand, variables)?)?)),
+        Node::Negate(operand) => match evaluate(operand, variables)? {
+            Value::Number(number) => Ok(match number.as_i64() {
+                Some(integer) => json!(-integer),
+                None => json!(-number.as_f64().unwrap_or_default()),
+            }),
+            other => Err(format!("{} can't be negated", type_name(&other))),
+        },
+        Node::And(left, right) => Ok(Value::Bool(boolean(&evaluate(left, variables)?)?
+            && boolean(&evaluate(right, variables)?)?)),
+        Node::Or(left, right) => Ok(Value::Bool(boolean(&evaluate(left, variables)?)?
+            || boolean(&evaluate(right, variables)?)?)),
+        Node::Conditional(condition, then, otherwise) => match boolean(&evaluate(condition, variables)?)? {
+            true => evaluate(then, variables),
+            false => evaluate(otherwise, variables),
+        },
+        Node::Binary(left, operator, right) => {
+            binary(&evaluate(left, variables)?, *operator, &evaluate(right, variables)?)
+        },
+    }
+}
+
+fn call(name: &str, arguments: &[Node], variables: &Map<String, Value>) -> std::result::Result<Value, String> {
+    // has() tells whether a field is there instead of failing without it
+    if let ("has", [Node::Field(target, field)]) = (name, arguments) {
+        return match evaluate(target, variables)? {
+            Value::Object(object) => Ok(Value::Bool(object.contains_key(field))),
+            _ => Ok(Value::Bool(false)),
+        };
+    }
+    let arguments = arguments.iter().map(|argument| evaluate(argument, variables))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    match (name, arguments.as_slice()) {
+        ("size", [value]) => method(value, "size", &[]),
+        ("int", [Value::Number(number)]) => number.as_i64()
+            .or_else(|| number.as_f64().map(|number| number.trunc() as i64))
+            .map(|number| json!(number)).ok_or_else(|| format!("{} is not an int", number)),
+        ("int", [Value::String(text)]) => text.trim().parse::<i64>().map(|number| json!(number))
+            .map_err(|_| format!("{:?} is not an int", text)),
+        ("double", [Value::Number(number)]) => Ok(json!(number.as_f64().unwrap_or_default())),
+        ("double", [Value::String(text)]) => text.trim().parse::<f64>().map(|number| json!(number))
+            .map_err(|_| format!("{:?} is not a double", text)),
+        ("string", [Value::String(text)]) => Ok(Value::String(text.clone())),
+        ("string", [value]) => Ok(Value::String(value.to_string())),
+        _ => Err(format!("Unknown function {} of {} arguments", name, arguments.len())),
+    }
+}
+
+fn method(target: &Value, name: &str, arguments: &[Value]) -> std::result::Result<Value, String> {
+    match (target, name, arguments) {
+        (Value::String(text), "contains", [Value::String(part)]) => Ok(Value::Bool(text.contains(part.as_str()))),
+        (Value::String(text), "startsWith", [Value::String(prefix)]) => Ok(Value::Bool(text.starts_with(prefix.as_str()))),
+        (Value::String(text), "endsWith", [Value::String(suffix)]) => Ok(Value::Bool(text.ends_with(suffix.as_str()))),
+        (Value::String(text), "matches", [Value::String(pattern)]) => Regex::new(pattern)
+            .map(|pattern| Value::Bool(pattern.is_match(text)))
+            .map_err(|e| format!("Invalid pattern {}: {}", pattern, e)),
+        (Value::String(text), "lowerAscii", []) => Ok(Value::String(text.to_ascii_lowercase())),
+        (Value::String(text), "size", []) => Ok(json!(text.chars().count())),
+        (Value::Array(items), "size", []) => Ok(json!(items.len())),
+        (Value::Object(object), "size", []) => Ok(json!(object.len())),
+        _ => Err(format!("{} has no method {} of {} arguments", type_name(target), name, arguments.len())),
+    }
+}
+
+fn binary(left: &Value, operator: Operator, right: &Value) -> std::result::Result<Value, String> {
+    let mismatch = || format!("{} and {} don't fit {:?}", type_name(left), type_name(right), operator);
+    match operator {
+        Operator::Equal => Ok(Value::Bool(equal(left, right))),
+        Operator::NotEqual => Ok(Value::Bool(!equal(left, right))),
+        Operator::Less | Operator::LessOrEqual | Operator::Greater | Operator::GreaterOrEqual => {
+            let ordering = match (left, right) {
+                (Value::Number(left), Value::Number(right)) => left.as_f64().unwrap_or_default()
+                    .partial_cmp(&right.as_f64().unwrap_or_default()),
+                (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+                _ => None,
+            }.ok_or_else(mismatch)?;
+            Ok(Value::Bool(match operator {
+                Operator::Less => ordering.is_lt(),
+                Operator::LessOrEqual => ordering.is_le(),
+                Operator::Greater => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            }))
+        },
+        Operator::In => match right {
+            Value::Array(items) => Ok(Value::Bool(items.iter().any(|item| equal(left, item)))),
+            Value::Object(object) => match left {
+                Value::String(key) => Ok(Value::Bool(object.contains_key(key))),
+                _ => Err(mismatch()),
+            },
+            _ => Err(mismatch()),
+        },
+        Operator::Add => match (left, right) {
+            (Value::String(left), Value::String(right)) => Ok(Value::String(format!("{}{}", left, right))),
+            (Value::Array(left), Value::Array(right)) => Ok(Value::Array(left.iter().chain(right).cloned().collect())),
+            (Value::Number(left), Value::Number(right)) => arithmetic(left, right, i64::checked_add, |a, b| a + b),
+            _ => Err(mismatch()),
+        },
+        _ => match (left, right) {
+            (Value::Number(left), Value::Number(right)) => match operator {
+                Operator::Subtract => arithmetic(left, right, i64::checked_sub, |a, b| a - b),
+                Operator::Multiply => arithmetic(left, right, i64::checked_mul, |a, b| a * b),
+                Operator::Divide => arithmetic(left, right, i64::checked_div, |a, b| a / b),
+                _ => arithmetic(left, right, i64::checked_rem, |a, b| a % b),
+            },
+            _ => Err(mismatch()),
+        },
+    }
+}
+
+/// Integer arithmetic when both numbers are integers, floating point otherwise.
+fn arithmetic(left: &Number, right: &Number, integers: fn(i64, i64) -> Option<i64>, floats: fn(f64, f64) -> f64)
+              -> std::result::Result<Value, String> {
+    if let (Some(left), Some(right)) = (left.as_i64(), right.as_i64()) {
+        return integers(left, right).map(|result| json!(result))
+            .ok_or_else(|| "Integer overflow or division by zero".to_string());
+    }
+    Number::from_f64(floats(left.as_f64().unwrap_or_default(), right.as_f64().unwrap_or_default()))
+        .map(Value::Number).ok_or_else(|| "Result is not a number".to_string())
+}
+
+/// Numbers are equal by value, whether they are integers or not.
+fn equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64() == right.as_f64(),
+        _ => left == right,
+    }
+}
+
+fn boolean(value: &Value) -> std::result::Result<bool, String> {
+    value.as_bool().ok_or_else(|| format!("{} is not a bool", type_name(value)))
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "list",
+        Value::Object(_) => "map",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tree of an expression with its operations in prefix notation, like `(+ 1 (* 2 3))`.
+    fn tree(source: &str) -> String {
+        fn shape(node: &Node) -> String {
+            let all = |nodes: &[Node]| nodes.iter().map(shape).collect::<Vec<_>>().join(" ");
+            match node {
+                Node::Literal(value) => value.to_string(),
+                Node::List(items) => format!("[{}]", all(items)),
+                Node::Variable(name) => name.clone(),
+                Node::Field(target, name) => format!("(. {} {})", shape(target), name),
+                Node::Index(target, index) => format!("([] {} {})", shape(target), shape(index)),
+                Node::Call(name, arguments) => format!("({} {})", name, all(arguments)),
+                Node::Method(target, name, arguments) => format!("(.{} {} {})", name, shape(target), all(arguments)),
+                Node::Matches(target, pattern) => format!("(matches {} /{}/)", shape(target), pattern),
+                Node::Not(operand) => format!("(! {})", shape(operand)),
+                Node::Negate(operand) => format!("(- {})", shape(operand)),
+                Node::Binary(left, operator, right) => format!("({:?} {} {})", operator, shape(left), shape(right)),
+                Node::And(left, right) => format!("(&& {} {})", shape(left), shape(right)),
+                Node::Or(left, right) => format!("(|| {} {})", shape(left), shape(right)),
+                Node::Conditional(condition, then, otherwise) => {
+                    format!("(? {} {} {})", shape(condition), shape(then), shape(otherwise))
+                },
+            }
+        }
+        shape(&Expression::parse(source).unwrap().root)
+    }
+
+    #[test]
+    fn tokens_are_numbers_strings_names_and_symbols() {
+        assert_eq!(tokenize("a.b>=1.5&&'x\\'y'!=\"\\n\" 10").unwrap(), [
+            Token::Name("a".to_string()), Token::Symbol("."), Token::Name("b".to_string()), Token::Symbol(">="),
+            Token::Number(json!(1.5)), Token::Symbol("&&"), Token::Text("x'y".to_string()), Token::Symbol("!="),
+            Token::Text("\n".to_string()), Token::Number(json!(10)),
+        ]);
+        // A dot without digits after it ends the number
+        assert_eq!(tokenize("1.size").unwrap()[..2], [Token::Number(json!(1)), Token::Symbol(".")]);
+        assert_eq!(tokenize("\"open\\").unwrap_err(), "Unterminated string");
+        assert_eq!(tokenize("a = 1").unwrap_err(), "Unexpected =");
+    }
+
+    #[test]
+    fn operators_bind_by_precedence_and_from_the_left() {
+        assert_eq!(tree("1 - 2 - 3"), "(Subtract (Subtract 1 2) 3)");
+        assert_eq!(tree("1 + 2 * -x % 4"), "(Add 1 (Remainder (Multiply 2 (- x)) 4))");
+        assert_eq!(tree("a || b && !c"), "(|| a (&& b (! c)))");
+        // Relations don't chain, comparing their results takes parentheses
+        assert_eq!(Expression::parse("a < 1 == true").err().unwrap(), "Unexpected ==");
+        assert_eq!(tree("(a < 1) == true"), "(Equal (Less a 1) true)");
+        assert_eq!(tree("a ? b : c ? d : e"), "(? a b (? c d e))");
+        assert_eq!(tree("x in [1, 'a'] && (y)"), "(&& (In x [1 \"a\"]) y)");
+    }
+
+    #[test]
+    fn postfix_operations_chain() {
+        assert_eq!(tree("payload.items[0].name.size()"), "(.size (. ([] (. payload items) 0) name) )");
+        assert_eq!(tree("has(payload.type)"), "(has (. payload type))");
+        assert_eq!(tree("text.matches('^a+$')"), "(matches text /^a+$/)");
+        // Patterns which aren't literals are compiled when evaluated
+        assert_eq!(tree("text.matches(pattern)"), "(.matches text pattern)");
+    }
+}
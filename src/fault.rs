@@ -0,0 +1,208 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
+use ws::Message;
+
+use crate::closecodes::Leg;
+
+/// Faults injected into the messages of a side, or of both, given with --fault.
+#[derive(Clone, Copy, Debug)]
+pub struct FaultPlan {
+    pub leg: Option<Leg>,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub corrupt: f64,
+}
+
+impl FaultPlan {
+    /// Parses `[<client|server>:]<fault>=<probability>[,...]` with faults drop, duplicate
+    /// and corrupt, like `drop=0.05,duplicate=0.01` or `server:corrupt=0.01`.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (leg, faults) = match spec.split_once(':') {
+            Some((leg, faults)) => (Some(Leg::parse(leg)?), faults),
+            None => (None, spec),
+        };
+        let mut plan = FaultPlan { leg, drop: 0.0, duplicate: 0.0, corrupt: 0.0 };
+        for fault in faults.split(',') {
+            let (name, probability) = fault.split_once('=')
+                .ok_or_else(|| format!("Fault {} in {} is not <fault>=<probability>", fault, spec))?;
+            let probability = probability.parse::<f64>().ok()
+                .filter(|probability| (0.0..=1.0).contains(probability))
+                .ok_or_else(|| format!("Probability {} in {} is not between 0 and 1", probability, spec))?;
+            match name {
+                "drop" => plan.drop = probability,
+                "duplicate" => plan.duplicate = probability,
+                "corrupt" => plan.corrupt = probability,
+                _ => return Err(format!("Unknown fault {} in {}, expected drop, duplicate or corrupt", name, spec)),
+            }
+        }
+        if plan.drop + plan.duplicate + plan.corrupt > 1.0 {
+            return Err(format!("Probabilities of the faults in {} add up to more than 1", spec));
+        }
+        Ok(plan)
+    }
+}
+
+/// Fault drawn for a message.
+pub enum Fault {
+    Drop,
+    Duplicate,
+    /// The message with a character or a bit changed, and where.
+    Corrupt(Message, Value),
+}
+
+impl Fault {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fault::Drop => "drop",
+            Fault::Duplicate => "duplicate",
+            Fault::Corrupt(..) => "corrupt",
+        }
+    }
+}
+
+/// Draws faults for messages from a generator seeded once, so that a run with the same
+/// seed and the same traffic in the same order gets the same faults.
+pub struct Faults {
+    plans: Vec<FaultPlan>,
+    seed: u64,
+    random: StdRng,
+}
+
+impl Faults {
+    /// Faults seeded with the seed, or with a random one.
+    pub fn new(plans: Vec<FaultPlan>, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(rand::random);
+        Faults { plans, seed, random: StdRng::seed_from_u64(seed) }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Fault for a message from the side, if any. Every plan of the side draws one
+    /// number, so that the draws don't depend on which faults came before.
+    pub fn draw(&mut self, from: Leg, msg: &Message) -> Option<Fault> {
+        let mut fault = None;
+        for plan in self.plans.iter().filter(|plan| plan.leg.is_none() || plan.leg == Some(from)) {
+            let draw: f64 = self.random.gen();
+            if fault.is_some() {
+                continue;
+            }
+            fault = if draw < plan.drop {
+                Some(Fault::Drop)
+            } else if draw < plan.drop + plan.duplicate {
+                Some(Fault::Duplicate)
+            } else if draw < plan.drop + plan.duplicate + plan.corrupt {
+                corrupt(&mut self.random, msg)
+            } else {
+                None
+            };
+        }
+        fault
+    }
+}
+
+/// Replaces a character of a text with another printable one, so that it stays UTF-8,
+/// or flips a bit of binary data. Empty messages can't be corrupted.
+fn corrupt(random: &mut StdRng, msg: &Message) -> Option<Fault> {
+    match msg {
+        Message::Text(text) => {
+            let chars: Vec<char> = text.chars().collect();
+            if chars.is_empty() {
+                return None;
+            }
+            let at = random.gen_range(0..chars.len());
+            let original = chars[at];
+            let replacement = loop {
+                let replacement = random.gen_range(b'!'..=b'~') as char;
+                if replacement != original {
+                    break replacement;
+                }
+            };
+            let corrupted: String = chars.iter().enumerate()
+                .map(|(index, c)| if index == at { replacement } else { *c })
+                .collect();
+            let diff = json!({ "changed": { "char": at, "from": original.to_string(), "to": replacement.to_string() } });
+            Some(Fault::Corrupt(Message::text(corrupted), diff))
+        },
+        Message::Binary(data) => {
+            if data.is_empty() {
+                return None;
+            }
+            let at = random.gen_range(0..data.len());
+            let bit = random.gen_range(0..8);
+            let mut corrupted = data.clone();
+            corrupted[at] ^= 1 << bit;
+            let diff = json!({ "changed": { "byte": at, "bit": bit } });
+            Some(Fault::Corrupt(Message::binary(corrupted), diff))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans_are_probabilities_of_faults() {
+        let plan = FaultPlan::parse("server:drop=0.05,duplicate=0.01").unwrap();
+        assert_eq!((plan.leg, plan.drop, plan.duplicate, plan.corrupt), (Some(Leg::Server), 0.05, 0.01, 0.0));
+        assert_eq!(FaultPlan::parse("corrupt=1").unwrap().leg, None);
+
+        let error = |spec: &str| FaultPlan::parse(spec).unwrap_err();
+        assert_eq!(error("drop"), "Fault drop in drop is not <fault>=<probability>");
+        assert_eq!(error("drop=2"), "Probability 2 in drop=2 is not between 0 and 1");
+        assert_eq!(error("delay=0.1"), "Unknown fault delay in delay=0.1, expected drop, duplicate or corrupt");
+        assert_eq!(error("drop=0.6,corrupt=0.6"),
+            "Probabilities of the faults in drop=0.6,corrupt=0.6 add up to more than 1");
+    }
+
+    #[test]
+    fn faults_of_a_seed_repeat() {
+        let plans = vec![FaultPlan::parse("drop=0.3,duplicate=0.3").unwrap()];
+        let names = |faults: &mut Faults| -> Vec<&str> {
+            (0..50).map(|_| faults.draw(Leg::Client, &Message::text("x")).map(|fault| fault.name()).unwrap_or("-"))
+                .collect()
+        };
+        let drawn = names(&mut Faults::new(plans.clone(), Some(7)));
+        assert_eq!(drawn, names(&mut Faults::new(plans, Some(7))));
+        assert!(drawn.contains(&"drop") && drawn.contains(&"duplicate") && drawn.contains(&"-"));
+    }
+
+    #[test]
+    fn faults_apply_to_their_side() {
+        let mut faults = Faults::new(vec![FaultPlan::parse("server:drop=1").unwrap()], None);
+        assert!(faults.draw(Leg::Client, &Message::text("x")).is_none());
+        assert!(matches!(faults.draw(Leg::Server, &Message::text("x")), Some(Fault::Drop)));
+    }
+
+    #[test]
+    fn corrupted_messages_differ_by_a_character_or_a_bit() {
+        let mut faults = Faults::new(vec![FaultPlan::parse("corrupt=1").unwrap()], Some(1));
+        let text = "{\"type\":\"quote\"}";
+        match faults.draw(Leg::Server, &Message::text(text)) {
+            Some(Fault::Corrupt(Message::Text(corrupted), diff)) => {
+                let at = diff["changed"]["char"].as_u64().unwrap() as usize;
+                let changed: Vec<usize> = text.chars().zip(corrupted.chars()).enumerate()
+                    .filter(|(_, (original, corrupted))| original != corrupted)
+                    .map(|(index, _)| index)
+                    .collect();
+                assert_eq!(changed, [at]);
+                assert_eq!(diff["changed"]["to"], corrupted.chars().nth(at).unwrap().to_string());
+            },
+            _ => panic!("the text is corrupted"),
+        }
+        let data = vec![0u8; 16];
+        match faults.draw(Leg::Client, &Message::binary(data.clone())) {
+            Some(Fault::Corrupt(Message::Binary(corrupted), diff)) => {
+                let flipped: u32 = data.iter().zip(corrupted.iter()).map(|(a, b)| (a ^ b).count_ones()).sum();
+                assert_eq!(flipped, 1);
+                assert_eq!(corrupted[diff["changed"]["byte"].as_u64().unwrap() as usize],
+                    1 << diff["changed"]["bit"].as_u64().unwrap());
+            },
+            _ => panic!("the data is corrupted"),
+        }
+        assert!(faults.draw(Leg::Client, &Message::text("")).is_none());
+    }
+}
@@ -0,0 +1,50 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// Hex digits of the digest kept as the fingerprint.
+const FINGERPRINT_LENGTH: usize = 12;
+
+/// Describes the client of a connection by its handshake: user agent, origin, extensions
+/// and subprotocols it requested, and the order of its headers. The fingerprint is a digest
+/// of all of them except values which change with every request, so that connections of
+/// one build of an app share it and other builds differ.
+/// TLS fingerprints aren't available, ws doesn't expose the handshake of TLS clients.
+pub fn client(headers: &[(String, Vec<u8>)]) -> Value {
+    let header = |name: &str| headers.iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| String::from_utf8_lossy(value).into_owned());
+    let list = |name: &str| -> Vec<String> {
+        headers.iter()
+            .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| String::from_utf8_lossy(value)
+                .split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect::<Vec<String>>())
+            .collect()
+    };
+
+    let user_agent = header("user-agent");
+    let origin = header("origin");
+    let extensions = list("sec-websocket-extensions");
+    let protocols = list("sec-websocket-protocol");
+    let header_order: Vec<String> = headers.iter().map(|(name, _)| name.to_ascii_lowercase()).collect();
+
+    let mut digest = Sha256::new();
+    for part in [user_agent.as_deref().unwrap_or_default(), origin.as_deref().unwrap_or_default(),
+                 &extensions.join(","), &protocols.join(","), &header_order.join(",")] {
+        digest.update(part.as_bytes());
+        digest.update([0]);
+    }
+    let fingerprint = hex::encode(digest.finalize())[..FINGERPRINT_LENGTH].to_string();
+
+    json!({
+        "fingerprint": fingerprint,
+        "user_agent": user_agent,
+        "origin": origin,
+        "extensions": extensions,
+        "protocols": protocols,
+        "header_order": header_order,
+        "tls": Value::Null,
+    })
+}
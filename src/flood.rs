@@ -0,0 +1,201 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ws::{Frame, OpCode};
+
+use crate::closecodes::Leg;
+use crate::memory::{self, format_size};
+
+const PING_PREFIX: &[u8] = b"ws-proxy flood ";
+
+/// Content of the flooding messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Payload {
+    /// Zero bytes, compressed by permessage-deflate into a tiny frame
+    /// which the peer has to inflate back into the full size.
+    Zeros,
+    /// Incompressible bytes, so that the frame on the wire has the full size.
+    Random,
+}
+
+impl Payload {
+    fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "zeros" => Ok(Payload::Zeros),
+            "random" => Ok(Payload::Random),
+            _ => Err(format!("Unknown flood payload {}, expected zeros or random", value)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Payload::Zeros => "zeros",
+            Payload::Random => "random",
+        }
+    }
+}
+
+/// Large messages to send toward one side of the proxy at a steady rate.
+#[derive(Clone, Copy, Debug)]
+pub struct FloodPlan {
+    pub leg: Leg,
+    pub payload: Payload,
+    pub size: usize,
+    pub per_second: f64,
+}
+
+impl FloodPlan {
+    /// Parses `<client|server>:<zeros|random>:<size>[@<messages per second>]`,
+    /// one message per second is sent by default.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (target, per_second) = match spec.find('@') {
+            Some(index) => {
+                let rate = spec[index + 1..].parse::<f64>()
+                    .map_err(|e| format!("Invalid rate in {}: {}", spec, e))?;
+                if !rate.is_finite() || rate <= 0.0 {
+                    return Err(format!("Rate in {} must be positive", spec));
+                }
+                (&spec[..index], rate)
+            },
+            None => (spec, 1.0)
+        };
+
+        let mut parts = target.splitn(3, ':');
+        let leg = Leg::parse(parts.next().unwrap_or(""))?;
+        let payload = Payload::parse(parts.next().unwrap_or(""))?;
+        let size = memory::parse_size(parts.next().unwrap_or(""))?;
+
+        Ok(FloodPlan { leg, payload, size, per_second })
+    }
+}
+
+/// Flooding of a single connection, together with observations of how the peer copes:
+/// round trip time of pings sent along with the messages, and how long it lasted.
+pub struct Flood {
+    plan: FloodPlan,
+    payload: Vec<u8>,
+    started: Option<Instant>,
+    sent: u64,
+    pings: u64,
+    pongs: u64,
+    last_rtt: Option<Duration>,
+    max_rtt: Option<Duration>,
+}
+
+impl Flood {
+    pub fn new(plan: FloodPlan) -> Self {
+        let payload = match plan.payload {
+            Payload::Zeros => vec![0; plan.size],
+            Payload::Random => random_bytes(plan.size),
+        };
+
+        Flood {
+            plan,
+            payload,
+            started: None,
+            sent: 0,
+            pings: 0,
+            pongs: 0,
+            last_rtt: None,
+            max_rtt: None,
+        }
+    }
+
+    pub fn plan(&self) -> &FloodPlan {
+        &self.plan
+    }
+
+    /// Delay between ticks. Timeouts of ws have a resolution of 100ms,
+    /// higher rates are reached by sending several messages per tick.
+    pub fn tick_ms(&self) -> u64 {
+        ((1000.0 / self.plan.per_second) as u64).max(100)
+    }
+
+    /// Returns the number of messages to send now to keep up with the rate.
+    pub fn tick(&mut self) -> u64 {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let expected = (started.elapsed().as_secs_f64() * self.plan.per_second) as u64 + 1;
+        let due = expected.saturating_sub(self.sent);
+        self.sent += due;
+        due
+    }
+
+    pub fn message(&self) -> Vec<u8> {
+        self.payload.clone()
+    }
+
+    /// Payload of the next ping, it carries the time of sending.
+    pub fn ping(&mut self) -> Vec<u8> {
+        self.pings += 1;
+        let elapsed = self.started.map(|started| started.elapsed()).unwrap_or_default();
+        let mut data = PING_PREFIX.to_vec();
+        data.extend_from_slice(&(elapsed.as_micros() as u64).to_be_bytes());
+        data
+    }
+
+    /// Accounts a pong if it answers one of our pings.
+    pub fn pong(&mut self, frame: &Frame) {
+        let data = frame.payload();
+        if frame.opcode() != OpCode::Pong || !data.starts_with(PING_PREFIX)
+            || data.len() != PING_PREFIX.len() + 8 {
+            return;
+        }
+
+        let mut sent = [0; 8];
+        sent.copy_from_slice(&data[PING_PREFIX.len()..]);
+        let sent = Duration::from_micros(u64::from_be_bytes(sent));
+        let elapsed = self.started.map(|started| started.elapsed()).unwrap_or_default();
+        let rtt = elapsed.saturating_sub(sent);
+
+        self.pongs += 1;
+        self.last_rtt = Some(rtt);
+        self.max_rtt = Some(self.max_rtt.map_or(rtt, |max| max.max(rtt)));
+    }
+
+    /// Returns true if the frame is one of the flooding messages.
+    pub fn is_own(&self, frame: &Frame) -> bool {
+        frame.opcode() == OpCode::Binary && *frame.payload() == self.payload
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    pub fn pongs(&self) -> (u64, u64) {
+        (self.pongs, self.pings)
+    }
+
+    pub fn max_rtt(&self) -> Option<Duration> {
+        self.max_rtt
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.started.map(|started| started.elapsed()).unwrap_or_default()
+    }
+
+    pub fn report(&self) -> String {
+        let rtt = |rtt: Option<Duration>| rtt
+            .map(|rtt| format!("{:.1} ms", rtt.as_secs_f64() * 1000.0))
+            .unwrap_or_else(|| "-".to_string());
+
+        format!("{} messages of {} {} in {:.1}s, pongs {}/{}, last rtt {}, max rtt {}",
+            self.sent, format_size(self.plan.size), self.plan.payload.name(),
+            self.duration().as_secs_f64(), self.pongs, self.pings,
+            rtt(self.last_rtt), rtt(self.max_rtt))
+    }
+}
+
+/// Xorshift bytes, good enough to defeat compression.
+fn random_bytes(size: usize) -> Vec<u8> {
+    let mut state = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or_default() | 1;
+    let mut bytes = Vec::with_capacity(size + 8);
+    while bytes.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        bytes.extend_from_slice(&state.to_le_bytes());
+    }
+    bytes.truncate(size);
+    bytes
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use ws::Message;
+
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::clock::Clock;
+use crate::closecodes::Leg;
+use crate::logqueue::LogFile;
+use crate::session::{self, MessageId};
+
+/// Length of message previews given as context of outages.
+const PREVIEW: usize = 200;
+
+/// Detects periods where a direction goes silent for longer than the threshold
+/// and records them into the capture as `outage` and `resumed` events.
+pub struct Gaps {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    threshold: Duration,
+    legs: Option<Leg>,
+    state: Mutex<[Direction; 2]>,
+    capture: LogFile,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Default)]
+struct Direction {
+    last: Option<(Instant, DateTime<Utc>, String)>,
+    outage: bool,
+}
+
+impl Gaps {
+    /// Parses `[<client|server>:]<seconds>`, without a leg both directions are watched.
+    pub fn parse(value: &str) -> std::result::Result<(Option<Leg>, Duration), String> {
+        let (leg, seconds) = match value.find(':') {
+            Some(index) => (Some(Leg::parse(&value[..index])?), &value[index + 1..]),
+            None => (None, value),
+        };
+        let seconds = seconds.parse::<f64>().ok().filter(|seconds| *seconds > 0.0)
+            .ok_or_else(|| format!("Invalid gap threshold {}", value))?;
+        Ok((leg, Duration::from_secs_f64(seconds)))
+    }
+
+    /// Starts watching in a background thread, which stops when the detector is dropped.
+    pub fn start(legs: Option<Leg>, threshold: Duration, capture: LogFile, clock: Arc<dyn Clock>) -> Self {
+        let inner = Arc::new(Inner { threshold, legs, state: Mutex::default(), capture, clock });
+        let (watched, clock) = (Arc::downgrade(&inner), inner.clock.clone());
+        thread::spawn(move || watch(watched, clock));
+        Gaps { inner }
+    }
+
+    pub fn message(&self, id: MessageId, from: Leg, message: &Message) {
+        let inner = &self.inner;
+        if inner.legs.is_some() && inner.legs != Some(from) {
+            return;
+        }
+
+        let preview = preview(message);
+        let mut state = inner.state.lock().unwrap();
+        let direction = &mut state[from as usize];
+        if direction.outage {
+            let (silent, since) = direction.last.as_ref()
+                .map(|(last, since, _)| (inner.clock.now() - *last, *since))
+                .unwrap_or_default();
+            info!("Messages from {} resumed after {:.1}s", from, silent.as_secs_f64());
+            let record = session::resumed_record(id, from, since, silent, &preview);
+            inner.capture.write(format!("{}\n", record));
+            direction.outage = false;
+        }
+        direction.last = Some((inner.clock.now(), inner.clock.utc(), preview));
+    }
+}
+
+fn watch(inner: Weak<Inner>, clock: Arc<dyn Clock>) {
+    loop {
+        clock.sleep(Duration::from_millis(100));
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+
+        let mut state = inner.state.lock().unwrap();
+        for leg in [Leg::Client, Leg::Server] {
+            let direction = &mut state[leg as usize];
+            if direction.outage {
+                continue;
+            }
+            // Outages are detected only after the direction had some traffic
+            if let Some((last, since, preview)) = &direction.last {
+                let silent = clock.now() - *last;
+                if silent >= inner.threshold {
+                    info!("No messages from {} for {:.1}s", leg, silent.as_secs_f64());
+                    let record = session::outage_record(leg, *since, inner.threshold, preview);
+                    inner.capture.write(format!("{}\n", record));
+                    direction.outage = true;
+                }
+            }
+        }
+    }
+}
+
+/// Message shortened to be printed on one line.
+pub(crate) fn preview(message: &Message) -> String {
+    match message {
+        Message::Text(text) if text.chars().count() > PREVIEW => {
+            format!("{}...", text.chars().take(PREVIEW).collect::<String>())
+        },
+        Message::Text(text) => text.clone(),
+        Message::Binary(data) => format!("binary, {} bytes", data.len()),
+    }
+}
@@ -0,0 +1,172 @@
+use serde_json::Value;
+use ws::Message;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::bundle::Recorded;
+use crate::closecodes::Leg;
+use crate::schema;
+
+/// Fields of JSON messages carrying the id a response repeats from its request.
+pub const CORRELATION_FIELDS: [&str; 6] = ["id", "requestId", "request_id", "correlationId", "correlation_id", "nonce"];
+
+/// Upper bounds of the latency buckets, the last bucket takes the rest.
+const BUCKETS: [u64; 10] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500];
+/// Shades of the cells, from none to the most of the responses of a type.
+const SHADES: [char; 10] = [' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+
+/// Latencies of the requests of a capture by their message type.
+///
+/// A response is the first server message repeating the value of a correlation field of
+/// a request waiting for one, like the id of JSON-RPC. Without such a value the server
+/// message answers the oldest request without one, as requests and responses in turn do.
+#[derive(Default)]
+pub struct Heatmap {
+    types: BTreeMap<String, Row>,
+}
+
+#[derive(Default)]
+struct Row {
+    latencies: Vec<Duration>,
+    unanswered: usize,
+}
+
+/// Request waiting for its response.
+struct Pending {
+    kind: String,
+    correlation: Option<String>,
+    time: chrono::DateTime<chrono::Utc>,
+}
+
+impl Heatmap {
+    /// Pairs the requests of the messages with responses, correlated by the given fields.
+    pub fn of(messages: &[Recorded], fields: &[String]) -> Self {
+        let mut heatmap = Heatmap::default();
+        let mut pending: VecDeque<Pending> = VecDeque::new();
+        for recorded in messages.iter() {
+            let time = match recorded.time {
+                Some(time) => time,
+                None => continue,
+            };
+            let payload = match &recorded.message {
+                Message::Text(text) => serde_json::from_str::<Value>(text).ok(),
+                Message::Binary(_) => None,
+            };
+            let correlation = payload.as_ref().and_then(|payload| correlation(payload, fields));
+            match recorded.from {
+                Leg::Client => {
+                    let kind = match (&recorded.message, &payload) {
+                        (Message::Binary(_), _) => "binary".to_string(),
+                        (Message::Text(_), Some(payload)) => schema::kind(payload).unwrap_or_else(|| "json".to_string()),
+                        (Message::Text(_), None) => "text".to_string(),
+                    };
+                    heatmap.types.entry(kind.clone()).or_default();
+                    pending.push_back(Pending { kind, correlation, time });
+                },
+                Leg::Server => {
+                    let answered = match &correlation {
+                        Some(_) => pending.iter().position(|request| request.correlation == correlation),
+                        None => None,
+                    }.or_else(|| pending.iter().position(|request| request.correlation.is_none()));
+                    if let Some(request) = answered.and_then(|index| pending.remove(index)) {
+                        let latency = (time - request.time).to_std().unwrap_or_default();
+                        heatmap.types.entry(request.kind).or_default().latencies.push(latency);
+                    }
+                },
+            }
+        }
+        for request in pending {
+            heatmap.types.entry(request.kind).or_default().unanswered += 1;
+        }
+        heatmap
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    /// Table of the types, the slowest first by their 90th percentile, with the share
+    /// of the responses of each type in every latency bucket as a shade.
+    pub fn render(&self) -> String {
+        let mut rows: Vec<(&String, &Row, Vec<Duration>)> = self.types.iter()
+            .map(|(kind, row)| {
+                let mut sorted = row.latencies.clone();
+                sorted.sort();
+                (kind, row, sorted)
+            })
+            .collect();
+        rows.sort_by_key(|(_, _, sorted)| std::cmp::Reverse(percentile(sorted, 90)));
+
+        let width = rows.iter().map(|(kind, _, _)| kind.chars().count()).max().unwrap_or_default().max(4);
+        let mut buckets: Vec<String> = BUCKETS.iter().map(|bound| format!("<{}", format_bound(*bound))).collect();
+        buckets.push(format!(">={}", format_bound(BUCKETS[BUCKETS.len() - 1])));
+        let header = format!("{:<width$} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}  {}", "type", "n", "lost",
+            "p50", "p90", "p99", "max", buckets.iter().map(|bucket| format!("{:^7}", bucket)).collect::<String>(),
+            width = width);
+        let mut table = format!("{}\n", header.trim_end());
+        for (kind, row, sorted) in rows {
+            let mut counts = [0usize; BUCKETS.len() + 1];
+            for latency in sorted.iter() {
+                let millis = latency.as_millis() as u64;
+                counts[BUCKETS.iter().position(|bound| millis < *bound).unwrap_or(BUCKETS.len())] += 1;
+            }
+            let cells: String = counts.iter()
+                .map(|count| format!("{:^7}", shade(*count, sorted.len())))
+                .collect();
+            let statistic = |latency: Option<Duration>| latency.map(format_latency).unwrap_or_else(|| "-".to_string());
+            let line = format!("{:<width$} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}  {}", kind, sorted.len(),
+                row.unanswered, statistic(percentile(&sorted, 50)), statistic(percentile(&sorted, 90)),
+                statistic(percentile(&sorted, 99)), statistic(sorted.last().copied()), cells, width = width);
+            table.push_str(&format!("{}\n", line.trim_end()));
+        }
+        table
+    }
+}
+
+/// Value of the first correlation field of a JSON message, as text.
+fn correlation(payload: &Value, fields: &[String]) -> Option<String> {
+    fields.iter()
+        .filter_map(|field| payload.get(field))
+        .find_map(|found| match found {
+            Value::String(id) => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        })
+}
+
+/// Nearest-rank percentile of sorted latencies.
+pub(crate) fn percentile(sorted: &[Duration], percent: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+/// Shade of a cell holding the count out of all responses of its row, empty only
+/// for no responses at all.
+fn shade(count: usize, total: usize) -> char {
+    if count == 0 {
+        return SHADES[0];
+    }
+    SHADES[(count * (SHADES.len() - 1)).div_ceil(total)]
+}
+
+fn format_bound(millis: u64) -> String {
+    match millis >= 1000 {
+        true => format!("{}s", millis as f64 / 1000.0),
+        false => format!("{}ms", millis),
+    }
+}
+
+pub(crate) fn format_latency(latency: Duration) -> String {
+    let millis = latency.as_secs_f64() * 1000.0;
+    if millis < 10.0 {
+        format!("{:.1}ms", millis)
+    } else if millis < 10_000.0 {
+        format!("{:.0}ms", millis)
+    } else {
+        format!("{:.1}s", millis / 1000.0)
+    }
+}
@@ -0,0 +1,204 @@
+use log::error;
+use rhai::{Blob, CallFnOptions, Dynamic, Engine, EvalAltResult, ImmutableString, Map, Scope, AST};
+use ws::Message;
+
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::closecodes::Leg;
+use crate::condition::Facts;
+use crate::session::MessageId;
+use crate::wasm::Verdict;
+
+/// Operations a hook may run for one message before it is stopped.
+const MAX_OPERATIONS: u64 = 1_000_000;
+/// Largest string or blob a script may build.
+const MAX_STRING: usize = 16 << 20;
+/// Most items of an array or a map a script may build.
+const MAX_ITEMS: usize = 64 * 1024;
+/// Deepest calls of functions of a script.
+const MAX_CALL_LEVELS: usize = 64;
+
+/// Hooks called with messages of clients and of the server.
+const ON_CLIENT_MESSAGE: &str = "on_client_message";
+const ON_SERVER_MESSAGE: &str = "on_server_message";
+/// Function called by `ws-proxy process` once every message of the capture passed the hooks.
+const REPORT: &str = "report";
+
+/// Message hooks of a rhai script given with --script, the counterpart of WASM plugins for
+/// rules too small to be compiled. The script defines
+///
+/// ```text
+/// fn on_client_message(msg) { ... }
+/// fn on_server_message(msg) { ... }
+/// ```
+///
+/// or one of them, and `msg` is a map with the message and the connection it came on:
+/// `id`, `from` (client or server), `connection`, `binary`, `text` of text messages or `data`
+/// of binary ones as a blob, `size`, `messages` received on the connection so far, `path` and
+/// `query` of the handshake, `client` fingerprint and `headers` with lowercase names. A hook
+/// returns `false` to drop the message, a string or a blob to replace it, and anything else,
+/// like nothing, to forward it. `send("client" | "server", text or blob)` makes up a message
+/// sent at once, to the client of the message or to all clients for messages of the server,
+/// and `print` logs a line with the id of the message. Hooks keep their state across the
+/// messages of all connections in the map `this`. Statements outside of functions run once
+/// when the script is loaded, and `report()` runs once `ws-proxy process` passed a capture.
+pub struct Hooks {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    state: RefCell<Dynamic>,
+    sent: Rc<RefCell<Vec<(Leg, Message)>>>,
+    lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl Hooks {
+    /// Compiles the script and runs its statements, it has to define at least one hook.
+    pub fn load(path: &Path) -> std::result::Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("Can't read script {}: {}", path.display(), e))?;
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS)
+            .set_max_string_size(MAX_STRING)
+            .set_max_array_size(MAX_ITEMS)
+            .set_max_map_size(MAX_ITEMS)
+            .set_max_call_levels(MAX_CALL_LEVELS);
+        let lines: Rc<RefCell<Vec<String>>> = Rc::default();
+        let printed = lines.clone();
+        engine.on_print(move |line| printed.borrow_mut().push(line.to_string()));
+        let sent: Rc<RefCell<Vec<(Leg, Message)>>> = Rc::default();
+        let texts = sent.clone();
+        engine.register_fn("send", move |to: &str, text: ImmutableString| -> Result<(), Box<EvalAltResult>> {
+            texts.borrow_mut().push((Leg::parse(to)?, Message::text(text.to_string())));
+            Ok(())
+        });
+        let blobs = sent.clone();
+        engine.register_fn("send", move |to: &str, data: Blob| -> Result<(), Box<EvalAltResult>> {
+            blobs.borrow_mut().push((Leg::parse(to)?, Message::binary(data)));
+            Ok(())
+        });
+
+        let ast = engine.compile(&source).map_err(|e| format!("Script {} is invalid: {}", name, e))?;
+        let hooks = [ON_CLIENT_MESSAGE, ON_SERVER_MESSAGE];
+        if !ast.iter_functions().any(|function| hooks.contains(&function.name) && function.params.len() == 1) {
+            return Err(format!("Script {} defines neither {}(msg) nor {}(msg)", name, ON_CLIENT_MESSAGE,
+                ON_SERVER_MESSAGE));
+        }
+        engine.run_ast(&ast).map_err(|e| format!("Script {} failed: {}", name, e))?;
+        for line in lines.borrow_mut().drain(..) {
+            println!("Script {}: {}", name, line);
+        }
+        Ok(Hooks { name, engine, ast, state: RefCell::new(Dynamic::from_map(Map::new())), sent, lines })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Passes the message through the hook of the side it came from. Returns the verdict of
+    /// the hook and the messages it made up, to be sent to the sides. A hook failing, like by
+    /// running too long, leaves the message as it was, and those it made up aren't sent.
+    pub fn process(&self, id: MessageId, facts: &Facts) -> (Verdict, Vec<(Leg, Message)>) {
+        let hook = match facts.from {
+            Leg::Client => ON_CLIENT_MESSAGE,
+            Leg::Server => ON_SERVER_MESSAGE,
+        };
+        if !self.ast.iter_functions().any(|function| function.name == hook && function.params.len() == 1) {
+            return (Verdict::Forward, vec![]);
+        }
+        let mut state = self.state.borrow_mut();
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut state);
+        let returned = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, hook,
+            (Dynamic::from_map(message(id, facts)),));
+        for line in self.lines.borrow_mut().drain(..) {
+            println!("Script {} on message {}: {}", self.name, id, line);
+        }
+        let sent = std::mem::take(&mut *self.sent.borrow_mut());
+        let verdict = match returned {
+            Ok(returned) => self.verdict(returned),
+            Err(e) => Err(e.to_string()),
+        };
+        match verdict {
+            Ok(verdict) => (verdict, sent),
+            Err(e) => {
+                error!("Script {} failed on message {}: {}", self.name, id, e);
+                (Verdict::Forward, vec![])
+            },
+        }
+    }
+
+    /// Calls `report()` of the script, if it defines one, with the state the hooks left in `this`.
+    /// A map is reported one entry per line, nothing returned reports nothing.
+    pub fn report(&self) -> std::result::Result<Option<String>, String> {
+        if !self.ast.iter_functions().any(|function| function.name == REPORT && function.params.is_empty()) {
+            return Ok(None);
+        }
+        let mut state = self.state.borrow_mut();
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut state);
+        let returned = self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, REPORT, ())
+            .map_err(|e| format!("Script {} failed to report: {}", self.name, e))?;
+        for line in self.lines.borrow_mut().drain(..) {
+            println!("Script {}: {}", self.name, line);
+        }
+        if returned.is_unit() {
+            return Ok(None);
+        }
+        Ok(Some(match returned.try_cast_result::<Map>() {
+            Ok(map) => map.iter().map(|(name, value)| format!("{}: {}\n", name, value)).collect(),
+            Err(returned) => format!("{}\n", returned),
+        }))
+    }
+
+    fn verdict(&self, returned: Dynamic) -> std::result::Result<Verdict, String> {
+        let name = self.name.clone();
+        if returned.is_string() {
+            let text = returned.into_string().map_err(|kind| format!("Hook returned {}", kind))?;
+            return Ok(Verdict::Replace(name, Message::text(text)));
+        }
+        if returned.is_blob() {
+            let data = returned.into_blob().map_err(|kind| format!("Hook returned {}", kind))?;
+            return Ok(Verdict::Replace(name, Message::binary(data)));
+        }
+        Ok(match returned.as_bool() {
+            Ok(false) => Verdict::Drop(name),
+            _ => Verdict::Forward,
+        })
+    }
+}
+
+/// Map of the message passed to a hook.
+fn message(id: MessageId, facts: &Facts) -> Map {
+    let mut msg = Map::new();
+    let mut set = |key: &str, value: Dynamic| {
+        msg.insert(key.into(), value);
+    };
+    set("id", id.to_string().into());
+    set("from", facts.from.to_string().into());
+    set("connection", (facts.connection_id as i64).into());
+    match facts.message {
+        Message::Text(text) => {
+            set("binary", false.into());
+            set("text", text.clone().into());
+        },
+        Message::Binary(data) => {
+            set("binary", true.into());
+            set("data", Dynamic::from_blob(data.clone()));
+        },
+    }
+    set("size", (facts.message.len() as i64).into());
+    set("messages", (facts.messages as i64).into());
+    set("path", facts.environment.path.clone().into());
+    let query: Map = facts.environment.query.iter()
+        .map(|(name, value)| (name.into(), value.clone().into()))
+        .collect();
+    set("query", query.into());
+    set("client", facts.environment.client.clone().into());
+    let headers: Map = facts.headers.iter()
+        .map(|(name, value)| (name.into(), value.clone().into()))
+        .collect();
+    set("headers", headers.into());
+    msg
+}
@@ -0,0 +1,128 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use ws::Message;
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bundle::Recorded;
+use crate::closecodes::Leg;
+
+/// Header of the handshake with the upstream naming the hop of the proxy,
+/// so that the next proxy in a chain records which one its client is.
+pub const HOP_HEADER: &str = "X-WS-Proxy-Hop";
+
+/// Capture of one proxy of a chain.
+pub struct Hop {
+    pub name: String,
+    pub messages: Vec<Recorded>,
+}
+
+/// Delays of messages between two neighbouring hops in one direction.
+pub struct HopLatency {
+    pub from_hop: String,
+    pub to_hop: String,
+    pub direction: Leg,
+    pub matched: usize,
+    pub total_ms: f64,
+    pub max_ms: f64,
+}
+
+impl fmt::Display for HopLatency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}: {} messages from {}, avg {:.1}ms, max {:.1}ms",
+            self.from_hop, self.to_hop, self.matched, self.direction,
+            self.total_ms / self.matched.max(1) as f64, self.max_ms)
+    }
+}
+
+/// Aligns the captures of hops given from the client side to the server side:
+/// the same message is recognized at every hop by its direction and the digest
+/// of its payload, counting repeated payloads in order. Returns a record for every
+/// message with its id and time at each hop seen, and delays between neighbouring hops.
+/// Clocks of the hosts are trusted, so their skew adds to the delays.
+pub fn merge(hops: &[Hop]) -> (Vec<Value>, Vec<HopLatency>) {
+    // Occurrences of every message at every hop, in the order of the first hop seeing it
+    let mut keys: Vec<(Leg, String, usize)> = vec![];
+    let mut seen: HashMap<(Leg, String, usize), Vec<Option<&Recorded>>> = HashMap::new();
+    for (index, hop) in hops.iter().enumerate() {
+        let mut occurrences: HashMap<(Leg, String), usize> = HashMap::new();
+        for recorded in hop.messages.iter() {
+            let digest = digest(&recorded.message);
+            let occurrence = occurrences.entry((recorded.from, digest.clone())).or_insert(0);
+            *occurrence += 1;
+            let key = (recorded.from, digest, *occurrence);
+            let at_hops = seen.entry(key.clone()).or_insert_with(|| {
+                keys.push(key);
+                vec![None; hops.len()]
+            });
+            at_hops[index] = Some(recorded);
+        }
+    }
+
+    let mut latencies: Vec<HopLatency> = vec![];
+    for pair in hops.windows(2) {
+        for direction in [Leg::Client, Leg::Server] {
+            let (from_hop, to_hop) = match direction {
+                Leg::Client => (&pair[0].name, &pair[1].name),
+                Leg::Server => (&pair[1].name, &pair[0].name),
+            };
+            latencies.push(HopLatency {
+                from_hop: from_hop.clone(),
+                to_hop: to_hop.clone(),
+                direction,
+                matched: 0,
+                total_ms: 0.0,
+                max_ms: 0.0,
+            });
+        }
+    }
+
+    let mut merged = vec![];
+    for key in keys.iter() {
+        let at_hops = &seen[key];
+        let (direction, digest, _) = key;
+        let hops_seen: Vec<Value> = at_hops.iter().zip(hops.iter())
+            .filter_map(|(recorded, hop)| recorded.map(|recorded| json!({
+                "hop": hop.name,
+                "id": recorded.id,
+                "time": recorded.time.map(|time| time.to_rfc3339()),
+            })))
+            .collect();
+
+        let mut delays = serde_json::Map::new();
+        for index in 0..hops.len().saturating_sub(1) {
+            let (earlier, later) = match direction {
+                Leg::Client => (at_hops[index], at_hops[index + 1]),
+                Leg::Server => (at_hops[index + 1], at_hops[index]),
+            };
+            let times = earlier.and_then(|earlier| earlier.time).zip(later.and_then(|later| later.time));
+            if let Some((earlier_time, later_time)) = times {
+                let delay = (later_time - earlier_time).num_microseconds().unwrap_or_default() as f64 / 1000.0;
+                let latency = &mut latencies[index * 2 + if *direction == Leg::Client { 0 } else { 1 }];
+                latency.matched += 1;
+                latency.total_ms += delay;
+                latency.max_ms = latency.max_ms.max(delay);
+                delays.insert(format!("{} -> {}", latency.from_hop, latency.to_hop), json!(delay));
+            }
+        }
+
+        let size = at_hops.iter().flatten().next().map(|recorded| recorded.message.len()).unwrap_or_default();
+        merged.push(json!({
+            "from": direction.to_string(),
+            "sha256": digest,
+            "size": size,
+            "hops": hops_seen,
+            "delays_ms": delays,
+        }));
+    }
+    (merged, latencies)
+}
+
+fn digest(message: &Message) -> String {
+    let data: &[u8] = match message {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(data) => data,
+    };
+    hex::encode(Sha256::digest(data))
+}
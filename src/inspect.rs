@@ -0,0 +1,254 @@
+use chrono::{DateTime, Utc};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use serde_json::Value;
+use serde_json_path::JsonPath;
+
+use std::convert::TryInto;
+
+use crate::closecodes::Leg;
+use crate::normalize::Normalization;
+use crate::tags;
+
+/// Message of a capture as the inspector shows it.
+pub struct Entry {
+    pub id: String,
+    pub from: Leg,
+    pub time: Option<DateTime<Utc>>,
+    pub data: String,
+    pub tags: Vec<String>,
+}
+
+/// Capture opened in the inspector.
+pub struct Side {
+    pub name: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Side {
+    /// Messages of a capture with their tags, binary ones shown in base64 as they are captured.
+    pub fn load(name: &str, capture: &str) -> Self {
+        let entries = tags::tagged(capture).into_iter()
+            .map(|(record, tags)| Entry {
+                id: record["id"].as_str().unwrap_or("-").to_string(),
+                from: record["from"].as_str().and_then(|from| Leg::parse(from).ok()).unwrap_or(Leg::Client),
+                time: record["time"].as_str()
+                    .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                    .map(|time| time.with_timezone(&Utc)),
+                data: record["data"].as_str().unwrap_or_default().to_string(),
+                tags: tags.into_iter().collect(),
+            })
+            .collect();
+        Side { name: name.to_string(), entries }
+    }
+
+    fn start(&self) -> Option<DateTime<Utc>> {
+        self.entries.iter().find_map(|entry| entry.time)
+    }
+
+    /// Seconds since the first message of the capture.
+    fn elapsed(&self, entry: &Entry) -> Option<f64> {
+        let (start, time) = (self.start()?, entry.time?);
+        Some((time - start).num_microseconds()? as f64 / 1_000_000.0)
+    }
+}
+
+/// How the message selected on one side finds its counterpart on the other.
+pub enum Sync {
+    /// The message with the same number.
+    Order,
+    /// The first message from the same side with the same value of the expression,
+    /// like the id of a request and its response.
+    Key(String, JsonPath),
+    /// The message closest in time since the start of its capture, with the second
+    /// capture shifted by the seconds.
+    Offset(f64),
+}
+
+impl Sync {
+    fn describe(&self) -> String {
+        match self {
+            Sync::Order => "by order".to_string(),
+            Sync::Key(expression, _) => format!("by {}", expression),
+            Sync::Offset(seconds) => format!("by time, offset {}s", seconds),
+        }
+    }
+
+    /// Counterpart on the other side of the selected message of a side, if there is one.
+    fn follow(&self, sides: &[Side; 2], driver: usize, selected: usize) -> Option<usize> {
+        let (from, to) = (&sides[driver], &sides[1 - driver]);
+        let entry = from.entries.get(selected)?;
+        match self {
+            Sync::Order => Some(selected.min(to.entries.len().checked_sub(1)?)),
+            Sync::Key(_, path) => {
+                let wanted = key(path, entry)?;
+                to.entries.iter().position(|other| other.from == entry.from && key(path, other).as_ref() == Some(&wanted))
+            },
+            Sync::Offset(offset) => {
+                // The offset shifts the second capture, whichever side drives
+                let shift = if driver == 0 { *offset } else { -offset };
+                let time = from.elapsed(entry)? + shift;
+                to.entries.iter().enumerate()
+                    .filter_map(|(index, other)| to.elapsed(other).map(|elapsed| (index, (elapsed - time).abs())))
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(index, _)| index)
+            },
+        }
+    }
+}
+
+fn key(path: &JsonPath, entry: &Entry) -> Option<String> {
+    let json = serde_json::from_str::<Value>(&entry.data).ok()?;
+    let found = path.query(&json).all();
+    found.first().map(|value| value.to_string())
+}
+
+struct Inspector {
+    sides: Vec<Side>,
+    states: Vec<ListState>,
+    sync: Sync,
+    /// Side which is scrolled, the other one follows it.
+    driver: usize,
+    normalization: Normalization,
+}
+
+/// Opens one capture, or two side by side with the selection of the second one following
+/// the first, until q is pressed.
+pub fn run(sides: Vec<Side>, sync: Sync, normalization: Normalization) -> std::result::Result<(), String> {
+    let states = sides.iter()
+        .map(|side| ListState::default().with_selected((!side.entries.is_empty()).then_some(0)))
+        .collect();
+    let mut inspector = Inspector { sides, states, sync, driver: 0, normalization };
+    inspector.follow();
+
+    let mut terminal = ratatui::try_init().map_err(|e| format!("Can't open the terminal: {}", e))?;
+    let result = inspector.show(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+impl Inspector {
+    fn show(&mut self, terminal: &mut DefaultTerminal) -> std::result::Result<(), String> {
+        loop {
+            terminal.draw(|frame| self.draw(frame)).map_err(|e| e.to_string())?;
+            let key = match event::read().map_err(|e| e.to_string())? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            let page = terminal.size().map(|size| size.height as isize / 2).unwrap_or(10);
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => self.scroll(1),
+                KeyCode::Up | KeyCode::Char('k') => self.scroll(-1),
+                KeyCode::PageDown => self.scroll(page),
+                KeyCode::PageUp => self.scroll(-page),
+                KeyCode::Home | KeyCode::Char('g') => self.scroll(isize::MIN / 2),
+                KeyCode::End | KeyCode::Char('G') => self.scroll(isize::MAX / 2),
+                KeyCode::Tab if self.sides.len() == 2 => self.driver = 1 - self.driver,
+                _ => {}
+            }
+        }
+    }
+
+    fn scroll(&mut self, by: isize) {
+        let length = self.sides[self.driver].entries.len();
+        if length == 0 {
+            return;
+        }
+        let state = &mut self.states[self.driver];
+        let selected = state.selected().unwrap_or(0) as isize;
+        state.select(Some((selected + by).clamp(0, length as isize - 1) as usize));
+        self.follow();
+    }
+
+    fn follow(&mut self) {
+        let sides: &[Side; 2] = match self.sides.as_slice().try_into() {
+            Ok(sides) => sides,
+            Err(_) => return,
+        };
+        let selected = match self.states[self.driver].selected() {
+            Some(selected) => selected,
+            None => return,
+        };
+        let counterpart = self.sync.follow(sides, self.driver, selected);
+        self.states[1 - self.driver].select(counterpart);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [lists, details, help] = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(65), Constraint::Min(5), Constraint::Length(1)])
+            .areas(frame.area());
+        let constraints = vec![Constraint::Ratio(1, self.sides.len() as u32); self.sides.len()];
+        let columns = Layout::default().direction(Direction::Horizontal).constraints(constraints.clone()).split(lists);
+        let detail_columns = Layout::default().direction(Direction::Horizontal).constraints(constraints).split(details);
+
+        let selected: Vec<Option<&Entry>> = self.sides.iter().zip(self.states.iter())
+            .map(|(side, state)| state.selected().and_then(|selected| side.entries.get(selected)))
+            .collect();
+        // Counterparts with other data are marked, they are where the runs diverge
+        let differ = match selected.as_slice() {
+            [Some(left), Some(right)] =>
+                Some(self.normalization.text(&left.data) != self.normalization.text(&right.data)),
+            _ => None,
+        };
+
+        for (index, side) in self.sides.iter().enumerate() {
+            let items: Vec<ListItem> = side.entries.iter().map(|entry| ListItem::new(line(side, entry))).collect();
+            let mut title = format!(" {} ({} messages) ", side.name, side.entries.len());
+            if self.sides.len() == 2 {
+                title.push_str(&if index == self.driver {
+                    format!("scrolled, {} ", self.sync.describe())
+                } else {
+                    "follows ".to_string()
+                });
+            }
+            let highlight = match differ {
+                Some(true) => Style::default().bg(Color::Red),
+                Some(false) => Style::default().bg(Color::Green),
+                None => Style::default().bg(Color::Blue),
+            };
+            let list = List::new(items)
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .highlight_style(highlight.fg(Color::Black).add_modifier(Modifier::BOLD));
+            frame.render_stateful_widget(list, columns[index], &mut self.states[index]);
+            render_details(frame, detail_columns[index], selected[index]);
+        }
+
+        let keys = if self.sides.len() == 2 {
+            "↑↓ PgUp PgDn Home End scroll, Tab scrolls the other side, q quits"
+        } else {
+            "↑↓ PgUp PgDn Home End scroll, q quits"
+        };
+        frame.render_widget(Paragraph::new(keys), help);
+    }
+}
+
+fn line<'a>(side: &Side, entry: &'a Entry) -> Line<'a> {
+    let arrow = match entry.from {
+        Leg::Client => "→",
+        Leg::Server => "←",
+    };
+    let elapsed = side.elapsed(entry).map(|elapsed| format!("+{:.3}s", elapsed)).unwrap_or_default();
+    let tags = if entry.tags.is_empty() { String::new() } else { format!(" [{}]", entry.tags.join(",")) };
+    Line::from(format!("{} {} {}{} {}", entry.id, elapsed, arrow, tags, entry.data))
+}
+
+fn render_details(frame: &mut Frame, area: Rect, entry: Option<&Entry>) {
+    let text = match entry {
+        Some(entry) => match serde_json::from_str::<Value>(&entry.data) {
+            Ok(json) => serde_json::to_string_pretty(&json).unwrap_or_else(|_| entry.data.clone()),
+            Err(_) => entry.data.clone(),
+        },
+        None => "No counterpart".to_string(),
+    };
+    let title = entry.map(|entry| format!(" {} from {} ", entry.id, entry.from)).unwrap_or_default();
+    let details = Paragraph::new(text)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .wrap(Wrap { trim: false });
+    frame.render_widget(details, area);
+}
@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+
+use ws::{Frame, Message, OpCode};
+
+use crate::closecodes::Leg;
+
+const INJECTED: &str = "ws-proxy: interleaved frame";
+const TAIL: &[u8] = b"ws-proxy: continuation";
+
+/// Frame put between the fragments of a message. Control frames are allowed there
+/// by RFC 6455, data frames are not and a strict peer must fail the connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Injection {
+    Ping,
+    Pong,
+    Text,
+    Binary,
+}
+
+impl Injection {
+    fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "ping" => Ok(Injection::Ping),
+            "pong" => Ok(Injection::Pong),
+            "text" => Ok(Injection::Text),
+            "binary" => Ok(Injection::Binary),
+            _ => Err(format!("Unknown interleaved frame {}, expected ping, pong, text or binary", value)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Injection::Ping => "ping",
+            Injection::Pong => "pong",
+            Injection::Text => "text",
+            Injection::Binary => "binary",
+        }
+    }
+}
+
+/// Frames to interleave into messages sent toward one side of the proxy.
+#[derive(Clone, Debug)]
+pub struct InterleavePlan {
+    pub leg: Leg,
+    pub injections: Vec<Injection>,
+}
+
+impl InterleavePlan {
+    /// Parses `<client|server>:<frame>[,<frame>...]`.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let mut parts = spec.splitn(2, ':');
+        let leg = Leg::parse(parts.next().unwrap_or(""))?;
+        let injections = parts.next().unwrap_or("")
+            .split(',')
+            .map(Injection::parse)
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(InterleavePlan { leg, injections })
+    }
+}
+
+/// Splitting of messages on a single connection.
+///
+/// A message is sent as two fragments. The frames to interleave and a placeholder
+/// for the second fragment are queued to the connection right after the first one,
+/// so that they pass `on_send_frame` in order, where the placeholder is replaced
+/// with the actual continuation frame.
+pub struct Interleave {
+    plan: InterleavePlan,
+    tails: VecDeque<Vec<u8>>,
+}
+
+impl Interleave {
+    pub fn new(plan: InterleavePlan) -> Self {
+        Interleave { plan, tails: VecDeque::new() }
+    }
+
+    pub fn injections(&self) -> &[Injection] {
+        &self.plan.injections
+    }
+
+    /// Returns the frame to send instead, if the frame was queued by the interleaving itself.
+    pub fn substitute(&mut self, frame: &Frame) -> Option<Frame> {
+        match frame.opcode() {
+            OpCode::Binary if frame.payload() == TAIL => {
+                self.tails.pop_front().map(|tail| Frame::message(tail, OpCode::Continue, true))
+            },
+            OpCode::Text | OpCode::Binary if frame.payload() == INJECTED.as_bytes() => {
+                Some(frame.clone())
+            },
+            _ => None
+        }
+    }
+
+    /// Cuts the message frame into the first fragment, returning it together with
+    /// the messages to queue after it. Messages which don't fit into a single frame
+    /// and messages sent while another one is split are left untouched.
+    pub fn split(&mut self, mut frame: Frame) -> (Frame, Vec<Queued>) {
+        let splittable = matches!(frame.opcode(), OpCode::Text | OpCode::Binary)
+            && frame.payload().len() <= u16::MAX as usize
+            && self.tails.is_empty();
+        if !splittable {
+            return (frame, vec![]);
+        }
+
+        let half = frame.payload().len() / 2;
+        let tail = frame.payload_mut().split_off(half);
+        frame.set_final(false);
+        self.tails.push_back(tail);
+
+        let mut queued: Vec<Queued> = self.plan.injections.iter()
+            .map(|injection| match injection {
+                Injection::Ping => Queued::Ping(INJECTED.as_bytes().to_vec()),
+                Injection::Pong => Queued::Pong(INJECTED.as_bytes().to_vec()),
+                Injection::Text => Queued::Message(Message::text(INJECTED)),
+                Injection::Binary => Queued::Message(Message::binary(INJECTED.as_bytes())),
+            })
+            .collect();
+        queued.push(Queued::Message(Message::binary(TAIL)));
+
+        (frame, queued)
+    }
+}
+
+/// Something to queue to the connection with its `Sender`.
+pub enum Queued {
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Message(Message),
+}
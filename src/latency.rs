@@ -0,0 +1,73 @@
+use rand::Rng;
+
+use std::time::Duration;
+
+use crate::closecodes::Leg;
+
+/// Latency added to the messages of one direction, given with --delay.
+#[derive(Clone, Copy, Debug)]
+pub struct Latency {
+    /// Side the delayed messages come from.
+    pub from: Leg,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Latency {
+    /// Parses `<client->server|server->client>=<delay>[..<delay>]` with delays in
+    /// milliseconds or seconds, like `client->server=200ms` or `server->client=50..500ms`.
+    /// A range gives every message a delay drawn from it.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (direction, delay) = spec.split_once('=')
+            .ok_or_else(|| format!("Delay {} is not <direction>=<delay>", spec))?;
+        let from = parse_direction(direction)?;
+        let (min, max) = match delay.split_once("..") {
+            Some((min, max)) => {
+                // The unit of the upper bound applies to a lower bound without one
+                let unit = max.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+                let min = match min.ends_with(|c: char| c.is_ascii_digit()) {
+                    true => format!("{}{}", min, unit),
+                    false => min.to_string(),
+                };
+                (parse_delay(&min)?, parse_delay(max)?)
+            },
+            None => (parse_delay(delay)?, parse_delay(delay)?),
+        };
+        if min > max {
+            return Err(format!("Delays of {} are not in increasing order", spec));
+        }
+        Ok(Latency { from, min, max })
+    }
+
+    /// Delay of a message, drawn anew for each one in a range.
+    pub fn draw(&self) -> Duration {
+        if self.min == self.max {
+            return self.min;
+        }
+        rand::thread_rng().gen_range(self.min..=self.max)
+    }
+}
+
+/// Parses `client->server` or `server->client` into the side messages come from.
+pub(crate) fn parse_direction(direction: &str) -> std::result::Result<Leg, String> {
+    match direction {
+        "client->server" => Ok(Leg::Client),
+        "server->client" => Ok(Leg::Server),
+        _ => Err(format!("Unknown direction {}, expected client->server or server->client", direction)),
+    }
+}
+
+/// Parses `<number>ms` or `<number>s`.
+fn parse_delay(delay: &str) -> std::result::Result<Duration, String> {
+    let (number, scale) = if let Some(number) = delay.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = delay.strip_suffix('s') {
+        (number, 1.0)
+    } else {
+        return Err(format!("Delay {} has no unit, expected ms or s", delay));
+    };
+    number.parse::<f64>().ok()
+        .filter(|number| number.is_finite() && *number >= 0.0)
+        .map(|number| Duration::from_secs_f64(number * scale))
+        .ok_or_else(|| format!("Invalid delay {}", delay))
+}
@@ -0,0 +1,242 @@
+use serde_json::Value;
+use ws::Message;
+
+use crate::bundle::{self, Recorded};
+
+/// Fields telling kinds of JSON requests apart, requests differing in them get their own rules.
+const KIND_FIELDS: [&str; 6] = ["type", "event", "op", "method", "action", "kind"];
+
+/// Requests sharing the structure and the kind, with the replies captured after each of them.
+struct Group {
+    key: String,
+    samples: Vec<Sample>,
+}
+
+struct Sample {
+    id: Option<String>,
+    request: Request,
+    replies: Vec<Message>,
+}
+
+/// JSON objects and arrays are generalized, anything else is matched exactly.
+enum Request {
+    Text(String),
+    Json(Value),
+}
+
+impl Request {
+    fn pointer(&self, pointer: &str) -> Option<&Value> {
+        match self {
+            Request::Json(value) => value.pointer(pointer),
+            Request::Text(_) => None,
+        }
+    }
+}
+
+/// Generalizes the captured messages into a script of the test server: messages sent
+/// by the server on open, and a rule for each kind of request. Values of JSON requests
+/// which differ between requests of a kind become variables, which are substituted
+/// into the replies where they carried the same value.
+pub fn learn(messages: Vec<Recorded>) -> String {
+    let mut steps = bundle::steps(messages).into_iter();
+    let mut script = String::new();
+
+    if let Some(opening) = steps.next() {
+        for reply in opening.replies.iter() {
+            if let Some(text) = scriptable(&reply.message) {
+                script.push_str(&format!("on-open {}\n", text));
+            }
+        }
+    }
+
+    let mut groups: Vec<Group> = vec![];
+    for step in steps {
+        let request = match step.expected {
+            Some(request) => request,
+            None => continue,
+        };
+        let value = match scriptable(&request.message) {
+            Some(text) => match serde_json::from_str::<Value>(&text) {
+                Ok(value) if value.is_object() || value.is_array() => Request::Json(value),
+                _ => Request::Text(text),
+            },
+            None => {
+                script.push_str(&format!("# request {} can't be scripted\n", request.id.as_deref().unwrap_or("-")));
+                continue;
+            }
+        };
+        let sample = Sample {
+            id: request.id,
+            replies: step.replies.into_iter().map(|reply| reply.message).collect(),
+            request: value,
+        };
+        let key = group_key(&sample.request);
+        match groups.iter_mut().find(|group| group.key == key) {
+            Some(group) => group.samples.push(sample),
+            None => groups.push(Group { key, samples: vec![sample] }),
+        }
+    }
+
+    for group in groups.iter() {
+        script.push_str(&rules(group));
+    }
+    script
+}
+
+/// Text of a message which fits into a line of a script.
+fn scriptable(message: &Message) -> Option<String> {
+    let text = match message {
+        Message::Text(text) => text,
+        Message::Binary(_) => return None,
+    };
+    let text = serde_json::from_str::<Value>(text)
+        .map(|value| value.to_string())
+        .unwrap_or_else(|_| text.trim().to_string());
+    let ambiguous = text.is_empty() || text.contains('\n') || text.contains("=>") || text == "*"
+        || text.starts_with('#') || text.starts_with("on-open ") || text.starts_with("every ");
+    if ambiguous { None } else { Some(text) }
+}
+
+/// Structure of a JSON request with all values left out except the kind fields,
+/// other messages are only grouped with equal ones.
+fn group_key(request: &Request) -> String {
+    match request {
+        Request::Text(text) => format!("text {}", text),
+        Request::Json(request) => {
+            let kinds: Vec<String> = KIND_FIELDS.iter()
+                .map(|field| request.get(field).map(|value| value.to_string()).unwrap_or_default())
+                .collect();
+            format!("json {} {}", skeleton(request), kinds.join(","))
+        }
+    }
+}
+
+fn skeleton(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(object.iter()
+            .map(|(key, value)| (key.clone(), skeleton(value)))
+            .collect()),
+        Value::Array(array) => Value::Array(array.iter().map(skeleton).collect()),
+        _ => Value::Null
+    }
+}
+
+/// JSON pointers and values of all scalars in the value.
+fn leaves(value: &Value, pointer: String, found: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(object) => for (key, value) in object.iter() {
+            leaves(value, format!("{}/{}", pointer, key.replace('~', "~0").replace('/', "~1")), found);
+        },
+        Value::Array(array) => for (index, value) in array.iter().enumerate() {
+            leaves(value, format!("{}/{}", pointer, index), found);
+        },
+        _ => found.push((pointer, value.clone()))
+    }
+}
+
+/// Name of the variable for a value at the pointer, after the field holding it.
+fn variable_name(pointer: &str, taken: &[(String, String)]) -> String {
+    let field: String = pointer.rsplit('/')
+        .find(|segment| segment.parse::<usize>().is_err())
+        .unwrap_or("value")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let field = match field.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => field,
+        _ => format!("v{}", field),
+    };
+    let mut name = field.clone();
+    let mut suffix = 1;
+    while taken.iter().any(|(_, taken)| *taken == name) || name == "message" {
+        suffix += 1;
+        name = format!("{}_{}", field, suffix);
+    }
+    name
+}
+
+fn marker(name: &str) -> Value {
+    Value::String(format!("@@ws-proxy:{}@@", name))
+}
+
+/// Writes the value with markers of variables turned into placeholders.
+fn template(value: &Value, variables: &[(String, String)]) -> String {
+    let mut text = value.to_string();
+    for (_, name) in variables.iter() {
+        text = text.replace(&marker(name).to_string(), &format!("{{{}}}", name));
+    }
+    text
+}
+
+fn rules(group: &Group) -> String {
+    let first = &group.samples[0];
+    let ids: Vec<&str> = group.samples.iter().take(3).filter_map(|sample| sample.id.as_deref()).collect();
+    let mut rules = match ids.is_empty() {
+        true => format!("# {} request(s)\n", group.samples.len()),
+        false => format!("# {} request(s), like {}\n", group.samples.len(), ids.join(", ")),
+    };
+
+    let request = match &first.request {
+        Request::Text(text) => {
+            for reply in first.replies.iter().filter_map(scriptable) {
+                rules.push_str(&format!("{} => {}\n", text, reply));
+            }
+            return rules;
+        },
+        Request::Json(request) => request,
+    };
+
+    // Values differing between the requests of the group become variables
+    let mut found = vec![];
+    leaves(request, String::new(), &mut found);
+    let mut variables: Vec<(String, String)> = vec![];
+    for (pointer, value) in found {
+        let varies = group.samples.iter().any(|sample| sample.request.pointer(&pointer) != Some(&value));
+        if varies {
+            let name = variable_name(&pointer, &variables);
+            variables.push((pointer, name));
+        }
+    }
+    let mut pattern = request.clone();
+    for (pointer, name) in variables.iter() {
+        if let Some(variable) = pattern.pointer_mut(pointer) {
+            *variable = marker(name);
+        }
+    }
+    let pattern = template(&pattern, &variables);
+
+    for (index, reply) in first.replies.iter().enumerate() {
+        let text = match scriptable(reply) {
+            Some(text) => text,
+            None => continue,
+        };
+        let mut reply = match serde_json::from_str::<Value>(&text) {
+            Ok(reply) => reply,
+            Err(_) => {
+                rules.push_str(&format!("{} => {}\n", pattern, text));
+                continue;
+            }
+        };
+
+        // A value of the reply comes from a variable if it equals it in every sample
+        let mut found = vec![];
+        leaves(&reply, String::new(), &mut found);
+        for (pointer, _) in found {
+            let source = variables.iter().find(|(variable, _)| group.samples.iter().all(|sample| {
+                let reply = sample.replies.get(index)
+                    .and_then(|reply| reply.as_text().ok())
+                    .and_then(|reply| serde_json::from_str::<Value>(reply).ok());
+                match reply {
+                    Some(reply) => reply.pointer(&pointer).is_some()
+                        && reply.pointer(&pointer) == sample.request.pointer(variable),
+                    None => true,
+                }
+            }));
+            if let (Some((_, name)), Some(value)) = (source, reply.pointer_mut(&pointer)) {
+                *value = marker(name);
+            }
+        }
+        rules.push_str(&format!("{} => {}\n", pattern, template(&reply, &variables)));
+    }
+    rules
+}
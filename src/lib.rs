@@ -0,0 +1,83 @@
+pub mod agent;
+pub mod alert;
+pub mod anonymize;
+pub mod asyncapi;
+pub mod auth;
+pub mod backoff;
+pub mod bundle;
+pub mod clock;
+pub mod closecodes;
+pub mod commands;
+pub mod composer;
+pub mod condition;
+pub mod config;
+pub mod console;
+pub mod contract;
+pub mod control;
+pub mod deflate;
+pub mod devtools;
+pub mod digest;
+pub mod drift;
+pub mod encryption;
+pub mod expr;
+pub mod fault;
+pub mod fingerprint;
+pub mod flood;
+pub mod gaps;
+pub mod heatmap;
+pub mod hooks;
+pub mod hops;
+pub mod inspect;
+pub mod interleave;
+pub mod latency;
+pub mod learn;
+pub mod logqueue;
+pub mod manifest;
+pub mod memory;
+pub mod metrics;
+pub mod normalize;
+pub mod notify;
+pub mod overhead;
+pub mod observer;
+pub mod palette;
+pub mod plugins;
+pub mod probe;
+pub mod process;
+pub mod profile;
+pub mod prometheus;
+pub mod proxy;
+pub mod recorder;
+pub mod relay;
+pub mod render;
+pub mod repair;
+pub mod replay;
+pub mod resubscribe;
+pub mod retention;
+pub mod sampling;
+pub mod scaffold;
+pub mod schema;
+pub mod segments;
+pub mod selfcheck;
+pub mod session;
+pub mod setup;
+pub mod shutdown;
+pub mod snapshot;
+pub mod sse;
+pub mod storage;
+pub mod tags;
+pub mod telemetry;
+pub mod testserver;
+pub mod throttle;
+pub mod tls;
+pub mod topology;
+pub mod track;
+pub mod truncation;
+pub mod tui;
+pub mod tunnel;
+pub mod upstreamloss;
+pub mod upstreamqueue;
+pub mod verbosity;
+pub mod views;
+pub mod wasm;
+pub mod webui;
+pub mod wizard;
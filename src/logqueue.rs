@@ -0,0 +1,445 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use crate::encryption::Sink;
+use crate::memory::{MemoryMonitor, Shedding};
+use crate::storage::Storage;
+
+enum Command {
+    Open(usize, Sink, Option<Rotated>),
+    Write(usize, String),
+    Close(usize),
+    Finish(mpsc::Sender<()>),
+}
+
+/// When written entries are forced to the disk, so that they survive a crash or power loss.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Left to the operating system.
+    Never,
+    /// After every entry.
+    Always,
+    /// At most this long after an entry is written.
+    Every(Duration),
+}
+
+impl SyncPolicy {
+    /// Parses `never`, `always` or an interval in seconds.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => value.parse::<f64>().ok()
+                .filter(|seconds| *seconds > 0.0)
+                .map(|seconds| SyncPolicy::Every(Duration::from_secs_f64(seconds)))
+                .ok_or_else(|| format!("Invalid fsync policy {}, expected never, always or seconds", value)),
+        }
+    }
+}
+
+/// How messages are written into the client and server logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A line of text with the time, the connection and the message, pretty-printed.
+    #[default]
+    Text,
+    /// A JSON object per line, for jq and log pipelines.
+    Ndjson,
+}
+
+impl LogFormat {
+    /// Parses `text` or `ndjson`.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "ndjson" => Ok(LogFormat::Ndjson),
+            _ => Err(format!("Invalid log format {}, expected text or ndjson", value)),
+        }
+    }
+}
+
+/// Limits of the size of a log file. When an entry would make the file larger than
+/// max_size, the file is renamed to `<name>.1`, the older ones to `<name>.2` and so on,
+/// and those beyond max_files are removed. Entries are never split between files.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    pub max_size: u64,
+    /// Rotated files kept next to the current one.
+    pub max_files: usize,
+}
+
+/// File of the queue which is rotated, with the size written into it.
+struct Rotated {
+    rotation: Rotation,
+    path: PathBuf,
+    size: u64,
+}
+
+impl Rotated {
+    /// Renames the closed file and the previous ones and opens a new file. Encrypted
+    /// files are rotated under their own names, files in memory are never rotated.
+    fn rotate(&mut self, storage: &Storage) -> io::Result<Sink> {
+        let numbered = |number: usize| {
+            let mut name = self.path.as_os_str().to_owned();
+            name.push(format!(".{}", number));
+            storage.disk_path(Path::new(&name))
+        };
+        if let Some(current) = storage.disk_path(&self.path) {
+            if let Some(oldest) = numbered(self.rotation.max_files) {
+                fs::remove_file(oldest).ok();
+            }
+            for number in (1..self.rotation.max_files).rev() {
+                if let (Some(from), Some(to)) = (numbered(number), numbered(number + 1)) {
+                    if from.exists() {
+                        fs::rename(from, to)?;
+                    }
+                }
+            }
+            match numbered(1).filter(|_| self.rotation.max_files > 0) {
+                Some(first) => fs::rename(&current, first)?,
+                None => fs::remove_file(&current)?,
+            }
+        }
+        self.size = 0;
+        storage.open(&self.path)
+    }
+}
+
+/// Paths of the client and server logs: a name template with `{session}`, `{role}`
+/// (client or server) and `{id}` (the connection id, 0 for the server) in a directory.
+/// Without a template they are ws-proxy.server.log and ws-proxy.client.log, or a log
+/// per client when each client is logged on its own.
+#[derive(Clone, Debug)]
+pub struct LogNames {
+    dir: PathBuf,
+    session: String,
+    server: String,
+    client: String,
+}
+
+impl LogNames {
+    const PLACEHOLDERS: [&'static str; 3] = ["session", "role", "id"];
+
+    /// Checks that a template has only known placeholders and names a file.
+    pub fn parse_template(template: &str) -> std::result::Result<String, String> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format!("Log name {} has an unclosed placeholder", template))?;
+            let placeholder = &rest[start + 1..start + end];
+            if !Self::PLACEHOLDERS.contains(&placeholder) {
+                return Err(format!("Log name {} has unknown placeholder {{{}}}, expected {{session}}, {{role}} or {{id}}",
+                    template, placeholder));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if template.is_empty() || template.contains(['/', '\\']) {
+            return Err(format!("Log name {} must be a file name, --log-dir sets the directory", template));
+        }
+        Ok(template.to_string())
+    }
+
+    pub fn new(dir: PathBuf, session: &str, template: Option<String>, per_connection: bool) -> Self {
+        let (server, client) = match template {
+            Some(template) => (template.clone(), template),
+            None if per_connection => ("ws-proxy.{role}.log".to_string(), "ws-proxy.{role}-{id}.log".to_string()),
+            None => ("ws-proxy.{role}.log".to_string(), "ws-proxy.{role}.log".to_string()),
+        };
+        LogNames { dir, session: session.to_string(), server, client }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn server(&self) -> PathBuf {
+        self.path(&self.server, "server", 0)
+    }
+
+    pub fn client(&self, connection_id: u32) -> PathBuf {
+        self.path(&self.client, "client", connection_id)
+    }
+
+    fn path(&self, template: &str, role: &str, connection_id: u32) -> PathBuf {
+        let name = template.replace("{session}", &self.session)
+            .replace("{role}", role)
+            .replace("{id}", &connection_id.to_string());
+        self.dir.join(name)
+    }
+}
+
+/// Ids and numbers of handles of files opened in the queue, by path.
+type Opened = Arc<Mutex<HashMap<PathBuf, (usize, usize)>>>;
+
+/// Writes log entries to files in a background thread,
+/// so that forwarding of messages never waits for the disk.
+pub struct LogQueue {
+    commands: mpsc::Sender<Command>,
+    next_id: AtomicUsize,
+    opened: Opened,
+    memory: MemoryMonitor,
+    storage: Storage,
+}
+
+impl LogQueue {
+    pub fn start(memory: MemoryMonitor, storage: Storage, sync: SyncPolicy) -> Self {
+        let (commands, queue) = mpsc::channel();
+        let monitor = memory.clone();
+        let disk = storage.clone();
+
+        thread::spawn(move || {
+            let mut files: HashMap<usize, Sink> = HashMap::new();
+            let mut rotated: HashMap<usize, Rotated> = HashMap::new();
+            let mut unsynced: HashSet<usize> = HashSet::new();
+            let mut synced = Instant::now();
+            loop {
+                let command = match sync {
+                    SyncPolicy::Every(interval) => match queue.recv_timeout(interval) {
+                        Ok(command) => Some(command),
+                        Err(mpsc::RecvTimeoutError::Timeout) => None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    },
+                    _ => match queue.recv() {
+                        Ok(command) => Some(command),
+                        Err(_) => break,
+                    }
+                };
+                if let SyncPolicy::Every(interval) = sync {
+                    if synced.elapsed() >= interval {
+                        for id in unsynced.drain() {
+                            if let Some(file) = files.get_mut(&id) {
+                                file.sync().unwrap_or_else(|e| {
+                                    error!("Error: {}", e);
+                                });
+                            }
+                        }
+                        synced = Instant::now();
+                    }
+                }
+
+                let command = match command {
+                    Some(command) => command,
+                    None => continue,
+                };
+                match command {
+                    Command::Open(id, file, rotation) => {
+                        files.insert(id, file);
+                        rotated.extend(rotation.map(|rotation| (id, rotation)));
+                    },
+                    Command::Write(id, text) => {
+                        let full = rotated.get_mut(&id).filter(|rotated| rotated.size > 0
+                            && rotated.size + text.len() as u64 > rotated.rotation.max_size);
+                        if let Some(rotated) = full {
+                            // Dropping the file completes it before it is renamed
+                            files.remove(&id);
+                            let file = rotated.rotate(&disk).or_else(|e| {
+                                error!("Error: failed to rotate {}: {}", rotated.path.display(), e);
+                                disk.open(&rotated.path)
+                            });
+                            match file {
+                                Ok(file) => {
+                                    files.insert(id, file);
+                                },
+                                Err(e) => error!("Error: {}", e),
+                            }
+                        }
+                        if let Some(rotated) = rotated.get_mut(&id) {
+                            rotated.size += text.len() as u64;
+                        }
+                        if let Some(file) = files.get_mut(&id) {
+                            let written = file.write_all(text.as_bytes()).and_then(|_| match sync {
+                                SyncPolicy::Always => file.sync(),
+                                _ => Ok(()),
+                            });
+                            written.unwrap_or_else(|e| {
+                                error!("Error: {}", e);
+                            });
+                            unsynced.insert(id);
+                        }
+                        monitor.log_written(text.len());
+                    },
+                    Command::Close(id) => {
+                        files.remove(&id);
+                        rotated.remove(&id);
+                    },
+                    Command::Finish(done) => {
+                        files.clear();
+                        rotated.clear();
+                        done.send(()).ok();
+                    }
+                }
+            }
+        });
+
+        LogQueue {
+            commands,
+            next_id: AtomicUsize::new(0),
+            opened: Opened::default(),
+            memory,
+            storage,
+        }
+    }
+
+    /// Opens a file to append entries to, encrypted if the queue encrypts files.
+    /// Handles opened with the same path share the file.
+    pub fn open(&self, path: &Path) -> io::Result<LogFile> {
+        self.open_rotated(path, None)
+    }
+
+    /// Opens a file like `open`, rotated if the handle opening it first gives a rotation.
+    pub fn open_rotated(&self, path: &Path, rotation: Option<Rotation>) -> io::Result<LogFile> {
+        let mut opened = self.opened.lock().unwrap();
+        let id = match opened.get_mut(path) {
+            Some((id, handles)) => {
+                *handles += 1;
+                *id
+            },
+            None => {
+                let file = self.storage.open(path)?;
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let rotated = rotation.map(|rotation| Rotated {
+                    rotation,
+                    path: path.to_path_buf(),
+                    // Plain files are appended to
+                    size: self.storage.disk_path(path).filter(|_| !self.storage.is_encrypted())
+                        .and_then(|path| fs::metadata(path).ok())
+                        .map(|metadata| metadata.len())
+                        .unwrap_or(0),
+                });
+                self.commands.send(Command::Open(id, file, rotated)).ok();
+                opened.insert(path.to_path_buf(), (id, 1));
+                id
+            }
+        };
+
+        Ok(LogFile {
+            id,
+            path: path.to_path_buf(),
+            commands: self.commands.clone(),
+            opened: self.opened.clone(),
+            memory: self.memory.clone(),
+        })
+    }
+
+    /// Waits until all queued entries are written and closes all files.
+    pub fn finish(&self) {
+        let (done, finished) = mpsc::channel();
+        self.commands.send(Command::Finish(done)).ok();
+        finished.recv().ok();
+    }
+}
+
+/// Handle to a file opened in the `LogQueue`.
+pub struct LogFile {
+    id: usize,
+    path: PathBuf,
+    commands: mpsc::Sender<Command>,
+    opened: Opened,
+    memory: MemoryMonitor,
+}
+
+impl LogFile {
+    pub fn write(&self, text: String) {
+        if self.memory.shedding() == Some(Shedding::Drop) {
+            self.memory.dropped();
+            return;
+        }
+
+        self.memory.log_queued(text.len());
+        self.commands.send(Command::Write(self.id, text)).ok();
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        let mut opened = self.opened.lock().unwrap();
+        if let Some((_, handles)) = opened.get_mut(&self.path) {
+            *handles -= 1;
+            if *handles == 0 {
+                opened.remove(&self.path);
+                self.commands.send(Command::Close(self.id)).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue() -> LogQueue {
+        LogQueue::start(MemoryMonitor::new(None), Storage::Disk(None), SyncPolicy::Never)
+    }
+
+    fn read(path: &Path, number: Option<usize>) -> Option<String> {
+        let mut name = path.as_os_str().to_owned();
+        if let Some(number) = number {
+            name.push(format!(".{}", number));
+        }
+        fs::read_to_string(PathBuf::from(name)).ok()
+    }
+
+    #[test]
+    fn full_files_are_rotated_keeping_entries_whole() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ws-proxy.client.log");
+        let queue = queue();
+        let log = queue.open_rotated(&path, Some(Rotation { max_size: 10, max_files: 2 })).unwrap();
+        for entry in ["aaaa\n", "bbbb\n", "cccc\n", "dddd\n", "eeee\n", "ffff\n", "gggg\n", "long entry\n"] {
+            log.write(entry.to_string());
+        }
+        queue.finish();
+
+        assert_eq!(read(&path, None).as_deref(), Some("long entry\n"));
+        assert_eq!(read(&path, Some(1)).as_deref(), Some("gggg\n"));
+        assert_eq!(read(&path, Some(2)).as_deref(), Some("eeee\nffff\n"));
+        assert_eq!(read(&path, Some(3)), None);
+    }
+
+    #[test]
+    fn files_appended_to_count_what_they_had() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ws-proxy.server.log");
+        fs::write(&path, "previous\n").unwrap();
+        let queue = queue();
+        let rotation = Rotation { max_size: 12, max_files: 0 };
+        let first = queue.open_rotated(&path, Some(rotation)).unwrap();
+        // Handles of the same path share the file and its rotation
+        let second = queue.open(&path).unwrap();
+        first.write("next\n".to_string());
+        second.write("last\n".to_string());
+        queue.finish();
+
+        // Without rotated files to keep the full one is removed
+        assert_eq!(read(&path, None).as_deref(), Some("next\nlast\n"));
+        assert_eq!(read(&path, Some(1)), None);
+    }
+
+    #[test]
+    fn log_names_fill_their_templates() {
+        let names = LogNames::new(PathBuf::from("logs"), "s1", Some("{session}-{role}-{id}.log".to_string()), false);
+        assert_eq!(names.server(), Path::new("logs/s1-server-0.log"));
+        assert_eq!(names.client(3), Path::new("logs/s1-client-3.log"));
+        let per_connection = LogNames::new(PathBuf::from("logs"), "s1", None, true);
+        assert_eq!(per_connection.client(3), Path::new("logs/ws-proxy.client-3.log"));
+
+        assert!(LogNames::parse_template("{role}.log").is_ok());
+        assert_eq!(LogNames::parse_template("{host}.log").unwrap_err(),
+            "Log name {host}.log has unknown placeholder {host}, expected {session}, {role} or {id}");
+        assert_eq!(LogNames::parse_template("{role.log").unwrap_err(),
+            "Log name {role.log has an unclosed placeholder");
+        assert_eq!(LogNames::parse_template("logs/{role}.log").unwrap_err(),
+            "Log name logs/{role}.log must be a file name, --log-dir sets the directory");
+
+        assert_eq!(SyncPolicy::parse("0.5"), Ok(SyncPolicy::Every(Duration::from_millis(500))));
+        assert_eq!(SyncPolicy::parse("0").unwrap_err(), "Invalid fsync policy 0, expected never, always or seconds");
+    }
+}
@@ -1,221 +1,303 @@
+mod cli;
+mod doctor;
+mod selftest;
+mod stress;
+
+use clap::Parser;
 use url::Url;
-use chrono::Utc;
-use serde_json::{Value};
-use ws::{CloseCode, Handshake, Message, Result, Sender, Builder};
 
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::sync::Arc;
+use std::path::Path;
 use std::net::SocketAddr;
-use std::cell::RefCell;
-use std::rc::Rc;
-
-use log::{info, warn, error, debug, log_enabled, Level};
-use std::io::Write;
-
-const HELP: &str =
-    "This is a proxy, which dumps all messages passing through specified port.\n\
-    \nSyntax: ws-proxy <server-url> <proxy-port> [--pretty-jsons]\n\
-    \nThe only two parameters are a port number to listen and a websocket url\
-    \nto redirect messages to. If a message comes from the <server-url>, it is directed\
-    \nto the last client connected to the debug proxy. Looping is forbidden.\n\
-    \nYou can provide --pretty-jsons flag to pretty print jsons when they are encountered.\
-    \nThe program will create a separate file for server and client.";
-
-const SERVER_PREFIX: &str = "[server]";
-
-fn main() {
-    let mut prettify_json = false;
-    let args: Vec<String> = env::args().skip(1)
-        .filter(|arg| {
-            if arg.as_str() == "--help" {
-                println!("{}", HELP);
-                std::process::exit(0);
-            }
-            if arg.as_str() == "--pretty-jsons" {
-                prettify_json = true;
-                return false;
-            }
-            return true;
-        })
-        .collect();
-
-    match args.as_slice() {
-        [arg1, arg2] => {
-            let server_url = Url::parse(arg1).unwrap_or_else(|e| {
-                error!("Error: {}", e);
-                println!("Websocket URL {} is invalid", arg1);
-                std::process::exit(-1);
-            });
-            let proxy_port = arg2.parse::<u16>().unwrap_or_else(|e| {
-                error!("Error: {}", e);
-                println!("Port number {} is invalid", arg2);
-                std::process::exit(-1);
-            });
-
-            listen(proxy_port, server_url, prettify_json)
-        },
-        _ => println!("{}", HELP)
-    }
+use std::io;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use ws_proxy::auth::SigV4;
+use ws_proxy::testserver::{self, Script};
+use ws_proxy::tls;
+use ws_proxy::views;
+use ws_proxy::proxy::{Options, ProxyBuilder};
+use ws_proxy::wizard::Wizard;
+use ws_proxy::contract::Contract;
+use ws_proxy::commands::{self, Outcome, ReplayTarget};
+use ws_proxy::retention::Retention;
+use ws_proxy::manifest;
+use ws_proxy::console;
+use ws_proxy::render::Renderer;
+use ws_proxy::snapshot::Snapshot;
+use ws_proxy::config::Config;
+use ws_proxy::upstreamqueue::{Overflow, DEFAULT_QUEUE_SIZE};
+use ws_proxy::verbosity;
+
+use cli::{Analyze, Cli, Command, PluginsAction, ReplayArgs, RunArgs, ViewCommand};
+
+/// Bound of files kept in memory with --no-files.
+const DEFAULT_NO_FILES_LIMIT: usize = 64 * 1024 * 1024;
+
+fn main() -> ExitCode {
+    verbosity::Logger::init();
+
+    let arguments = cli::arguments(env::args_os());
+    let done = match Cli::parse_from(&arguments).command {
+        // The command line is kept in snapshots as it is given
+        Command::Run(_) => run(arguments.iter().skip(2).map(|arg| arg.to_string_lossy().into_owned()).collect()),
+        Command::Restore { snapshot } => restored_command_line(&snapshot).and_then(run),
+        Command::Wizard => wizard(),
+        Command::Selftest(args) => selftest::run(args),
+        Command::Doctor(args) => doctor::run(args),
+        Command::Stress(test) => stress::run(test),
+        Command::Bundle { session, output } => commands::create_bundle(&session, output),
+        Command::ServeBundle { bundle, port, timing, normalize } =>
+            commands::serve_bundle(&bundle, port, timing, normalize),
+        Command::Replay(args) => replay_session(args),
+        Command::Learn { source, output } => commands::learn_script(&source, output),
+        Command::Asyncapi { session, output } => commands::export_asyncapi(&session, output),
+        Command::Validate { session, contract } => commands::validate_session(&session, &contract),
+        Command::Analyze(Analyze::Drift { old, new }) => commands::analyze_drift(&old, &new),
+        Command::Analyze(Analyze::Metrics { session, connection, csv }) =>
+            commands::analyze_metrics(&session, connection, csv),
+        Command::Analyze(Analyze::Latency { session, correlate }) => commands::analyze_latency(&session, correlate),
+        Command::Analyze(Analyze::Overhead { session }) => commands::analyze_overhead(&session),
+        Command::Merge { sessions, output } => commands::merge_hops(&sessions, output),
+        Command::Attach { url, agent_token, rule } => commands::attach_agent(url, &agent_token, rule),
+        Command::Tag { session, messages, add, remove } =>
+            commands::tag_messages(&session, &messages, &add, &remove),
+        Command::Grep { session, condition, tag, without, view } =>
+            commands::grep_messages(&session, condition, &tag, &without, view),
+        Command::Inspect { sessions, by, offset, normalize } =>
+            commands::inspect_captures(&sessions, by, offset, normalize),
+        Command::View(ViewCommand::List) => commands::list_views(),
+        Command::View(ViewCommand::Delete { name }) => commands::delete_view(&name),
+        Command::View(ViewCommand::Save { name, filter, from, tag, highlight, project }) =>
+            commands::save_view(&name, filter, from, tag, highlight, project),
+        Command::Process { session, script, output } => commands::process_capture(&session, &script, output),
+        Command::Init { preset, dir, port, server, force } =>
+            commands::init_scaffold(preset, &dir, port, server, force),
+        Command::Plugins { action, dir } => commands::manage_plugins(&dir, matches!(action, PluginsAction::Verify)),
+        Command::Verify { session, sign_key } => commands::verify_session(&session, &sign_key),
+        Command::Repair { capture, output } => commands::repair_capture(capture, output),
+    };
+    done.unwrap_or_else(|e| {
+        println!("{}", e);
+        ExitCode::from(255)
+    })
 }
 
-fn listen(proxy_port: u16, server_url: Url, prettify_json: bool) {
-    env_logger::init();
-    info!("Listening port {}, redirecting messages to {}", proxy_port, server_url);
-
-    let server: RefCell<Option<Rc<Sender>>> = RefCell::new(None);
-    let client: Rc<RefCell<Option<Sender>>> = Rc::new(RefCell::new(None));
-
-    let server_label = server_url.to_string();
-
-    let mut ws = Builder::new()
-        .build(|out: Sender| {
-            if out.connection_id() == 0 {
-                debug!("Creating handler for the server");
-                *server.borrow_mut() = Some(Rc::new(out));
-
-                let mut file = provide_file("ws-proxy.server.log");
-                file.write_fmt(format_args!("{} Proxy connected to the server at {}\n",
-                    Utc::now(), server_label)).unwrap();
-
-                Handler::Server {
-                    client: client.clone(),
-                    log_file: file,
-                    prettify_json
-                }
-            } else {
-                debug!("Creating handler for a client");
-                let id = out.connection_id();
-
-                let mut client = client.borrow_mut();
-                *client = Some(out);
-
-                let mut file = provide_file("ws-proxy.client.log");
-                file.write_fmt(format_args!("{} Client connected to the proxy with id {}\n",
-                    Utc::now(), id)).unwrap();
-
-                Handler::Client {
-                    server: server.borrow().as_ref().unwrap().clone(),
-                    connection_id: id,
-                    log_file: file,
-                    prettify_json
-                }
-            }
-        })
-        .unwrap();
-
-    ws.connect(server_url).unwrap();
-    ws.listen(SocketAddr::from(([127,0,0,1], proxy_port))).unwrap();
+/// Starts the proxy with the arguments of run.
+fn run(mut command_line: Vec<String>) -> Outcome {
+    let mut args = run_args(&command_line);
+    // Flags of the command line come after those of the config and override them
+    let mut parameters = vec![];
+    if let Some(path) = args.config.take() {
+        let config = Config::load(&path)?;
+        if let Some(index) = command_line.iter().position(|arg| arg == "--config") {
+            command_line.drain(index..index + 2);
+        }
+        command_line.retain(|arg| !arg.starts_with("--config="));
+        command_line.splice(0..0, config.flags);
+        args = run_args(&command_line);
+        if !args.with_test_server {
+            parameters.extend(config.upstream);
+        }
+        parameters.extend(config.port);
+    }
+    // The command line is kept with the profile, which is expanded again when it's restored
+    if let Some(profile) = args.profile {
+        let expanded: Vec<String> = profile.flags().into_iter().chain(command_line.iter().cloned()).collect();
+        args = run_args(&expanded);
+    }
+    if !args.parameters.is_empty() {
+        parameters = args.parameters.clone();
+    }
+
+    let server_url = if args.with_test_server {
+        let script = match &args.test_script {
+            Some(path) => Script::load(path)?,
+            None => Script::echo(),
+        };
+        let address = testserver::spawn(script).map_err(|e| format!("Failed to start the test server: {}", e))?;
+        Url::parse(&format!("ws://{}", address)).unwrap()
+    } else {
+        let url = match parameters.as_slice() {
+            [url, _] => url,
+            _ => return Err("<server-url> and <proxy-port> are required, see ws-proxy run --help".to_string()),
+        };
+        Url::parse(url).map_err(|e| format!("Websocket URL {} is invalid: {}", url, e))?
+    };
+    let port = match parameters.as_slice() {
+        [port] if args.with_test_server => port,
+        [_, port] if !args.with_test_server => port,
+        _ => return Err("Only <proxy-port> is given with --with-test-server, see ws-proxy run --help".to_string()),
+    };
+    let port = port.parse::<u16>().map_err(|e| format!("Port number {} is invalid: {}", port, e))?;
+
+    let mut options = options(args)?;
+    options.command_line = command_line;
+    options.terminal = true;
+    ProxyBuilder::new(server_url)
+        .listen(SocketAddr::from(([127,0,0,1], port)))
+        .options(options)
+        .run()?;
+    Ok(ExitCode::SUCCESS)
 }
 
-enum Handler {
-    Server {
-        client: Rc<RefCell<Option<Sender>>>,
-        log_file: File,
-        prettify_json: bool,
-    },
-    Client {
-        server: Rc<Sender>,
-        connection_id: u32,
-        log_file: File,
-        prettify_json: bool,
+fn run_args(command_line: &[String]) -> RunArgs {
+    let arguments = ["ws-proxy", "run"].iter().map(|arg| arg.to_string()).chain(command_line.iter().cloned());
+    match Cli::parse_from(arguments).command {
+        Command::Run(args) => *args,
+        _ => unreachable!("run is the command"),
     }
 }
 
-impl ws::Handler for Handler {
-    fn on_open(&mut self, h: Handshake) -> Result<()> {
-        debug!("Connection opened: we are {:?}, they are {:?}", h.local_addr, h.peer_addr);
-        if log_enabled!(Level::Warn) && h.peer_addr.is_none() {
-            warn!("Connection with unknown address opened");
-        }
-        Ok(())
+/// Settings of the proxy given with the flags of run, with the files they name loaded.
+fn options(args: RunArgs) -> std::result::Result<Options, String> {
+    let mut options = Options::default();
+
+    if args.pretty_jsons {
+        options.renderers.enable(Renderer::Json);
+    }
+    if args.pretty {
+        options.renderers.enable_all();
+    }
+    if args.pretty_xml {
+        options.renderers.enable(Renderer::Xml);
+    }
+    for xpath in args.xpath.iter() {
+        options.renderers.add_xpath(xpath)?;
     }
+    for rule in args.render.iter() {
+        options.renderers.add_rule(rule)?;
+    }
+
+    options.self_check = args.self_check;
+    options.strict = args.strict_passthrough;
+    options.stats_interval = args.stats;
+    options.max_memory = args.max_memory;
+    options.shedding = args.shed;
+    options.sample_connections = args.sample_connections;
+    options.pausable = args.pausable;
+    options.intercepts = args.intercept;
+    options.probe_response = args.probe_response.unwrap_or_default();
+    options.labels = args.label;
+    options.shutdown = args.shutdown;
+    options.interleave = args.interleave;
+    options.flood = args.flood;
+    options.faults = args.fault;
+    options.fault_seed = args.fault_seed;
+    options.delays = args.delay;
+    options.throttle = args.throttle;
+    options.deflate = args.deflate;
+    options.notify_clients = args.notify_clients;
 
-    fn on_message(&mut self, msg: Message) -> Result<()> {
-        match self {
-            Handler::Server { client, log_file, prettify_json } => {
-                debug!("Redirecting message from server to client");
-
-                let client = client.borrow_mut();
-                assert!(client.is_some());
-
-                client.as_ref().unwrap().send(msg.clone()).unwrap();
-                log_to_file(log_file, SERVER_PREFIX, msg, *prettify_json)
-            },
-            Handler::Client {
-                server, connection_id,
-                log_file, prettify_json
-            } => {
-                debug!("Redirecting message from client to server");
-                let prefix = format!("[connection id: {}]", connection_id);
-
-                server.send(msg.clone()).unwrap();
-                log_to_file(log_file, &prefix, msg, *prettify_json)
-            }
+    options.observer_port = args.observer_port;
+    options.agent_port = args.agent_port;
+    options.agent_token = args.agent_token.as_deref().map(manifest::load_key).transpose()?;
+    options.devtools_port = args.devtools_port;
+    options.control_port = args.control_port;
+    options.prometheus_port = args.prometheus_port;
+    options.otlp_endpoint = args.otlp_endpoint;
+
+    options.recipients = args.encrypt_logs;
+    if !args.retain.is_empty() {
+        let mut retention = Retention::default();
+        for rule in args.retain.iter() {
+            retention.add(rule)?;
         }
-        Ok(())
+        options.retention = Some(retention);
     }
+    options.sign_key = args.sign_key.as_deref().map(manifest::load_key).transpose()?;
+    options.anonymize = args.anonymize;
+    options.anonymize_key = args.anonymize_key.as_deref().map(manifest::load_key).transpose()?;
+    options.log_max_payload = args.log_max_payload;
+    options.log_blobs = args.log_blobs;
+
+    options.track = args.track;
+    options.alerts = args.alert;
+    options.alert_webhook = args.alert_webhook;
+    options.digest_every = args.digest_every;
+    options.digest_webhook = args.digest_webhook;
+    options.metrics_every = args.metrics_every;
+    options.overhead = args.overhead;
+    // Clients closed on the loss of the server have nothing to wait for
+    let reconnect = !args.no_reconnect && args.on_upstream_loss.is_none();
+    options.reconnect = if reconnect { Some(args.reconnect.unwrap_or_default()) } else { None };
+    options.on_upstream_loss = args.on_upstream_loss;
+    options.resubscribe = args.resubscribe;
+    options.upstream_queue = Some(args.upstream_queue.unwrap_or(DEFAULT_QUEUE_SIZE)).filter(|size| *size > 0);
+    // Strict passthrough closes clients rather than dropping their messages
+    let overflow = if args.strict_passthrough { Overflow::Close } else { Overflow::default() };
+    options.upstream_overflow = args.upstream_overflow.unwrap_or(overflow);
+    options.tag_rules = args.tag;
+    options.split_rules = args.split_on;
+    options.capture_frames = args.capture_frames;
+    options.view = args.view.map(|name| views::find(Path::new(views::VIEWS), &name)).transpose()?;
+    options.gap = args.gap;
+    options.state = args.state;
+    options.fsync = args.fsync;
+    options.log_format = args.format.unwrap_or_default();
+    options.max_log_size = args.max_log_size;
+    options.max_log_files = args.max_log_files;
+    options.log_per_connection = args.log_per_connection;
+    options.sse = args.sse;
+    options.tunnel = args.tunnel;
+    options.log_dir = args.log_dir;
+    options.log_name = args.log_name;
+    options.no_files = args.no_files_limit.or(if args.no_files { Some(DEFAULT_NO_FILES_LIMIT) } else { None });
+    options.console = args.console_summary.map(Duration::from_secs)
+        .or(if args.console { Some(console::DEFAULT_SUMMARY) } else { None });
+    options.tui = args.tui;
+    options.contract = args.contract.as_deref().map(Contract::load).transpose()?;
+    options.hop = args.hop;
+    options.plugins = args.plugins;
+    options.script = args.script;
 
-    fn on_close(&mut self, code: CloseCode, reason: &str) {
-        debug!("Connection closed: code={:?}, reason=\"{}\"", code, reason);
+    // Pairs of certificates and keys are required together by the parser
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        options.tls = Some(tls::acceptor(cert, key)?);
     }
+    let identity = args.upstream_cert.as_deref().zip(args.upstream_key.as_deref());
+    if args.upstream_ca.is_some() || identity.is_some() || args.insecure {
+        options.upstream_tls = Some(tls::connector(args.upstream_ca.as_deref(), identity, args.insecure)?);
+    }
+    if let Some(spec) = args.aws_sigv4 {
+        let signer = SigV4::parse(&spec).map_err(|e| format!("AWS signing can't be configured: {}", e))?;
+        options.protocols.extend(signer.subprotocol().map(String::from));
+        options.auth = Some(Arc::new(signer));
+    }
+    options.protocols.extend(args.subprotocol);
+    Ok(options)
 }
 
-fn log_to_file(file: &mut File, prefix: &str, msg: Message, prettify_json: bool) {
-    let text = pretty_print(msg, prettify_json);
-    let result = file.write_fmt(format_args!("{} {} {}",
-        Utc::now(), prefix, text));
-
-    result.unwrap_or_else(|e| {
-        error!("Error: {}", e);
-    })
+/// Command line answered in the wizard, the proxy is started with it if asked to.
+fn wizard() -> Outcome {
+    let stdin = io::stdin();
+    let answers = Wizard::new(stdin.lock(), io::stdout()).run()?;
+    if !answers.start {
+        return Ok(ExitCode::SUCCESS);
+    }
+    run(answers.args)
 }
 
-fn pretty_print(msg: Message, prettify_json: bool) -> String {
-    match msg {
-        Message::Binary(bytes) => {
-            debug!("Binary message received while expecting a JSON");
-            format!("Binary({:?})", bytes)
-        },
-        Message::Text(raw) => {
-            if prettify_json {
-                let value: serde_json::Result<Value> = serde_json::from_str(&raw[..]);
-
-                match value {
-                    Ok(value) => {
-                        let text = serde_json::to_string_pretty(&value)
-                            .map(|mut s| {
-                                s.push('\n');
-                                return s;
-                            });
-
-                        text.unwrap_or_else(|e| {
-                            warn!("Error: {}", e);
-                            raw
-                        })
-                    },
-                    Err(e) => {
-                        warn!("Error: {}", e);
-                        return raw;
-                    }
-                }
-            } else {
-                raw
-            }
-        }
+/// Command line of the run saved in the snapshot, which keeps saving into the same snapshot.
+fn restored_command_line(path: &Path) -> std::result::Result<Vec<String>, String> {
+    let snapshot = Snapshot::load(path).map_err(|e| format!("Failed to restore: {}", e))?;
+
+    let mut command_line = snapshot.args;
+    if !command_line.iter().any(|arg| arg == "--state") {
+        command_line.extend(["--state".to_string(), path.display().to_string()]);
     }
+    println!("Restoring: ws-proxy {}", command_line.join(" "));
+    Ok(command_line)
 }
 
-//todo: manage resource release
-fn provide_file(name: &str) -> File {
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(name.clone());
-
-    file.unwrap_or_else(|e| {
-        error!("Error: {}", e);
-        println!("Failed to create file {}", name);
-        std::process::exit(-1);
-    })
+fn replay_session(args: ReplayArgs) -> Outcome {
+    let target = match (args.server_url, args.serve) {
+        (Some(server_url), _) => ReplayTarget::Server(server_url),
+        (None, Some(port)) => ReplayTarget::Serve(port),
+        (None, None) => unreachable!("clap requires the server url without --serve"),
+    };
+    let timeout = Duration::from_secs(args.timeout);
+    commands::replay_session(&args.source, target, &args.ignore, args.normalize, timeout, args.timing, args.output)
 }
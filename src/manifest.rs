@@ -0,0 +1,155 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Signed list of the session files with their sizes and digests,
+/// written when the session is over.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Reads the signing key from a file, surrounding whitespace is ignored.
+pub fn load_key(path: &Path) -> std::result::Result<Vec<u8>, String> {
+    let key = fs::read(path).map_err(|e| format!("Can't read key {}: {}", path.display(), e))?;
+    let key = String::from_utf8_lossy(&key).trim().as_bytes().to_vec();
+    if key.is_empty() {
+        return Err(format!("Key {} is empty", path.display()));
+    }
+    Ok(key)
+}
+
+/// Writes the manifest of all files in the session directory, signed with HMAC-SHA256.
+pub fn write(dir: &Path, key: &[u8]) -> io::Result<()> {
+    let session = dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let body = json!({
+        "session": session,
+        "created": Utc::now().to_rfc3339(),
+        "files": files(dir)?,
+    });
+
+    let mut manifest = body.clone();
+    manifest["hmac"] = Value::String(sign(&body, key));
+    fs::write(dir.join(MANIFEST), serde_json::to_string_pretty(&manifest)?)
+}
+
+/// Verifies the session directory against its manifest, returns the problems found.
+pub fn verify(dir: &Path, key: &[u8]) -> std::result::Result<Vec<String>, String> {
+    let text = fs::read_to_string(dir.join(MANIFEST))
+        .map_err(|e| format!("Can't read {} in {}: {}", MANIFEST, dir.display(), e))?;
+    let mut manifest: Value = serde_json::from_str(&text)
+        .map_err(|e| format!("{} is damaged: {}", MANIFEST, e))?;
+
+    let mut problems = vec![];
+    let hmac = manifest.as_object_mut()
+        .and_then(|manifest| manifest.remove("hmac"))
+        .and_then(|hmac| hmac.as_str().map(String::from))
+        .unwrap_or_default();
+    let verified = hex::decode(&hmac).ok()
+        .map(|tag| mac(&manifest, key).verify_slice(&tag).is_ok())
+        .unwrap_or(false);
+    if !verified {
+        problems.push("signature of the manifest doesn't match the key".to_string());
+    }
+
+    let listed: BTreeMap<String, Value> = manifest["files"].as_object().cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let actual = files(dir).map_err(|e| format!("Can't read {}: {}", dir.display(), e))?;
+    let actual = actual.as_object().cloned().unwrap_or_default();
+    for (name, entry) in listed.iter() {
+        match actual.get(name) {
+            None => problems.push(format!("{} is missing", name)),
+            Some(found) if found != entry => problems.push(format!("{} is modified", name)),
+            _ => {}
+        }
+    }
+    for name in actual.keys() {
+        if !listed.contains_key(name) {
+            problems.push(format!("{} is not listed", name));
+        }
+    }
+    Ok(problems)
+}
+
+fn files(dir: &Path) -> io::Result<Value> {
+    let mut files = serde_json::Map::new();
+    list(dir, "", &mut files)?;
+    Ok(Value::Object(files))
+}
+
+/// Lists files of the directory and its subdirectories, like blobs, named relative to the session.
+fn list(dir: &Path, prefix: &str, files: &mut serde_json::Map<String, Value>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.metadata()?.is_dir() {
+            list(&entry.path(), &format!("{}/", name), files)?;
+            continue;
+        }
+        if name == MANIFEST {
+            continue;
+        }
+
+        let contents = fs::read(entry.path())?;
+        files.insert(name, json!({
+            "size": contents.len(),
+            "sha256": hex::encode(Sha256::digest(&contents)),
+        }));
+    }
+    Ok(())
+}
+
+fn sign(body: &Value, key: &[u8]) -> String {
+    hex::encode(mac(body, key).finalize().into_bytes())
+}
+
+fn mac(body: &Value, key: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(body.to_string().as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_of_a_signed_session_are_found() {
+        let session = tempfile::tempdir().unwrap();
+        let dir = session.path();
+        fs::write(dir.join("capture.jsonl"), "{\"event\":\"message\"}\n").unwrap();
+        fs::write(dir.join("index.jsonl"), "{\"event\":\"open\"}\n").unwrap();
+        fs::create_dir(dir.join("blobs")).unwrap();
+        fs::write(dir.join("blobs").join("1.bin"), [1, 2, 3]).unwrap();
+        write(dir, b"key").unwrap();
+        assert_eq!(verify(dir, b"key").unwrap(), Vec::<String>::new());
+        assert_eq!(verify(dir, b"other").unwrap(), ["signature of the manifest doesn't match the key"]);
+
+        fs::write(dir.join("capture.jsonl"), "{\"event\":\"forged\"}\n").unwrap();
+        fs::remove_file(dir.join("blobs").join("1.bin")).unwrap();
+        fs::write(dir.join("extra.txt"), "").unwrap();
+        assert_eq!(verify(dir, b"key").unwrap(),
+            ["blobs/1.bin is missing", "capture.jsonl is modified", "extra.txt is not listed"]);
+
+        // Editing the manifest to match breaks its signature
+        let mut manifest: Value = serde_json::from_str(&fs::read_to_string(dir.join(MANIFEST)).unwrap()).unwrap();
+        manifest["files"] = files(dir).unwrap();
+        fs::write(dir.join(MANIFEST), manifest.to_string()).unwrap();
+        assert_eq!(verify(dir, b"key").unwrap(), ["signature of the manifest doesn't match the key"]);
+    }
+
+    #[test]
+    fn keys_are_trimmed_and_not_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("key");
+        fs::write(&key, " secret\n").unwrap();
+        assert_eq!(load_key(&key).unwrap(), b"secret");
+        fs::write(&key, "\n").unwrap();
+        assert_eq!(load_key(&key).unwrap_err(), format!("Key {} is empty", key.display()));
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// What to do when the memory limit is exceeded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shedding {
+    /// Buffered and incoming messages are discarded, log entries are skipped.
+    Drop,
+    /// Client connections are closed, so their peers can retry later.
+    Close,
+}
+
+impl Shedding {
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "drop" => Ok(Shedding::Drop),
+            "close" => Ok(Shedding::Close),
+            _ => Err(format!("Unknown shedding policy {}, expected drop or close", value)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryLimit {
+    pub max_bytes: usize,
+    pub shedding: Shedding,
+}
+
+/// Accounting of memory held by the proxy on behalf of connections:
+/// messages which were received but not yet handed over to the destination connection,
+/// and log entries which were not yet written to disk.
+#[derive(Clone, Default)]
+pub struct MemoryMonitor {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    buffers: Mutex<HashMap<u32, usize>>,
+    log_queue: AtomicUsize,
+    dropped: AtomicU64,
+    limit: Option<MemoryLimit>,
+}
+
+impl MemoryMonitor {
+    pub fn new(limit: Option<MemoryLimit>) -> Self {
+        MemoryMonitor {
+            inner: Arc::new(Inner { limit, ..Inner::default() })
+        }
+    }
+
+    pub fn buffered(&self, connection: u32, bytes: usize) {
+        *self.inner.buffers.lock().unwrap().entry(connection).or_insert(0) += bytes;
+    }
+
+    pub fn sent(&self, connection: u32, bytes: usize) {
+        if let Some(buffered) = self.inner.buffers.lock().unwrap().get_mut(&connection) {
+            *buffered = buffered.saturating_sub(bytes);
+        }
+    }
+
+    pub fn released(&self, connection: u32) {
+        self.inner.buffers.lock().unwrap().remove(&connection);
+    }
+
+    pub fn log_queued(&self, bytes: usize) {
+        self.inner.log_queue.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn log_written(&self, bytes: usize) {
+        self.inner.log_queue.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> usize {
+        let buffered: usize = self.inner.buffers.lock().unwrap().values().sum();
+        buffered + self.inner.log_queue.load(Ordering::Relaxed)
+    }
+
+    /// Returns the shedding policy if the limit is currently exceeded.
+    pub fn shedding(&self) -> Option<Shedding> {
+        self.inner.limit
+            .filter(|limit| self.total() > limit.max_bytes)
+            .map(|limit| limit.shedding)
+    }
+
+    pub fn report(&self) -> String {
+        let buffers = self.inner.buffers.lock().unwrap();
+        let buffered: usize = buffers.values().sum();
+        let largest = buffers.iter()
+            .max_by_key(|(_, bytes)| **bytes)
+            .map(|(connection, bytes)| format!(" (largest: connection {}, {})",
+                connection, format_size(*bytes)))
+            .unwrap_or_default();
+        let limit = self.inner.limit
+            .map(|limit| format!(", limit {}", format_size(limit.max_bytes)))
+            .unwrap_or_default();
+
+        format!("Memory: buffered {} in {} connections{}, log queue {}, dropped {} messages{}",
+            format_size(buffered), buffers.len(), largest,
+            format_size(self.inner.log_queue.load(Ordering::Relaxed)),
+            self.inner.dropped.load(Ordering::Relaxed), limit)
+    }
+}
+
+/// Parses sizes like `512`, `64KB`, `10MB` or `2GB` (powers of 1024).
+pub fn parse_size(value: &str) -> std::result::Result<usize, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number = number.parse::<usize>()
+        .map_err(|e| format!("Invalid size {}: {}", value, e))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        other => return Err(format!("Unknown size unit {}", other)),
+    };
+
+    Ok(number * multiplier)
+}
+
+pub fn format_size(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.1} GB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.1} MB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{} bytes", b),
+    }
+}
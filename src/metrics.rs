@@ -0,0 +1,240 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::closecodes::Leg;
+use crate::logqueue::LogFile;
+use crate::memory::{format_size, MemoryMonitor};
+
+/// Snapshots of the metrics, appended to the session directory.
+pub const METRICS: &str = "metrics.jsonl";
+
+/// Width of the bars of a chart.
+const BAR: usize = 40;
+
+/// Appends a snapshot of the traffic every interval, so that a run of days can be
+/// followed without a monitoring stack: open client connections, messages and bytes
+/// of each side and of each connection since the previous snapshot, and memory held.
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    interval: Duration,
+    file: LogFile,
+    memory: MemoryMonitor,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+struct State {
+    since: DateTime<Utc>,
+    /// Client connections open now.
+    open: BTreeSet<u32>,
+    period: Period,
+}
+
+#[derive(Default)]
+struct Period {
+    opened: u64,
+    closed: u64,
+    traffic: Traffic,
+    connections: BTreeMap<u32, Traffic>,
+}
+
+/// Messages and bytes of each side.
+#[derive(Default)]
+struct Traffic {
+    messages: [u64; 2],
+    bytes: [u64; 2],
+}
+
+impl Metrics {
+    /// Starts writing snapshots every interval in a background thread.
+    pub fn start(interval: Duration, file: LogFile, memory: MemoryMonitor, clock: Arc<dyn Clock>) -> Self {
+        let state = State { since: clock.utc(), open: BTreeSet::new(), period: Period::default() };
+        let inner = Arc::new(Inner { interval, file, memory, clock: clock.clone(), state: Mutex::new(state) });
+        let watched = Arc::downgrade(&inner);
+        thread::spawn(move || watch(watched, clock));
+        Metrics { inner }
+    }
+
+    pub fn opened(&self, connection_id: u32) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.open.insert(connection_id);
+        state.period.opened += 1;
+    }
+
+    pub fn closed(&self, connection_id: u32) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.open.remove(&connection_id);
+        state.period.closed += 1;
+    }
+
+    pub fn message(&self, connection_id: u32, from: Leg, size: usize) {
+        let mut state = self.inner.state.lock().unwrap();
+        let period = &mut state.period;
+        period.traffic.add(from, size);
+        period.connections.entry(connection_id).or_default().add(from, size);
+    }
+}
+
+impl Drop for Metrics {
+    /// The traffic since the last snapshot gets one as well.
+    fn drop(&mut self) {
+        self.inner.snapshot();
+    }
+}
+
+impl Inner {
+    fn snapshot(&self) {
+        let record = {
+            let mut state = self.state.lock().unwrap();
+            let until = self.clock.utc();
+            let since = std::mem::replace(&mut state.since, until);
+            let period = std::mem::take(&mut state.period);
+            period.record(since, until, state.open.len(), self.memory.total())
+        };
+        self.file.write(format!("{}\n", record));
+    }
+}
+
+fn watch(inner: Weak<Inner>, clock: Arc<dyn Clock>) {
+    let interval = match inner.upgrade() {
+        Some(inner) => inner.interval,
+        None => return,
+    };
+    loop {
+        clock.sleep(interval);
+        match inner.upgrade() {
+            Some(inner) => inner.snapshot(),
+            None => return,
+        }
+    }
+}
+
+impl Traffic {
+    fn add(&mut self, from: Leg, size: usize) {
+        self.messages[from as usize] += 1;
+        self.bytes[from as usize] += size as u64;
+    }
+
+    fn value(&self) -> Value {
+        json!({
+            "messages": {
+                "client": self.messages[Leg::Client as usize],
+                "server": self.messages[Leg::Server as usize],
+            },
+            "bytes": {
+                "client": self.bytes[Leg::Client as usize],
+                "server": self.bytes[Leg::Server as usize],
+            },
+        })
+    }
+}
+
+impl Period {
+    fn record(&self, since: DateTime<Utc>, until: DateTime<Utc>, open: usize, memory: usize) -> Value {
+        let connections: Vec<Value> = self.connections.iter()
+            .map(|(connection_id, traffic)| {
+                let mut value = traffic.value();
+                value["connection_id"] = json!(connection_id);
+                value
+            })
+            .collect();
+        let mut record = self.traffic.value();
+        record["event"] = json!("metrics");
+        record["since"] = json!(since.to_rfc3339());
+        record["until"] = json!(until.to_rfc3339());
+        record["connections"] = json!({ "open": open, "opened": self.opened, "closed": self.closed });
+        record["memory_bytes"] = json!(memory);
+        record["per_connection"] = json!(connections);
+        record
+    }
+}
+
+/// Snapshots of a session directory, in the order they were taken.
+pub fn load(dir: &Path) -> std::result::Result<Vec<Value>, String> {
+    let path = dir.join(METRICS);
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}, was the proxy run with --metrics-every? {}", path.display(), e))?;
+    Ok(text.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|record| record["event"] == "metrics")
+        .collect())
+}
+
+/// One row of a series: when the snapshot was taken, its traffic, and the open
+/// connections unless the series is of one connection.
+struct Row<'a> {
+    until: &'a str,
+    traffic: &'a Value,
+    open: Option<u64>,
+}
+
+fn rows(snapshots: &[Value], connection_id: Option<u32>) -> Vec<Row<'_>> {
+    snapshots.iter()
+        .map(|snapshot| {
+            let until = snapshot["until"].as_str().unwrap_or_default();
+            match connection_id {
+                Some(connection_id) => {
+                    let traffic = snapshot["per_connection"].as_array().into_iter().flatten()
+                        .find(|traffic| traffic["connection_id"] == connection_id)
+                        .unwrap_or(&Value::Null);
+                    Row { until, traffic, open: None }
+                },
+                None => Row { until, traffic: snapshot, open: snapshot["connections"]["open"].as_u64() },
+            }
+        })
+        .collect()
+}
+
+fn count(traffic: &Value, kind: &str, leg: &str) -> u64 {
+    traffic[kind][leg].as_u64().unwrap_or_default()
+}
+
+/// Series of the whole traffic or of one connection as CSV.
+pub fn csv(snapshots: &[Value], connection_id: Option<u32>) -> String {
+    let mut csv = String::from("time,client_messages,server_messages,client_bytes,server_bytes");
+    if connection_id.is_none() {
+        csv.push_str(",open_connections,memory_bytes");
+    }
+    csv.push('\n');
+    for (row, snapshot) in rows(snapshots, connection_id).iter().zip(snapshots) {
+        csv.push_str(&format!("{},{},{},{},{}", row.until,
+            count(row.traffic, "messages", "client"), count(row.traffic, "messages", "server"),
+            count(row.traffic, "bytes", "client"), count(row.traffic, "bytes", "server")));
+        if let Some(open) = row.open {
+            csv.push_str(&format!(",{},{}", open, snapshot["memory_bytes"].as_u64().unwrap_or_default()));
+        }
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Series of the whole traffic or of one connection as text, with a bar of the messages
+/// of each snapshot scaled to the busiest one.
+pub fn chart(snapshots: &[Value], connection_id: Option<u32>) -> String {
+    let rows = rows(snapshots, connection_id);
+    let messages = |row: &Row| count(row.traffic, "messages", "client") + count(row.traffic, "messages", "server");
+    let busiest = rows.iter().map(messages).max().unwrap_or_default().max(1);
+    rows.iter()
+        .map(|row| {
+            let time = DateTime::parse_from_rfc3339(row.until)
+                .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|_| row.until.to_string());
+            let bytes = count(row.traffic, "bytes", "client") + count(row.traffic, "bytes", "server");
+            let open = row.open.map(|open| format!("  open {:>4}", open)).unwrap_or_default();
+            let bar = "#".repeat((messages(row) as usize * BAR).div_ceil(busiest as usize));
+            format!("{}  client {:>7}  server {:>7}  {:>9}{}  {}\n", time,
+                count(row.traffic, "messages", "client"), count(row.traffic, "messages", "server"),
+                format_size(bytes as usize), open, bar)
+        })
+        .collect()
+}
@@ -0,0 +1,186 @@
+use serde_json::{json, Value};
+use serde_json_path::JsonPath;
+
+use crate::schema;
+use crate::track;
+
+/// Largest integer a float holds exactly.
+const MAX_EXACT: f64 = 9_007_199_254_740_992.0;
+
+/// Rule canonicalizing messages before they are compared, given with --normalize.
+#[derive(Clone)]
+pub struct NormalizeRule {
+    /// Type of the messages, like the `type` field of JSON, all messages without one.
+    kind: Option<String>,
+    steps: Vec<Step>,
+}
+
+#[derive(Clone)]
+enum Step {
+    /// Numbers are written the same way, rounded to the decimal places if given.
+    Numbers(Option<i32>),
+    /// Strings are trimmed and their runs of whitespace are collapsed into a space.
+    Whitespace,
+    /// Fields at the JSONPath are removed.
+    Strip(JsonPath),
+}
+
+impl NormalizeRule {
+    /// Parses `[<type>:]<step>[,<step>]...` with steps `numbers[=<decimals>]`, `whitespace`
+    /// and `strip=<jsonpath>`, like `numbers,whitespace` or `quote:strip=$.ts,numbers=2`.
+    pub fn parse(rule: &str) -> std::result::Result<Self, String> {
+        let (kind, steps) = match rule.split_once(':') {
+            Some((kind, steps)) if !kind.contains(['=', ',', '$']) => (Some(kind.to_string()), steps),
+            _ => (None, rule),
+        };
+        let steps = split(steps).into_iter()
+            .map(|step| match step.split_once('=') {
+                None if step == "numbers" => Ok(Step::Numbers(None)),
+                None if step == "whitespace" => Ok(Step::Whitespace),
+                Some(("numbers", decimals)) => decimals.parse::<i32>().ok()
+                    .filter(|decimals| (0..=15).contains(decimals))
+                    .map(|decimals| Step::Numbers(Some(decimals)))
+                    .ok_or_else(|| format!("Invalid decimal places {} in {}", decimals, rule)),
+                Some(("strip", path)) => track::parse(path).map(Step::Strip),
+                _ => Err(format!("Unknown normalization {} in {}, expected numbers, whitespace or strip=<jsonpath>",
+                    step, rule)),
+            })
+            .collect::<std::result::Result<Vec<Step>, String>>()?;
+        Ok(NormalizeRule { kind, steps })
+    }
+}
+
+/// Steps separated by commas outside of the brackets of JSONPath expressions.
+fn split(steps: &str) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (index, c) in steps.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&steps[start..index]);
+                start = index + 1;
+            },
+            _ => (),
+        }
+    }
+    parts.push(&steps[start..]);
+    parts
+}
+
+/// Canonical form of messages, so that semantically identical ones compare equal:
+/// JSON is compared with its keys sorted and its strings unescaped, and the steps of
+/// the rules for all messages and for the type of the message are applied in order.
+#[derive(Clone, Default)]
+pub struct Normalization {
+    rules: Vec<NormalizeRule>,
+}
+
+impl Normalization {
+    pub fn new(rules: Vec<NormalizeRule>) -> Self {
+        Normalization { rules }
+    }
+
+    /// Canonical text of a message. Texts which aren't JSON only get whitespace
+    /// collapsed, by the rules for all messages.
+    pub fn text(&self, text: &str) -> String {
+        match serde_json::from_str::<Value>(text) {
+            Ok(mut value) => {
+                self.value(&mut value);
+                value.to_string()
+            },
+            Err(_) => {
+                let whitespace = self.rules.iter()
+                    .filter(|rule| rule.kind.is_none())
+                    .any(|rule| rule.steps.iter().any(|step| matches!(step, Step::Whitespace)));
+                if whitespace { collapse(text) } else { text.to_string() }
+            },
+        }
+    }
+
+    pub fn value(&self, value: &mut Value) {
+        let kind = schema::kind(value);
+        let rules = self.rules.iter().filter(|rule| rule.kind.is_none() || rule.kind == kind);
+        for step in rules.flat_map(|rule| rule.steps.iter()) {
+            match step {
+                Step::Numbers(decimals) => numbers(value, *decimals),
+                Step::Whitespace => whitespace(value),
+                Step::Strip(path) => strip(value, path),
+            }
+        }
+    }
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Visits the scalars nested in the value, or the value itself when it's one.
+fn walk(value: &mut Value, visit: &mut dyn FnMut(&mut Value)) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(|item| walk(item, visit)),
+        Value::Object(fields) => fields.values_mut().for_each(|field| walk(field, visit)),
+        _ => visit(value),
+    }
+}
+
+/// Integers stay exact, other numbers are written as the shortest float, which is an
+/// integer when it has no fraction, so that 1.0, 1e0 and 1 are all 1.
+fn numbers(value: &mut Value, decimals: Option<i32>) {
+    walk(value, &mut |value| {
+        let number = match value {
+            Value::Number(number) if number.is_f64() => number,
+            _ => return,
+        };
+        let mut float = match number.as_f64() {
+            Some(float) => float,
+            None => return,
+        };
+        if let Some(decimals) = decimals {
+            let scale = 10f64.powi(decimals);
+            float = (float * scale).round() / scale;
+        }
+        *value = if float.fract() == 0.0 && float.abs() < MAX_EXACT { json!(float as i64) } else { json!(float) };
+    });
+}
+
+fn whitespace(value: &mut Value) {
+    walk(value, &mut |value| {
+        if let Value::String(text) = value {
+            *text = collapse(text);
+        }
+    });
+}
+
+/// Removes the fields and items at the path, later items of an array first so that
+/// the locations of the others stay.
+fn strip(value: &mut Value, path: &JsonPath) {
+    let mut pointers: Vec<String> = path.query_located(value).locations()
+        .map(|location| location.to_json_pointer())
+        .collect();
+    pointers.sort_by_cached_key(|pointer| std::cmp::Reverse(tokens(pointer)));
+    for pointer in pointers {
+        let (parent, last) = match pointer.rsplit_once('/') {
+            Some(split) => split,
+            None => continue,
+        };
+        let key = last.replace("~1", "/").replace("~0", "~");
+        match value.pointer_mut(parent) {
+            Some(Value::Object(fields)) => {
+                fields.remove(&key);
+            },
+            Some(Value::Array(items)) => {
+                if let Some(index) = key.parse::<usize>().ok().filter(|index| *index < items.len()) {
+                    items.remove(index);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Tokens of a JSON pointer, indices of arrays ordered as numbers.
+fn tokens(pointer: &str) -> Vec<(Option<usize>, String)> {
+    pointer.split('/').map(|token| (token.parse().ok(), token.to_string())).collect()
+}
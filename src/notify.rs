@@ -0,0 +1,20 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+
+/// Field wrapping notifications, so that clients can't mistake them for messages of the server.
+pub const MARKER: &str = "ws-proxy";
+
+/// Synthetic message telling clients about an event of the proxy, like
+/// `{"ws-proxy":{"event":"dropped","rule":"palette drop:ping","connection_id":1,"time":"..."}}`.
+/// Fields of the details are added to the event.
+pub fn notification(event: &str, connection_id: u32, details: Value) -> String {
+    let mut notification = json!({
+        "event": event,
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+    });
+    if let (Some(notification), Value::Object(details)) = (notification.as_object_mut(), details) {
+        notification.extend(details);
+    }
+    json!({ MARKER: notification }).to_string()
+}
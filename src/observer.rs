@@ -0,0 +1,73 @@
+use serde_json::Value;
+use ws::{Builder, CloseCode, Handshake, Message, Result, Sender};
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+
+use log::{debug, error, info, warn};
+
+/// Server for read-only observers of the proxied traffic.
+/// Every connected observer receives each record as a text message with a single JSON line,
+/// anything sent by observers is discarded.
+#[derive(Clone)]
+pub struct Observers {
+    broadcaster: Sender,
+}
+
+impl Observers {
+    pub fn start(port: u16) -> std::result::Result<Self, String> {
+        let (broadcaster_tx, broadcaster_rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let ws = match Builder::new().build(|out: Sender| Observer { out }) {
+                Ok(ws) => ws.bind(SocketAddr::from(([0,0,0,0], port))),
+                Err(e) => Err(e)
+            };
+
+            let ws = match ws {
+                Ok(ws) => ws,
+                Err(e) => {
+                    broadcaster_tx.send(Err(e.to_string())).unwrap();
+                    return;
+                }
+            };
+
+            broadcaster_tx.send(Ok(ws.broadcaster())).unwrap();
+            if let Err(e) = ws.run() {
+                error!("Error: {}", e);
+            }
+        });
+
+        let broadcaster = broadcaster_rx.recv()
+            .map_err(|e| e.to_string())??;
+        info!("Observers can connect to port {}", port);
+        Ok(Observers { broadcaster })
+    }
+
+    pub fn publish(&self, record: &Value) {
+        self.broadcaster.broadcast(Message::text(record.to_string())).unwrap_or_else(|e| {
+            error!("Error: {}", e);
+        })
+    }
+}
+
+struct Observer {
+    out: Sender,
+}
+
+impl ws::Handler for Observer {
+    fn on_open(&mut self, h: Handshake) -> Result<()> {
+        info!("Observer {} connected from {:?}", self.out.connection_id(), h.peer_addr);
+        Ok(())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        warn!("Observer {} is read-only, its message is discarded", self.out.connection_id());
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        debug!("Observer {} disconnected: {:?}", self.out.connection_id(), code);
+    }
+}
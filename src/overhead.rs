@@ -0,0 +1,168 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+
+use std::cell::Cell;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::closecodes::Leg;
+use crate::heatmap::{format_latency, percentile};
+use crate::logqueue::LogFile;
+use crate::sampling::Rate;
+use crate::session::MessageId;
+
+/// Time spent on the measured messages, appended to the session directory.
+pub const OVERHEAD: &str = "overhead.jsonl";
+
+/// Stage of the pipeline a message goes through in the proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Inflating compressed frames and decoding with plugins for the log.
+    Decode,
+    /// Conditions of agents, the palette, segments and faults.
+    Rules,
+    /// WASM plugins processing the message.
+    Script,
+    /// Records of the capture, the log and the consumers of the traffic.
+    Log,
+    /// Throttling, delaying and queueing the message on the connections it goes to.
+    Send,
+}
+
+const STAGES: [Stage; 5] = [Stage::Decode, Stage::Rules, Stage::Script, Stage::Log, Stage::Send];
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Rules => "rules",
+            Stage::Script => "script",
+            Stage::Log => "log",
+            Stage::Send => "send",
+        }
+    }
+}
+
+/// Stopwatch of one message, charging the time to the current stage until the next one starts.
+#[derive(Clone, Copy)]
+pub struct Timing {
+    stages: [Duration; STAGES.len()],
+    current: Stage,
+    since: Instant,
+}
+
+impl Timing {
+    /// Starts with the rules, given the time spent decoding the frames of the message.
+    pub fn start(decoded: Duration) -> Self {
+        let mut stages = [Duration::ZERO; STAGES.len()];
+        stages[Stage::Decode as usize] = decoded;
+        Timing { stages, current: Stage::Rules, since: Instant::now() }
+    }
+
+    pub fn switch(&mut self, stage: Stage) {
+        let now = Instant::now();
+        self.stages[self.current as usize] += now - self.since;
+        self.current = stage;
+        self.since = now;
+    }
+}
+
+/// Measures the time the proxy spends on messages in each stage, given with --overhead,
+/// for all messages or the first `captured` of every `of` ones in the order they come.
+/// Writing the sockets is left to the event loop after the message is sent, and isn't measured.
+pub struct Overhead {
+    rate: Rate,
+    messages: Cell<u32>,
+    file: LogFile,
+}
+
+impl Overhead {
+    /// Parses `all` or a share like `1/100`.
+    pub fn parse_rate(value: &str) -> std::result::Result<Rate, String> {
+        match value {
+            "all" => Rate::parse("1/1"),
+            share => Rate::parse(share),
+        }
+    }
+
+    pub fn new(rate: Rate, file: LogFile) -> Self {
+        Overhead { rate, messages: Cell::new(0), file }
+    }
+
+    /// Counts a message, returns whether it's measured.
+    pub fn sampled(&self) -> bool {
+        let message = self.messages.get().wrapping_add(1);
+        self.messages.set(message);
+        self.rate.includes(message)
+    }
+
+    /// Appends the time spent on a message, which went through all of its stages.
+    pub fn measured(&self, id: MessageId, from: Leg, mut timing: Timing) {
+        // The current stage ends with the message
+        timing.switch(timing.current);
+        let nanos = |duration: &Duration| duration.as_nanos() as u64;
+        let stages: serde_json::Map<String, Value> = STAGES.iter()
+            .map(|stage| (stage.name().to_string(), json!(nanos(&timing.stages[*stage as usize]))))
+            .collect();
+        let record = json!({
+            "event": "overhead",
+            "id": id.to_string(),
+            "from": from.to_string(),
+            "time": Utc::now().to_rfc3339(),
+            "stages_ns": stages,
+            "total_ns": timing.stages.iter().map(nanos).sum::<u64>(),
+        });
+        self.file.write(format!("{}\n", record));
+    }
+}
+
+/// Measurements of a session directory.
+pub fn load(dir: &Path) -> std::result::Result<Vec<Value>, String> {
+    let path = dir.join(OVERHEAD);
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}, was the proxy run with --overhead? {}", path.display(), e))?;
+    Ok(text.lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|record| record["event"] == "overhead")
+        .collect())
+}
+
+/// Table of the stages with percentiles of the time a message spent in each one,
+/// and the share of all the time spent, then the same for whole messages.
+pub fn table(records: &[Value]) -> String {
+    let durations = |field: &dyn Fn(&Value) -> Option<u64>| -> Vec<Duration> {
+        let mut durations: Vec<Duration> = records.iter().filter_map(field).map(Duration::from_nanos).collect();
+        durations.sort();
+        durations
+    };
+    let totals = durations(&|record| record["total_ns"].as_u64());
+    let spent: Duration = totals.iter().sum();
+    let mut table = format!("{} messages measured\n{:<7} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6}\n",
+        records.len(), "stage", "mean", "p50", "p90", "p99", "max", "share");
+    let mut row = |name: &str, sorted: &[Duration]| {
+        let sum: Duration = sorted.iter().sum();
+        let statistic = |duration: Option<Duration>| duration.map(format_time).unwrap_or_else(|| "-".to_string());
+        let mean = (!sorted.is_empty()).then(|| sum / sorted.len() as u32);
+        let share = match spent.is_zero() {
+            true => "-".to_string(),
+            false => format!("{:.0}%", sum.as_secs_f64() * 100.0 / spent.as_secs_f64()),
+        };
+        table.push_str(&format!("{:<7} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6}\n", name, statistic(mean),
+            statistic(percentile(sorted, 50)), statistic(percentile(sorted, 90)),
+            statistic(percentile(sorted, 99)), statistic(sorted.last().copied()), share));
+    };
+    for stage in STAGES.iter() {
+        row(stage.name(), &durations(&|record| record["stages_ns"][stage.name()].as_u64()));
+    }
+    row("total", &totals);
+    table
+}
+
+/// Time of a stage, which is mostly less than a millisecond.
+fn format_time(duration: Duration) -> String {
+    let micros = duration.as_secs_f64() * 1_000_000.0;
+    match micros < 1000.0 {
+        true => format!("{:.1}µs", micros),
+        false => format_latency(duration),
+    }
+}
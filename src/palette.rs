@@ -0,0 +1,563 @@
+use ws::util::Token;
+use ws::{Message, Sender};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::LevelFilter;
+
+use crate::closecodes::Leg;
+use crate::composer::Composer;
+use crate::condition::{Condition, Facts};
+use crate::gaps;
+use crate::session::MessageId;
+use crate::throttle::{Throttle, ThrottlePlan};
+use crate::topology::Edge;
+use crate::verbosity;
+use crate::views::LiveView;
+
+pub const HELP: &str = "\
+:send client|server <text>      send a message to all clients or to the server
+:templates                      list templates of messages, those of the detected protocol first
+:compose <template> [<variable>=<value> ...]
+                                send a message made from a template, values are remembered
+:history                        list the messages sent from the terminal
+:again <number>                 send a message of the history again
+:drop <condition>               stop forwarding matching messages
+:break <condition>              pause all traffic at a matching message
+:intercept <condition>          hold matching messages only, letting the others through
+:continue                       forward all held messages and go on
+:step                           forward the first held message
+:held                           list the held messages
+:show <id>                      print a held message in full
+:edit <id> <text>               replace a held message with the text
+:discard <id>                   drop a held message
+:forward <id>                   forward a held message
+:rules                          list drop rules and breakpoints with their numbers
+:toggle <number>                turn a rule on or off
+:clear                          remove all rules and breakpoints
+:view [<name>]                  print messages through a saved view, or all of them
+:filter <condition>             print matching messages
+:quiet                          stop printing messages
+:record on|off                  resume or pause writing messages into logs and the capture
+:pause <connection>             stop reading from the socket of a client, with --pausable
+:resume <connection>            read from the socket of the client again
+:on <connection> drop <condition>
+                                stop forwarding matching messages from and to one client
+:on <connection> delay <ms>     forward messages from and to the client that much later
+:on <connection> throttle [client->server|server->client=]<rate>
+                                limit the bandwidth of the link of the client
+:on <connection> log off|error|warn|info|debug|trace
+                                log what the proxy does with the client at the level
+:on <connection> record on|off  resume or pause writing messages of the client
+:on <connection> reset          remove the settings of the client";
+
+/// Command typed while the proxy runs, one per line starting with `:`.
+pub enum Command {
+    Send(Leg, String),
+    Rule(Rule),
+    Continue,
+    Step,
+    Held,
+    Show(MessageId),
+    Edit(MessageId, String),
+    Discard(MessageId),
+    Forward(MessageId),
+    Rules,
+    Toggle(usize),
+    Clear,
+    Record(bool),
+    Pause(u32, bool),
+    Override(u32, Setting),
+}
+
+/// Setting of a single client connection, taking precedence over those of the proxy.
+pub enum Setting {
+    Drop(Rule),
+    Delay(Duration),
+    Throttle(ThrottlePlan),
+    Log(LevelFilter),
+    Record(bool),
+    Reset,
+}
+
+impl Setting {
+    /// Parses what follows the connection id of `:on`, like `delay 500` or `log debug`.
+    pub fn parse(setting: &str) -> std::result::Result<Self, String> {
+        let usage = || "Setting is drop|delay|throttle|log|record|reset ...".to_string();
+        let setting = setting.trim();
+        let (name, value) = setting.split_once(char::is_whitespace).unwrap_or((setting, ""));
+        match (name, value.trim()) {
+            ("drop", condition) if !condition.is_empty() => Ok(Setting::Drop(Rule::parse(Action::Drop, condition)?)),
+            ("delay", delay) => delay.parse::<u64>().map(|delay| Setting::Delay(Duration::from_millis(delay)))
+                .map_err(|_| format!("Delay {} is not a number of milliseconds", delay)),
+            ("throttle", rate) => ThrottlePlan::parse(rate).map(Setting::Throttle),
+            ("log", level) => level.parse::<LevelFilter>().map(Setting::Log).map_err(|_| {
+                format!("Log level {} is unknown, expected off, error, warn, info, debug or trace", level)
+            }),
+            ("record", "on") => Ok(Setting::Record(true)),
+            ("record", "off") => Ok(Setting::Record(false)),
+            ("reset", "") => Ok(Setting::Reset),
+            _ => Err(usage()),
+        }
+    }
+
+    /// Whether the setting changes what is forwarded, which strict passthrough forbids.
+    pub fn changes_traffic(&self) -> bool {
+        matches!(self, Setting::Drop(_) | Setting::Delay(_) | Setting::Throttle(_))
+    }
+}
+
+/// Settings of a client connection given with `:on` or the control API.
+#[derive(Default)]
+struct Override {
+    drops: Vec<Rule>,
+    delay: Option<Duration>,
+    throttle: Option<Throttle>,
+    verbosity: Option<LevelFilter>,
+    recording: Option<bool>,
+}
+
+impl Override {
+    fn settings(&self) -> Vec<String> {
+        let mut settings: Vec<String> = self.drops.iter().map(Rule::to_string).collect();
+        settings.extend(self.delay.map(|delay| format!("delay {}ms", delay.as_millis())));
+        if let Some(throttle) = &self.throttle {
+            settings.extend(throttle.plans().iter().map(|plan| format!("throttle {}", plan)));
+        }
+        settings.extend(self.verbosity.map(|level| format!("log {}", level.to_string().to_lowercase())));
+        settings.extend(self.recording.map(|recording| format!("record {}", if recording { "on" } else { "off" })));
+        settings
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    Break,
+    Intercept,
+}
+
+/// Drop rule or breakpoint added with the palette.
+pub struct Rule {
+    action: Action,
+    condition: Condition,
+    enabled: bool,
+}
+
+impl Rule {
+    fn parse(action: Action, condition: &str) -> std::result::Result<Self, String> {
+        Ok(Rule { action, condition: Condition::parse(condition)?, enabled: true })
+    }
+
+    fn matches(&self, action: Action, facts: &Facts) -> bool {
+        self.enabled && self.action == action && self.condition.matches(facts)
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Drop => "drop",
+            Action::Break => "break",
+            Action::Intercept => "intercept",
+        };
+        write!(f, "{}:{}", action, self.condition)
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> std::result::Result<Self, String> {
+        let line = line.strip_prefix(':').ok_or_else(|| format!("Command {} doesn't start with :", line))?;
+        let (name, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let argument = argument.trim();
+        match (name, argument) {
+            ("send", argument) => {
+                let (to, text) = argument.split_once(' ')
+                    .ok_or_else(|| "Command is :send client|server <text>".to_string())?;
+                Ok(Command::Send(Leg::parse(to)?, text.to_string()))
+            },
+            ("drop", condition) if !condition.is_empty() => Ok(Command::Rule(Rule::parse(Action::Drop, condition)?)),
+            ("break", condition) if !condition.is_empty() => Ok(Command::Rule(Rule::parse(Action::Break, condition)?)),
+            ("intercept", condition) if !condition.is_empty() => {
+                Ok(Command::Rule(Rule::parse(Action::Intercept, condition)?))
+            },
+            ("continue", "") => Ok(Command::Continue),
+            ("step", "") => Ok(Command::Step),
+            ("held", "") => Ok(Command::Held),
+            ("show", id) => MessageId::parse(id).map(Command::Show),
+            ("edit", argument) => {
+                let (id, text) = argument.split_once(' ')
+                    .ok_or_else(|| "Command is :edit <id> <text>".to_string())?;
+                Ok(Command::Edit(MessageId::parse(id)?, text.to_string()))
+            },
+            ("discard", id) => MessageId::parse(id).map(Command::Discard),
+            ("forward", id) => MessageId::parse(id).map(Command::Forward),
+            ("rules", "") => Ok(Command::Rules),
+            ("toggle", number) => number.parse::<usize>().map(Command::Toggle)
+                .map_err(|_| format!("Rule number {} is invalid", number)),
+            ("clear", "") => Ok(Command::Clear),
+            ("record", "on") => Ok(Command::Record(true)),
+            ("record", "off") => Ok(Command::Record(false)),
+            ("pause", connection) | ("resume", connection) => connection.parse::<u32>()
+                .map(|connection| Command::Pause(connection, name == "pause"))
+                .map_err(|_| format!("Connection id {} is invalid", connection)),
+            ("on", argument) => {
+                let (connection, setting) = argument.split_once(char::is_whitespace)
+                    .ok_or_else(|| "Command is :on <connection> drop|delay|throttle|log|record|reset ...".to_string())?;
+                let connection = connection.parse::<u32>()
+                    .map_err(|_| format!("Connection id {} is invalid", connection))?;
+                Ok(Command::Override(connection, Setting::parse(setting)?))
+            },
+            _ => Err(format!("Unknown command :{}, :help lists them", line)),
+        }
+    }
+
+    /// Whether the command changes what is forwarded, which strict passthrough forbids.
+    pub fn changes_traffic(&self) -> bool {
+        match self {
+            Command::Override(_, setting) => setting.changes_traffic(),
+            command => matches!(command, Command::Send(..) | Command::Rule(_) | Command::Continue | Command::Step
+                | Command::Pause(..) | Command::Edit(..) | Command::Discard(_) | Command::Forward(_)),
+        }
+    }
+}
+
+/// Message kept from being forwarded by a breakpoint or an interception.
+pub struct Held {
+    pub id: MessageId,
+    pub from: Leg,
+    pub message: Message,
+    /// Rule which held it, the breakpoint for messages held while the traffic is paused.
+    pub rule: String,
+}
+
+/// Runtime control of the proxy with commands typed into its terminal. Commands changing
+/// what is printed take effect at once, the others are handed to the event loop, which is
+/// woken up with a timeout of the server connection and takes them with `next`.
+pub struct Palette {
+    commands: mpsc::Receiver<Command>,
+    rules: RefCell<Vec<Rule>>,
+    held: RefCell<VecDeque<Held>>,
+    /// Breakpoint the traffic is paused at.
+    paused: RefCell<Option<String>>,
+    recording: Cell<bool>,
+    overrides: RefCell<BTreeMap<u32, Override>>,
+    composer: Option<Composer>,
+}
+
+/// Reader of the lines typed into the palette, from the standard input or the command line
+/// of the terminal UI.
+#[derive(Clone)]
+pub struct Prompt {
+    view: LiveView,
+    composer: Composer,
+    commands: mpsc::Sender<Command>,
+    waker: Arc<Mutex<Option<Sender>>>,
+    token: Token,
+    strict: bool,
+}
+
+impl Prompt {
+    /// Carries out a typed line, printing what it shows. Returns false once the proxy is stopped.
+    pub fn run(&self, line: &str) -> bool {
+        let line = line.trim();
+        let (name, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let argument = argument.trim();
+        let (view, composer) = (&self.view, &self.composer);
+        let shown = match name {
+            "" => return true,
+            ":help" => Ok(HELP.to_string()),
+            // Typed without the colon as well, as views were switched before the palette
+            ":view" | "view" => view.switch(Some(argument).filter(|name| !name.is_empty()))
+                .map(|_| match argument {
+                    "" => "Showing all messages".to_string(),
+                    name => format!("Switched to view {}", name),
+                }),
+            ":filter" => view.filter(argument).map(|_| format!("Showing messages matching {}", argument)),
+            ":quiet" => {
+                view.hide();
+                Ok("Messages aren't printed".to_string())
+            },
+            ":templates" => composer.list(),
+            ":history" => Ok(composer.history()),
+            _ => {
+                // Composed messages and those of the history are sent like :send
+                let command = match name {
+                    ":compose" => composer.compose(argument).map(|(to, text)| Command::Send(to, text)),
+                    ":again" => composer.again(argument).map(|(to, text)| Command::Send(to, text)),
+                    _ => Command::parse(line),
+                };
+                match command {
+                    Ok(command) if self.strict && command.changes_traffic() => {
+                        Err(format!("{} changes the traffic, which --strict-passthrough forbids", name))
+                    },
+                    Ok(command) => {
+                        if let Command::Send(to, text) = &command {
+                            composer.sent(*to, text);
+                        }
+                        if self.commands.send(command).is_err() {
+                            return false;
+                        }
+                        if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+                            waker.timeout(0, self.token).ok();
+                        }
+                        return true;
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+        };
+        match shown {
+            Ok(text) => println!("{}", text),
+            Err(e) => println!("{}", e),
+        }
+        true
+    }
+}
+
+impl Palette {
+    /// Starts reading commands from the standard input. The waker is the connection to
+    /// the server, set once it is created. With strict passthrough commands changing
+    /// the traffic are refused.
+    pub fn start(view: LiveView, composer: Composer, waker: Arc<Mutex<Option<Sender>>>, token: Token,
+                 strict: bool) -> Self {
+        let (palette, prompt) = Palette::prompted(view, composer, waker, token, strict);
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(std::result::Result::ok) {
+                if !prompt.run(&line) {
+                    return;
+                }
+            }
+        });
+        palette
+    }
+
+    /// Palette of the commands run with the prompt, which the caller reads lines for.
+    pub fn prompted(view: LiveView, composer: Composer, waker: Arc<Mutex<Option<Sender>>>, token: Token,
+                    strict: bool) -> (Self, Prompt) {
+        let (commands_tx, commands) = mpsc::channel();
+        let palette = Palette {
+            commands,
+            rules: RefCell::new(vec![]),
+            held: RefCell::new(VecDeque::new()),
+            paused: RefCell::new(None),
+            recording: Cell::new(true),
+            overrides: RefCell::new(BTreeMap::new()),
+            composer: Some(composer.clone()),
+        };
+        (palette, Prompt { view, composer, commands: commands_tx, waker, token, strict })
+    }
+
+    /// Palette of a proxy without a terminal, which gets no commands.
+    pub fn detached() -> Self {
+        let (_, commands) = mpsc::channel();
+        Palette {
+            commands,
+            rules: RefCell::new(vec![]),
+            held: RefCell::new(VecDeque::new()),
+            paused: RefCell::new(None),
+            recording: Cell::new(true),
+            overrides: RefCell::new(BTreeMap::new()),
+            composer: None,
+        }
+    }
+
+    /// Composer of the messages sent from the terminal, which learns the protocol from the traffic.
+    pub fn composer(&self) -> Option<&Composer> {
+        self.composer.as_ref()
+    }
+
+    /// Holds the messages matching the condition from the start, as given with --intercept.
+    pub fn intercept(&self, condition: Condition) {
+        self.rules.borrow_mut().push(Rule { action: Action::Intercept, condition, enabled: true });
+    }
+
+    /// Next command to be carried out by the event loop.
+    pub fn next(&self) -> Option<Command> {
+        self.commands.try_recv().ok()
+    }
+
+    /// Carries out a command changing the rules or recording.
+    pub fn apply(&self, command: Command) {
+        let mut rules = self.rules.borrow_mut();
+        match command {
+            Command::Rule(rule) => {
+                println!("Rule {} is {}", rules.len() + 1, rule);
+                rules.push(rule);
+            },
+            Command::Rules => {
+                let overrides = self.overrides.borrow();
+                if rules.is_empty() && overrides.is_empty() {
+                    println!("There are no rules");
+                }
+                for (index, rule) in rules.iter().enumerate() {
+                    println!("{:>3} {:<3} {}", index + 1, if rule.enabled { "on" } else { "off" }, rule);
+                }
+                for (connection_id, settings) in overrides.iter() {
+                    println!("Connection {}: {}", connection_id, settings.settings().join(", "));
+                }
+            },
+            Command::Toggle(number) => match rules.get_mut(number.wrapping_sub(1)) {
+                Some(rule) => {
+                    rule.enabled = !rule.enabled;
+                    println!("Rule {} {} is {}", number, rule, if rule.enabled { "on" } else { "off" });
+                },
+                None => println!("There is no rule {}", number),
+            },
+            Command::Clear => {
+                rules.clear();
+                println!("All rules are removed");
+            },
+            Command::Held => {
+                let held = self.held.borrow();
+                if held.is_empty() {
+                    println!("There are no held messages");
+                }
+                for held in held.iter() {
+                    println!("{:<10} from {:<6} by {}: {}",
+                        held.id, held.from, held.rule, gaps::preview(&held.message));
+                }
+            },
+            Command::Show(id) => match self.held.borrow().iter().find(|held| held.id == id) {
+                Some(Held { message: Message::Text(text), .. }) => println!("{}", text),
+                Some(Held { message: Message::Binary(data), .. }) => println!("{}", hex::encode(data)),
+                None => println!("Message {} isn't held", id),
+            },
+            Command::Record(recording) => {
+                self.recording.set(recording);
+                println!("Messages are {}", if recording { "recorded" } else { "not recorded" });
+            },
+            Command::Override(connection_id, setting) => match self.set(connection_id, setting).as_slice() {
+                [] => println!("Connection {} has the settings of the proxy", connection_id),
+                settings => println!("Connection {}: {}", connection_id, settings.join(", ")),
+            },
+            Command::Send(..) | Command::Continue | Command::Step | Command::Pause(..)
+            | Command::Edit(..) | Command::Discard(_) | Command::Forward(_) => {}
+        }
+    }
+
+    /// Changes a setting of the client connection, returning all of its settings.
+    pub fn set(&self, connection_id: u32, setting: Setting) -> Vec<String> {
+        let mut overrides = self.overrides.borrow_mut();
+        let settings = overrides.entry(connection_id).or_default();
+        match setting {
+            Setting::Drop(rule) => settings.drops.push(rule),
+            Setting::Delay(delay) => settings.delay = Some(delay).filter(|delay| !delay.is_zero()),
+            Setting::Throttle(plan) => settings.throttle.get_or_insert_with(|| Throttle::new(vec![])).add(plan),
+            Setting::Log(level) => {
+                settings.verbosity = Some(level);
+                verbosity::set(connection_id, Some(level));
+            },
+            Setting::Record(recording) => settings.recording = Some(recording),
+            Setting::Reset => {
+                overrides.remove(&connection_id);
+                verbosity::set(connection_id, None);
+                return vec![];
+            },
+        }
+        settings.settings()
+    }
+
+    /// Settings of the client connection, none if it has those of the proxy.
+    pub fn settings(&self, connection_id: u32) -> Vec<String> {
+        self.overrides.borrow().get(&connection_id).map(Override::settings).unwrap_or_default()
+    }
+
+    /// The drop rule the message is dropped by, if any.
+    pub fn drops(&self, facts: &Facts) -> Option<String> {
+        self.rules.borrow().iter()
+            .find(|rule| rule.matches(Action::Drop, facts))
+            .map(Rule::to_string)
+    }
+
+    /// The drop rule of the client connection the message is dropped by, if any.
+    pub fn drops_for(&self, connection_id: u32, facts: &Facts) -> Option<String> {
+        self.overrides.borrow().get(&connection_id)?.drops.iter()
+            .find(|rule| rule.matches(Action::Drop, facts))
+            .map(|rule| format!("on {} {}", connection_id, rule))
+    }
+
+    /// How much later messages from and to the client connection are forwarded.
+    pub fn delay(&self, connection_id: u32) -> Option<Duration> {
+        self.overrides.borrow().get(&connection_id)?.delay
+    }
+
+    /// How long a message along the edge of the client connection waits for the bandwidth
+    /// of the client, none unless the client has its own for the direction.
+    pub fn throttle(&self, connection_id: u32, edge: Edge, size: usize) -> Option<Duration> {
+        let mut overrides = self.overrides.borrow_mut();
+        let throttle = overrides.get_mut(&connection_id)?.throttle.as_mut()
+            .filter(|throttle| throttle.limits(edge.from.leg()))?;
+        Some(throttle.wait(edge, size))
+    }
+
+    /// Drops the settings of a closed client connection, ids aren't reused.
+    pub fn forget(&self, connection_id: u32) {
+        if self.overrides.borrow_mut().remove(&connection_id).is_some() {
+            verbosity::set(connection_id, None);
+        }
+    }
+
+    /// Keeps the message if the traffic is paused, a breakpoint pauses it or it is
+    /// intercepted. Returns false if the message is to be forwarded.
+    pub fn holds(&self, id: MessageId, facts: &Facts) -> bool {
+        let mut paused = self.paused.borrow_mut();
+        let rule = match paused.as_ref() {
+            Some(breakpoint) => breakpoint.clone(),
+            None => {
+                let rules = self.rules.borrow();
+                if let Some(breakpoint) = rules.iter().find(|rule| rule.matches(Action::Break, facts)) {
+                    println!("Paused at message {} from {} by {}, :continue or :step to go on",
+                        id, facts.from, breakpoint);
+                    *paused = Some(breakpoint.to_string());
+                    breakpoint.to_string()
+                } else if let Some(interception) = rules.iter().find(|rule| rule.matches(Action::Intercept, facts)) {
+                    println!("Intercepted message {} from {} by {}: {}", id, facts.from, interception,
+                        gaps::preview(facts.message));
+                    println!("  :show, :edit, :discard or :forward {}", id);
+                    interception.to_string()
+                } else {
+                    return false;
+                }
+            },
+        };
+        self.held.borrow_mut().push_back(Held { id, from: facts.from, message: facts.message.clone(), rule });
+        true
+    }
+
+    /// Messages to be forwarded now, all of them unless only one step is made.
+    pub fn release(&self, step: bool) -> Vec<Held> {
+        let mut held = self.held.borrow_mut();
+        if step {
+            return held.pop_front().into_iter().collect();
+        }
+        *self.paused.borrow_mut() = None;
+        held.drain(..).collect()
+    }
+
+    /// Takes a held message out, to be forwarded or dropped.
+    pub fn take(&self, id: MessageId) -> std::result::Result<Held, String> {
+        let mut held = self.held.borrow_mut();
+        let index = held.iter().position(|held| held.id == id).ok_or_else(|| format!("Message {} isn't held", id))?;
+        Ok(held.remove(index).expect("the index is of a held message"))
+    }
+
+    /// Replaces a held message with a text, returning the message it was.
+    pub fn edit(&self, id: MessageId, text: String) -> std::result::Result<Message, String> {
+        let mut held = self.held.borrow_mut();
+        let held = held.iter_mut().find(|held| held.id == id).ok_or_else(|| format!("Message {} isn't held", id))?;
+        Ok(std::mem::replace(&mut held.message, Message::text(text)))
+    }
+
+    /// Whether messages of the connection are written, clients may be set apart from the rest.
+    pub fn is_recording(&self, connection_id: u32) -> bool {
+        self.overrides.borrow().get(&connection_id).and_then(|settings| settings.recording)
+            .unwrap_or_else(|| self.recording.get())
+    }
+}
@@ -0,0 +1,258 @@
+use libloading::{Library, Symbol};
+use regex::Regex;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use ws::Message;
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::wasm;
+
+/// Directory of plugins the plugins command looks into unless it's given another one,
+/// with one directory per plugin. The proxy loads plugins only from --plugins.
+pub const PLUGINS: &str = "ws-proxy.plugins";
+
+/// Manifest of a plugin in its directory.
+pub const MANIFEST: &str = "plugin.yaml";
+
+/// Version of the interface between the proxy and decoders. A dynamic library exports:
+///
+/// ```c
+/// uint32_t ws_proxy_abi_version(void);
+/// /* 0 if the message is decoded into a UTF-8 text in a buffer allocated by the plugin */
+/// int32_t ws_proxy_decode(const uint8_t *data, size_t len, uint8_t **out, size_t *out_len);
+/// void ws_proxy_free(uint8_t *out, size_t len);
+/// ```
+pub const ABI_VERSION: u32 = 1;
+
+type AbiVersion = unsafe extern "C" fn() -> u32;
+type Decode = unsafe extern "C" fn(*const u8, usize, *mut *mut u8, *mut usize) -> i32;
+type Free = unsafe extern "C" fn(*mut u8, usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Dylib,
+    Wasm,
+}
+
+/// Messages a decoder is for.
+pub enum Applies {
+    /// Binary messages starting with the bytes.
+    Binary(Vec<u8>),
+    /// Text messages matching the pattern.
+    Text(Regex),
+}
+
+/// Declaration of a plugin in YAML:
+///
+/// ```yaml
+/// name: acme-frames
+/// version: 1.2.0
+/// kind: dylib            # or wasm, for a message processor
+/// abi: 1
+/// library: libacme_frames.so  # or a .wasm module
+/// sha256: 9f86d081...
+/// binary_prefix: "a1b2"  # hex, or text_pattern: <regex>
+/// description: Frames of the ACME market data feed
+/// ```
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub kind: Kind,
+    pub abi: u32,
+    pub library: PathBuf,
+    pub sha256: String,
+    pub description: Option<String>,
+    pub applies: Applies,
+}
+
+impl Manifest {
+    pub fn load(dir: &Path) -> std::result::Result<Self, String> {
+        let path = dir.join(MANIFEST);
+        let text = fs::read_to_string(&path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let manifest: Value = serde_yaml::from_str(&text)
+            .map_err(|e| format!("{} is not YAML: {}", path.display(), e))?;
+        let field = |name: &str| manifest[name].as_str().map(String::from)
+            .ok_or_else(|| format!("{} has no {}", path.display(), name));
+
+        let kind = match field("kind")?.as_str() {
+            "dylib" => Kind::Dylib,
+            "wasm" => Kind::Wasm,
+            other => return Err(format!("Kind {} in {} is neither dylib nor wasm", other, path.display())),
+        };
+        let applies = match (manifest["binary_prefix"].as_str(), manifest["text_pattern"].as_str()) {
+            (Some(prefix), None) => Applies::Binary(hex::decode(prefix)
+                .map_err(|e| format!("Invalid binary_prefix in {}: {}", path.display(), e))?),
+            (None, Some(pattern)) => Applies::Text(Regex::new(pattern)
+                .map_err(|e| format!("Invalid text_pattern in {}: {}", path.display(), e))?),
+            _ => return Err(format!("{} must have either binary_prefix or text_pattern", path.display())),
+        };
+        Ok(Manifest {
+            name: field("name")?,
+            version: field("version")?,
+            kind,
+            abi: manifest["abi"].as_u64().ok_or_else(|| format!("{} has no abi", path.display()))? as u32,
+            library: dir.join(field("library")?),
+            sha256: field("sha256")?.to_lowercase(),
+            description: manifest["description"].as_str().map(String::from),
+            applies,
+        })
+    }
+
+    /// Checks the library is the one declared and of a supported interface.
+    pub fn verify(&self) -> std::result::Result<(), String> {
+        let supported = match self.kind {
+            Kind::Dylib => ABI_VERSION,
+            Kind::Wasm => wasm::ABI_VERSION,
+        };
+        if self.abi != supported {
+            return Err(format!("ABI {} is not supported, only {} is", self.abi, supported));
+        }
+        let library = fs::read(&self.library).map_err(|e| format!("Can't read {}: {}", self.library.display(), e))?;
+        let digest = hex::encode(Sha256::digest(&library));
+        if digest != self.sha256 {
+            return Err(format!("Digest of {} is {}, not {} as declared", self.library.display(), digest, self.sha256));
+        }
+        Ok(())
+    }
+
+    /// Whether the plugin is for the message.
+    pub fn applies(&self, message: &Message) -> bool {
+        match (&self.applies, message) {
+            (Applies::Binary(prefix), Message::Binary(data)) => data.starts_with(prefix),
+            (Applies::Text(pattern), Message::Text(text)) => pattern.is_match(text),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Dylib => "dylib",
+            Kind::Wasm => "wasm",
+        };
+        write!(f, "{} {} ({}, abi {})", self.name, self.version, kind, self.abi)?;
+        match &self.applies {
+            Applies::Binary(prefix) => write!(f, " for binary messages starting with {}", hex::encode(prefix))?,
+            Applies::Text(pattern) => write!(f, " for text messages matching {}", pattern)?,
+        }
+        if let Some(description) = &self.description {
+            write!(f, ": {}", description)?;
+        }
+        Ok(())
+    }
+}
+
+/// Plugin found in a directory, with its manifest unless it is invalid.
+pub type Found = (PathBuf, std::result::Result<Manifest, String>);
+
+/// Directories of the plugins in the directory with their manifests, sorted by name.
+pub fn discover(dir: &Path) -> std::result::Result<Vec<Found>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Can't read plugins {}: {}", dir.display(), e))?;
+    let mut plugins: Vec<Found> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.join(MANIFEST).is_file())
+        .map(|path| {
+            let manifest = Manifest::load(&path);
+            (path, manifest)
+        })
+        .collect();
+    plugins.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(plugins)
+}
+
+/// Decoder of a loaded plugin.
+pub struct Decoder {
+    pub manifest: Manifest,
+    library: Library,
+}
+
+impl Decoder {
+    /// Loads the library of a verified plugin and checks it exports the interface.
+    pub fn load(manifest: Manifest) -> std::result::Result<Self, String> {
+        if manifest.kind != Kind::Dylib {
+            return Err(format!("{} is not a dynamic library plugin", manifest.name));
+        }
+        manifest.verify()?;
+        // Safety: loading runs initializers of the library, which is trusted as much as
+        // the manifest declaring its digest
+        let library = unsafe { Library::new(&manifest.library) }
+            .map_err(|e| format!("Can't load {}: {}", manifest.library.display(), e))?;
+        let abi = unsafe {
+            let version: Symbol<AbiVersion> = library.get(b"ws_proxy_abi_version")
+                .map_err(|e| format!("{} doesn't export ws_proxy_abi_version: {}", manifest.library.display(), e))?;
+            version()
+        };
+        if abi != ABI_VERSION {
+            return Err(format!("{} implements ABI {}, not {}", manifest.library.display(), abi, ABI_VERSION));
+        }
+        for symbol in [&b"ws_proxy_decode"[..], &b"ws_proxy_free"[..]] {
+            unsafe { library.get::<unsafe extern "C" fn()>(symbol) }
+                .map_err(|e| format!("{} doesn't export {}: {}", manifest.library.display(),
+                    String::from_utf8_lossy(symbol), e))?;
+        }
+        Ok(Decoder { manifest, library })
+    }
+
+    fn decode(&self, data: &[u8]) -> Option<String> {
+        // Safety: both symbols were checked when loading, and the plugin owns the buffer
+        // it returns until it is given back
+        unsafe {
+            let decode: Symbol<Decode> = self.library.get(b"ws_proxy_decode").ok()?;
+            let free: Symbol<Free> = self.library.get(b"ws_proxy_free").ok()?;
+            let mut out: *mut u8 = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            if decode(data.as_ptr(), data.len(), &mut out, &mut out_len) != 0 || out.is_null() {
+                return None;
+            }
+            let text = String::from_utf8_lossy(std::slice::from_raw_parts(out, out_len)).into_owned();
+            free(out, out_len);
+            Some(text)
+        }
+    }
+}
+
+/// Decoders of all valid plugins in a directory.
+pub struct Decoders {
+    decoders: Vec<Decoder>,
+}
+
+impl Decoders {
+    /// Loads all dynamic library plugins, those failing to load are reported and skipped.
+    /// WASM plugins are left to the message processors.
+    pub fn load(dir: &Path) -> std::result::Result<Self, String> {
+        let mut decoders = vec![];
+        for (path, manifest) in discover(dir)? {
+            let manifest = match manifest {
+                Ok(manifest) if manifest.kind == Kind::Wasm => continue,
+                manifest => manifest,
+            };
+            match manifest.and_then(Decoder::load) {
+                Ok(decoder) => {
+                    println!("Loaded decoder {}", decoder.manifest);
+                    decoders.push(decoder);
+                },
+                Err(e) => println!("Plugin {} is skipped: {}", path.display(), e),
+            }
+        }
+        Ok(Decoders { decoders })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decoders.is_empty()
+    }
+
+    /// Text of the message decoded by the first plugin for it, with the plugin name.
+    pub fn decode(&self, message: &Message) -> Option<(&str, String)> {
+        let data: &[u8] = match message {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(data) => data,
+        };
+        self.decoders.iter()
+            .filter(|decoder| decoder.manifest.applies(message))
+            .find_map(|decoder| decoder.decode(data).map(|text| (decoder.manifest.name.as_str(), text)))
+    }
+}
@@ -0,0 +1,75 @@
+use ws::{Request, Response};
+
+/// Body of the status page answering plain HTTP requests.
+const STATUS_PAGE: &str = "ws-proxy is running. This port accepts WebSocket connections only, \
+    connect to it with a WebSocket client.\n";
+
+/// How plain HTTP requests on the listen port are answered, given with --probe-response.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProbeResponse {
+    /// 200 with a short page saying that the proxy is up, for health checks.
+    #[default]
+    Status,
+    /// 426 Upgrade Required, for checks which expect the port to refuse plain HTTP.
+    UpgradeRequired,
+}
+
+impl ProbeResponse {
+    /// Parses `status` or `426`.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "status" => Ok(ProbeResponse::Status),
+            "426" => Ok(ProbeResponse::UpgradeRequired),
+            _ => Err(format!("Unknown probe response {}, expected status or 426", value)),
+        }
+    }
+
+    /// Response to the request, without a body for HEAD requests.
+    pub fn respond(&self, request: &Request) -> Response {
+        let (status, reason) = match self {
+            ProbeResponse::Status => (200, "OK"),
+            ProbeResponse::UpgradeRequired => (426, "Upgrade Required"),
+        };
+        let body = if request.method() == "HEAD" { vec![] } else { STATUS_PAGE.as_bytes().to_vec() };
+        let mut response = Response::new(status, reason, body);
+        // HEAD is answered with the length of the page it would get
+        if let Some(length) = response.header_mut("content-length") {
+            *length = STATUS_PAGE.len().to_string().into_bytes();
+        }
+        let headers = response.headers_mut();
+        if *self == ProbeResponse::UpgradeRequired {
+            headers.push(("Upgrade".to_string(), b"websocket".to_vec()));
+        }
+        headers.push(("Content-Type".to_string(), b"text/plain; charset=utf-8".to_vec()));
+        headers.push(("Connection".to_string(), b"close".to_vec()));
+        response
+    }
+}
+
+/// What a connection to the listen port which isn't a WebSocket client turned out to be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProbeKind {
+    /// A plain HTTP request, like those of health checks.
+    Http,
+    /// Bytes which aren't an HTTP request, like those of port scanners, or a failed TLS handshake.
+    Garbage,
+    /// A connection closed without sending anything, like TCP health checks.
+    Empty,
+}
+
+impl ProbeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProbeKind::Http => "http",
+            ProbeKind::Garbage => "garbage",
+            ProbeKind::Empty => "empty",
+        }
+    }
+}
+
+/// Whether the request asks for the WebSocket upgrade, however broken it is otherwise.
+pub fn is_upgrade(request: &Request) -> bool {
+    request.header("upgrade")
+        .map(|upgrade| String::from_utf8_lossy(upgrade).to_ascii_lowercase().contains("websocket"))
+        .unwrap_or(false)
+}
@@ -0,0 +1,162 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{json, Value};
+use ws::Message;
+
+use std::collections::HashMap;
+
+use crate::bundle;
+use crate::closecodes::Leg;
+use crate::condition::{Environment, Facts};
+use crate::hooks::Hooks;
+use crate::session::MessageId;
+use crate::wasm::Verdict;
+
+/// Handshake of a captured connection, as its hooks see it live.
+struct Connection {
+    headers: Vec<(String, String)>,
+    environment: Environment,
+}
+
+/// Post-processing of a capture with the hooks of a rhai script, the same script `--script`
+/// runs live. Messages pass `on_client_message(msg)` and `on_server_message(msg)` in the order
+/// they were captured, with the handshake of their connection when the index of the session
+/// knows it. Dropped messages are left out of the processed capture, replaced ones are written
+/// as replaced and those made up with `send` follow the message they were made up on.
+pub struct Processing {
+    connections: HashMap<u32, Connection>,
+}
+
+impl Processing {
+    /// Processing with the connections opened in the index of the session, if there is one.
+    pub fn new(index: &[Value], upstream: &str) -> Self {
+        let connections = index.iter()
+            .filter(|record| record["event"] == "open" && record["role"] == "client")
+            .filter_map(|record| {
+                let connection_id = record["connection_id"].as_u64()? as u32;
+                let strings = |object: &Value| -> Vec<(String, String)> {
+                    object.as_object().into_iter().flatten()
+                        .map(|(name, value)| (name.clone(), value.as_str().unwrap_or_default().to_string()))
+                        .collect()
+                };
+                let client = record["client"]["fingerprint"].as_str().unwrap_or_default().to_string();
+                let resource = record["resource"].as_str().unwrap_or("/");
+                let environment = Environment::new(client, resource, upstream.to_string(), strings(&record["labels"]));
+                Some((connection_id, Connection { headers: strings(&record["request_headers"]), environment }))
+            })
+            .collect();
+        Processing { connections }
+    }
+
+    /// Runs the message records of a capture through the hooks. Records of other events are kept as they are.
+    pub fn run(&self, hooks: &Hooks, records: Vec<Value>) -> Vec<Value> {
+        let mut processed = vec![];
+        for mut record in records {
+            let recorded = match bundle::recorded(&record).filter(|_| record["event"] == "message") {
+                Some(recorded) => recorded,
+                None => {
+                    processed.push(record);
+                    continue;
+                }
+            };
+            let id = recorded.id.as_deref().and_then(|id| MessageId::parse(id).ok()).unwrap_or(MessageId {
+                connection_id: record["connection_id"].as_u64().unwrap_or_default() as u32,
+                sequence: record["sequence"].as_u64().unwrap_or_default(),
+            });
+            let facts = match self.connections.get(&id.connection_id) {
+                Some(connection) => Facts {
+                    connection_id: id.connection_id,
+                    headers: &connection.headers,
+                    messages: id.sequence,
+                    environment: &connection.environment,
+                    ..Facts::of(recorded.from, &recorded.message)
+                },
+                None => Facts { connection_id: id.connection_id, messages: id.sequence,
+                    ..Facts::of(recorded.from, &recorded.message) },
+            };
+            let (verdict, sent) = hooks.process(id, &facts);
+            let time = record["time"].clone();
+            match verdict {
+                Verdict::Drop(_) => {},
+                Verdict::Replace(_, replacement) => {
+                    let (kind, data) = payload(&replacement);
+                    record["type"] = json!(kind);
+                    record["data"] = json!(data);
+                    processed.push(record);
+                },
+                Verdict::Forward => processed.push(record),
+            }
+            for (to, message) in sent {
+                let (kind, data) = payload(&message);
+                let from = match to {
+                    Leg::Client => Leg::Server,
+                    Leg::Server => Leg::Client,
+                };
+                processed.push(json!({
+                    "event": "message",
+                    "connection_id": id.connection_id,
+                    "time": time,
+                    "from": from.to_string(),
+                    "type": kind,
+                    "data": data,
+                    "made_up_on": id.to_string(),
+                }));
+            }
+        }
+        processed
+    }
+}
+
+/// Type and data of a message as a capture records them.
+fn payload(message: &Message) -> (&'static str, String) {
+    match message {
+        Message::Text(text) => ("text", text.clone()),
+        Message::Binary(data) => ("binary", BASE64.encode(data)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn message(id: &str, from: &str, data: &str) -> Value {
+        json!({ "event": "message", "id": id, "from": from, "type": "text", "data": data })
+    }
+
+    #[test]
+    fn hooks_drop_replace_and_make_up_captured_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("hooks.rhai");
+        fs::write(&script, r#"
+            fn on_client_message(msg) {
+                if msg.text == "ping" { send("client", "pong"); return false; }
+                `${msg.text} on ${msg.path} from ${msg.headers.origin}`
+            }
+            fn on_server_message(msg) {
+                this.seen = (this.seen ?? 0) + msg.size;
+            }
+            fn report() {
+                #{ seen: this.seen }
+            }
+        "#).unwrap();
+        let hooks = Hooks::load(&script).unwrap();
+        let index = [json!({ "event": "open", "connection_id": 1, "role": "client", "resource": "/feed?room=a",
+            "request_headers": { "origin": "https://example.com" }, "labels": {} })];
+
+        let processed = Processing::new(&index, "ws://upstream/").run(&hooks, vec![
+            message("c1:1", "client", "ping"),
+            message("c1:2", "client", "hello"),
+            json!({ "event": "close", "connection_id": 1 }),
+            message("c0:1", "server", "welcome"),
+        ]);
+        let data: Vec<(&str, &str)> = processed.iter()
+            .map(|record| (record["from"].as_str().unwrap_or("-"), record["data"].as_str().unwrap_or("-")))
+            .collect();
+        assert_eq!(data, [("server", "pong"), ("client", "hello on /feed from https://example.com"), ("-", "-"),
+            ("server", "welcome")]);
+        assert_eq!(processed[0]["made_up_on"], "c1:1");
+        assert_eq!(hooks.report(), Ok(Some("seen: 7\n".to_string())));
+    }
+}
@@ -0,0 +1,89 @@
+/// Settings of run for a protocol commonly debugged, given with --profile: the subprotocol
+/// negotiated, tags of heartbeats and of the messages worth finding, pretty-printing, silences
+/// longer than the heartbeats and the fields pairing responses with requests in analyze latency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// GraphQL over the graphql-transport-ws protocol of the graphql-ws library.
+    GraphqlWs,
+    /// Socket.IO 4 over Engine.IO 4, whose server pings every 25 seconds.
+    SocketIo,
+    /// ASP.NET Core SignalR with the JSON hub protocol.
+    SignalR,
+    /// Market streams and requests of the Binance WebSocket API.
+    BinanceWs,
+}
+
+const PROFILES: [Profile; 4] = [Profile::GraphqlWs, Profile::SocketIo, Profile::SignalR, Profile::BinanceWs];
+
+impl Profile {
+    pub fn parse(name: &str) -> std::result::Result<Self, String> {
+        PROFILES.iter().copied()
+            .find(|profile| profile.name() == name)
+            .ok_or_else(|| format!("Profile {} is none of {}", name,
+                PROFILES.iter().map(Profile::name).collect::<Vec<_>>().join(", ")))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::GraphqlWs => "graphql-ws",
+            Profile::SocketIo => "socketio",
+            Profile::SignalR => "signalr",
+            Profile::BinanceWs => "binance-ws",
+        }
+    }
+
+    /// Flags of run the profile stands for.
+    pub fn flags(&self) -> Vec<String> {
+        let flags: &[&str] = match self {
+            Profile::GraphqlWs => &[
+                "--subprotocol", "graphql-transport-ws",
+                "--tag", "heartbeat=\"type\":\"(ping|pong)\"",
+                "--tag", "error=server:\"type\":\"error\"|\"errors\":",
+                "--pretty-jsons",
+            ],
+            // Pings of the server are 2 and pongs of the client 3, events 42 and acks 43
+            Profile::SocketIo => &[
+                "--tag", "heartbeat=^[23]$",
+                "--tag", "event=^4[23]",
+                "--gap", "server:45",
+            ],
+            // Records end with 0x1e, both sides ping with type 6 every 15 seconds
+            Profile::SignalR => &[
+                "--tag", "heartbeat=\"type\":6[,}]",
+                "--tag", "invocation=client:\"type\":[14][,}]",
+                "--tag", "error=server:\"error\":",
+                "--gap", "30",
+            ],
+            // The server pings with control frames, which are captured to see them
+            Profile::BinanceWs => &[
+                "--capture-frames",
+                "--tag", "response=server:\"result\":",
+                "--tag", "error=server:\"error\":",
+                "--pretty-jsons",
+            ],
+        };
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    /// Fields of JSON messages pairing responses with requests. Socket.IO keeps the ids
+    /// of acknowledgements out of the JSON, so its requests are paired in turn.
+    pub fn correlation_fields(&self) -> &'static [&'static str] {
+        match self {
+            Profile::GraphqlWs | Profile::BinanceWs => &["id"],
+            Profile::SocketIo => &[],
+            Profile::SignalR => &["invocationId"],
+        }
+    }
+
+    /// Profile given in a command line of run, as kept in the config of a session.
+    pub fn of(command_line: &[String]) -> Option<Self> {
+        command_line.iter()
+            .enumerate()
+            .find_map(|(index, arg)| match arg.strip_prefix("--profile=") {
+                Some(name) => Some(name),
+                None if arg == "--profile" => command_line.get(index + 1).map(String::as_str),
+                None => None,
+            })
+            .and_then(|name| Profile::parse(name).ok())
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+use crate::closecodes::Leg;
+use crate::sse;
+
+/// How long a scrape waits for the request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bounds of the buckets of the forwarding latency, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0];
+/// Upper bounds of the buckets of message sizes, in bytes.
+const SIZE_BUCKETS: [f64; 8] = [64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+
+const LEGS: [Leg; 2] = [Leg::Client, Leg::Server];
+
+/// Metrics of the proxy in the Prometheus text format at /metrics of a port on all interfaces,
+/// given with --prometheus-port, for a proxy running semi-permanently in front of a staging server.
+///
+/// Messages and bytes received from each side with histograms of their sizes, the time the proxy
+/// spends on a message until it is queued on the connections it goes to, clients connected and
+/// connections opened, whether the server is connected and how often it was connected again,
+/// and errors of connections. Counters start at zero with the proxy.
+#[derive(Clone)]
+pub struct Prometheus {
+    inner: Arc<Mutex<Registry>>,
+}
+
+struct Registry {
+    messages: [u64; 2],
+    bytes: [u64; 2],
+    sizes: [Histogram; 2],
+    latencies: [Histogram; 2],
+    clients: BTreeSet<u32>,
+    opened: [u64; 2],
+    upstream_connected: bool,
+    reconnects: u64,
+    errors: [u64; 2],
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations in each bucket, not cumulative.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Prometheus {
+    pub fn start(port: u16) -> std::result::Result<Self, String> {
+        let address = SocketAddr::from(([0, 0, 0, 0], port));
+        let listener = TcpListener::bind(address).map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+        let registry = Registry {
+            messages: [0; 2],
+            bytes: [0; 2],
+            sizes: [Histogram::new(&SIZE_BUCKETS), Histogram::new(&SIZE_BUCKETS)],
+            latencies: [Histogram::new(&LATENCY_BUCKETS), Histogram::new(&LATENCY_BUCKETS)],
+            clients: BTreeSet::new(),
+            opened: [0; 2],
+            upstream_connected: false,
+            reconnects: 0,
+            errors: [0; 2],
+        };
+        let prometheus = Prometheus { inner: Arc::new(Mutex::new(registry)) };
+        let scraped = prometheus.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => scraped.serve(stream),
+                    Err(e) => error!("Error: {}", e),
+                }
+            }
+        });
+        info!("Prometheus metrics are served on http://{}/metrics", address);
+        Ok(prometheus)
+    }
+
+    /// Counts an opened connection, the server connected after failed attempts is a reconnect.
+    pub fn opened(&self, connection_id: u32, leg: Leg, reconnected: bool) {
+        let mut registry = self.inner.lock().unwrap();
+        registry.opened[leg as usize] += 1;
+        match leg {
+            Leg::Client => {
+                registry.clients.insert(connection_id);
+            },
+            Leg::Server => {
+                registry.upstream_connected = true;
+                registry.reconnects += reconnected as u64;
+            },
+        }
+    }
+
+    pub fn closed(&self, connection_id: u32, leg: Leg) {
+        let mut registry = self.inner.lock().unwrap();
+        match leg {
+            Leg::Client => {
+                registry.clients.remove(&connection_id);
+            },
+            Leg::Server => registry.upstream_connected = false,
+        }
+    }
+
+    /// Counts a message with the time the proxy spent forwarding it.
+    pub fn message(&self, from: Leg, size: usize, spent: Duration) {
+        let mut registry = self.inner.lock().unwrap();
+        registry.messages[from as usize] += 1;
+        registry.bytes[from as usize] += size as u64;
+        registry.sizes[from as usize].observe(size as f64);
+        registry.latencies[from as usize].observe(spent.as_secs_f64());
+    }
+
+    pub fn error(&self, leg: Leg) {
+        self.inner.lock().unwrap().errors[leg as usize] += 1;
+    }
+
+    fn serve(&self, stream: TcpStream) {
+        stream.set_read_timeout(Some(TIMEOUT)).ok();
+        let request_line = match sse::read_head(&mut BufReader::new(&stream)) {
+            Ok((request_line, _)) => request_line,
+            Err(e) => {
+                debug!("Metrics request is not read: {}", e);
+                return;
+            },
+        };
+        let path = request_line.split(' ').nth(1).unwrap_or_default();
+        let (status, body) = match (request_line.split(' ').next(), path.split('?').next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.inner.lock().unwrap().exposition()),
+            _ => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        };
+        let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+        if let Err(e) = (&stream).write_all(response.as_bytes()) {
+            debug!("Metrics response is not sent: {}", e);
+        }
+    }
+}
+
+impl Registry {
+    fn exposition(&self) -> String {
+        let mut text = String::new();
+        let mut by_leg = |name: &str, kind: &str, help: &str, label: &str, values: &[u64; 2]| {
+            header(&mut text, name, kind, help);
+            for leg in LEGS {
+                writeln!(text, "{}{{{}=\"{}\"}} {}", name, label, leg, values[leg as usize]).unwrap();
+            }
+        };
+        by_leg("ws_proxy_messages_total", "counter", "Messages received from each side.",
+            "from", &self.messages);
+        by_leg("ws_proxy_bytes_total", "counter", "Bytes of the messages received from each side.",
+            "from", &self.bytes);
+        by_leg("ws_proxy_connections_total", "counter", "Connections opened with clients and to the server.",
+            "side", &self.opened);
+        by_leg("ws_proxy_errors_total", "counter", "Errors of the connections with clients and to the server.",
+            "side", &self.errors);
+        let mut single = |name: &str, kind: &str, help: &str, value: u64| {
+            header(&mut text, name, kind, help);
+            writeln!(text, "{} {}", name, value).unwrap();
+        };
+        single("ws_proxy_clients", "gauge", "Clients connected.", self.clients.len() as u64);
+        single("ws_proxy_upstream_connected", "gauge", "Whether the server is connected.",
+            self.upstream_connected as u64);
+        single("ws_proxy_upstream_reconnects_total", "counter",
+            "Times the server was connected again after failed attempts.", self.reconnects);
+        header(&mut text, "ws_proxy_message_size_bytes", "histogram", "Sizes of the messages received.");
+        for leg in LEGS {
+            self.sizes[leg as usize].render(&mut text, "ws_proxy_message_size_bytes", leg);
+        }
+        header(&mut text, "ws_proxy_forward_latency_seconds", "histogram",
+            "Time the proxy spends on a message until it is queued on the connections it goes to.");
+        for leg in LEGS {
+            self.latencies[leg as usize].render(&mut text, "ws_proxy_forward_latency_seconds", leg);
+        }
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, text: &mut String, name: &str, from: Leg) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            writeln!(text, "{}_bucket{{from=\"{}\",le=\"{}\"}} {}", name, from, bound, cumulative).unwrap();
+        }
+        writeln!(text, "{}_bucket{{from=\"{}\",le=\"+Inf\"}} {}", name, from, self.count).unwrap();
+        writeln!(text, "{}_sum{{from=\"{}\"}} {}", name, from, self.sum).unwrap();
+        writeln!(text, "{}_count{{from=\"{}\"}} {}", name, from, self.count).unwrap();
+    }
+}
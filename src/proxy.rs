@@ -0,0 +1,2711 @@
+use chrono::Utc;
+use log::{debug, error, info, log_enabled, warn, Level};
+use openssl::ssl::{SslAcceptor, SslConnector, SslStream};
+use serde_json::{json, Value};
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+use url::Url;
+use ws::util::{TcpStream, Token};
+use ws::{Builder, CloseCode, Factory, Frame, Handshake, Message, OpCode, Request, Response, Result, Sender, Settings};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::agent::Agent;
+use crate::alert::{AlertRule, Alerts};
+use crate::anonymize::Anonymizer;
+use crate::auth::{self, AuthProvider};
+use crate::backoff::Backoff;
+use crate::clock::{self, Clock};
+use crate::closecodes::{CloseStats, Initiator, Leg};
+use crate::composer::{self, Composer};
+use crate::condition::{Condition, Environment, Facts};
+use crate::console::Console;
+use crate::deflate::{Deflate, DeflatePlan};
+use crate::contract::{Contract, Validator};
+use crate::control::{Answer, Control, Operation};
+use crate::devtools::{self, DevTools};
+use crate::digest::{self, Digests};
+use crate::encryption::Encryption;
+use crate::fingerprint;
+use crate::fault::{Fault, FaultPlan, Faults};
+use crate::flood::{Flood, FloodPlan};
+use crate::gaps::Gaps;
+use crate::hooks::Hooks;
+use crate::hops;
+use crate::interleave::{Interleave, InterleavePlan, Queued};
+use crate::latency::Latency;
+use crate::logqueue::{LogFile, LogFormat, LogNames, LogQueue, Rotation, SyncPolicy};
+use crate::manifest;
+use crate::memory::{self, MemoryLimit, MemoryMonitor, Shedding};
+use crate::metrics::{self, Metrics};
+use crate::notify;
+use crate::observer::Observers;
+use crate::overhead::{self, Overhead, Stage, Timing};
+use crate::palette::{Command, Held, Palette};
+use crate::plugins::Decoders;
+use crate::probe::{self, ProbeKind, ProbeResponse};
+use crate::prometheus::Prometheus;
+use crate::render::Renderers;
+use crate::relay::Relay;
+use crate::resubscribe::Resubscribe;
+use crate::retention::Retention;
+use crate::sampling::{Rate, Sampling};
+use crate::segments::{Segments, SplitRule};
+use crate::selfcheck::SelfCheck;
+use crate::session::{self, MessageId, Session};
+use crate::setup::{self, Setup, TlsProgress};
+use crate::shutdown::{Action, Shutdown, ShutdownPlan};
+use crate::snapshot::{Snapshot, StateFile};
+use crate::sse;
+use crate::storage::{MemoryStore, Storage};
+use crate::tags::{self, TagRule};
+use crate::telemetry::{self, Telemetry};
+use crate::throttle::{Throttle, ThrottlePlan};
+use crate::tls;
+use crate::topology::{Edge, Party, SERVER_ID};
+use crate::track::{self, Tracker};
+use crate::truncation::{Blobs, Truncation};
+use crate::tui::Tui;
+use crate::tunnel::{self, Framing};
+use crate::upstreamloss::{self, Diagnosis, Loss, LossNotice};
+use crate::upstreamqueue::{Overflow, Pushed, UpstreamQueue};
+use crate::verbosity;
+use crate::views::{self, LiveView, View};
+use crate::wasm::{Processors, Verdict};
+
+/// Rotated client and server logs kept with --max-log-size.
+pub(crate) const DEFAULT_MAX_LOG_FILES: usize = 5;
+
+const SHUTDOWN_TIMEOUT: Token = Token(1);
+const FLOOD_TIMEOUT: Token = Token(2);
+const PALETTE_TIMEOUT: Token = Token(3);
+const CLIENT_DELAY_TIMEOUT: Token = Token(4);
+const RECONNECT_TIMEOUT: Token = Token(5);
+const SERVER_DELAY_TIMEOUT: Token = Token(6);
+const CONTROL_TIMEOUT: Token = Token(7);
+const SETUP_TIMEOUT: Token = Token(8);
+const PREPARED_TIMEOUT: Token = Token(9);
+
+/// Settings of the proxy, as given with the flags of the command line.
+#[derive(Default)]
+pub struct Options {
+    pub renderers: Renderers,
+    pub self_check: bool,
+    pub max_memory: Option<usize>,
+    pub shedding: Option<Shedding>,
+    pub stats_interval: Option<u64>,
+    pub labels: Vec<(String, String)>,
+    pub shutdown: Vec<ShutdownPlan>,
+    pub interleave: Vec<InterleavePlan>,
+    pub flood: Vec<FloodPlan>,
+    pub faults: Vec<FaultPlan>,
+    pub fault_seed: Option<u64>,
+    pub delays: Vec<Latency>,
+    pub throttle: Vec<ThrottlePlan>,
+    pub deflate: Vec<DeflatePlan>,
+    pub observer_port: Option<u16>,
+    pub agent_port: Option<u16>,
+    pub devtools_port: Option<u16>,
+    pub control_port: Option<u16>,
+    pub prometheus_port: Option<u16>,
+    pub otlp_endpoint: Option<Url>,
+    pub agent_token: Option<Vec<u8>>,
+    pub recipients: Vec<String>,
+    pub retention: Option<Retention>,
+    pub sign_key: Option<Vec<u8>>,
+    pub anonymize: Option<String>,
+    pub anonymize_key: Option<Vec<u8>>,
+    pub log_max_payload: Option<usize>,
+    pub log_blobs: bool,
+    pub track: Vec<String>,
+    pub tag_rules: Vec<TagRule>,
+    pub split_rules: Vec<SplitRule>,
+    pub capture_frames: bool,
+    pub view: Option<View>,
+    pub plugins: Option<PathBuf>,
+    pub script: Option<PathBuf>,
+    pub tls: Option<SslAcceptor>,
+    pub upstream_tls: Option<SslConnector>,
+    pub alerts: Vec<AlertRule>,
+    pub alert_webhook: Option<Url>,
+    pub digest_every: Option<Duration>,
+    pub digest_webhook: Option<Url>,
+    pub metrics_every: Option<Duration>,
+    /// Messages whose time in each stage of the proxy is recorded.
+    pub overhead: Option<Rate>,
+    pub reconnect: Option<Backoff>,
+    pub resubscribe: Vec<Condition>,
+    pub on_upstream_loss: Option<LossNotice>,
+    pub upstream_queue: Option<usize>,
+    pub upstream_overflow: Overflow,
+    pub gap: Option<(Option<Leg>, Duration)>,
+    pub contract: Option<Contract>,
+    pub hop: Option<String>,
+    pub state: Option<PathBuf>,
+    pub fsync: Option<SyncPolicy>,
+    pub log_format: LogFormat,
+    pub max_log_size: Option<usize>,
+    pub max_log_files: Option<usize>,
+    pub log_per_connection: bool,
+    pub log_dir: Option<PathBuf>,
+    pub log_name: Option<String>,
+    pub no_files: Option<usize>,
+    pub console: Option<Duration>,
+    /// Traffic is shown in a terminal UI instead of printed.
+    pub tui: bool,
+    pub notify_clients: bool,
+    pub strict: bool,
+    pub sample_connections: Option<Rate>,
+    pub pausable: bool,
+    pub intercepts: Vec<Condition>,
+    pub probe_response: ProbeResponse,
+    pub command_line: Vec<String>,
+    pub protocols: Vec<String>,
+    pub auth: Option<Arc<dyn AuthProvider>>,
+    /// Commands are read from the standard input and signals stop the proxy,
+    /// as when it runs in a terminal.
+    pub terminal: bool,
+    /// Events of a Server-Sent Events endpoint are proxied instead of websockets.
+    pub sse: bool,
+    /// Bytes of a tcp:// or tls:// server are tunneled and cut into messages instead of websockets.
+    pub tunnel: Option<Framing>,
+    pub(crate) callbacks: Callbacks,
+    /// Time of delays and of the periodic reports, the system's unless it's simulated.
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+}
+
+/// Message passing through the proxy, as callbacks see it.
+pub struct MessageEvent<'a> {
+    pub id: MessageId,
+    pub from: Leg,
+    pub message: &'a Message,
+}
+
+/// Connection of a client or to the server opened or closed.
+pub enum ConnectionEvent {
+    Opened { connection_id: u32, leg: Leg },
+    Closed { connection_id: u32, leg: Leg, code: u16, reason: String },
+}
+
+type MessageCallback = Arc<dyn Fn(&MessageEvent) + Send + Sync>;
+type ConnectionCallback = Arc<dyn Fn(&ConnectionEvent) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct Callbacks {
+    pub(crate) messages: Vec<MessageCallback>,
+    pub(crate) connections: Vec<ConnectionCallback>,
+}
+
+/// Proxy embedded into another program, like a test which starts it in front of a server
+/// with `ProxyBuilder::new(url).on_message(...).start()`, connects clients to the address
+/// of the started proxy and stops it when done.
+pub struct ProxyBuilder {
+    upstream: Url,
+    address: SocketAddr,
+    options: Options,
+}
+
+impl ProxyBuilder {
+    /// Proxy to the server at the url, listening on a free port of the loopback interface.
+    pub fn new(upstream: Url) -> Self {
+        ProxyBuilder {
+            upstream,
+            address: SocketAddr::from(([127,0,0,1], 0)),
+            options: Options::default(),
+        }
+    }
+
+    pub fn listen(mut self, address: SocketAddr) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Everything else the command line can set. Callbacks and the clock given before are kept.
+    pub fn options(mut self, options: Options) -> Self {
+        let callbacks = mem::take(&mut self.options.callbacks);
+        let clock = self.options.clock.take();
+        self.options = Options { callbacks, clock, ..options };
+        self
+    }
+
+    /// Runs delays and periodic reports on the clock, like a simulated one in tests. Periodic
+    /// reports sleep on it in a loop, so an instant clock suits only proxies without them.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.options.clock = Some(clock);
+        self
+    }
+
+    /// Called for every message received by the proxy, whether it is forwarded or not.
+    pub fn on_message<F>(mut self, callback: F) -> Self
+        where F: Fn(&MessageEvent) + Send + Sync + 'static {
+        self.options.callbacks.messages.push(Arc::new(callback));
+        self
+    }
+
+    pub fn on_connection<F>(mut self, callback: F) -> Self
+        where F: Fn(&ConnectionEvent) + Send + Sync + 'static {
+        self.options.callbacks.connections.push(Arc::new(callback));
+        self
+    }
+
+    /// Runs the proxy in this thread until it is stopped.
+    pub fn run(self) -> std::result::Result<(), String> {
+        if self.options.sse {
+            return sse::serve(self.address, self.upstream, self.options);
+        }
+        if let Some(framing) = self.options.tunnel {
+            return tunnel::serve(self.address, self.upstream, framing, self.options);
+        }
+        serve(self.address, self.upstream, self.options, None)
+    }
+
+    /// Runs the proxy in a thread of its own, returns once it accepts clients.
+    pub fn start(self) -> std::result::Result<Proxy, String> {
+        if self.options.sse {
+            return Err("The proxy of event streams runs only in the foreground".to_string());
+        }
+        if self.options.tunnel.is_some() {
+            return Err("The proxy of tunnels runs only in the foreground".to_string());
+        }
+        let (started_tx, started) = mpsc::channel();
+        let thread = thread::spawn(move || serve(self.address, self.upstream, self.options, Some(started_tx)));
+        match started.recv() {
+            Ok((address, control)) => Ok(Proxy { address, control, thread }),
+            // The proxy failed before listening
+            Err(_) => Err(thread.join().map_err(|_| "The proxy panicked".to_string())?
+                .err().unwrap_or_else(|| "The proxy stopped".to_string())),
+        }
+    }
+}
+
+/// Proxy started with `ProxyBuilder::start`.
+pub struct Proxy {
+    address: SocketAddr,
+    control: Sender,
+    thread: JoinHandle<std::result::Result<(), String>>,
+}
+
+impl Proxy {
+    /// Address clients connect to.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Closes all connections and waits until the logs and the session are written.
+    pub fn stop(self) -> std::result::Result<(), String> {
+        self.control.shutdown().map_err(|e| format!("Can't stop the proxy: {}", e))?;
+        self.thread.join().map_err(|_| "The proxy panicked".to_string())?
+    }
+}
+
+/// Writes out the files kept in memory with --no-files.
+fn flush_memory(store: &MemoryStore) {
+    let (_, dropped) = store.usage();
+    match store.flush() {
+        Ok(written) if written.is_empty() => println!("Nothing to write yet"),
+        Ok(written) => {
+            let names: Vec<String> = written.iter().map(|path| path.display().to_string()).collect();
+            println!("Written {}", names.join(", "));
+            if dropped > 0 {
+                println!("{} oldest entries were dropped to stay within the memory limit", dropped);
+            }
+        },
+        Err(e) => {
+            error!("Error: {}", e);
+            println!("Failed to write files kept in memory: {}", e);
+        }
+    }
+}
+
+/// Runs the proxy until it is shut down. Once it listens, its address and a sender
+/// to stop it with are sent to `started`.
+fn serve(address: SocketAddr, server_url: Url, options: Options,
+         started: Option<mpsc::Sender<(SocketAddr, Sender)>>) -> std::result::Result<(), String> {
+    if options.upstream_tls.is_some() && server_url.scheme() != "wss" {
+        return Err("--upstream-ca, --upstream-cert and --insecure apply only to wss:// servers".to_string());
+    }
+    if !options.intercepts.is_empty() && !options.terminal {
+        return Err("--intercept needs a terminal to forward the held messages from".to_string());
+    }
+    if options.tui && !options.terminal {
+        return Err("--tui needs a terminal to draw in".to_string());
+    }
+    if options.strict {
+        // Everything which drops, delays, makes up or alters traffic
+        let mutating = [
+            ("--shutdown", !options.shutdown.is_empty()),
+            ("--interleave", !options.interleave.is_empty()),
+            ("--resubscribe", !options.resubscribe.is_empty()),
+            ("--flood", !options.flood.is_empty()),
+            ("--fault", !options.faults.is_empty()),
+            ("--delay", !options.delays.is_empty()),
+            ("--throttle", !options.throttle.is_empty()),
+            ("--deflate", !options.deflate.is_empty()),
+            ("--max-memory", options.max_memory.is_some()),
+            ("--agent-port", options.agent_port.is_some()),
+            ("--notify-clients", options.notify_clients),
+            ("--intercept", !options.intercepts.is_empty()),
+            ("--hop", options.hop.is_some()),
+            ("--on-upstream-loss json", options.on_upstream_loss == Some(LossNotice::Json)),
+            ("--state", options.state.is_some()),
+            ("--pausable", options.pausable),
+            ("--script", options.script.is_some()),
+            // Messages of clients are dropped while the server isn't connected
+            ("--upstream-queue 0", options.upstream_queue.is_none()),
+            ("--upstream-overflow other than close",
+                options.upstream_queue.is_some() && options.upstream_overflow != Overflow::Close),
+        ];
+        let given: Vec<&str> = mutating.iter().filter(|(_, given)| *given).map(|(flag, _)| *flag).collect();
+        if !given.is_empty() {
+            return Err(format!("--strict-passthrough can't be combined with {}", given.join(", ")));
+        }
+    }
+
+    // Pausable clients connect to the relay, which connects to the proxy on the loopback interface
+    let relay = if options.pausable { Some(Relay::bind(address)?) } else { None };
+    let delayed: Delayed = Rc::new(RefCell::new(HashMap::new()));
+
+    let server_label = server_url.to_string();
+    // Renderers can be changed with the control API from its own thread
+    let renderers = Arc::new(RwLock::new(options.renderers));
+
+    let mut headers = vec![];
+    // The next proxy of a chain records which hop its client is
+    if let Some(hop) = &options.hop {
+        headers.push((hops::HOP_HEADER.to_string(), hop.clone()));
+    }
+    let overflow = options.upstream_overflow;
+    let queue = options.upstream_queue.map(|size| UpstreamQueue::new(size, overflow));
+    let resubscribe = Some(options.resubscribe).filter(|conditions| !conditions.is_empty()).map(Resubscribe::new);
+    let mut upstream = Upstream::new(server_url.clone(), server_label.clone(), options.protocols, headers,
+        options.reconnect, queue, resubscribe);
+    upstream.auth = options.auth;
+    let topology = Topology::new(upstream);
+    let hop = options.hop.map(Rc::new);
+    let notify_clients = options.notify_clients;
+    let strict = options.strict;
+    let callbacks = options.callbacks;
+    let sampling = options.sample_connections.map(|rate| Rc::new(Sampling::new(rate)));
+    let self_check = if options.self_check || strict {
+        Some(Rc::new(RefCell::new(SelfCheck::new())))
+    } else {
+        None
+    };
+
+    let shedding = options.shedding.unwrap_or(Shedding::Drop);
+    let memory = MemoryMonitor::new(options.max_memory.map(|max_bytes| MemoryLimit {
+        max_bytes,
+        shedding
+    }));
+    let encryption = if options.recipients.is_empty() {
+        None
+    } else {
+        Some(Encryption::parse(&options.recipients).map_err(|e| format!("Logs can't be encrypted: {}", e))?)
+    };
+    let memory_store = options.no_files.map(MemoryStore::new);
+    if (memory_store.is_some() || options.console.is_some()) && encryption.is_some() {
+        return Err("--no-files and --console can't be combined with --encrypt-logs, nothing is written anyway"
+            .to_string());
+    }
+    let storage = match &memory_store {
+        Some(store) => Storage::Memory(store.clone()),
+        // Console-only mode keeps nothing at all
+        None if options.console.is_some() => Storage::Memory(MemoryStore::new(0)),
+        None => Storage::Disk(encryption.clone())
+    };
+    let log_queue = Rc::new(LogQueue::start(memory.clone(), storage.clone(),
+        options.fsync.unwrap_or(SyncPolicy::Never)));
+
+    let close_stats = Arc::new(Mutex::new(CloseStats::new()));
+
+    let observers = options.observer_port
+        .map(|port| Observers::start(port).map_err(|e| format!("Failed to listen for observers on port {}: {}", port, e)))
+        .transpose()?;
+
+    if let Some(interval) = options.stats_interval {
+        let memory = memory.clone();
+        let close_stats = close_stats.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(interval.max(1)));
+            info!("{}", memory.report());
+            info!("{}", close_stats.lock().unwrap().report());
+        });
+    }
+
+    let session = Session::start(Path::new(session::WORKSPACE), Utc::now(), address.port(), &storage)
+        .map_err(|e| format!("Failed to create session directory in {}: {}", session::WORKSPACE, e))?;
+    if options.console.is_some() {
+        info!("Session {} is only shown on the console", session.id());
+    } else if memory_store.is_some() {
+        info!("Session {} is kept in memory until SIGUSR1 writes it to {}", session.id(), session.dir().display());
+    } else {
+        info!("Session {} is recorded in {}", session.id(), session.dir().display());
+    }
+    if let (Some(retention), Storage::Disk(_)) = (options.retention, &storage) {
+        retention.spawn(PathBuf::from(session::WORKSPACE), session.dir().to_path_buf());
+    }
+
+    session.write_file(session::CONFIG, &json!({ "args": options.command_line }).to_string());
+    let capture = Rc::new(RefCell::new(open_log(&log_queue, &session.capture_path())?));
+    let segments = if options.split_rules.is_empty() {
+        None
+    } else {
+        Some(Rc::new(Segments::new(options.split_rules)))
+    };
+    if options.log_blobs && options.log_max_payload.is_none() {
+        return Err("--log-blobs requires --log-max-payload".to_string());
+    }
+    let tracker = if options.track.is_empty() {
+        None
+    } else {
+        let file = open_log(&log_queue, &session.dir().join(track::TRACK))?;
+        Some(Rc::new(Tracker::new(&options.track, file)?))
+    };
+    let clock = options.clock.clone().unwrap_or_else(clock::system);
+    let alerts = if options.alerts.is_empty() {
+        None
+    } else {
+        let capture = open_log(&log_queue, &session.capture_path())?;
+        Some(Rc::new(Alerts::start(options.alerts, capture, options.alert_webhook.clone(), clock.clone())))
+    };
+    let digest_webhook = options.digest_webhook;
+    let digests = options.digest_every
+        .map(|interval| open_log(&log_queue, &session.dir().join(digest::DIGEST))
+            .map(|file| Rc::new(Digests::start(interval, file, digest_webhook, clock.clone()))))
+        .transpose()?;
+    let metrics = options.metrics_every
+        .map(|interval| open_log(&log_queue, &session.dir().join(metrics::METRICS))
+            .map(|file| Rc::new(Metrics::start(interval, file, memory.clone(), clock.clone()))))
+        .transpose()?;
+    let overhead = options.overhead
+        .map(|rate| open_log(&log_queue, &session.dir().join(overhead::OVERHEAD))
+            .map(|file| Rc::new(Overhead::new(rate, file))))
+        .transpose()?;
+    let console = options.console.map(|interval| Rc::new(Console::start(interval)));
+    let devtools = options.devtools_port
+        .map(|port| DevTools::start(port, session.id())
+            .map_err(|e| format!("Failed to listen for devtools extensions on port {}: {}", port, e)))
+        .transpose()?;
+    let control = options.control_port
+        .map(|port| Control::start(port, renderers.clone(), CONTROL_TIMEOUT, strict)
+            .map_err(|e| format!("Failed to listen for the control API on port {}: {}", port, e)))
+        .transpose()?
+        .map(Rc::new);
+    let prometheus = options.prometheus_port
+        .map(|port| Prometheus::start(port)
+            .map_err(|e| format!("Failed to serve Prometheus metrics on port {}: {}", port, e)))
+        .transpose()?
+        .map(Rc::new);
+    let telemetry = options.otlp_endpoint.as_ref()
+        .map(|endpoint| Telemetry::install(endpoint, session.id()))
+        .transpose()?;
+    let mut contract = None;
+    if let Some(given) = &options.contract {
+        let (validator, violation) = given.validator(server_url.path());
+        if let Some(violation) = violation {
+            println!("Contract violation: {}", violation);
+            let record = session::violation_record(None, None, &violation);
+            open_log(&log_queue, &session.capture_path())?.write(format!("{}\n", record));
+        }
+        contract = Some(Rc::new(validator));
+    }
+    let gaps = options.gap
+        .map(|(legs, threshold)| open_log(&log_queue, &session.capture_path())
+            .map(|capture| Rc::new(Gaps::start(legs, threshold, capture, clock.clone()))))
+        .transpose()?;
+    let blobs = if options.log_blobs {
+        Some(Blobs::start(session.dir(), storage.clone())
+            .map_err(|e| format!("Failed to create blob store in {}: {}", session.dir().display(), e))?)
+    } else {
+        None
+    };
+    let truncation = options.log_max_payload.map(|max_bytes| Rc::new(Truncation::new(max_bytes, blobs)));
+    let anonymize_key = options.anonymize_key;
+    let anonymizer = options.anonymize
+        .map(|rules| Anonymizer::parse(&rules, anonymize_key).map_err(|e| format!("Invalid anonymization rules: {}", e)))
+        .transpose()?
+        .map(Rc::new);
+    let log_dir = match options.log_dir {
+        Some(dir) => dir,
+        // Encrypted files can't be appended to, and files in memory are written out
+        // as a whole session, so they are kept per session
+        None if storage.is_encrypted() || matches!(storage, Storage::Memory(_)) => session.dir().to_path_buf(),
+        None => PathBuf::new(),
+    };
+    let log_names = LogNames::new(log_dir, session.id(), options.log_name, options.log_per_connection);
+    storage.create_dir(log_names.dir())
+        .map_err(|e| format!("Failed to create log directory {}: {}", log_names.dir().display(), e))?;
+
+    let snapshot = options.state.as_ref().filter(|path| path.exists())
+        .map(|path| Snapshot::load(path).map_err(|e| format!("Failed to restore state: {}", e)))
+        .transpose()?;
+    let mut labels = options.labels;
+    let mut undelivered = vec![];
+    let mut restored = None;
+    if let Some(snapshot) = snapshot {
+        info!("State of session {} is restored from {}", snapshot.session,
+            options.state.as_ref().unwrap().display());
+        for (key, value) in snapshot.labels {
+            if !labels.iter().any(|(given, _)| *given == key) {
+                labels.push((key, value));
+            }
+        }
+        let mut stats = close_stats.lock().unwrap();
+        for (leg, code, initiator, count) in snapshot.close_codes {
+            stats.add(leg, code, initiator, count);
+        }
+        restored = Some(session::restored_record(&snapshot.session,
+            &options.state.as_ref().unwrap().display().to_string(), snapshot.undelivered.len()));
+        undelivered = snapshot.undelivered;
+    }
+    let state = match &options.state {
+        Some(path) => Some(Rc::new(StateFile::new(path, options.command_line.clone(), session.id(),
+            labels.clone(), undelivered))),
+        None => None
+    };
+
+    let session = Rc::new(RefCell::new(session));
+    let fingerprints = Rc::new(RefCell::new(HashSet::new()));
+    let tag_rules = Rc::new(options.tag_rules);
+    let view = LiveView::new(Path::new(views::VIEWS), options.view);
+    // Commands of the palette wake the event loop up through the connection to the server
+    let waker = Arc::new(Mutex::new(None));
+    // The terminal UI takes the keys typed, commands come from its command line instead
+    let (palette, prompt) = match (options.terminal, options.tui) {
+        (true, tui) => {
+            let composer = Composer::new(Path::new(composer::TEMPLATES), Path::new(composer::HISTORY), &server_url);
+            match tui {
+                true => {
+                    let (palette, prompt) = Palette::prompted(view.clone(), composer, waker.clone(),
+                        PALETTE_TIMEOUT, strict);
+                    (palette, Some(prompt))
+                },
+                false => (Palette::start(view.clone(), composer, waker.clone(), PALETTE_TIMEOUT, strict), None),
+            }
+        },
+        (false, _) => (Palette::detached(), None),
+    };
+    let palette = Rc::new(palette);
+    for condition in options.intercepts {
+        palette.intercept(condition);
+    }
+    // Plugins run with the rights of the proxy, so only those the user points at are loaded
+    let plugins = options.plugins;
+    let decoders = plugins.as_ref().map(|dir| Decoders::load(dir)).transpose()?;
+    let decoders = decoders.filter(|decoders| !decoders.is_empty()).map(Rc::new);
+    let processors = plugins.as_ref().map(|dir| Processors::load(dir)).transpose()?;
+    let processors = processors.filter(|processors| !processors.is_empty()).map(Rc::new);
+    let hooks = options.script.as_ref().map(|path| Hooks::load(path)).transpose()?.map(Rc::new);
+    if let (true, Some(dir)) = (strict && processors.is_some(), &plugins) {
+        return Err(format!("--strict-passthrough can't be combined with the WASM plugins in {}", dir.display()));
+    }
+    let labels = Rc::new(labels);
+    let capture_frames = options.capture_frames;
+    // NDJSON logs have messages only, the capture records the connections
+    let log_format = options.log_format;
+    let max_log_files = options.max_log_files.unwrap_or(DEFAULT_MAX_LOG_FILES);
+    let rotation = options.max_log_size.map(|max_size| Rotation { max_size: max_size as u64, max_files: max_log_files });
+    let shutdown = options.shutdown;
+    let interleave = options.interleave;
+    let flood = options.flood;
+    let deflate = options.deflate;
+    let faults = match options.faults.is_empty() {
+        true => None,
+        false => {
+            let faults = Faults::new(options.faults, options.fault_seed);
+            println!("Injecting faults with seed {}, repeat them with --fault-seed {}", faults.seed(), faults.seed());
+            Some(Rc::new(RefCell::new(faults)))
+        },
+    };
+    let latency = Rc::new(options.delays);
+    let throttle = match options.throttle.is_empty() {
+        true => None,
+        false => Some(Rc::new(RefCell::new(Throttle::new(options.throttle)))),
+    };
+    let tls = options.tls.map(Rc::new);
+    let upstream_tls = options.upstream_tls.map(Rc::new);
+    let mut settings = Settings { encrypt_server: tls.is_some(), ..Settings::default() };
+    if !flood.is_empty() || self_check.is_some() {
+        // Flooding messages must reach the peer as single frames,
+        // and the self-check compares whole messages with frames
+        settings.fragment_size = usize::MAX;
+    }
+    let record = session::session_record(session.borrow().id(), &server_label, address.port(), &labels,
+        hop.as_deref().map(String::as_str));
+    // Controllers learn which session they are attached to from the session record
+    let agent = match (options.agent_port, options.agent_token) {
+        (Some(port), Some(token)) => Some(Agent::start(port, token, record.clone())
+            .map_err(|e| format!("Failed to listen for controllers on port {}: {}", port, e))?),
+        (Some(_), None) => return Err("--agent-port requires --agent-token".to_string()),
+        (None, _) => None
+    };
+    {
+        let mut session = session.borrow_mut();
+        session.record(record);
+        if let Some(record) = restored {
+            session.record(record);
+        }
+    }
+
+    // Anything printed from now on goes to the output pane of the UI
+    let tui = prompt.map(Tui::start).transpose()?.map(Rc::new);
+    let mut last_client = 0;
+    let probe_response = options.probe_response;
+    let notice = options.on_upstream_loss;
+    let handler = |out: Sender, leg: Leg| {
+        let party = match leg {
+            Leg::Server => Party::Server,
+            Leg::Client => {
+                last_client += 1;
+                Party::Client(last_client)
+            },
+        };
+        let connection_id = party.connection_id();
+        let setup = match party {
+            Party::Server => topology.server.borrow().attempt.as_ref()
+                .map(|attempt| attempt.setup.clone())
+                .unwrap_or_else(Setup::start),
+            Party::Client(_) => Setup::start(),
+        };
+        let log_file = if party == Party::Server {
+            debug!("Creating handler for the server");
+            {
+                let mut upstream = topology.server.borrow_mut();
+                upstream.out = Some(out.clone());
+                upstream.requested = false;
+            }
+            *waker.lock().unwrap() = Some(out.clone());
+
+            let file = open_rotated_log(&log_queue, &log_names.server(), rotation);
+            if let (Ok(file), LogFormat::Text) = (&file, log_format) {
+                file.write(format!("{} Proxy connected to the server at {}\n",
+                    Utc::now(), server_label));
+            }
+            file
+        } else {
+            debug!("Creating handler for a client");
+            // It joins the clients once its request turns out to be a websocket upgrade
+            open_rotated_log(&log_queue, &log_names.client(connection_id), rotation)
+        };
+        // The connection is proxied all the same, only without its log
+        let log_file = log_file.map_err(|e| error!("{}", e)).ok();
+
+        Handler {
+            party,
+            topology: topology.clone(),
+            out,
+            connection_id,
+            sequence: Cell::new(0),
+            headers: vec![],
+            environment: Environment::default(),
+            opened: Instant::now(),
+            setup,
+            tls_progress: None,
+            last_message: Instant::now(),
+            gap: Duration::ZERO,
+            log_file,
+            log_format,
+            memory: memory.clone(),
+            self_check: self_check.clone(),
+            session: session.clone(),
+            labels: labels.clone(),
+            close_stats: close_stats.clone(),
+            close_sent: false,
+            loss: Loss::default(),
+            notice,
+            shutdown: shutdown.iter()
+                .find(|plan| plan.leg == leg)
+                .map(|plan| Shutdown::new(*plan)),
+            interleave: interleave.iter()
+                .find(|plan| plan.leg == leg)
+                .map(|plan| Interleave::new(plan.clone())),
+            interleave_reported: false,
+            capture: capture.clone(),
+            segments: segments.clone(),
+            capture_frames,
+            log_queue: log_queue.clone(),
+            anonymizer: anonymizer.clone(),
+            truncation: truncation.clone(),
+            tracker: tracker.clone(),
+            tag_rules: tag_rules.clone(),
+            view: view.clone(),
+            palette: palette.clone(),
+            delayed: delayed.clone(),
+            clock: clock.clone(),
+            latency: latency.clone(),
+            throttle: throttle.clone(),
+            decoders: decoders.clone(),
+            processors: processors.clone(),
+            hooks: hooks.clone(),
+            alerts: alerts.clone(),
+            digests: digests.clone(),
+            metrics: metrics.clone(),
+            overhead: overhead.clone(),
+            timing: RefCell::new(None),
+            inflating: Duration::ZERO,
+            gaps: gaps.clone(),
+            console: console.clone(),
+            tui: tui.clone(),
+            contract: contract.clone(),
+            hop: hop.clone(),
+            notify_clients,
+            strict,
+            callbacks: callbacks.clone(),
+            sampled: true,
+            requested: false,
+            probe: None,
+            probe_response,
+            sampling: sampling.clone(),
+            relay: relay.clone(),
+            state: state.clone(),
+            observers: observers.clone(),
+            agent: agent.clone(),
+            devtools: devtools.clone(),
+            control: control.clone(),
+            prometheus: prometheus.clone(),
+            span: tracing::Span::none(),
+            fingerprints: fingerprints.clone(),
+            tls: tls.clone(),
+            upstream_tls: upstream_tls.clone(),
+            flood: flood.iter()
+                .find(|plan| plan.leg == leg)
+                .map(|plan| Flood::new(*plan)),
+            deflate_plan: deflate.iter().find(|plan| plan.leg == leg).copied(),
+            deflate: None,
+            faults: faults.clone(),
+            renderers: renderers.clone()
+        }
+    };
+    let ws = Builder::new()
+        .with_settings(settings)
+        .build(Connections {
+            handler,
+            topology: topology.clone(),
+        })
+        .map_err(|e| format!("Can't set up the proxy: {}", e))?;
+    topology.server.borrow_mut().dialer = Some(ws.broadcaster());
+
+    if options.terminal {
+        let broadcaster = ws.broadcaster();
+        let store = memory_store.clone();
+        let mut signals = if store.is_some() {
+            Signals::new([SIGINT, SIGTERM, SIGUSR1]).unwrap()
+        } else {
+            Signals::new([SIGINT, SIGTERM]).unwrap()
+        };
+        thread::spawn(move || {
+            let mut stopping = false;
+            for signal in signals.forever() {
+                if let (SIGUSR1, Some(store)) = (signal, &store) {
+                    flush_memory(store);
+                    continue;
+                }
+                if stopping {
+                    std::process::exit(0);
+                }
+                stopping = true;
+                info!("Shutting down");
+                broadcaster.shutdown().unwrap_or_else(|e| {
+                    error!("Error: {}", e);
+                    std::process::exit(0);
+                });
+            }
+        });
+    }
+
+    let bound = if relay.is_some() { SocketAddr::from(([127, 0, 0, 1], 0)) } else { address };
+    let ws = ws.bind(bound).map_err(|e| format!("Can't listen on {}: {}", bound, e))?;
+    let mut address = ws.local_addr().map_err(|e| e.to_string())?;
+    if let Some(relay) = &relay {
+        relay.start(address)?;
+        address = relay.address();
+    }
+    info!("Listening on {}, redirecting messages to {} once a client connects", address, server_label);
+    if let Some(started) = started {
+        started.send((address, ws.broadcaster())).ok();
+    }
+    let ws = ws.run().map_err(|e| format!("The proxy failed: {}", e));
+
+    // Files are complete only after all handles to them are dropped
+    let session_dir = session.borrow().dir().to_path_buf();
+    drop(ws?);
+    drop(tui);
+    drop(telemetry);
+    drop(capture);
+    drop(truncation);
+    drop(tracker);
+    drop(alerts);
+    drop(digests);
+    drop(metrics);
+    drop(overhead);
+    drop(gaps);
+    if let Some(state) = &state {
+        state.save(&close_stats.lock().unwrap());
+    }
+    if let Some(sampling) = &sampling {
+        let (connections, messages, _) = sampling.skipped_counts();
+        info!("Sampling {} of client connections left out {} connections with {} messages",
+            sampling.rate(), connections, messages);
+        session.borrow_mut().record(session::sampling_record(sampling));
+    }
+    drop(session);
+    log_queue.finish();
+    if options.terminal {
+        println!("{}", close_stats.lock().unwrap().report());
+    } else {
+        info!("{}", close_stats.lock().unwrap().report());
+    }
+
+    if let Some(store) = &memory_store {
+        let (size, _) = store.usage();
+        println!("{} of session {} kept in memory since the last SIGUSR1 are discarded",
+            memory::format_size(size), session_dir.display());
+    }
+
+    if let (Some(key), true) = (options.sign_key, session_dir.exists()) {
+        manifest::write(&session_dir, &key).unwrap_or_else(|e| {
+            error!("Error: {}", e);
+            println!("Failed to write the manifest of session {}", session_dir.display());
+        });
+    }
+
+    match self_check.map(|check| check.borrow().violations()) {
+        Some(violations) if strict && violations > 0 => Err(format!("{} messages failed the integrity check", violations)),
+        _ => Ok(()),
+    }
+}
+
+struct Handler {
+    party: Party,
+    topology: Topology,
+    out: Sender,
+    connection_id: u32,
+    sequence: Cell<u64>,
+    /// Handshake headers of the peer with lowercase names, for conditions on its messages.
+    headers: Vec<(String, String)>,
+    /// Variables of the connection for conditions, known once it's open.
+    environment: Environment,
+    opened: Instant,
+    /// Timing of setting the connection up, recorded when it opens.
+    setup: Setup,
+    /// Progress of the TLS handshake with the server.
+    tls_progress: Option<TlsProgress>,
+    last_message: Instant,
+    /// Time between the last message and the one before it, or the opening.
+    gap: Duration,
+    log_file: Option<LogFile>,
+    log_format: LogFormat,
+    memory: MemoryMonitor,
+    self_check: Option<Rc<RefCell<SelfCheck>>>,
+    /// Stops the proxy at the first message failing the self-check.
+    strict: bool,
+    callbacks: Callbacks,
+    /// Whether the connection gets into the logs and the index, or is only counted.
+    sampled: bool,
+    /// The handshake request of the client was read.
+    requested: bool,
+    /// What the connection turned out to be if it's not a websocket client.
+    probe: Option<ProbeKind>,
+    probe_response: ProbeResponse,
+    sampling: Option<Rc<Sampling>>,
+    relay: Option<Relay>,
+    session: Rc<RefCell<Session>>,
+    labels: Rc<Vec<(String, String)>>,
+    close_stats: Arc<Mutex<CloseStats>>,
+    close_sent: bool,
+    /// How the connection to the server ended, told to clients with --on-upstream-loss.
+    loss: Loss,
+    notice: Option<LossNotice>,
+    shutdown: Option<Shutdown>,
+    interleave: Option<Interleave>,
+    interleave_reported: bool,
+    /// Current segment of the capture, replaced at session boundaries.
+    capture: Rc<RefCell<LogFile>>,
+    segments: Option<Rc<Segments>>,
+    /// Control frames received are captured along with the messages.
+    capture_frames: bool,
+    log_queue: Rc<LogQueue>,
+    anonymizer: Option<Rc<Anonymizer>>,
+    truncation: Option<Rc<Truncation>>,
+    tracker: Option<Rc<Tracker>>,
+    tag_rules: Rc<Vec<TagRule>>,
+    view: LiveView,
+    palette: Rc<Palette>,
+    delayed: Delayed,
+    clock: Arc<dyn Clock>,
+    /// Latency added to the messages of each direction, the last one given for it wins.
+    latency: Rc<Vec<Latency>>,
+    /// Token buckets of the edges, given with --throttle.
+    throttle: Option<Rc<RefCell<Throttle>>>,
+    decoders: Option<Rc<Decoders>>,
+    /// WASM plugins inspecting, replacing and dropping messages.
+    processors: Option<Rc<Processors>>,
+    /// Hooks of the script given with --script, after the plugins.
+    hooks: Option<Rc<Hooks>>,
+    alerts: Option<Rc<Alerts>>,
+    digests: Option<Rc<Digests>>,
+    metrics: Option<Rc<Metrics>>,
+    overhead: Option<Rc<Overhead>>,
+    /// Stopwatch of the message being handled, if it's measured.
+    timing: RefCell<Option<Timing>>,
+    /// Time spent inflating the frames of the next message.
+    inflating: Duration,
+    gaps: Option<Rc<Gaps>>,
+    console: Option<Rc<Console>>,
+    tui: Option<Rc<Tui>>,
+    contract: Option<Rc<Validator>>,
+    hop: Option<Rc<String>>,
+    notify_clients: bool,
+    state: Option<Rc<StateFile>>,
+    observers: Option<Observers>,
+    agent: Option<Agent>,
+    devtools: Option<DevTools>,
+    control: Option<Rc<Control>>,
+    prometheus: Option<Rc<Prometheus>>,
+    /// Span of the connection, the parent of the spans of its messages, given with --otlp-endpoint.
+    span: tracing::Span,
+    /// Fingerprints of the clients seen in the session.
+    fingerprints: Rc<RefCell<HashSet<String>>>,
+    tls: Option<Rc<SslAcceptor>>,
+    upstream_tls: Option<Rc<SslConnector>>,
+    flood: Option<Flood>,
+    /// permessage-deflate offered to the server or accepted from the client, given with --deflate.
+    deflate_plan: Option<DeflatePlan>,
+    /// Compression agreed in the handshake of the connection.
+    deflate: Option<Deflate>,
+    /// Faults injected into the forwarded messages, shared by all connections.
+    faults: Option<Rc<RefCell<Faults>>>,
+    renderers: Arc<RwLock<Renderers>>,
+}
+
+/// Connected clients by their connection ids, messages of the server are sent to all of them.
+type Clients = Rc<RefCell<BTreeMap<u32, Sender>>>;
+
+/// Delayed messages by the edges they are sent along, in the order they came. Each one has
+/// a timeout of the client at an end of its edge, which sends the first one, so that messages
+/// along an edge keep their order with random delays.
+type Delayed = Rc<RefCell<HashMap<Edge, VecDeque<(Sender, Message)>>>>;
+
+/// Connection to the server, made when a client comes and none is open,
+/// so that the proxy starts even while the server is down, and made again
+/// with a growing delay when it's lost while clients stay connected.
+struct Upstream {
+    out: Option<Sender>,
+    /// A client asked for the connection and its handler isn't made yet.
+    requested: bool,
+    /// The handshake with the server is done.
+    open: bool,
+    /// Messages of clients held until the connection is open, given with --upstream-queue.
+    queue: Option<UpstreamQueue>,
+    /// Setup messages of clients sent again once the connection is open again.
+    resubscribe: Option<Resubscribe>,
+    url: Url,
+    /// Address of the server without credentials, for the logs.
+    label: String,
+    /// Subprotocols and headers added to the handshake request, like the hop.
+    protocols: Vec<String>,
+    headers: Vec<(String, String)>,
+    /// Source of credentials asked for before every attempt, since they may expire.
+    auth: Option<Arc<dyn AuthProvider>>,
+    backoff: Option<Backoff>,
+    /// Attempts to connect since the connection was last open.
+    attempts: u32,
+    /// Client whose timeout makes the next attempt.
+    retry: Option<u32>,
+    stopping: bool,
+    /// Attempt being prepared on a helper thread.
+    preparation: Option<Arc<Mutex<Preparation>>>,
+    /// Attempt prepared last, until the connection opens or all its addresses fail.
+    attempt: Option<Attempt>,
+    /// Sender of no connection, which ws only logs failures to connect of, while
+    /// failures to connect through a client's connection would close the client.
+    dialer: Option<Sender>,
+}
+
+/// Attempt to connect to the server. The server is resolved on a helper thread, since ws
+/// would resolve it on the event loop, holding up all clients meanwhile. ws connects to
+/// an address of it, while the request, its Host header, and the name the TLS certificate
+/// is verified for and sent with SNI are those of the url.
+struct Attempt {
+    url: Url,
+    headers: Vec<(String, String)>,
+    /// IPv4 addresses of the server not tried yet, the next one is tried when one fails.
+    addresses: VecDeque<SocketAddr>,
+    /// The server has IPv6 addresses, tried last by the url itself, since ws can't parse
+    /// urls of IPv6 addresses and resolves the server again on the event loop for them.
+    named: bool,
+    setup: Setup,
+}
+
+/// Attempt being prepared, with the client whose timeout takes it to the event loop.
+struct Preparation {
+    result: Option<std::result::Result<Attempt, (Diagnosis, String)>>,
+    waker: Option<(u32, Sender)>,
+}
+
+impl Preparation {
+    /// Wakes the client up once the attempt is prepared.
+    fn wake(&self) {
+        if let (Some(_), Some((client, out))) = (&self.result, &self.waker) {
+            out.timeout(0, PREPARED_TIMEOUT).unwrap_or_else(|e| {
+                error!("Error: client {} can't take the connection to the server: {}", client, e)
+            });
+        }
+    }
+}
+
+/// Obtains credentials, resolves the server and times connecting to it, on a helper thread.
+fn prepare(url: Url, mut headers: Vec<(String, String)>, auth: Option<Arc<dyn AuthProvider>>)
+           -> std::result::Result<Attempt, (Diagnosis, String)> {
+    let mut setup = Setup::start();
+    let url = match auth {
+        Some(provider) => {
+            let credentials = auth::obtain(provider, &url).map_err(|e| (Diagnosis::Auth, e))?;
+            setup.authorized();
+            let (url, mut credentials) = credentials.apply(&url);
+            credentials.append(&mut headers);
+            headers = credentials;
+            url
+        },
+        None => url,
+    };
+    let mut addresses = url.socket_addrs(|| None).map_err(|e| (Diagnosis::Dns, e.to_string()))?;
+    setup.resolved();
+    addresses.dedup();
+    if addresses.is_empty() {
+        return Err((Diagnosis::Dns, "no addresses are found".to_string()));
+    }
+    let named = addresses.iter().any(SocketAddr::is_ipv6);
+    // ws tries the IPv4 addresses first
+    if let Some(address) = addresses.iter().find(|address| address.is_ipv4()).or_else(|| addresses.first()) {
+        setup.connect(*address);
+    }
+    addresses.retain(SocketAddr::is_ipv4);
+    Ok(Attempt { url, headers, addresses: addresses.into(), named, setup })
+}
+
+impl Upstream {
+    fn new(url: Url, label: String, protocols: Vec<String>, headers: Vec<(String, String)>,
+           backoff: Option<Backoff>, queue: Option<UpstreamQueue>, resubscribe: Option<Resubscribe>) -> Self {
+        Upstream {
+            out: None,
+            requested: false,
+            open: false,
+            queue,
+            resubscribe,
+            url,
+            label,
+            protocols,
+            headers,
+            auth: None,
+            backoff,
+            attempts: 0,
+            retry: None,
+            stopping: false,
+            preparation: None,
+            attempt: None,
+            dialer: None,
+        }
+    }
+
+    /// Takes the attempts made before the connection opened and the messages queued meanwhile.
+    fn opened(&mut self) -> (u32, VecDeque<(u32, Message)>) {
+        self.open = true;
+        self.attempt = None;
+        let queued = self.queue.as_mut().map(UpstreamQueue::take).unwrap_or_default();
+        (std::mem::take(&mut self.attempts), queued)
+    }
+
+    /// Setup messages clients sent over the previous connections, to be sent over this one.
+    fn replays(&self) -> Vec<(u32, Message)> {
+        self.resubscribe.as_ref().map(Resubscribe::replays).unwrap_or_default()
+    }
+
+    /// Connects through the connection of a client, unless the server is connected,
+    /// being connected, or waiting for the next attempt. The attempt is prepared on
+    /// a helper thread, and made on a timeout of the client once it is.
+    fn connect(&mut self, out: &Sender, client: u32) {
+        if self.out.is_some() || self.retry.is_some() {
+            return;
+        }
+        if self.requested {
+            // The clients waiting for the attempt may have all left
+            if let Some(preparation) = &self.preparation {
+                let mut preparation = preparation.lock().unwrap();
+                if preparation.waker.is_none() {
+                    preparation.waker = Some((client, out.clone()));
+                    preparation.wake();
+                }
+            }
+            return;
+        }
+        self.requested = true;
+        info!("Connecting to {} for client {}", self.label, client);
+        let preparation = Arc::new(Mutex::new(Preparation { result: None, waker: Some((client, out.clone())) }));
+        self.preparation = Some(preparation.clone());
+        let (url, headers, auth) = (self.url.clone(), self.headers.clone(), self.auth.clone());
+        thread::spawn(move || {
+            let result = prepare(url, headers, auth);
+            let mut preparation = preparation.lock().unwrap();
+            preparation.result = Some(result);
+            preparation.wake();
+        });
+    }
+
+    /// Connects to the first address of the attempt prepared, or tells why it can't be made.
+    fn prepared(&mut self) -> std::result::Result<(), Value> {
+        let result = match self.preparation.as_ref().and_then(|preparation| preparation.lock().unwrap().result.take()) {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        self.preparation = None;
+        match result {
+            Ok(attempt) => {
+                self.attempt = Some(attempt);
+                self.dial();
+                Ok(())
+            },
+            Err((diagnosis, error)) => {
+                error!("Error: can't connect to {}: {}", self.label, error);
+                Err(upstreamloss::unreachable(diagnosis, &error))
+            },
+        }
+    }
+
+    /// Has ws connect to the next address of the attempt, if any is left.
+    fn dial(&mut self) -> bool {
+        let (attempt, dialer) = match (&mut self.attempt, &self.dialer) {
+            (Some(attempt), Some(dialer)) => (attempt, dialer),
+            _ => return false,
+        };
+        let url = match attempt.addresses.pop_front() {
+            Some(address) => {
+                let mut url = attempt.url.clone();
+                url.set_ip_host(address.ip()).ok();
+                url.set_port(Some(address.port())).ok();
+                url
+            },
+            None if attempt.named => {
+                attempt.named = false;
+                attempt.url.clone()
+            },
+            None => return false,
+        };
+        self.requested = true;
+        attempt.setup.dialed();
+        dialer.connect(url).unwrap_or_else(|e| {
+            error!("Error: can't connect to {}: {}", self.label, e)
+        });
+        true
+    }
+
+    /// Tries the next address of the server once the connection to one failed before opening,
+    /// while clients wait for it.
+    fn fallback(&mut self, clients: &Clients) -> bool {
+        let next = match &self.attempt {
+            Some(attempt) if !attempt.addresses.is_empty() || attempt.named => attempt,
+            _ => return false,
+        };
+        if self.open || self.stopping || clients.borrow().is_empty() {
+            return false;
+        }
+        match next.addresses.front() {
+            Some(address) => warn!("Connecting to {} failed, trying its address {}", self.label, address),
+            None => warn!("Connecting to {} failed, trying its IPv6 addresses", self.label),
+        }
+        self.out = None;
+        self.dial()
+    }
+
+    /// Forgets the connection, and schedules the next attempt while clients are connected.
+    fn lost(&mut self, clients: &Clients) {
+        self.out = None;
+        self.requested = false;
+        self.open = false;
+        self.preparation = None;
+        self.attempt = None;
+        if !self.stopping && self.backoff.is_some() {
+            self.schedule(clients);
+        }
+    }
+
+    /// Schedules the next attempt on the timeout of the first connected client.
+    fn schedule(&mut self, clients: &Clients) {
+        self.retry = None;
+        let (backoff, (client, out)) = match (self.backoff, clients.borrow().iter().next()) {
+            (Some(backoff), Some((client, out))) => (backoff, (*client, out.clone())),
+            _ => return,
+        };
+        let delay = backoff.delay(self.attempts);
+        self.attempts += 1;
+        warn!("Connecting to {} again in {} ms, attempt {}", self.label, delay.as_millis(), self.attempts);
+        match out.timeout(delay.as_millis() as u64, RECONNECT_TIMEOUT) {
+            Ok(()) => self.retry = Some(client),
+            Err(e) => error!("Error: can't schedule connecting to {}: {}", self.label, e),
+        }
+    }
+
+    /// Makes the attempt scheduled on the timeout of the client.
+    fn retry(&mut self, out: &Sender, client: u32) {
+        if self.retry == Some(client) {
+            self.retry = None;
+            self.connect(out, client);
+        }
+    }
+
+    /// Drops the queued messages of the client, which is closed, and moves the attempt
+    /// scheduled on its timeout to another one. Returns how many messages are dropped.
+    fn left(&mut self, clients: &Clients, client: u32) -> usize {
+        if let Some(preparation) = &self.preparation {
+            let mut preparation = preparation.lock().unwrap();
+            if preparation.waker.as_ref().is_some_and(|(waker, _)| *waker == client) {
+                preparation.waker = clients.borrow().iter().next().map(|(id, out)| (*id, out.clone()));
+                preparation.wake();
+            }
+        }
+        let dropped = self.queue.as_mut().map(|queue| queue.forget(client)).unwrap_or_default();
+        if let Some(resubscribe) = &mut self.resubscribe {
+            resubscribe.forget(client);
+        }
+        if dropped > 0 {
+            warn!("{} queued messages of client {} to the server are dropped", dropped, client);
+        }
+        if self.retry == Some(client) {
+            self.attempts = self.attempts.saturating_sub(1);
+            self.schedule(clients);
+        }
+        dropped
+    }
+}
+
+type Server = Rc<RefCell<Upstream>>;
+
+/// Connections of a client and to the server, told apart by ws, which numbers them in
+/// the order they are made. The proxy gives them ids of its own instead, the server's
+/// is always 0, and forgets the server when its connection is lost.
+struct Connections<F> {
+    handler: F,
+    topology: Topology,
+}
+
+impl<F: FnMut(Sender, Leg) -> Handler> Factory for Connections<F> {
+    type Handler = Handler;
+
+    fn connection_made(&mut self, out: Sender) -> Handler {
+        (self.handler)(out, Leg::Client)
+    }
+
+    fn client_connected(&mut self, out: Sender) -> Handler {
+        (self.handler)(out, Leg::Server)
+    }
+
+    fn connection_lost(&mut self, mut handler: Handler) {
+        if handler.party == Party::Server {
+            if self.topology.server.borrow_mut().fallback(&self.topology.clients) {
+                return;
+            }
+            let stopping = self.topology.server.borrow().stopping;
+            if let (Some(notice), false) = (handler.notice, stopping) {
+                handler.close_clients(notice, handler.loss.reason());
+            }
+            self.topology.server.borrow_mut().lost(&self.topology.clients);
+        } else {
+            handler.lost();
+        }
+    }
+
+    fn on_shutdown(&mut self) {
+        self.topology.server.borrow_mut().stopping = true;
+    }
+}
+
+/// Parties connected through the proxy and the edges between them: messages of every
+/// client go to the server, messages of the server go to every client. Handlers forward
+/// along the edges of their party, so that other parties only need edges of their own.
+#[derive(Clone)]
+struct Topology {
+    server: Server,
+    clients: Clients,
+}
+
+impl Topology {
+    fn new(server: Upstream) -> Self {
+        Topology { server: Rc::new(RefCell::new(server)), clients: Rc::new(RefCell::new(BTreeMap::new())) }
+    }
+
+    /// Connections of the clients with their ids.
+    fn clients(&self) -> Vec<(u32, Sender)> {
+        self.clients.borrow().iter().map(|(id, out)| (*id, out.clone())).collect()
+    }
+
+    /// Connection of the party, unless it's not connected.
+    fn sender(&self, party: Party) -> Option<Sender> {
+        match party {
+            Party::Server => self.server.borrow().out.clone(),
+            Party::Client(id) => self.clients.borrow().get(&id).cloned(),
+        }
+    }
+
+    /// Edges of the messages of the party, with the connections of the parties they lead to.
+    fn edges(&self, from: Party) -> Vec<(Edge, Sender)> {
+        match from {
+            Party::Client(_) => self.sender(Party::Server).into_iter()
+                .map(|out| (Edge::new(from, Party::Server), out))
+                .collect(),
+            Party::Server => self.clients().into_iter()
+                .map(|(id, out)| (Edge::new(from, Party::Client(id)), out))
+                .collect(),
+        }
+    }
+}
+
+impl Handler {
+    /// Applies the shedding policy if the memory limit is exceeded.
+    /// Returns true if the message must not be forwarded.
+    fn shed(&self, msg: &Message) -> bool {
+        let shedding = match self.memory.shedding() {
+            None => return false,
+            Some(shedding) => shedding
+        };
+        let diff = json!({ "removed": { "type": message_kind(msg), "size": msg.len() } });
+        self.provenance("memory-limit", "dropped", Some(self.next_id().to_string()), diff);
+        match shedding {
+            Shedding::Drop => {
+                debug!("Memory limit exceeded, message is dropped");
+                self.memory.dropped();
+                true
+            },
+            Shedding::Close => {
+                let clients = match self.party {
+                    Party::Server => self.topology.clients(),
+                    Party::Client(_) => vec![(self.connection_id, self.out.clone())]
+                };
+                for (id, client) in clients {
+                    warn!("Memory limit exceeded, closing connection {}", id);
+                    client.close_with_reason(CloseCode::Again, "Proxy memory limit exceeded").ok();
+                }
+                self.memory.dropped();
+                true
+            }
+        }
+    }
+
+    /// Makes a client whose request is a websocket upgrade one of the parties. The first
+    /// client, or the first one since the server closed, connects to the server.
+    fn join(&mut self) {
+        self.topology.server.borrow_mut().connect(&self.out, self.connection_id);
+        self.topology.clients.borrow_mut().insert(self.connection_id, self.out.clone());
+        self.sampled = self.sampling.as_ref().map(|sampling| sampling.admit(self.connection_id)).unwrap_or(true);
+        if let (true, LogFormat::Text, Some(file)) = (self.sampled, self.log_format, &self.log_file) {
+            file.write(format!("{} Client connected to the proxy with id {}\n",
+                Utc::now(), self.connection_id));
+        }
+    }
+
+    /// Forgets a client which ws dropped without closing it, like one whose handshake
+    /// failed, and records connections which sent nothing as probes.
+    fn lost(&mut self) {
+        let stopping = self.topology.server.borrow().stopping;
+        if !self.requested && self.probe.is_none() && !stopping {
+            self.probed(ProbeKind::Empty, None, None, None);
+        }
+        if self.topology.clients.borrow_mut().remove(&self.connection_id).is_some() {
+            let dropped = self.topology.server.borrow_mut().left(&self.topology.clients, self.connection_id);
+            self.withdraw(dropped);
+        }
+        if let Some(control) = &self.control {
+            control.closed(self.connection_id);
+        }
+    }
+
+    /// Records a connection to the proxy port which isn't a websocket client.
+    fn probed(&mut self, kind: ProbeKind, request: Option<&Request>, response: Option<&Response>,
+              error: Option<String>) {
+        info!("Connection {} is not a websocket client but a {} probe", self.connection_id, kind.name());
+        self.probe = Some(kind);
+        let record = session::probe_record(self.connection_id, kind, request, response, error);
+        self.session.borrow_mut().record(record);
+    }
+
+    /// Sends a message of the party along its edges: messages of clients to the server,
+    /// and messages of the server to every client, as the settings of each client allow.
+    fn forward(&self, id: MessageId, from: Party, msg: Message) {
+        self.stage(Stage::Send);
+        if let Party::Client(client) = from {
+            let mut upstream = self.topology.server.borrow_mut();
+            if let (false, Some(queue)) = (upstream.open, upstream.queue.as_mut()) {
+                self.queue(queue, id, msg);
+                return;
+            }
+            // Kept even when the server isn't connected, the next connection gets it
+            if let Some(resubscribe) = &mut upstream.resubscribe {
+                resubscribe.sent(client, &self.facts(id, from.leg(), &msg));
+            }
+        }
+        let edges = self.topology.edges(from);
+        debug!("Forwarding message {} from {} along {} edges", id, from, edges.len());
+
+        let facts = self.facts(id, from.leg(), &msg);
+        for (edge, target) in edges.iter() {
+            let client = edge.client().unwrap_or(SERVER_ID);
+            if let (Party::Server, Some(rule)) = (from, self.palette.drops_for(client, &facts)) {
+                debug!("Message {} is not sent to connection {} by rule {}", id, client, rule);
+                if let Some(check) = &self.self_check {
+                    check.borrow_mut().skipped(id, client);
+                }
+                let diff = json!({ "removed": { "type": message_kind(&msg), "size": msg.len(), "to": client } });
+                self.provenance(&format!("palette {}", rule), "dropped", Some(id.to_string()), diff);
+                continue;
+            }
+            // The bandwidth of a client given with :on replaces that of --throttle
+            let wait = self.palette.throttle(client, *edge, msg.len())
+                .or_else(|| self.throttle.as_ref().map(|throttle| throttle.borrow_mut().wait(*edge, msg.len())))
+                .unwrap_or_default();
+            let delay = self.palette.delay(client).unwrap_or_default() + self.latency(from.leg()) + wait;
+            // Messages aren't sent before the delayed ones of their edge
+            let waiting = self.delayed.borrow().get(edge).is_some_and(|queue| !queue.is_empty());
+            match delay.is_zero() && !waiting {
+                true => self.send(edge.to.connection_id(), target, msg.clone()),
+                false => self.delay(*edge, target, msg.clone(), delay),
+            }
+        }
+        if !edges.is_empty() {
+            return;
+        }
+        match (from, &self.state) {
+            (Party::Client(_), _) => warn!("No connection to the server is open, message {} is not delivered", id),
+            (Party::Server, Some(state)) => {
+                debug!("No client is connected, message from server is kept");
+                state.undelivered(id, msg);
+                state.changed(&self.close_stats.lock().unwrap());
+            },
+            (Party::Server, None) => warn!("No client is connected, message from server is not delivered"),
+        }
+    }
+
+    /// Holds a message of the client until the connection to the server is open.
+    fn queue(&self, queue: &mut UpstreamQueue, id: MessageId, msg: Message) {
+        let client = id.connection_id;
+        let pushed = queue.push(client, msg);
+        if let Some(check) = &self.self_check {
+            let why = "the queue of messages to the server is full";
+            match pushed {
+                Pushed::Kept(0) => (),
+                Pushed::Kept(dropped) => check.borrow_mut().dropped_oldest(SERVER_ID, dropped, why),
+                Pushed::Dropped | Pushed::Refused => check.borrow_mut().dropped(id, SERVER_ID, why),
+            }
+        }
+        match pushed {
+            Pushed::Kept(0) => debug!("Message {} is queued until the server is connected", id),
+            Pushed::Kept(dropped) => warn!("{} queued messages to the server are dropped for message {}", dropped, id),
+            Pushed::Dropped => warn!("Queue of messages to the server is full, message {} is dropped", id),
+            Pushed::Refused => {
+                warn!("Queue of messages to the server is full, client {} is closed", client);
+                if let Some(out) = self.topology.sender(Party::Client(client)) {
+                    out.close_with_reason(CloseCode::Again, "Too many messages while the server is unavailable")
+                        .unwrap_or_else(|e| warn!("Connection {} is not closed: {}", client, e));
+                }
+            },
+        }
+    }
+
+    /// Sends a message to the connection with the id, accounted for like forwarded ones.
+    fn send(&self, to: u32, target: &Sender, msg: Message) {
+        self.memory.buffered(to, msg.len());
+        target.send(msg).unwrap_or_else(|e| {
+            warn!("Message is not delivered to connection {}: {}", to, e)
+        });
+    }
+
+    /// Sends a message made up by the proxy to the connection with the id.
+    fn synthesize(&self, to: u32, target: &Sender, msg: Message) {
+        if let Some(check) = &self.self_check {
+            check.borrow_mut().synthesized(to, &msg);
+        }
+        self.send(to, target, msg);
+    }
+
+    /// Registers a received message with the self-check, for the connections it's routed to.
+    fn check_received(&self, id: MessageId, from: Party, msg: &Message) {
+        let check = match &self.self_check {
+            Some(check) => check,
+            None => return,
+        };
+        let queued = match from {
+            Party::Client(_) => {
+                let upstream = self.topology.server.borrow();
+                !upstream.open && upstream.queue.is_some()
+            },
+            Party::Server => false,
+        };
+        let destinations: Vec<u32> = match queued {
+            true => vec![SERVER_ID],
+            false => self.topology.edges(from).iter().map(|(edge, _)| edge.to.connection_id()).collect(),
+        };
+        check.borrow_mut().received(id, &destinations, msg);
+    }
+
+    /// Tells the self-check what a change the user enabled made of a received message.
+    fn check_replaced(&self, id: MessageId, forwarded: &[Message]) {
+        if let Some(check) = &self.self_check {
+            check.borrow_mut().replaced(id, forwarded);
+        }
+    }
+
+    /// Tells the self-check about messages of the client which left that aren't sent to the server.
+    fn withdraw(&self, count: usize) {
+        if let (Some(check), true) = (&self.self_check, count > 0) {
+            check.borrow_mut().withdrawn(SERVER_ID, self.connection_id, count);
+        }
+    }
+
+    /// Closes every client with the reason why the connection to the server was lost,
+    /// or couldn't be made.
+    fn close_clients(&self, notice: LossNotice, reason: Value) {
+        let clients = self.topology.clients();
+        warn!("Connection to the server is lost, closing {} clients: {}", clients.len(), reason);
+        let code = upstreamloss::close_code(&reason);
+        let text = upstreamloss::close_reason(&reason);
+        for (id, client) in clients.iter() {
+            if notice == LossNotice::Json {
+                let message = notify::notification("upstream lost", self.connection_id, reason.clone());
+                self.synthesize(*id, client, Message::text(message));
+            }
+            client.close_with_reason(code, text.clone()).unwrap_or_else(|e| {
+                warn!("Connection {} is not closed: {}", id, e)
+            });
+        }
+    }
+
+    /// Latency of the direction of messages from the side, if any is added with --delay.
+    fn latency(&self, from: Leg) -> Duration {
+        self.latency.iter().rev()
+            .find(|latency| latency.from == from)
+            .map(Latency::draw)
+            .unwrap_or_default()
+    }
+
+    /// Sends a message along the edge once the delay passes, on a timeout of its client.
+    fn delay(&self, edge: Edge, target: &Sender, msg: Message, delay: Duration) {
+        let waker = match edge.client().and_then(|client| self.topology.sender(Party::Client(client))) {
+            Some(waker) => waker,
+            None => return self.send(edge.to.connection_id(), target, msg),
+        };
+        self.delayed.borrow_mut().entry(edge).or_default().push_back((target.clone(), msg));
+        let token = match edge.from {
+            Party::Client(_) => CLIENT_DELAY_TIMEOUT,
+            Party::Server => SERVER_DELAY_TIMEOUT,
+        };
+        self.clock.wake(&waker, delay, token).unwrap_or_else(|e| {
+            warn!("Message from {} to {} can't be delayed: {}", edge.from, edge.to, e)
+        });
+    }
+
+    /// Tells every client about an event of the proxy with a synthetic message.
+    fn notify(&self, event: &str, details: Value) {
+        // Under memory pressure the proxy adds no traffic of its own
+        if !self.notify_clients || self.memory.shedding().is_some() {
+            return;
+        }
+        let clients = self.topology.clients();
+        if clients.is_empty() {
+            return;
+        }
+        let text = notify::notification(event, self.connection_id, details);
+        for (id, client) in clients.iter() {
+            self.synthesize(*id, client, Message::text(text.clone()));
+        }
+        // Recorded directly, notifications of provenance events would notify again otherwise
+        let record = session::provenance_record(self.connection_id, "notify", "synthesized", None,
+            json!({ "added": { "type": "text", "data": text, "clients": clients.len() } }));
+        self.capture.borrow().write(format!("{}\n", record));
+    }
+
+    /// Marks what is logged while an event of a client is handled as the client's, which may
+    /// have a level of its own.
+    fn log_scope(&self) -> verbosity::Scope {
+        verbosity::Scope::enter(match self.party {
+            Party::Client(connection_id) => Some(connection_id),
+            Party::Server => None,
+        })
+    }
+
+    /// Carries out the operations requested with the control API, in the handler woken up for them.
+    fn run_control(&self) {
+        let control = match &self.control {
+            Some(control) => control.clone(),
+            None => return,
+        };
+        while let Some((operation, answer)) = control.next() {
+            answer.send(self.act(operation)).ok();
+        }
+    }
+
+    fn act(&self, operation: Operation) -> Answer {
+        match operation {
+            Operation::Send { to, connection, message } => {
+                let targets = match (to, connection) {
+                    (Leg::Server, _) => self.topology.sender(Party::Server).into_iter()
+                        .map(|out| (SERVER_ID, out))
+                        .collect(),
+                    (Leg::Client, Some(connection)) => self.topology.sender(Party::Client(connection)).into_iter()
+                        .map(|out| (connection, out))
+                        .collect(),
+                    (Leg::Client, None) => self.topology.clients(),
+                };
+                if targets.is_empty() {
+                    return (404, json!({ "error": match (to, connection) {
+                        (Leg::Server, _) => "No connection to the server is open".to_string(),
+                        (Leg::Client, Some(connection)) => format!("There is no client connection {}", connection),
+                        (Leg::Client, None) => "No client is connected".to_string(),
+                    } }));
+                }
+                let added = json!({ "type": message_kind(&message), "to": to.to_string(), "size": message.len() });
+                let diff = json!({ "added": added });
+                for (id, target) in targets.iter() {
+                    self.synthesize(*id, target, message.clone());
+                }
+                info!("Message is sent to {} connections with the control API", targets.len());
+                self.provenance("control send", "synthesized", None, diff);
+                let connections: Vec<u32> = targets.iter().map(|(id, _)| *id).collect();
+                (200, json!({ "sent": connections }))
+            },
+            Operation::Disconnect { connection, code, reason } => {
+                let out = match self.topology.sender(Party::Client(connection)) {
+                    Some(out) => out,
+                    None => return (404, json!({ "error": format!("There is no client connection {}", connection) })),
+                };
+                info!("Connection {} is closed with the control API", connection);
+                match out.close_with_reason(CloseCode::from(code), reason) {
+                    Ok(()) => (200, json!({ "closed": connection })),
+                    Err(e) => (503, json!({ "error": e.to_string() })),
+                }
+            },
+            Operation::Settings { connection, setting } => {
+                if self.topology.sender(Party::Client(connection)).is_none() {
+                    return (404, json!({ "error": format!("There is no client connection {}", connection) }));
+                }
+                let settings = match setting {
+                    Some(setting) => {
+                        info!("Settings of connection {} are changed with the control API", connection);
+                        self.palette.set(connection, setting)
+                    },
+                    None => self.palette.settings(connection),
+                };
+                (200, json!({ "connection": connection, "settings": settings }))
+            },
+        }
+    }
+
+    /// Carries out the commands typed into the palette, in the handler of the server.
+    fn run_palette(&self) {
+        while let Some(command) = self.palette.next() {
+            match command {
+                Command::Send(to, text) => {
+                    let targets = match to {
+                        Leg::Server => self.topology.sender(Party::Server).into_iter()
+                            .map(|out| (SERVER_ID, out))
+                            .collect(),
+                        Leg::Client => self.topology.clients(),
+                    };
+                    for (id, target) in targets.iter() {
+                        self.synthesize(*id, target, Message::text(text.clone()));
+                    }
+                    match to {
+                        Leg::Server => println!("Sent to the server"),
+                        Leg::Client => println!("Sent to {} clients", targets.len()),
+                    }
+                    self.provenance("palette send", "synthesized", None,
+                        json!({ "added": { "type": "text", "to": to.to_string(), "data": text } }));
+                },
+                Command::Continue | Command::Step => {
+                    let step = matches!(command, Command::Step);
+                    for held in self.palette.release(step) {
+                        self.release(held);
+                    }
+                },
+                Command::Forward(id) => match self.palette.take(id) {
+                    Ok(held) => {
+                        self.release(held);
+                        println!("Message {} is forwarded", id);
+                    },
+                    Err(e) => println!("{}", e),
+                },
+                Command::Discard(id) => match self.palette.take(id) {
+                    Ok(held) => {
+                        let removed = &held.message;
+                        let diff = json!({ "removed": { "type": message_kind(removed), "size": removed.len() } });
+                        self.provenance("palette discard", "dropped", Some(id.to_string()), diff);
+                        println!("Message {} is dropped", id);
+                    },
+                    Err(e) => println!("{}", e),
+                },
+                Command::Edit(id, text) => match self.palette.edit(id, text.clone()) {
+                    Ok(original) => {
+                        let diff = json!({ "changed": {
+                            "from": { "type": message_kind(&original), "size": original.len() },
+                            "to": { "type": "text", "data": text },
+                        } });
+                        self.provenance("palette edit", "edited", Some(id.to_string()), diff);
+                        println!("Message {} is edited, :forward {} to send it", id, id);
+                    },
+                    Err(e) => println!("{}", e),
+                },
+                Command::Pause(connection_id, paused) => {
+                    let done = match &self.relay {
+                        Some(relay) => relay.pause(connection_id, paused),
+                        None => Err("Connections can be paused only with --pausable".to_string()),
+                    };
+                    match done {
+                        Ok(()) if paused => println!("Connection {} isn't read from", connection_id),
+                        Ok(()) => println!("Connection {} is read from again", connection_id),
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                command => self.palette.apply(command),
+            }
+        }
+    }
+
+    /// Forwards a message held by the palette, marking it as delayed by the rule which held it.
+    fn release(&self, held: Held) {
+        let Held { id, from, message, rule } = held;
+        self.provenance(&format!("palette {}", rule), "delayed", Some(id.to_string()), Value::Null);
+        let from = Party::new(from, id.connection_id);
+        self.check_received(id, from, &message);
+        self.forward(id, from, message);
+    }
+
+    /// Facts of a message for conditions, what is known about the connection
+    /// only if the message was received on this one.
+    fn facts<'a>(&'a self, id: MessageId, from: Leg, msg: &'a Message) -> Facts<'a> {
+        if id.connection_id != self.connection_id {
+            return Facts { connection_id: id.connection_id, ..Facts::of(from, msg) };
+        }
+        Facts {
+            from,
+            message: msg,
+            connection_id: id.connection_id,
+            headers: &self.headers,
+            gap: self.gap,
+            age: self.last_message - self.opened,
+            messages: id.sequence,
+            environment: &self.environment,
+        }
+    }
+
+    /// Takes the id of the next message received on the connection.
+    fn next_id(&self) -> MessageId {
+        self.sequence.set(self.sequence.get() + 1);
+        MessageId { connection_id: self.connection_id, sequence: self.sequence.get() }
+    }
+
+    /// Continues the capture in the file of the next segment.
+    fn split(&self, segments: &Segments, rule: &SplitRule, message: Option<MessageId>) {
+        let segment = segments.next();
+        let mut session = self.session.borrow_mut();
+        let path = session.segment_path(segment);
+        // The capture goes on in the last segment if the next one can't be created
+        match open_log(&self.log_queue, &path) {
+            Ok(capture) => *self.capture.borrow_mut() = capture,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            },
+        }
+        let file = path.file_name().unwrap_or_default().to_string_lossy();
+        info!("Capture segment {} is written to {} by rule {}", segment, file, rule);
+        session.record(session::segment_record(segment, &file, &rule.to_string(), message));
+    }
+
+    /// Records into the capture how a rule of the proxy changed the traffic.
+    fn provenance(&self, rule: &str, action: &str, original: Option<String>, diff: Value) {
+        let record = session::provenance_record(self.connection_id, rule, action, original.clone(), diff);
+        self.capture.borrow().write(format!("{}\n", record));
+        if let Some(observers) = &self.observers {
+            observers.publish(&record);
+        }
+        if let Some(agent) = &self.agent {
+            agent.publish(&record);
+        }
+        self.notify(action, json!({ "rule": rule, "message": original }));
+    }
+
+    /// Passes a message, as the plugins `replaced` it, through the hooks of the script and sends
+    /// the messages they make up. Returns what became of the message, with the rules replacing it.
+    fn run_hooks(&self, hooks: &Hooks, id: MessageId, msg: &Message, replaced: Option<String>) -> Verdict {
+        let (verdict, sent) = hooks.process(id, &self.facts(id, self.party.leg(), msg));
+        let rule = format!("script {}", hooks.name());
+        for (to, message) in sent {
+            let targets: Vec<(u32, Sender)> = match (to, self.party) {
+                (Leg::Server, _) => self.topology.sender(Party::Server).into_iter()
+                    .map(|out| (SERVER_ID, out))
+                    .collect(),
+                (Leg::Client, Party::Client(client)) => self.topology.sender(Party::Client(client)).into_iter()
+                    .map(|out| (client, out))
+                    .collect(),
+                (Leg::Client, Party::Server) => self.topology.clients(),
+            };
+            if targets.is_empty() {
+                warn!("Message made up by {} on message {} is not delivered, no {} is connected", rule, id, to);
+                continue;
+            }
+            let added = json!({ "type": message_kind(&message), "to": to.to_string(), "size": message.len() });
+            let diff = json!({ "added": added });
+            for (connection_id, target) in targets.iter() {
+                self.synthesize(*connection_id, target, message.clone());
+            }
+            self.provenance(&rule, "synthesized", Some(id.to_string()), diff);
+        }
+        match (verdict, replaced) {
+            (Verdict::Forward, Some(plugins)) => Verdict::Replace(plugins, msg.clone()),
+            (Verdict::Replace(_, replacement), Some(plugins)) => {
+                Verdict::Replace(format!("{}, {}", plugins, rule), replacement)
+            },
+            (Verdict::Replace(_, replacement), None) => Verdict::Replace(rule, replacement),
+            (Verdict::Drop(_), _) => Verdict::Drop(rule),
+            (Verdict::Forward, None) => Verdict::Forward,
+        }
+    }
+
+    /// Records the provenance of a message replaced by plugins or the script.
+    fn replaced(&self, id: MessageId, replaced: Option<(String, Value)>) {
+        if let Some((rule, diff)) = replaced {
+            self.provenance(&rule, "replaced", Some(id.to_string()), diff);
+        }
+    }
+
+    /// Charges the time from now on to the stage, if the message is measured.
+    fn stage(&self, stage: Stage) {
+        if let Some(timing) = self.timing.borrow_mut().as_mut() {
+            timing.switch(stage);
+        }
+    }
+
+    /// Passes a message through the rules and plugins, then forwards and records it.
+    fn handle(&mut self, id: MessageId, msg: Message) {
+        let (from, prefix) = (self.party.leg(), self.party.prefix());
+        for callback in self.callbacks.messages.iter() {
+            callback(&MessageEvent { id, from, message: &msg });
+        }
+        let facts = self.facts(id, from, &msg);
+        if let Some(segments) = &self.segments {
+            if let Some(rule) = segments.starts(&facts) {
+                self.split(segments, rule, Some(id));
+            }
+        }
+        if let Some(rule) = self.agent.as_ref().and_then(|agent| agent.drops(&facts)) {
+            debug!("Message {} is dropped by agent rule {}", id, rule);
+            self.check_replaced(id, &[]);
+            let diff = json!({ "removed": { "type": message_kind(&msg), "size": msg.len() } });
+            self.record(id, from, &prefix, msg);
+            self.provenance(&format!("agent {}", rule), "dropped", Some(id.to_string()), diff);
+            return;
+        }
+
+        let rule = self.palette.drops(&facts).or_else(|| match from {
+            Leg::Client => self.palette.drops_for(self.connection_id, &facts),
+            Leg::Server => None,
+        });
+        if let Some(rule) = rule {
+            debug!("Message {} is dropped by rule {}", id, rule);
+            self.check_replaced(id, &[]);
+            let diff = json!({ "removed": { "type": message_kind(&msg), "size": msg.len() } });
+            self.record(id, from, &prefix, msg);
+            self.provenance(&format!("palette {}", rule), "dropped", Some(id.to_string()), diff);
+            return;
+        }
+        if self.palette.holds(id, &facts) {
+            debug!("Message {} is held until the traffic is resumed", id);
+            // Routed again once it's released
+            self.check_replaced(id, &[]);
+            self.record(id, from, &prefix, msg);
+            return;
+        }
+        // Messages replaced by plugins and the script are forwarded replaced, the capture keeps them as received
+        self.stage(Stage::Script);
+        let verdict = match self.processors.as_ref().map(|processors| processors.process(id, from, &msg)) {
+            Some(Verdict::Drop(plugin)) => Verdict::Drop(format!("plugin {}", plugin)),
+            Some(Verdict::Replace(plugins, replacement)) => {
+                Verdict::Replace(format!("plugin {}", plugins), replacement)
+            },
+            Some(Verdict::Forward) | None => Verdict::Forward,
+        };
+        let verdict = match (&self.hooks, verdict) {
+            (Some(hooks), Verdict::Forward) => self.run_hooks(hooks, id, &msg, None),
+            (Some(hooks), Verdict::Replace(plugins, replacement)) => {
+                self.run_hooks(hooks, id, &replacement, Some(plugins))
+            },
+            (_, verdict) => verdict,
+        };
+        self.stage(Stage::Rules);
+        let (forwarded, replaced) = match verdict {
+            Verdict::Drop(rule) => {
+                debug!("Message {} is dropped by {}", id, rule);
+                self.check_replaced(id, &[]);
+                let diff = json!({ "removed": { "type": message_kind(&msg), "size": msg.len() } });
+                self.record(id, from, &prefix, msg);
+                self.provenance(&rule, "dropped", Some(id.to_string()), diff);
+                return;
+            },
+            Verdict::Replace(rule, replacement) => {
+                self.check_replaced(id, std::slice::from_ref(&replacement));
+                let diff = json!({ "changed": {
+                    "from": { "type": message_kind(&msg), "size": msg.len() },
+                    "to": { "type": message_kind(&replacement), "size": replacement.len() },
+                } });
+                (replacement, Some((rule, diff)))
+            },
+            Verdict::Forward => (msg.clone(), None),
+        };
+        let fault = self.faults.as_ref().and_then(|faults| faults.borrow_mut().draw(from, &forwarded));
+        if let Some(fault) = fault {
+            info!("Fault {} is injected into message {}", fault.name(), id);
+            let rule = format!("fault {}", fault.name());
+            match fault {
+                Fault::Drop => {
+                    self.check_replaced(id, &[]);
+                    let diff = json!({ "removed": { "type": message_kind(&forwarded), "size": forwarded.len() } });
+                    self.record(id, from, &prefix, msg);
+                    self.replaced(id, replaced);
+                    self.provenance(&rule, "dropped", Some(id.to_string()), diff);
+                },
+                Fault::Duplicate => {
+                    let diff = json!({ "added": { "type": message_kind(&forwarded), "size": forwarded.len() } });
+                    self.check_replaced(id, &[forwarded.clone(), forwarded.clone()]);
+                    self.forward(id, self.party, forwarded.clone());
+                    self.forward(id, self.party, forwarded);
+                    self.record(id, from, &prefix, msg);
+                    self.replaced(id, replaced);
+                    self.provenance(&rule, "duplicated", Some(id.to_string()), diff);
+                },
+                Fault::Corrupt(corrupted, diff) => {
+                    self.check_replaced(id, std::slice::from_ref(&corrupted));
+                    self.forward(id, self.party, corrupted);
+                    self.record(id, from, &prefix, msg);
+                    self.replaced(id, replaced);
+                    self.provenance(&rule, "corrupted", Some(id.to_string()), diff);
+                },
+            }
+            return;
+        }
+
+        self.forward(id, self.party, forwarded);
+        self.record(id, from, &prefix, msg);
+        self.replaced(id, replaced);
+    }
+
+    /// Records a forwarded message into the log and the capture and shows it to observers.
+    /// The message is anonymized and truncated if required.
+    fn record(&self, id: MessageId, from: Leg, prefix: &str, msg: Message) {
+        self.stage(Stage::Log);
+        if let (false, Some(sampling)) = (self.sampled, &self.sampling) {
+            sampling.skipped(msg.len());
+        }
+        let msg = match &self.anonymizer {
+            Some(anonymizer) => anonymizer.message(&msg),
+            None => msg
+        };
+        if let Some(tracker) = &self.tracker {
+            for (expression, value) in tracker.track(id, from, &msg) {
+                if let Some(tui) = &self.tui {
+                    tui.tracked(expression, value);
+                }
+            }
+        }
+        if let Some(alerts) = &self.alerts {
+            alerts.inspect(id, &self.facts(id, from, &msg));
+        }
+        if let Some(gaps) = &self.gaps {
+            gaps.message(id, from, &msg);
+        }
+        if let Some(console) = &self.console {
+            console.message(id, from, &msg);
+        }
+        if let Some(composer) = self.palette.composer() {
+            composer.message(from, &msg);
+        }
+        if let Some(digests) = &self.digests {
+            digests.message(id.connection_id, from, &msg);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.message(id.connection_id, from, msg.len());
+        }
+        if let Some(violation) = self.contract.as_ref().and_then(|contract| contract.check(from, &msg)) {
+            println!("Contract violation: {} from {}: {}", id, from, violation);
+            let record = session::violation_record(Some(id), Some(from), &violation);
+            self.capture.borrow().write(format!("{}\n", record));
+        }
+        let facts = self.facts(id, from, &msg);
+        let tags = tags::apply(&self.tag_rules, &facts);
+        if let (None, Some(shown)) = (&self.tui, self.view.show(&facts, &tags)) {
+            println!("[{}] {}: {}", id, from, shown);
+        }
+        self.stage(Stage::Decode);
+        let decoded = self.decoders.as_ref().and_then(|decoders| decoders.decode(&msg));
+        self.stage(Stage::Log);
+        let size = msg.len();
+        let (msg, truncated) = match &self.truncation {
+            Some(truncation) => truncation.apply(msg),
+            None => (msg, None)
+        };
+
+        let mut record = session::message_record(id, from, &msg);
+        if !tags.is_empty() {
+            record["tags"] = json!(tags);
+        }
+        if let Some(truncated) = &truncated {
+            record["truncated"] = truncated.to_value();
+        }
+        if let Some(hop) = &self.hop {
+            record["hop"] = json!(hop.as_str());
+        }
+        if let Some((plugin, text)) = &decoded {
+            record["decoded"] = json!({ "plugin": plugin, "text": text });
+        }
+        // Paused recording stops writing, live consumers still get the messages
+        let recording = self.palette.is_recording(self.connection_id) && self.sampled;
+        if recording {
+            self.capture.borrow().write(format!("{}\n", record));
+            if let Some(segments) = &self.segments {
+                segments.captured();
+            }
+        }
+        if let Some(observers) = &self.observers {
+            observers.publish(&record);
+        }
+        if let Some(agent) = &self.agent {
+            agent.publish(&record);
+        }
+        if self.devtools.is_some() || self.control.is_some() || self.tui.is_some() {
+            // Messages of the server are shown for every client they are forwarded to
+            let clients: Vec<u32> = match self.party {
+                Party::Server => self.topology.clients.borrow().keys().copied().collect(),
+                Party::Client(id) => vec![id]
+            };
+            if let Some(devtools) = &self.devtools {
+                for client in clients.iter() {
+                    devtools.frame(*client, &record);
+                }
+            }
+            if let Some(control) = &self.control {
+                control.message(&record, &clients);
+            }
+            if let Some(tui) = &self.tui {
+                tui.message(id, from, &msg, size, &tags, &clients);
+            }
+        }
+
+        let file = match (recording, &self.log_file) {
+            (true, Some(file)) => file,
+            _ => return,
+        };
+        if self.log_format == LogFormat::Ndjson {
+            file.write(format!("{}\n", session::log_record(id, from, &msg, size)));
+            return;
+        }
+        let text = match (decoded, truncated) {
+            (Some((plugin, text)), _) => format!("{} (decoded by {})", text, plugin),
+            // Cut payloads can't be pretty-printed
+            (None, Some(truncated)) => format!("{} {}", pretty_print(msg, None), truncated),
+            (None, None) => pretty_print(msg, Some(&self.renderers.read().unwrap()))
+        };
+        log_to_file(file, prefix, format!("[{}] {}", id, text))
+    }
+}
+
+impl ws::Handler for Handler {
+    fn build_request(&mut self, url: &Url) -> Result<Request> {
+        if self.party != Party::Server {
+            return Request::from_url(url);
+        }
+        // ws connects to an address resolved by the proxy, the request goes to the url
+        let upstream = self.topology.server.borrow();
+        let attempt = upstream.attempt.as_ref()
+            .ok_or_else(|| ws::Error::new(ws::ErrorKind::Internal, "No attempt to connect to the server"))?;
+        let mut request = Request::from_url(&attempt.url)?;
+        for protocol in upstream.protocols.iter() {
+            request.add_protocol(protocol);
+        }
+        for (name, value) in attempt.headers.iter() {
+            request.headers_mut().push((name.clone(), value.clone().into_bytes()));
+        }
+        self.out.timeout(setup::HANDSHAKE_TIMEOUT.as_millis() as u64, SETUP_TIMEOUT)?;
+        if let Some(plan) = &self.deflate_plan {
+            request.add_extension(&plan.offer());
+        }
+        let (span, traceparent) = telemetry::connection_span(Leg::Server, self.connection_id, None);
+        if !span.is_disabled() && request.header("traceparent").is_none() {
+            request.headers_mut().push(("traceparent".into(), traceparent.into_bytes()));
+        }
+        self.span = span;
+        Ok(request)
+    }
+
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        if let Some(plan) = &self.deflate_plan {
+            self.deflate = plan.accepted(&res.extensions()?)
+                .map_err(|e| ws::Error::new(ws::ErrorKind::Protocol, e))?;
+            if self.deflate.is_none() {
+                warn!("The server declined {}", plan.offer());
+            }
+        }
+        Ok(())
+    }
+
+    fn upgrade_ssl_client(&mut self, stream: TcpStream, url: &Url) -> Result<SslStream<TcpStream>> {
+        let connector = match &self.upstream_tls {
+            Some(connector) => connector.clone(),
+            None => Rc::new(tls::connector(None, None, false)
+                .map_err(|e| ws::Error::new(ws::ErrorKind::Internal, e))?),
+        };
+        let url = match self.party {
+            Party::Server => self.topology.server.borrow().url.clone(),
+            Party::Client(_) => url.clone(),
+        };
+        // Addresses are verified too, unlike by ws which requires a domain
+        let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        let mut ssl = connector.configure()
+            .and_then(|configuration| configuration.into_ssl(host))
+            .map_err(|e| ws::Error::new(ws::ErrorKind::Internal, format!("TLS can't be set up: {}", e)))?;
+        self.tls_progress = Some(TlsProgress::watch(&mut ssl));
+        // ws goes on with a handshake which would block on its event loop
+        ssl.connect(stream).map_err(ws::Error::from)
+    }
+
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        match &self.tls {
+            Some(tls) => tls.accept(stream).map_err(ws::Error::from),
+            None => Err(ws::Error::new(ws::ErrorKind::Internal, "The proxy port has no TLS"))
+        }
+    }
+
+    fn on_open(&mut self, h: Handshake) -> Result<()> {
+        let _scope = self.log_scope();
+        debug!("Connection opened: we are {:?}, they are {:?}", h.local_addr, h.peer_addr);
+        if log_enabled!(Level::Warn) && h.peer_addr.is_none() {
+            warn!("Connection with unknown address opened");
+        }
+
+        let role = self.party.leg().to_string();
+        // Relayed clients come from the loopback interface, the relay knows where from
+        let peer_addr = match (&self.relay, self.party) {
+            (Some(relay), Party::Client(_)) => h.peer_addr.and_then(|inner| relay.accepted(self.connection_id, inner)),
+            _ => h.peer_addr,
+        };
+        let mut record = session::open_record(self.connection_id, &role,
+            peer_addr.map(|address| address.to_string()), h.request.resource(),
+            h.request.headers(), h.response.headers(), &self.labels);
+        if let Some(progress) = &self.tls_progress {
+            self.setup.encrypted(progress);
+        }
+        record["setup"] = self.setup.record(Instant::now());
+        if let Party::Client(_) = self.party {
+            if let Some(metrics) = &self.metrics {
+                metrics.opened(self.connection_id);
+            }
+            if let Some(prometheus) = &self.prometheus {
+                prometheus.opened(self.connection_id, Leg::Client, false);
+            }
+            let traceparent = h.request.header("traceparent").map(Vec::as_slice);
+            self.span = telemetry::connection_span(Leg::Client, self.connection_id, traceparent).0;
+            let client = fingerprint::client(h.request.headers());
+            let fingerprint = client["fingerprint"].as_str().unwrap_or_default().to_string();
+            self.environment.client = fingerprint.clone();
+            if self.fingerprints.borrow_mut().insert(fingerprint.clone()) && self.tui.is_none() {
+                println!("Connection {} is from a new client {}: {}", self.connection_id, fingerprint,
+                    client["user_agent"].as_str().unwrap_or("no user agent"));
+            }
+            record["client"] = client;
+        }
+        if let Some(anonymizer) = &self.anonymizer {
+            anonymizer.value(&mut record);
+        }
+        if let (Party::Client(_), Some(devtools)) = (self.party, &self.devtools) {
+            let tab = h.request.header(devtools::TAB_HEADER)
+                .map(|tab| String::from_utf8_lossy(tab).into_owned());
+            devtools.opened(self.connection_id, tab, &record);
+        }
+        if let (Party::Server, Some(composer)) = (self.party, self.palette.composer()) {
+            composer.opened(h.response.protocol().ok().flatten(), h.request.resource());
+        }
+        if let (Party::Client(_), Some(tui)) = (self.party, &self.tui) {
+            tui.opened(self.connection_id, peer_addr.map(|address| address.to_string()), h.request.resource());
+        }
+        if let Some(control) = &self.control {
+            control.opened(self.connection_id, self.out.clone(), json!({
+                "id": self.connection_id,
+                "leg": role,
+                "peer": peer_addr.map(|address| address.to_string()),
+                "resource": h.request.resource(),
+                "client": record["client"]["fingerprint"],
+                "opened": Utc::now().to_rfc3339(),
+            }));
+        }
+        if self.sampled {
+            self.session.borrow_mut().record(record);
+        }
+        let headers = match self.party {
+            Party::Server => h.response.headers(),
+            Party::Client(_) => h.request.headers(),
+        };
+        self.headers = headers.iter()
+            .map(|(name, value)| (name.to_lowercase(), String::from_utf8_lossy(value).into_owned()))
+            .collect();
+        let upstream = self.topology.server.borrow().label.clone();
+        let client = std::mem::take(&mut self.environment.client);
+        self.environment = Environment::new(client, h.request.resource(), upstream, self.labels.to_vec());
+        self.opened = Instant::now();
+        self.last_message = self.opened;
+        if self.party == Party::Server {
+            self.loss.opened();
+            let (attempts, queued) = self.topology.server.borrow_mut().opened();
+            let replays = self.topology.server.borrow().replays();
+            if attempts > 0 {
+                warn!("Connected to the server again after {} attempts", attempts);
+            }
+            if let Some(prometheus) = &self.prometheus {
+                prometheus.opened(self.connection_id, Leg::Server, attempts > 0);
+            }
+            self.notify("upstream connected", Value::Null);
+            if !replays.is_empty() {
+                info!("Sending {} setup messages of clients to the server again", replays.len());
+            }
+            for (client, msg) in replays {
+                let diff = json!({ "added": { "type": message_kind(&msg), "size": msg.len(), "of": client } });
+                self.provenance("resubscribe", "synthesized", None, diff);
+                self.synthesize(self.connection_id, &self.out, msg);
+            }
+            if !queued.is_empty() {
+                info!("Sending {} messages queued while the server wasn't connected", queued.len());
+            }
+            for (client, msg) in queued {
+                if let Some(resubscribe) = &mut self.topology.server.borrow_mut().resubscribe {
+                    resubscribe.sent(client, &Facts { connection_id: client, ..Facts::of(Leg::Client, &msg) });
+                }
+                self.send(self.connection_id, &self.out, msg);
+            }
+        }
+        let leg = self.party.leg();
+        let event = ConnectionEvent::Opened { connection_id: self.connection_id, leg };
+        for callback in self.callbacks.connections.iter() {
+            callback(&event);
+        }
+
+        if let (Party::Client(_), Some(state)) = (self.party, &self.state) {
+            let undelivered = state.take_undelivered();
+            if !undelivered.is_empty() {
+                info!("Delivering {} kept messages from server to client {}", undelivered.len(), self.connection_id);
+                for (id, msg) in undelivered {
+                    self.provenance("state", "delayed", id, Value::Null);
+                    self.synthesize(self.connection_id, &self.out, msg);
+                }
+                state.changed(&self.close_stats.lock().unwrap());
+            }
+        }
+
+        if let Some(flood) = &self.flood {
+            let plan = flood.plan();
+            warn!("Flooding connection {} with {} messages of {} at {}/s", self.connection_id,
+                plan.payload.name(), memory::format_size(plan.size), plan.per_second);
+            self.provenance("flood", "synthesized", None, json!({ "added": {
+                "type": "binary",
+                "payload": plan.payload.name(),
+                "size": plan.size,
+                "per_second": plan.per_second,
+            }}));
+            self.out.timeout(0, FLOOD_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let _scope = self.log_scope();
+        let inflated = std::mem::take(&mut self.inflating);
+        if self.shed(&msg) {
+            return Ok(());
+        }
+        let id = self.next_id();
+        self.check_received(id, self.party, &msg);
+        let now = Instant::now();
+        self.gap = now - self.last_message;
+        self.last_message = now;
+
+        let timing = self.overhead.as_ref().filter(|overhead| overhead.sampled()).map(|_| Timing::start(inflated));
+        self.timing.replace(timing);
+        let size = msg.len();
+        let direction = match self.party.leg() {
+            Leg::Client => "client_to_server",
+            Leg::Server => "server_to_client",
+        };
+        let span = tracing::info_span!(parent: &self.span, "forward", direction, size,
+            connection_id = self.connection_id, message_id = %id);
+        span.in_scope(|| self.handle(id, msg));
+        if let Some(prometheus) = &self.prometheus {
+            prometheus.message(self.party.leg(), size, now.elapsed());
+        }
+        if let (Some(overhead), Some(timing)) = (&self.overhead, self.timing.take()) {
+            overhead.measured(id, self.party.leg(), timing);
+        }
+        Ok(())
+    }
+
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let _scope = self.log_scope();
+        self.requested = true;
+        if !probe::is_upgrade(req) {
+            let response = self.probe_response.respond(req);
+            self.probed(ProbeKind::Http, Some(req), Some(&response), None);
+            return Ok(response);
+        }
+        self.join();
+        let mut response = Response::from_request(req)?;
+        // Clients get the subprotocol offered to the server if they offer it too
+        let protocols = self.topology.server.borrow().protocols.clone();
+        let offered = req.protocols().unwrap_or_default();
+        if let Some(protocol) = offered.into_iter().find(|protocol| protocols.iter().any(|ours| ours == protocol)) {
+            response.set_protocol(protocol);
+        }
+        if let Some((deflate, extension)) = self.deflate_plan.and_then(|plan| plan.accept(&req.extensions().ok()?)) {
+            response.add_extension(&extension);
+            self.deflate = Some(deflate);
+        }
+        Ok(response)
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        let _scope = self.log_scope();
+        if let (Party::Client(_), false, None) = (self.party, self.requested, self.probe) {
+            // Anything but an HTTP request, or a connection broken before sending one
+            self.probed(ProbeKind::Garbage, None, None, Some(upstreamloss::describe(&err)));
+            return;
+        }
+        if self.party == Party::Server {
+            self.loss.failed(&err);
+        }
+        if let Some(prometheus) = &self.prometheus {
+            prometheus.error(self.party.leg());
+        }
+        error!("Error on connection {}: {:?}", self.connection_id, err);
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        let _scope = self.log_scope();
+        if event == PALETTE_TIMEOUT {
+            self.run_palette();
+            return Ok(());
+        }
+
+        if event == CONTROL_TIMEOUT {
+            self.run_control();
+            return Ok(());
+        }
+
+        if event == RECONNECT_TIMEOUT {
+            if let Party::Client(_) = self.party {
+                self.topology.server.borrow_mut().retry(&self.out, self.connection_id);
+            }
+            return Ok(());
+        }
+
+        if event == PREPARED_TIMEOUT {
+            let prepared = self.topology.server.borrow_mut().prepared();
+            if let Err(reason) = prepared {
+                if let Some(notice) = self.notice {
+                    self.close_clients(notice, reason);
+                }
+                self.topology.server.borrow_mut().lost(&self.topology.clients);
+            }
+            return Ok(());
+        }
+
+        if event == SETUP_TIMEOUT {
+            if self.party == Party::Server && !self.topology.server.borrow().open {
+                let timeout = io::Error::new(io::ErrorKind::TimedOut, "The server isn't connected in time");
+                return Err(ws::Error::from(timeout));
+            }
+            return Ok(());
+        }
+
+        if event == CLIENT_DELAY_TIMEOUT || event == SERVER_DELAY_TIMEOUT {
+            let edge = match event == CLIENT_DELAY_TIMEOUT {
+                true => Edge::new(self.party, Party::Server),
+                false => Edge::new(Party::Server, self.party),
+            };
+            let due = self.delayed.borrow_mut().get_mut(&edge).and_then(VecDeque::pop_front);
+            if let Some((target, msg)) = due {
+                self.send(edge.to.connection_id(), &target, msg);
+            }
+            return Ok(());
+        }
+
+        if event == SHUTDOWN_TIMEOUT {
+            let reason = "Simulated connection drop without close frame";
+            return Err(ws::Error::from(io::Error::other(reason)));
+        }
+
+        if event == FLOOD_TIMEOUT {
+            if let Some(flood) = &mut self.flood {
+                for _ in 0..flood.tick() {
+                    let message = Message::binary(flood.message());
+                    if let Some(check) = &self.self_check {
+                        check.borrow_mut().synthesized(self.connection_id, &message);
+                    }
+                    self.out.send(message)?;
+                }
+                self.out.ping(flood.ping())?;
+                self.out.timeout(flood.tick_ms(), FLOOD_TIMEOUT)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        let _scope = self.log_scope();
+        let frame = match &mut self.deflate {
+            Some(deflate) => {
+                let started = Instant::now();
+                let inflated = deflate.incoming(frame).map_err(|e| ws::Error::new(ws::ErrorKind::Protocol, e))?;
+                self.inflating += started.elapsed();
+                match inflated {
+                    Some(frame) => frame,
+                    None => return Ok(None),
+                }
+            },
+            None => frame,
+        };
+        if let Some(flood) = &mut self.flood {
+            flood.pong(&frame);
+        }
+        if self.capture_frames && frame.is_control() && self.sampled
+            && self.palette.is_recording(self.connection_id) {
+            let record = session::frame_record(self.connection_id, self.party.leg(), &frame);
+            self.capture.borrow().write(format!("{}\n", record));
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_send_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        let _scope = self.log_scope();
+        if let Some(interleave) = &mut self.interleave {
+            if let Some(frame) = interleave.substitute(&frame) {
+                return Ok(Some(frame));
+            }
+        }
+        if let Some(flood) = &self.flood {
+            if flood.is_own(&frame) {
+                return Ok(Some(frame));
+            }
+        }
+
+        if let OpCode::Text | OpCode::Binary = frame.opcode() {
+            self.memory.sent(self.connection_id, frame.payload().len());
+            if self.memory.shedding() == Some(Shedding::Drop) {
+                debug!("Memory limit exceeded, buffered message is dropped");
+                let kind = if frame.opcode() == OpCode::Text { "text" } else { "binary" };
+                // The message was captured by the handler of the other side already
+                self.provenance("memory-limit", "dropped", None,
+                    json!({ "removed": { "type": kind, "size": frame.payload().len() } }));
+                self.memory.dropped();
+                return Ok(None);
+            }
+        }
+
+        let due = self.shutdown.as_ref()
+            .filter(|shutdown| shutdown.is_due(&frame))
+            .map(|shutdown| shutdown.sequence().name());
+        if let Some(sequence) = due {
+            warn!("Performing shutdown sequence {} on connection {}", sequence, self.connection_id);
+            self.session.borrow_mut().record(
+                session::shutdown_record(self.connection_id, sequence));
+            self.notify("shutdown", json!({ "sequence": sequence }));
+        }
+
+        if let Some(shutdown) = &mut self.shutdown {
+            frame = match shutdown.apply(frame) {
+                Action::Send(frame) => frame,
+                Action::Suppress => return Ok(None),
+                Action::Disconnect => {
+                    // ws drops the socket only after errors in event callbacks,
+                    // so the connection is failed from a timeout
+                    self.close_sent = true;
+                    self.out.timeout(0, SHUTDOWN_TIMEOUT)?;
+                    return Ok(None);
+                },
+                Action::SendAndClose(frame) => {
+                    self.out.close(CloseCode::Normal)?;
+                    frame
+                }
+            };
+        }
+
+        if frame.opcode() == OpCode::Close {
+            // Closing handshake is ours if the close frame is sent before one is received
+            self.close_sent = true;
+        }
+
+        if let Some(check) = &self.self_check {
+            let checked = check.borrow_mut().egress(self.connection_id, &frame);
+            if let (Err(e), true) = (checked, self.strict) {
+                println!("Integrity violation, the proxy is stopped: {}", e);
+                self.session.borrow_mut().record(session::integrity_record(self.connection_id, &e));
+                self.out.shutdown()?;
+                return Ok(None);
+            }
+        }
+
+        if let Some(interleave) = &mut self.interleave {
+            let (head, queued) = interleave.split(frame);
+            if !queued.is_empty() && !self.interleave_reported {
+                let frames: Vec<&str> = interleave.injections().iter().map(|i| i.name()).collect();
+                warn!("Interleaving {} into fragmented messages on connection {}",
+                    frames.join(", "), self.connection_id);
+                self.session.borrow_mut().record(
+                    session::interleave_record(self.connection_id, &frames));
+                self.notify("interleave", json!({ "frames": frames }));
+                self.interleave_reported = true;
+            }
+
+            for item in queued {
+                match item {
+                    Queued::Ping(data) => self.out.ping(data)?,
+                    Queued::Pong(data) => self.out.pong(data)?,
+                    Queued::Message(message) => {
+                        if let Some(check) = &self.self_check {
+                            check.borrow_mut().synthesized(self.connection_id, &message);
+                        }
+                        self.out.send(message)?
+                    },
+                }
+            }
+            frame = head;
+        }
+        if let Some(deflate) = &mut self.deflate {
+            frame = deflate.outgoing(frame).map_err(|e| ws::Error::new(ws::ErrorKind::Internal, e))?;
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        let _scope = self.log_scope();
+        debug!("Connection closed: code={:?}, reason=\"{}\"", code, reason);
+        self.memory.released(self.connection_id);
+        if let Some(control) = &self.control {
+            control.closed(self.connection_id);
+        }
+        if let Some(tui) = &self.tui {
+            tui.closed(self.connection_id);
+        }
+        if let Some(prometheus) = &self.prometheus {
+            prometheus.closed(self.connection_id, self.party.leg());
+        }
+        if let Party::Client(_) = self.party {
+            self.topology.clients.borrow_mut().remove(&self.connection_id);
+            let mut dropped = self.topology.server.borrow_mut().left(&self.topology.clients, self.connection_id);
+            self.palette.forget(self.connection_id);
+            if let Some(throttle) = &self.throttle {
+                throttle.borrow_mut().forget(self.connection_id);
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.closed(self.connection_id);
+            }
+            for edge in [Edge::new(self.party, Party::Server), Edge::new(Party::Server, self.party)] {
+                if let Some(delayed) = self.delayed.borrow_mut().remove(&edge) {
+                    debug!("{} delayed messages of connection {} are dropped", delayed.len(), self.connection_id);
+                    if edge.to == Party::Server {
+                        dropped += delayed.len();
+                    }
+                }
+            }
+            self.withdraw(dropped);
+        }
+        if let Some(check) = &self.self_check {
+            check.borrow_mut().closed(self.connection_id);
+        }
+
+        let leg = self.party.leg();
+        let initiator = if self.close_sent { Initiator::Proxy } else { Initiator::Peer };
+        let code: u16 = code.into();
+
+        let report = {
+            let mut stats = self.close_stats.lock().unwrap();
+            stats.record(leg, code, initiator);
+            if let Some(state) = &self.state {
+                state.changed(&stats);
+            }
+            stats.report()
+        };
+
+        if let Some(digests) = &self.digests {
+            digests.closed(code);
+        }
+        if self.party == Party::Server {
+            self.loss.closed(code, reason);
+            self.notify("upstream closed", json!({ "code": code, "reason": reason }));
+        }
+        let event = ConnectionEvent::Closed { connection_id: self.connection_id, leg, code, reason: reason.to_string() };
+        for callback in self.callbacks.connections.iter() {
+            callback(&event);
+        }
+        let record = session::close_record(self.connection_id, code, reason, initiator);
+        if let (Party::Client(_), Some(devtools)) = (self.party, &self.devtools) {
+            devtools.closed(self.connection_id, &record);
+        }
+        let mut session = self.session.borrow_mut();
+        if self.sampled {
+            session.record(record);
+        }
+        if let Some(flood) = &self.flood {
+            println!("Flood of connection {} closed with {}: {}",
+                self.connection_id, code, flood.report());
+            session.record(session::flood_record(self.connection_id, flood));
+        }
+        session.write_file(session::CLOSE_CODES, &report);
+        drop(session);
+        if let Some(segments) = &self.segments {
+            if let Some(rule) = segments.ends(code) {
+                self.split(segments, rule, None);
+            }
+        }
+    }
+}
+
+pub(crate) fn log_to_file(file: &LogFile, prefix: &str, text: String) {
+    file.write(format!("{} {} {}", Utc::now(), prefix, text));
+}
+
+fn message_kind(msg: &Message) -> &'static str {
+    match msg {
+        Message::Text(_) => "text",
+        Message::Binary(_) => "binary",
+    }
+}
+
+pub(crate) fn pretty_print(msg: Message, renderers: Option<&Renderers>) -> String {
+    match msg {
+        Message::Binary(bytes) => format!("Binary({:?})", bytes),
+        Message::Text(raw) => match renderers {
+            Some(renderers) => renderers.render(raw),
+            None => raw
+        }
+    }
+}
+
+//todo: manage resource release
+fn open_log(log_queue: &LogQueue, path: &Path) -> std::result::Result<LogFile, String> {
+    open_rotated_log(log_queue, path, None)
+}
+
+pub(crate) fn open_rotated_log(log_queue: &LogQueue, path: &Path, rotation: Option<Rotation>)
+    -> std::result::Result<LogFile, String> {
+    log_queue.open_rotated(path, rotation).map_err(|e| format!("Failed to create file {}: {}", path.display(), e))
+}
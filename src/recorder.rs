@@ -0,0 +1,351 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use ws::Message;
+
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use log::{error, info};
+
+use crate::closecodes::{Initiator, Leg};
+use crate::condition::{Environment, Facts};
+use crate::logqueue::{LogFile, LogFormat, LogNames, LogQueue, Rotation, SyncPolicy};
+use crate::memory::MemoryMonitor;
+use crate::probe::ProbeResponse;
+use crate::proxy::{self, Callbacks, ConnectionEvent, MessageEvent, Options};
+use crate::render::Renderers;
+use crate::session::{self, MessageId, Session};
+use crate::storage::Storage;
+use crate::tags::{self, TagRule};
+use crate::upstreamqueue::Overflow;
+use crate::views::View;
+
+/// Capture pipeline of the proxies of other protocols than websockets, which serve
+/// every client in threads of its own: the session with its index and capture, the
+/// client and server logs, tags, the view and the callbacks, as the websocket proxy
+/// has them. Messages are recorded on the connection of the client from either side.
+pub(crate) struct Recorder {
+    session: Mutex<Session>,
+    log_queue: Arc<LogQueue>,
+    capture: LogFile,
+    server_log: LogFile,
+    client_logs: Mutex<HashMap<u32, LogFile>>,
+    log_names: LogNames,
+    log_format: LogFormat,
+    rotation: Option<Rotation>,
+    renderers: Renderers,
+    tag_rules: Vec<TagRule>,
+    view: Option<View>,
+    labels: Vec<(String, String)>,
+    callbacks: Callbacks,
+    /// Sockets of the open connections, shut down to stop the proxy.
+    sockets: Mutex<HashMap<u32, Vec<TcpStream>>>,
+    stopping: AtomicBool,
+}
+
+/// Connection of a client, shared by the threads forwarding its traffic.
+pub(crate) struct Connection {
+    pub(crate) id: u32,
+    sequence: AtomicU64,
+    /// Headers known for conditions, with lowercase names.
+    headers: Vec<(String, String)>,
+    opened: Instant,
+    last_message: Mutex<Instant>,
+}
+
+impl Connection {
+    pub(crate) fn new(id: u32, headers: Vec<(String, String)>) -> Self {
+        Connection {
+            id,
+            sequence: AtomicU64::new(0),
+            headers,
+            opened: Instant::now(),
+            last_message: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Recorder {
+    /// Starts the session of a proxy of the upstream listening at the address. The mode,
+    /// like --sse, is refused along with flags of run which only websockets have.
+    pub(crate) fn start(mode: &str, address: SocketAddr, upstream: &str, options: Options)
+        -> std::result::Result<Self, String> {
+        let given = unsupported(&options);
+        if !given.is_empty() {
+            return Err(format!("{} can't be combined with {}", mode, given.join(", ")));
+        }
+        let storage = Storage::Disk(None);
+        let log_queue = Arc::new(LogQueue::start(MemoryMonitor::default(), storage.clone(),
+            options.fsync.unwrap_or(SyncPolicy::Never)));
+        let mut session = Session::start(Path::new(session::WORKSPACE), Utc::now(), address.port(), &storage)
+            .map_err(|e| format!("Failed to create session directory in {}: {}", session::WORKSPACE, e))?;
+        info!("Session {} is recorded in {}", session.id(), session.dir().display());
+        if let Some(retention) = options.retention {
+            retention.spawn(PathBuf::from(session::WORKSPACE), session.dir().to_path_buf());
+        }
+        session.write_file(session::CONFIG, &json!({ "args": options.command_line }).to_string());
+        session.record(session::session_record(session.id(), upstream, address.port(), &options.labels, None));
+
+        let rotation = options.max_log_size.map(|max_size| Rotation {
+            max_size: max_size as u64,
+            max_files: options.max_log_files.unwrap_or(proxy::DEFAULT_MAX_LOG_FILES),
+        });
+        let log_names = LogNames::new(options.log_dir.unwrap_or_default(), session.id(), options.log_name,
+            options.log_per_connection);
+        storage.create_dir(log_names.dir())
+            .map_err(|e| format!("Failed to create log directory {}: {}", log_names.dir().display(), e))?;
+        let server_log = proxy::open_rotated_log(&log_queue, &log_names.server(), rotation)?;
+        if options.log_format == LogFormat::Text {
+            server_log.write(format!("{} Proxy of {} started\n", Utc::now(), upstream));
+        }
+        Ok(Recorder {
+            capture: proxy::open_rotated_log(&log_queue, &session.capture_path(), None)?,
+            session: Mutex::new(session),
+            log_queue,
+            server_log,
+            client_logs: Mutex::new(HashMap::new()),
+            log_names,
+            log_format: options.log_format,
+            rotation,
+            renderers: options.renderers,
+            tag_rules: options.tag_rules,
+            view: options.view,
+            labels: options.labels,
+            callbacks: options.callbacks,
+            sockets: Mutex::new(HashMap::new()),
+            stopping: AtomicBool::new(false),
+        })
+    }
+
+    pub(crate) fn labels(&self) -> &[(String, String)] {
+        &self.labels
+    }
+
+    /// Accepts clients on the listener, each served by the function in a thread of its
+    /// own, until SIGINT or SIGTERM. Then all connections are shut down and recorded
+    /// as closed by the proxy, and the logs are completed.
+    pub(crate) fn run<F>(self, listener: TcpListener, serve: F) -> std::result::Result<(), String>
+        where F: Fn(&Recorder, u32, TcpStream) -> (u16, String, Initiator) + Send + Sync + 'static {
+        let address = listener.local_addr().map_err(|e| e.to_string())?;
+        let recorder = Arc::new(self);
+        let serve = Arc::new(serve);
+        let threads: Arc<Mutex<Vec<JoinHandle<()>>>> = Arc::default();
+        {
+            let recorder = recorder.clone();
+            let threads = threads.clone();
+            thread::spawn(move || {
+                for (connection_id, client) in (1..).zip(listener.incoming()) {
+                    if recorder.stopping() {
+                        return;
+                    }
+                    let client = match client {
+                        Ok(client) => client,
+                        Err(e) => {
+                            error!("Error: {}", e);
+                            continue;
+                        }
+                    };
+                    let (recorder, serve) = (recorder.clone(), serve.clone());
+                    threads.lock().unwrap().push(thread::spawn(move || {
+                        if let Ok(socket) = client.try_clone() {
+                            recorder.watch(connection_id, socket);
+                        }
+                        let (code, reason, initiator) = serve(&recorder, connection_id, client);
+                        recorder.closed(connection_id, code, reason, initiator);
+                    }));
+                }
+            });
+        }
+
+        let mut signals = Signals::new([SIGINT, SIGTERM]).map_err(|e| e.to_string())?;
+        signals.forever().next();
+        info!("Shutting down");
+        recorder.stopping.store(true, Ordering::SeqCst);
+        for socket in recorder.sockets.lock().unwrap().values().flatten() {
+            socket.shutdown(Shutdown::Both).ok();
+        }
+        // Wakes up the listener to see that the proxy stops
+        TcpStream::connect(address).ok();
+        for thread in std::mem::take(&mut *threads.lock().unwrap()) {
+            thread.join().ok();
+        }
+        let log_queue = recorder.log_queue.clone();
+        drop(recorder);
+        log_queue.finish();
+        Ok(())
+    }
+
+    pub(crate) fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Keeps a socket of the connection to be shut down when the proxy stops.
+    pub(crate) fn watch(&self, connection_id: u32, socket: TcpStream) {
+        self.sockets.lock().unwrap().entry(connection_id).or_default().push(socket);
+    }
+
+    /// Records the connection opened with the record, made with `session::open_record`.
+    pub(crate) fn opened(&self, connection_id: u32, record: Value) {
+        // The connection is recorded all the same, only without its log
+        match proxy::open_rotated_log(&self.log_queue, &self.log_names.client(connection_id), self.rotation) {
+            Ok(file) => {
+                if self.log_format == LogFormat::Text {
+                    file.write(format!("{} Client connected to the proxy with id {}\n", Utc::now(), connection_id));
+                }
+                self.client_logs.lock().unwrap().insert(connection_id, file);
+            },
+            Err(e) => error!("{}", e),
+        }
+        self.session.lock().unwrap().record(record);
+        let event = ConnectionEvent::Opened { connection_id, leg: Leg::Client };
+        for callback in self.callbacks.connections.iter() {
+            callback(&event);
+        }
+    }
+
+    /// Writes a line about the connection into the text log of the client.
+    pub(crate) fn note(&self, connection_id: u32, text: &str) {
+        if let (LogFormat::Text, Some(file)) = (self.log_format, self.client_logs.lock().unwrap().get(&connection_id)) {
+            file.write(format!("{} [connection id: {}] {}\n", Utc::now(), connection_id, text));
+        }
+    }
+
+    /// Records how the connection ended, or that the proxy stopped it.
+    fn closed(&self, connection_id: u32, code: u16, reason: String, initiator: Initiator) {
+        self.sockets.lock().unwrap().remove(&connection_id);
+        self.client_logs.lock().unwrap().remove(&connection_id);
+        let (code, reason, initiator) = if self.stopping() {
+            (1001, "The proxy stopped".to_string(), Initiator::Proxy)
+        } else {
+            (code, reason, initiator)
+        };
+        self.session.lock().unwrap().record(session::close_record(connection_id, code, &reason, initiator));
+        let event = ConnectionEvent::Closed { connection_id, leg: Leg::Client, code, reason };
+        for callback in self.callbacks.connections.iter() {
+            callback(&event);
+        }
+    }
+
+    /// Records a message received on the connection from the side, with details
+    /// of the protocol kept in the capture under their name, and a label put
+    /// before the message in the text logs.
+    pub(crate) fn message(&self, connection: &Connection, from: Leg, message: Message,
+        details: Option<(&str, Value)>, label: Option<&str>) {
+        let now = Instant::now();
+        let gap = now - std::mem::replace(&mut *connection.last_message.lock().unwrap(), now);
+        let sequence = connection.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let id = MessageId { connection_id: connection.id, sequence };
+        for callback in self.callbacks.messages.iter() {
+            callback(&MessageEvent { id, from, message: &message });
+        }
+
+        let facts = Facts {
+            from,
+            message: &message,
+            connection_id: connection.id,
+            headers: &connection.headers,
+            gap,
+            age: now - connection.opened,
+            messages: sequence,
+            environment: Environment::none(),
+        };
+        let tags = tags::apply(&self.tag_rules, &facts);
+        let mut record = session::message_record(id, from, &message);
+        if let Some((name, details)) = details {
+            record[name] = details;
+        }
+        if !tags.is_empty() {
+            record["tags"] = json!(tags);
+        }
+        self.capture.write(format!("{}\n", record));
+
+        if let Some(shown) = self.view.as_ref().and_then(|view| view.show(&facts, &tags)) {
+            println!("[{}] {}: {}", id, from, shown);
+        }
+        let client_logs = self.client_logs.lock().unwrap();
+        let file = match from {
+            Leg::Server => &self.server_log,
+            Leg::Client => match client_logs.get(&connection.id) {
+                Some(file) => file,
+                None => return,
+            },
+        };
+        match self.log_format {
+            LogFormat::Ndjson => {
+                let size = message.len();
+                file.write(format!("{}\n", session::log_record(id, from, &message, size)));
+            },
+            LogFormat::Text => {
+                let label = label.map(|label| format!("({}) ", label)).unwrap_or_default();
+                proxy::log_to_file(file, &format!("[connection id: {}]", id.connection_id),
+                    format!("[{}] {}{}\n", id, label, proxy::pretty_print(message, Some(&self.renderers))));
+            },
+        }
+    }
+}
+
+/// Flags of run which only the websocket proxy has, of the traffic, the connection to
+/// the server and the processing of messages.
+fn unsupported(options: &Options) -> Vec<&'static str> {
+    let given = [
+        ("--shutdown", !options.shutdown.is_empty()),
+        ("--interleave", !options.interleave.is_empty()),
+        ("--flood", !options.flood.is_empty()),
+        ("--pausable", options.pausable),
+        ("--tls-cert", options.tls.is_some()),
+        ("--capture-frames", options.capture_frames),
+        ("--split-on", !options.split_rules.is_empty()),
+        ("--encrypt-logs", !options.recipients.is_empty()),
+        ("--no-files", options.no_files.is_some()),
+        ("--console", options.console.is_some()),
+        ("--tui", options.tui),
+        ("--agent-port", options.agent_port.is_some()),
+        ("--observer-port", options.observer_port.is_some()),
+        ("--devtools-port", options.devtools_port.is_some()),
+        ("--control-port", options.control_port.is_some()),
+        ("--prometheus-port", options.prometheus_port.is_some()),
+        ("--otlp-endpoint", options.otlp_endpoint.is_some()),
+        ("--state", options.state.is_some()),
+        ("--metrics-every", options.metrics_every.is_some()),
+        ("--overhead", options.overhead.is_some()),
+        ("--fault", !options.faults.is_empty()),
+        ("--delay", !options.delays.is_empty()),
+        ("--throttle", !options.throttle.is_empty()),
+        ("--intercept", !options.intercepts.is_empty()),
+        ("--deflate", !options.deflate.is_empty()),
+        ("--strict-passthrough", options.strict),
+        ("--self-check", options.self_check),
+        ("--max-memory", options.max_memory.is_some()),
+        ("--sample-connections", options.sample_connections.is_some()),
+        ("--notify-clients", options.notify_clients),
+        ("--sign-key", options.sign_key.is_some()),
+        ("--anonymize", options.anonymize.is_some()),
+        ("--log-max-payload", options.log_max_payload.is_some()),
+        ("--track", !options.track.is_empty()),
+        ("--alert", !options.alerts.is_empty()),
+        ("--digest-every", options.digest_every.is_some()),
+        ("--gap", options.gap.is_some()),
+        ("--contract", options.contract.is_some()),
+        ("--hop", options.hop.is_some()),
+        ("--plugins", options.plugins.is_some()),
+        ("--script", options.script.is_some()),
+        ("--stats", options.stats_interval.is_some()),
+        ("--shed", options.shedding.is_some()),
+        ("--agent-token", options.agent_token.is_some()),
+        ("--alert-webhook", options.alert_webhook.is_some()),
+        ("--probe-response", options.probe_response != ProbeResponse::default()),
+        ("--on-upstream-loss", options.on_upstream_loss.is_some()),
+        ("--resubscribe", !options.resubscribe.is_empty()),
+        ("--upstream-overflow", options.upstream_overflow != Overflow::default()),
+        ("--aws-sigv4", options.auth.is_some()),
+        ("--subprotocol", !options.protocols.is_empty()),
+    ];
+    given.iter().filter(|(_, given)| *given).map(|(flag, _)| *flag).collect()
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use log::{debug, error, warn};
+
+/// TCP relay in front of the proxy port, so that reading from a client can be stopped
+/// at the socket: while a connection is paused nothing is read from it, its receive
+/// buffer fills up and the client's writes block as with a slow peer. The proxy sees
+/// the relayed connections coming from the loopback interface and finds the clients
+/// behind them by their addresses.
+#[derive(Clone)]
+pub struct Relay {
+    address: SocketAddr,
+    listener: Arc<Mutex<Option<TcpListener>>>,
+    links: Arc<Mutex<HashMap<SocketAddr, Link>>>,
+}
+
+#[derive(Clone)]
+struct Link {
+    peer: SocketAddr,
+    connection_id: Option<u32>,
+    paused: Arc<(Mutex<bool>, Condvar)>,
+}
+
+/// Bytes read at once, at most this much is held by the relay while paused.
+const CHUNK: usize = 16 * 1024;
+
+impl Relay {
+    /// Listens on the address, clients are accepted once the relay is started.
+    pub fn bind(address: SocketAddr) -> std::result::Result<Self, String> {
+        let listener = TcpListener::bind(address).map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+        let address = listener.local_addr().map_err(|e| e.to_string())?;
+        Ok(Relay {
+            address,
+            listener: Arc::new(Mutex::new(Some(listener))),
+            links: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Accepts clients and relays each of them to the target.
+    pub fn start(&self, target: SocketAddr) -> std::result::Result<(), String> {
+        let listener = self.listener.lock().unwrap().take()
+            .ok_or_else(|| format!("Relay on {} is started already", self.address))?;
+        let accepting = self.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                match client.and_then(|client| accepting.connect(client, target)) {
+                    Ok(()) => {},
+                    Err(e) => error!("Error: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn connect(&self, client: TcpStream, target: SocketAddr) -> io::Result<()> {
+        let peer = client.peer_addr()?;
+        let proxy = TcpStream::connect(target)?;
+        let inner = proxy.local_addr()?;
+        let paused = Arc::new((Mutex::new(false), Condvar::new()));
+        self.links.lock().unwrap().insert(inner, Link { peer, connection_id: None, paused: paused.clone() });
+        debug!("Relaying {} through {}", peer, inner);
+
+        let (client_reader, proxy_writer) = (client.try_clone()?, proxy.try_clone()?);
+        thread::spawn(move || {
+            pump(client_reader, proxy_writer, Some(paused));
+        });
+        let links = self.links.clone();
+        thread::spawn(move || {
+            pump(proxy, client, None);
+            // A paused reader goes on to find the connection closed
+            if let Some(link) = links.lock().unwrap().remove(&inner) {
+                let (state, changed) = &*link.paused;
+                *state.lock().unwrap() = false;
+                changed.notify_all();
+            }
+        });
+        Ok(())
+    }
+
+    /// Links the connection of the proxy coming from the relay address to its id,
+    /// returns the address of the client behind it.
+    pub fn accepted(&self, connection_id: u32, inner: SocketAddr) -> Option<SocketAddr> {
+        let mut links = self.links.lock().unwrap();
+        let link = links.get_mut(&inner)?;
+        link.connection_id = Some(connection_id);
+        Some(link.peer)
+    }
+
+    /// Stops or resumes reading from the client of the connection.
+    pub fn pause(&self, connection_id: u32, paused: bool) -> std::result::Result<(), String> {
+        let links = self.links.lock().unwrap();
+        let link = links.values().find(|link| link.connection_id == Some(connection_id))
+            .ok_or_else(|| format!("There is no client connection {}", connection_id))?;
+        let (state, changed) = &*link.paused;
+        *state.lock().unwrap() = paused;
+        changed.notify_all();
+        Ok(())
+    }
+}
+
+/// Copies bytes until either side closes. While paused the bytes read are held
+/// and nothing more is read.
+fn pump(mut from: TcpStream, mut to: TcpStream, paused: Option<Arc<(Mutex<bool>, Condvar)>>) {
+    let mut buffer = [0; CHUNK];
+    loop {
+        let read = match from.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                warn!("Relayed connection failed: {}", e);
+                break;
+            }
+        };
+        // The pause may come while the read waits for the client
+        if let Some(paused) = &paused {
+            let (state, changed) = &**paused;
+            let _resumed = changed.wait_while(state.lock().unwrap(), |paused| *paused).unwrap();
+        }
+        if to.write_all(&buffer[..read]).is_err() {
+            break;
+        }
+    }
+    from.shutdown(Shutdown::Read).ok();
+    to.shutdown(Shutdown::Write).ok();
+}
@@ -0,0 +1,290 @@
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use regex::Regex;
+use serde_json::Value;
+use sxd_xpath::{Context, Factory, XPath};
+
+use std::fmt;
+
+/// Kinds of content recognized in text messages, each with its own pretty-printing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    Json,
+    Xml,
+    Html,
+    Form,
+    Csv,
+    Text,
+}
+
+const RENDERERS: [Renderer; 6] = [
+    Renderer::Json, Renderer::Xml, Renderer::Html, Renderer::Form, Renderer::Csv, Renderer::Text
+];
+
+const HTML_TAGS: [&str; 24] = [
+    "html", "head", "body", "div", "span", "p", "a", "ul", "ol", "li", "table", "tr", "td", "th",
+    "h1", "h2", "h3", "h4", "form", "input", "button", "section", "img", "br"
+];
+
+const HTML_VOID_TAGS: [&str; 8] = ["br", "hr", "img", "input", "meta", "link", "area", "col"];
+
+impl Renderer {
+    pub fn parse(name: &str) -> std::result::Result<Self, String> {
+        RENDERERS.iter()
+            .find(|renderer| renderer.to_string() == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown renderer {}, expected one of json, xml, html, form, csv, text", name))
+    }
+
+    /// Guesses the kind of content of the text.
+    pub fn detect(text: &str) -> Self {
+        let trimmed = text.trim();
+        if (trimmed.starts_with('{') || trimmed.starts_with('['))
+            && serde_json::from_str::<Value>(trimmed).is_ok() {
+            return Renderer::Json;
+        }
+        if trimmed.starts_with('<') && trimmed.ends_with('>') {
+            let lowercase = trimmed.to_lowercase();
+            let tag: String = lowercase[1..].chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+            return if lowercase.starts_with("<!doctype html") || HTML_TAGS.contains(&tag.as_str()) {
+                Renderer::Html
+            } else {
+                Renderer::Xml
+            };
+        }
+        if is_form(trimmed) {
+            return Renderer::Form;
+        }
+        if is_csv(trimmed) {
+            return Renderer::Csv;
+        }
+        Renderer::Text
+    }
+
+    /// Pretty-prints the text, or returns it as is if it isn't of this kind after all.
+    pub fn render(self, text: &str) -> Option<String> {
+        match self {
+            Renderer::Json => serde_json::from_str::<Value>(text).ok()
+                .and_then(|value| serde_json::to_string_pretty(&value).ok()),
+            Renderer::Xml => render_xml(text),
+            Renderer::Html => Some(render_html(text)),
+            Renderer::Form => Some(url::form_urlencoded::parse(text.trim().as_bytes())
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Renderer::Csv => render_csv(text),
+            Renderer::Text => None,
+        }
+    }
+}
+
+impl fmt::Display for Renderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Renderer::Json => "json",
+            Renderer::Xml => "xml",
+            Renderer::Html => "html",
+            Renderer::Form => "form",
+            Renderer::Csv => "csv",
+            Renderer::Text => "text",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Renderers used for logging: messages matching a user's pattern get the renderer
+/// mapped to it, others get the detected one if it is enabled.
+/// XML messages are projected to the values of XPath expressions if any are given.
+#[derive(Default)]
+pub struct Renderers {
+    enabled: Vec<Renderer>,
+    rules: Vec<(Regex, Renderer)>,
+    /// Compiled XPath can't be sent to the proxy thread, so expressions are kept as text
+    xpaths: Vec<String>,
+}
+
+impl Renderers {
+    pub fn enable(&mut self, renderer: Renderer) {
+        if !self.enabled.contains(&renderer) {
+            self.enabled.push(renderer);
+        }
+    }
+
+    pub fn enable_all(&mut self) {
+        RENDERERS.iter().for_each(|renderer| self.enable(*renderer));
+    }
+
+    pub fn disable(&mut self, renderer: Renderer) {
+        self.enabled.retain(|enabled| *enabled != renderer);
+    }
+
+    /// Renderers of detected kinds, rules apply whichever are enabled.
+    pub fn enabled(&self) -> &[Renderer] {
+        &self.enabled
+    }
+
+    /// Adds a rule `<regex>=<renderer>`, the first matching rule wins.
+    pub fn add_rule(&mut self, rule: &str) -> std::result::Result<(), String> {
+        let index = rule.rfind('=')
+            .ok_or_else(|| format!("Render rule {} doesn't look like <regex>=<renderer>", rule))?;
+        let pattern = Regex::new(&rule[..index])
+            .map_err(|e| format!("Invalid pattern in render rule {}: {}", rule, e))?;
+        self.rules.push((pattern, Renderer::parse(&rule[index + 1..])?));
+        Ok(())
+    }
+
+    /// Adds an XPath expression to extract from XML messages, XML is enabled as well.
+    pub fn add_xpath(&mut self, expression: &str) -> std::result::Result<(), String> {
+        compile(expression)?;
+        self.xpaths.push(expression.to_string());
+        self.enable(Renderer::Xml);
+        Ok(())
+    }
+
+    pub fn renderer(&self, text: &str) -> Renderer {
+        if let Some((_, renderer)) = self.rules.iter().find(|(pattern, _)| pattern.is_match(text)) {
+            return *renderer;
+        }
+        if self.enabled.is_empty() {
+            return Renderer::Text;
+        }
+        match Renderer::detect(text) {
+            renderer if self.enabled.contains(&renderer) => renderer,
+            _ => Renderer::Text,
+        }
+    }
+
+    /// Text for logging, pretty-printed output ends with a line break.
+    pub fn render(&self, text: String) -> String {
+        let renderer = self.renderer(&text);
+        let rendered = match renderer {
+            Renderer::Xml if !self.xpaths.is_empty() => self.project(&text),
+            _ => renderer.render(&text),
+        };
+        match rendered {
+            Some(mut pretty) => {
+                pretty.push('\n');
+                pretty
+            },
+            None => text
+        }
+    }
+
+    /// Values of the XPath expressions in the document, one per line.
+    fn project(&self, text: &str) -> Option<String> {
+        let package = sxd_document::parser::parse(text).ok()?;
+        let document = package.as_document();
+        let context = Context::new();
+        let lines: Vec<String> = self.xpaths.iter()
+            .map(|expression| {
+                let evaluated = compile(expression)
+                    .and_then(|xpath| xpath.evaluate(&context, document.root()).map_err(|e| e.to_string()));
+                let value = match evaluated {
+                    Ok(sxd_xpath::Value::Nodeset(nodes)) => nodes.document_order().iter()
+                        .map(|node| node.string_value())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    Ok(value) => value.string(),
+                    Err(e) => format!("error: {}", e),
+                };
+                format!("{} = {}", expression, value)
+            })
+            .collect();
+        Some(lines.join("\n"))
+    }
+}
+
+fn compile(expression: &str) -> std::result::Result<XPath, String> {
+    Factory::new().build(expression)
+        .map_err(|e| format!("Invalid XPath {}: {}", expression, e))?
+        .ok_or_else(|| format!("XPath {} is empty", expression))
+}
+
+fn is_form(text: &str) -> bool {
+    !text.is_empty() && !text.contains(char::is_whitespace) && text.split('&').all(|pair| {
+        match pair.find('=') {
+            Some(index) => index > 0,
+            None => false,
+        }
+    })
+}
+
+fn is_csv(text: &str) -> bool {
+    let rows = csv_rows(text).unwrap_or_default();
+    rows.len() > 1 && rows[0].len() > 1 && rows.iter().all(|row| row.len() == rows[0].len())
+}
+
+fn csv_rows(text: &str) -> Option<Vec<Vec<String>>> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.trim().as_bytes())
+        .records()
+        .map(|record| record.ok().map(|record| record.iter().map(String::from).collect()))
+        .collect()
+}
+
+fn render_csv(text: &str) -> Option<String> {
+    let rows = csv_rows(text)?;
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| rows.iter()
+            .filter_map(|row| row.get(column))
+            .map(|cell| cell.chars().count())
+            .max()
+            .unwrap_or(0))
+        .collect();
+    let lines: Vec<String> = rows.iter()
+        .map(|row| row.iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join(" | ")
+            .trim_end()
+            .to_string())
+        .collect();
+    Some(lines.join("\n"))
+}
+
+fn render_xml(text: &str) -> Option<String> {
+    let mut reader = Reader::from_str(text);
+    reader.config_mut().trim_text(true);
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    loop {
+        match reader.read_event().ok()? {
+            Event::Eof => break,
+            event => writer.write_event(event).ok()?,
+        }
+    }
+    String::from_utf8(writer.into_inner()).ok()
+}
+
+/// HTML is often not well-formed XML, so its tags are only indented by nesting.
+fn render_html(text: &str) -> String {
+    let tokens = Regex::new(r"<[^>]*>|[^<]+").unwrap();
+    let mut lines = vec![];
+    let mut depth: usize = 0;
+    for token in tokens.find_iter(text).map(|token| token.as_str().trim()) {
+        if token.is_empty() {
+            continue;
+        }
+        if token.starts_with("</") {
+            depth = depth.saturating_sub(1);
+            lines.push(format!("{}{}", "  ".repeat(depth), token));
+            continue;
+        }
+
+        lines.push(format!("{}{}", "  ".repeat(depth), token));
+        let tag: String = token.trim_start_matches('<')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_lowercase();
+        let opens = token.starts_with('<') && !tag.is_empty() && !token.ends_with("/>")
+            && !HTML_VOID_TAGS.contains(&tag.as_str());
+        if opens {
+            depth += 1;
+        }
+    }
+    lines.join("\n")
+}
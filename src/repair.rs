@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+use std::fs;
+use std::path::Path;
+
+/// Outcome of repairing a capture.
+#[derive(Debug, Default)]
+pub struct Repaired {
+    /// Records which were intact.
+    pub kept: usize,
+    /// Records recovered from lines where a torn write was followed by more output.
+    pub salvaged: usize,
+    /// Damaged lines which couldn't be recovered.
+    pub dropped: usize,
+    pub dropped_bytes: usize,
+}
+
+/// Salvages a JSONL capture truncated or torn by a crash or power loss: every line
+/// holding a complete record is kept, a record following the torn tail of the previous
+/// one on the same line is recovered, anything else is dropped.
+pub fn repair(capture: &Path, output: &Path) -> std::result::Result<Repaired, String> {
+    if capture.extension().map(|extension| extension == "age").unwrap_or(false) {
+        return Err("Encrypted captures can't be repaired, decrypt them first".to_string());
+    }
+    let contents = fs::read(capture).map_err(|e| format!("Can't read {}: {}", capture.display(), e))?;
+
+    let mut repaired = Repaired::default();
+    let mut records = String::new();
+    for line in contents.split(|byte| *byte == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if is_record(line) {
+            repaired.kept += 1;
+            records.push_str(line);
+            records.push('\n');
+            continue;
+        }
+
+        let salvaged = line.char_indices()
+            .skip(1)
+            .filter(|(_, c)| *c == '{')
+            .map(|(index, _)| &line[index..])
+            .find(|suffix| is_record(suffix));
+        match salvaged {
+            Some(record) => {
+                repaired.salvaged += 1;
+                repaired.dropped_bytes += line.len() - record.len();
+                records.push_str(record);
+                records.push('\n');
+            },
+            None => {
+                repaired.dropped += 1;
+                repaired.dropped_bytes += line.len();
+            }
+        }
+    }
+
+    fs::write(output, records).map_err(|e| format!("Can't write {}: {}", output.display(), e))?;
+    Ok(repaired)
+}
+
+fn is_record(line: &str) -> bool {
+    matches!(serde_json::from_str::<Value>(line), Ok(Value::Object(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn torn_records_are_salvaged_or_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (capture, output) = (dir.path().join("capture.jsonl"), dir.path().join("repaired.jsonl"));
+        let torn = "{\"event\":\"open\"}\n\n{\"event\":\"mess{\"event\":\"close\"}\n[1]\n{\"event\":\"me";
+        fs::write(&capture, torn).unwrap();
+
+        let repaired = repair(&capture, &output).unwrap();
+        assert_eq!((repaired.kept, repaired.salvaged, repaired.dropped), (1, 1, 2));
+        assert_eq!(repaired.dropped_bytes, "{\"event\":\"mess".len() + "[1]".len() + "{\"event\":\"me".len());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "{\"event\":\"open\"}\n{\"event\":\"close\"}\n");
+
+        let encrypted = dir.path().join("capture.jsonl.age");
+        assert_eq!(repair(&encrypted, &output).unwrap_err(),
+            "Encrypted captures can't be repaired, decrypt them first");
+    }
+}
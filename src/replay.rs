@@ -0,0 +1,351 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use url::Url;
+use ws::{CloseCode, Handshake, Message, Result, Sender};
+use ws::util::Token;
+
+use std::fmt;
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::bundle::{self, Recorded, Step};
+use crate::clock::Clock;
+use crate::normalize::Normalization;
+use crate::track;
+
+/// Timeout of a message of a client waiting for its gap to pass.
+const PACING: Token = Token(usize::MAX);
+/// Largest factor of the gaps, a thousand times slower than captured.
+const MAX_SCALE: f64 = 1000.0;
+
+/// Pace of replayed messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    /// Every message is sent as soon as the steps allow.
+    MaxSpeed,
+    /// Messages are sent with the gaps between them as captured, multiplied by the factor.
+    Scaled(f64),
+}
+
+impl Timing {
+    /// Parses `max-speed`, `original` or `scale=<factor>`, like scale=0.5 for twice as fast.
+    /// The factor is above zero and at most a thousand.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "max-speed" => Ok(Timing::MaxSpeed),
+            "original" => Ok(Timing::Scaled(1.0)),
+            _ => value.strip_prefix("scale=")
+                .and_then(|factor| factor.parse::<f64>().ok())
+                .filter(|factor| *factor > 0.0 && *factor <= MAX_SCALE)
+                .map(Timing::Scaled)
+                .ok_or_else(|| format!("Invalid timing {}, expected original, max-speed or scale=<factor> \
+                    with a factor above 0 up to {}", value, MAX_SCALE)),
+        }
+    }
+
+    /// Time to wait between sending two captured messages, none if either has no time.
+    pub fn gap(&self, previous: Option<&Recorded>, next: &Recorded) -> Duration {
+        let factor = match self {
+            Timing::MaxSpeed => return Duration::ZERO,
+            Timing::Scaled(factor) => *factor,
+        };
+        match (previous.and_then(|previous| previous.time), next.time) {
+            (Some(previous), Some(next)) => {
+                // Factors of timings made up in code aren't checked like parsed ones
+                let seconds = (next - previous).to_std().unwrap_or_default().as_secs_f64() * factor;
+                match seconds > 0.0 {
+                    true => Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX),
+                    false => Duration::ZERO,
+                }
+            },
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Message of a live server compared with the one captured in the same place.
+pub struct Divergence {
+    pub step: usize,
+    /// Id of the client message the replies followed, none before the first one.
+    pub request: Option<String>,
+    /// Captured reply with its id, none if the server sent an unexpected one.
+    pub expected: Option<(Option<String>, String)>,
+    /// Reply of the live server, none if it didn't send the captured one in time.
+    pub received: Option<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let after = match &self.request {
+            Some(request) => format!("after {}", request),
+            None => "on open".to_string(),
+        };
+        match (&self.expected, &self.received) {
+            (Some((id, expected)), Some(received)) => write!(f,
+                "step {} {}: reply {} differs\n  expected: {}\n  received: {}",
+                self.step, after, id.as_deref().unwrap_or("-"), expected, received),
+            (Some((id, expected)), None) => write!(f,
+                "step {} {}: reply {} is missing\n  expected: {}",
+                self.step, after, id.as_deref().unwrap_or("-"), expected),
+            (None, Some(received)) => write!(f,
+                "step {} {}: unexpected reply\n  received: {}", self.step, after, received),
+            (None, None) => write!(f, "step {} {}: no divergence", self.step, after),
+        }
+    }
+}
+
+/// Outcome of replaying a session against a live server.
+pub struct Report {
+    /// Steps completed before the server closed the connection, if it did.
+    pub steps: usize,
+    pub matched: usize,
+    pub divergences: Vec<Divergence>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for divergence in self.divergences.iter() {
+            writeln!(f, "{}", divergence)?;
+        }
+        write!(f, "{} steps replayed, {} replies matched, {} diverged",
+            self.steps, self.matched, self.divergences.len())
+    }
+}
+
+/// Compares messages as JSON when they are, so that formatting and order of fields
+/// don't matter, with values at the tolerated JSONPath expressions ignored and
+/// the messages normalized.
+pub struct Comparison {
+    ignored: Vec<JsonPath>,
+    normalization: Normalization,
+}
+
+impl Comparison {
+    pub fn new(ignored: &[String], normalization: Normalization) -> std::result::Result<Self, String> {
+        let ignored = ignored.iter()
+            .map(|expression| track::parse(expression))
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        Ok(Comparison { ignored, normalization })
+    }
+
+    /// Text of the message in the form it is compared in.
+    pub fn normalize(&self, message: &Message) -> String {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Binary(data) => return format!("binary {}", BASE64.encode(data)),
+        };
+        let mut value = match serde_json::from_str::<Value>(text) {
+            Ok(value) => value,
+            Err(_) => return self.normalization.text(text),
+        };
+        self.normalization.value(&mut value);
+        for path in self.ignored.iter() {
+            let pointers: Vec<String> = path.query_located(&value).locations()
+                .map(|location| location.to_json_pointer())
+                .collect();
+            for pointer in pointers {
+                if let Some(ignored) = value.pointer_mut(&pointer) {
+                    *ignored = Value::Null;
+                }
+            }
+        }
+        value.to_string()
+    }
+}
+
+/// Sends the captured messages of clients to the server step by step, waiting
+/// for as many replies as were captured after each of them, at most for the timeout.
+/// With timing other than max speed each message waits for its gap since the previous
+/// captured message, counted from the moment the previous step was over on the clock.
+pub fn replay(messages: Vec<Recorded>, url: &Url, comparison: Comparison, timeout: Duration,
+              timing: Timing, clock: Arc<dyn Clock>) -> std::result::Result<Report, String> {
+    let steps = bundle::steps(messages);
+    let (report_tx, report_rx) = mpsc::channel();
+
+    ws::connect(url.to_string(), |out: Sender| Replayer {
+        out,
+        steps: &steps,
+        comparison: &comparison,
+        timeout: timeout.as_millis() as u64,
+        timing,
+        clock: clock.clone(),
+        paced: false,
+        position: 0,
+        received: vec![],
+        report: Some(Report { steps: 0, matched: 0, divergences: vec![] }),
+        result: report_tx.clone(),
+    }).map_err(|e| format!("Can't connect to {}: {}", url, e))?;
+
+    report_rx.recv().map_err(|_| format!("Connection to {} failed", url))
+}
+
+struct Replayer<'a> {
+    out: Sender,
+    steps: &'a [Step],
+    comparison: &'a Comparison,
+    timeout: u64,
+    timing: Timing,
+    clock: Arc<dyn Clock>,
+    /// Whether the message of the client of the current step has waited for its gap.
+    paced: bool,
+    position: usize,
+    received: Vec<Message>,
+    report: Option<Report>,
+    result: mpsc::Sender<Report>,
+}
+
+impl<'a> Replayer<'a> {
+    /// Sends the message of the client of the current step and waits for the replies,
+    /// steps without replies are passed right away.
+    fn start_step(&mut self) {
+        while let Some(step) = self.steps.get(self.position) {
+            if let Some(request) = &step.expected {
+                let gap = self.timing.gap(self.previous(), request);
+                if !self.paced && !gap.is_zero() {
+                    self.paced = true;
+                    self.clock.wake(&self.out, gap, PACING).unwrap_or_else(|e| error!("Error: {}", e));
+                    return;
+                }
+                self.paced = false;
+                self.out.send(request.message.clone()).unwrap_or_else(|e| error!("Error: {}", e));
+            }
+            if !step.replies.is_empty() {
+                self.out.timeout(self.timeout, Token(self.position)).unwrap_or_else(|e| error!("Error: {}", e));
+                return;
+            }
+            self.position += 1;
+        }
+        self.out.close(CloseCode::Normal).unwrap_or_else(|e| error!("Error: {}", e));
+    }
+
+    /// Last message captured before the current step.
+    fn previous(&self) -> Option<&'a Recorded> {
+        let step = self.steps.get(self.position.checked_sub(1)?)?;
+        step.replies.last().or(step.expected.as_ref())
+    }
+
+    fn finish_step(&mut self) {
+        let step = &self.steps[self.position];
+        let received = std::mem::take(&mut self.received);
+        let request = step.expected.as_ref()
+            .map(|request| request.id.clone().unwrap_or_else(|| self.comparison.normalize(&request.message)));
+        debug!("Step {} is over with {} of {} replies", self.position, received.len(), step.replies.len());
+
+        let comparison = self.comparison;
+        if let Some(report) = &mut self.report {
+            for index in 0..step.replies.len().max(received.len()) {
+                let expected = step.replies.get(index)
+                    .map(|reply| (reply.id.clone(), comparison.normalize(&reply.message)));
+                let actual = received.get(index).map(|reply| comparison.normalize(reply));
+                match (&expected, &actual) {
+                    (Some((_, expected)), Some(actual)) if expected == actual => report.matched += 1,
+                    _ => report.divergences.push(Divergence {
+                        step: self.position,
+                        request: request.clone(),
+                        expected,
+                        received: actual,
+                    }),
+                }
+            }
+        }
+
+        self.position += 1;
+        self.start_step()
+    }
+
+    fn report(&mut self) {
+        if let Some(mut report) = self.report.take() {
+            report.steps = self.position;
+            self.result.send(report).ok();
+        }
+    }
+}
+
+impl ws::Handler for Replayer<'_> {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.start_step();
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if self.position >= self.steps.len() {
+            return Ok(());
+        }
+        self.received.push(msg);
+        if self.received.len() >= self.steps[self.position].replies.len() {
+            self.finish_step();
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event == PACING {
+            self.start_step();
+            return Ok(());
+        }
+        // Timeouts of steps finished by their replies are stale
+        if event == Token(self.position) && self.position < self.steps.len() {
+            self.finish_step();
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        if self.position < self.steps.len() {
+            error!("Error: server closed the connection at step {} with {:?} {}", self.position, code, reason);
+        }
+        self.report();
+        self.out.shutdown().ok();
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        error!("Error: {}", err);
+        self.report();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::{TimeZone, Utc};
+
+    use crate::closecodes::Leg;
+
+    fn recorded(seconds: i64) -> Recorded {
+        Recorded {
+            id: None,
+            time: Some(Utc.timestamp_opt(seconds, 0).unwrap()),
+            hop: None,
+            from: Leg::Client,
+            message: Message::text("message"),
+        }
+    }
+
+    #[test]
+    fn timings_are_parsed() {
+        assert_eq!(Timing::parse("max-speed"), Ok(Timing::MaxSpeed));
+        assert_eq!(Timing::parse("original"), Ok(Timing::Scaled(1.0)));
+        assert_eq!(Timing::parse("scale=0.5"), Ok(Timing::Scaled(0.5)));
+        assert_eq!(Timing::parse("scale=1000"), Ok(Timing::Scaled(1000.0)));
+        for invalid in ["scale=0", "scale=-1", "scale=1001", "scale=1e300", "scale=inf", "scale=nan", "fast"] {
+            assert!(Timing::parse(invalid).is_err(), "{} is parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn gaps_are_scaled() {
+        let (first, second) = (recorded(0), recorded(10));
+        assert_eq!(Timing::MaxSpeed.gap(Some(&first), &second), Duration::ZERO);
+        assert_eq!(Timing::Scaled(0.5).gap(Some(&first), &second), Duration::from_secs(5));
+        assert_eq!(Timing::Scaled(1.0).gap(None, &second), Duration::ZERO);
+        // Messages captured out of order are sent at once
+        assert_eq!(Timing::Scaled(1.0).gap(Some(&second), &first), Duration::ZERO);
+        assert_eq!(Timing::Scaled(-1.0).gap(Some(&first), &second), Duration::ZERO);
+        assert_eq!(Timing::Scaled(f64::MAX).gap(Some(&first), &second), Duration::MAX);
+    }
+}
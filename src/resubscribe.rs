@@ -0,0 +1,51 @@
+use ws::Message;
+
+use std::collections::BTreeMap;
+
+use crate::condition::{Condition, Facts};
+
+/// Setup messages kept for each client, the oldest are forgotten beyond it.
+const KEPT: usize = 100;
+
+/// Setup messages of clients, like authentication and subscriptions, matching the conditions
+/// given with --resubscribe. They are sent to the server again, in the order the client sent
+/// them, each time the connection to it is opened again, so that the logical session of
+/// a client resumes after a reconnect without it noticing. A message sent again unchanged,
+/// like a repeated subscription, is kept once.
+pub struct Resubscribe {
+    conditions: Vec<Condition>,
+    kept: BTreeMap<u32, Vec<Message>>,
+}
+
+impl Resubscribe {
+    pub fn new(conditions: Vec<Condition>) -> Self {
+        Resubscribe { conditions, kept: BTreeMap::new() }
+    }
+
+    /// Keeps a message of the client to the server if it matches a condition.
+    pub fn sent(&mut self, client: u32, facts: &Facts) {
+        if !self.conditions.iter().any(|condition| condition.matches(facts)) {
+            return;
+        }
+        let kept = self.kept.entry(client).or_default();
+        if kept.contains(facts.message) {
+            return;
+        }
+        if kept.len() == KEPT {
+            kept.remove(0);
+        }
+        kept.push(facts.message.clone());
+    }
+
+    /// Drops the messages of a client which closed.
+    pub fn forget(&mut self, client: u32) {
+        self.kept.remove(&client);
+    }
+
+    /// Messages to send again with their clients, which are kept for the next reconnect.
+    pub fn replays(&self) -> Vec<(u32, Message)> {
+        self.kept.iter()
+            .flat_map(|(client, messages)| messages.iter().map(move |msg| (*client, msg.clone())))
+            .collect()
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+
+use crate::memory::{self, format_size};
+
+/// Limits on the sessions kept in the workspace. Sessions beyond them are removed,
+/// oldest first, except for the one currently being recorded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+impl Retention {
+    /// Adds a rule: an age like `12h`, `7d` or `2w`, or a total size like `10GB`.
+    pub fn add(&mut self, rule: &str) -> std::result::Result<(), String> {
+        match parse_age(rule) {
+            Some(age) => self.max_age = Some(age?),
+            None => self.max_bytes = Some(memory::parse_size(rule)
+                .map_err(|e| format!("Retention {} is neither an age nor a size: {}", rule, e))? as u64),
+        }
+        Ok(())
+    }
+
+    /// Removes sessions exceeding the limits, returns their directories.
+    pub fn apply(&self, workspace: &Path, current: Option<&Path>) -> io::Result<Vec<PathBuf>> {
+        let mut sessions = vec![];
+        for entry in fs::read_dir(workspace)? {
+            let path = entry?.path();
+            if path.is_dir() && Some(path.as_path()) != current {
+                let (size, modified) = usage(&path)?;
+                sessions.push((path, size, modified));
+            }
+        }
+        // Names of sessions start with the time they were started at
+        sessions.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let now = SystemTime::now();
+        let mut total: u64 = sessions.iter().map(|(_, size, _)| size).sum();
+        let mut removed = vec![];
+        for (path, size, modified) in sessions {
+            let expired = self.max_age
+                .map(|max_age| now.duration_since(modified).unwrap_or_default() > max_age)
+                .unwrap_or(false);
+            let excess = self.max_bytes.map(|max_bytes| total > max_bytes).unwrap_or(false);
+            if !expired && !excess {
+                continue;
+            }
+
+            fs::remove_dir_all(&path)?;
+            info!("Session {} ({}) is removed by retention policy", path.display(), format_size(size as usize));
+            total -= size;
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+
+    /// Applies the policy now and then every hour in a background thread.
+    pub fn spawn(self, workspace: PathBuf, current: PathBuf) {
+        std::thread::spawn(move || loop {
+            if let Err(e) = self.apply(&workspace, Some(&current)) {
+                error!("Error: {}", e);
+            }
+            std::thread::sleep(Duration::from_secs(3600));
+        });
+    }
+}
+
+/// Parses an interval of periodic reports like `30s`, `5m` or `1h`.
+pub fn parse_interval(value: &str) -> std::result::Result<Duration, String> {
+    match parse_age(value) {
+        Some(Ok(interval)) if !interval.is_zero() => Ok(interval),
+        Some(Err(e)) => Err(e),
+        _ => Err(format!("Invalid interval {}, expected like 30s, 5m or 1h", value)),
+    }
+}
+
+/// Parses a number with a unit of time, none if the rule isn't one, an error if it's too long.
+fn parse_age(rule: &str) -> Option<std::result::Result<Duration, String>> {
+    let rule = rule.trim();
+    let split = rule.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = rule.split_at(split);
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let too_long = || format!("{} is too long", rule);
+    Some(number.parse::<u64>().ok()
+        .and_then(|number| number.checked_mul(seconds))
+        .map(Duration::from_secs)
+        .ok_or_else(too_long))
+}
+
+/// Total size of files in the directory and its subdirectories, like blobs,
+/// and the time of the latest modification.
+fn usage(dir: &Path) -> io::Result<(u64, SystemTime)> {
+    let mut size = 0;
+    let mut modified = fs::metadata(dir)?.modified()?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let (entry_size, entry_modified) = if metadata.is_dir() {
+            usage(&entry.path())?
+        } else {
+            (metadata.len(), metadata.modified()?)
+        };
+        size += entry_size;
+        modified = modified.max(entry_modified);
+    }
+    Ok((size, modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    /// Session directory with a file of the size, last modified the time ago.
+    fn session(workspace: &Path, name: &str, size: usize, age: Duration) -> PathBuf {
+        let dir = workspace.join(name);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("client.log"), vec![b'x'; size]).unwrap();
+        let modified = SystemTime::now() - age;
+        File::open(dir.join("client.log")).unwrap().set_modified(modified).unwrap();
+        File::open(&dir).unwrap().set_modified(modified).unwrap();
+        dir
+    }
+
+    #[test]
+    fn rules_are_ages_or_sizes() {
+        let mut retention = Retention::default();
+        retention.add("2w").unwrap();
+        retention.add("10KB").unwrap();
+        assert_eq!(retention.max_age, Some(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(retention.max_bytes, Some(10 * 1024));
+        assert!(retention.add("soon").unwrap_err().starts_with("Retention soon is neither an age nor a size"));
+        assert_eq!(retention.add("99999999999999999w"), Err("99999999999999999w is too long".to_string()));
+    }
+
+    #[test]
+    fn intervals_are_ages_above_zero() {
+        assert_eq!(parse_interval("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_interval(" 5m "), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(3600)));
+        for invalid in ["0s", "5", "5y", "m", "-1s"] {
+            assert!(parse_interval(invalid).is_err(), "{} is parsed", invalid);
+        }
+        assert_eq!(parse_interval("99999999999999999999d"), Err("99999999999999999999d is too long".to_string()));
+    }
+
+    #[test]
+    fn oldest_sessions_beyond_the_size_are_removed() {
+        let workspace = tempfile::tempdir().unwrap();
+        let hour = Duration::from_secs(3600);
+        let current = session(workspace.path(), "20260101-000000", 100, hour);
+        let oldest = session(workspace.path(), "20260102-000000", 100, hour);
+        let newer = session(workspace.path(), "20260103-000000", 100, hour);
+        let newest = session(workspace.path(), "20260104-000000", 100, hour);
+
+        let retention = Retention { max_age: None, max_bytes: Some(250) };
+        assert_eq!(retention.apply(workspace.path(), Some(&current)).unwrap(), vec![oldest.clone()]);
+        assert!(current.exists() && !oldest.exists() && newer.exists() && newest.exists());
+        assert!(retention.apply(workspace.path(), Some(&current)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sessions_older_than_the_age_are_removed() {
+        let workspace = tempfile::tempdir().unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let old = session(workspace.path(), "20260101-000000", 10, 8 * day);
+        let recent = session(workspace.path(), "20260110-000000", 10, day);
+
+        let retention = Retention { max_age: Some(7 * day), max_bytes: None };
+        assert_eq!(retention.apply(workspace.path(), None).unwrap(), vec![old]);
+        assert!(recent.exists());
+    }
+}
@@ -0,0 +1,101 @@
+use std::cell::Cell;
+use std::fmt;
+
+/// Share of client connections which are captured, like 1/10. Connections are
+/// numbered in the order they come, so the same connections of a run are captured
+/// every time: the first `captured` of every `of` consecutive ones.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    captured: u32,
+    of: u32,
+}
+
+impl Rate {
+    /// Parses `<captured>/<of>`.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("Invalid sampling rate {}, expected like 1/10", value);
+        let (captured, of) = value.split_once('/').ok_or_else(invalid)?;
+        let captured = captured.trim().parse::<u32>().map_err(|_| invalid())?;
+        let of = of.trim().parse::<u32>().map_err(|_| invalid())?;
+        if captured == 0 || of == 0 || captured > of {
+            return Err(invalid());
+        }
+        Ok(Rate { captured, of })
+    }
+
+    /// Whether the client connection is captured, clients are numbered from 1.
+    pub fn includes(&self, connection_id: u32) -> bool {
+        connection_id.saturating_sub(1) % self.of < self.captured
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.captured, self.of)
+    }
+}
+
+/// Client connections left out of the capture and their messages, which are only counted.
+pub struct Sampling {
+    rate: Rate,
+    connections: Cell<u64>,
+    messages: Cell<u64>,
+    bytes: Cell<u64>,
+}
+
+impl Sampling {
+    pub fn new(rate: Rate) -> Self {
+        Sampling { rate, connections: Cell::new(0), messages: Cell::new(0), bytes: Cell::new(0) }
+    }
+
+    /// Whether the client connection is captured, counting it if it is not.
+    pub fn admit(&self, connection_id: u32) -> bool {
+        let included = self.rate.includes(connection_id);
+        if !included {
+            self.connections.set(self.connections.get() + 1);
+        }
+        included
+    }
+
+    pub fn skipped(&self, size: usize) {
+        self.messages.set(self.messages.get() + 1);
+        self.bytes.set(self.bytes.get() + size as u64);
+    }
+
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    /// Connections, messages and bytes left out.
+    pub fn skipped_counts(&self) -> (u64, u64, u64) {
+        (self.connections.get(), self.messages.get(), self.bytes.get())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_first_connections_of_every_group_are_captured() {
+        let rate = Rate::parse("2/5").unwrap();
+        let captured: Vec<u32> = (1..=12).filter(|id| rate.includes(*id)).collect();
+        assert_eq!(captured, [1, 2, 6, 7, 11, 12]);
+        assert_eq!(rate.to_string(), "2/5");
+        assert!((1..=5).all(|id| Rate::parse("5/5").unwrap().includes(id)));
+        for invalid in ["0/3", "4/3", "1/0", "a/b", "3"] {
+            assert_eq!(Rate::parse(invalid).unwrap_err(),
+                format!("Invalid sampling rate {}, expected like 1/10", invalid));
+        }
+    }
+
+    #[test]
+    fn connections_left_out_are_counted() {
+        let sampling = Sampling::new(Rate::parse("1/2").unwrap());
+        let admitted: Vec<bool> = (1..=4).map(|id| sampling.admit(id)).collect();
+        assert_eq!(admitted, [true, false, true, false]);
+        sampling.skipped(10);
+        sampling.skipped(5);
+        assert_eq!(sampling.skipped_counts(), (2, 2, 15));
+    }
+}
@@ -0,0 +1,191 @@
+use serde_json::{json, Map, Value};
+
+/// Snapshot file the scaffolded setup is started from with `ws-proxy restore`.
+pub const STATE: &str = "ws-proxy.state.json";
+
+/// Protocol a scaffolded setup is made for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// JSON messages with a type field.
+    Json,
+    /// GraphQL subscriptions over the graphql-transport-ws protocol.
+    GraphqlWs,
+    /// Socket.IO 4 over Engine.IO 4.
+    SocketIo,
+}
+
+impl Preset {
+    pub fn parse(name: &str) -> std::result::Result<Self, String> {
+        match name {
+            "json" => Ok(Preset::Json),
+            "graphql-ws" => Ok(Preset::GraphqlWs),
+            "socket.io" => Ok(Preset::SocketIo),
+            _ => Err(format!("Preset {} is none of json, graphql-ws and socket.io", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Json => "json",
+            Preset::GraphqlWs => "graphql-ws",
+            Preset::SocketIo => "socket.io",
+        }
+    }
+}
+
+/// Files of a working setup for a protocol: a script of the test server playing its
+/// scenario, a processing script and saved views, with the command line using them
+/// together with tag and alert rules, to be saved as a snapshot.
+pub struct Scaffold {
+    pub scenario: (String, String),
+    pub script: (String, String),
+    pub views: Map<String, Value>,
+    pub args: Vec<String>,
+}
+
+/// Setup proxying the port to the server, or to the test server playing the scenario.
+pub fn scaffold(preset: Preset, port: u16, server: Option<&str>) -> Scaffold {
+    let scenario_file = format!("{}.scenario.txt", preset.name());
+    let script_file = format!("{}.process.rhai", preset.name());
+    let (scenario, script, views, tags) = match preset {
+        Preset::Json => (JSON_SCENARIO, JSON_SCRIPT, json!({
+            "quotes": {
+                "from": "server",
+                "filter": "\"type\":\"quote\"",
+                "project": ["$.symbol", "$.price"],
+            },
+            "errors": {
+                "filter": "\"type\":\"error\"",
+                "highlight": ["\"error\":\"[^\"]*\""],
+            },
+        }), ["noise=\"type\":\"(ping|pong)\"", "market=server:\"type\":\"quote\""]),
+        Preset::GraphqlWs => (GRAPHQL_WS_SCENARIO, GRAPHQL_WS_SCRIPT, json!({
+            "subscriptions": {
+                "filter": "\"type\":\"(subscribe|next|complete)\"",
+                "project": ["$.id", "$.type"],
+            },
+            "errors": {
+                "filter": "\"type\":\"error\"|\"errors\":",
+                "highlight": ["\"message\":\"[^\"]*\""],
+            },
+        }), ["noise=\"type\":\"(ping|pong)\"", "data=server:\"type\":\"next\""]),
+        Preset::SocketIo => (SOCKET_IO_SCENARIO, SOCKET_IO_SCRIPT, json!({
+            "events": {
+                "filter": "^42",
+                "highlight": ["^42\\[\"[^\"]*\""],
+            },
+            "heartbeats": {
+                "filter": "^[23]$",
+            },
+        }), ["noise=^[23]$", "event=^42"]),
+    };
+
+    let mut args = match server {
+        Some(server) => vec![server.to_string(), port.to_string()],
+        None => vec!["--with-test-server".to_string(), "--test-script".to_string(), scenario_file.clone(),
+                     port.to_string()],
+    };
+    args.extend(["--label".to_string(), format!("protocol={}", preset.name())]);
+    for tag in tags {
+        args.extend(["--tag".to_string(), tag.to_string()]);
+    }
+    args.extend(["--alert".to_string(), "error".to_string()]);
+
+    Scaffold {
+        scenario: (scenario_file, scenario.to_string()),
+        script: (script_file, script.to_string()),
+        views: views.as_object().cloned().unwrap_or_default(),
+        args,
+    }
+}
+
+const JSON_SCENARIO: &str = "\
+# Test server of a JSON protocol, run with --with-test-server --test-script <this file>.
+# Requests are matched as compact JSON with fields sorted, {name} matches any value.
+on-open {\"type\":\"welcome\"}
+{\"id\":\"{id}\",\"type\":\"ping\"} => {\"id\":\"{id}\",\"type\":\"pong\"}
+{\"channel\":\"{channel}\",\"type\":\"subscribe\"} => {\"channel\":\"{channel}\",\"type\":\"subscribed\"}
+{\"channel\":\"{channel}\",\"type\":\"subscribe\"} => {\"price\":101.5,\"symbol\":\"ACME\",\"type\":\"quote\"}
+* => {\"error\":\"unknown request\",\"type\":\"error\"}
+";
+
+const JSON_SCRIPT: &str = r#"// Run with: ws-proxy process <session> --script <this file>
+// Keeps the quotes of the server, counting its messages by type and summing the prices.
+fn on_client_message(msg) {
+    false
+}
+
+fn on_server_message(msg) {
+    if msg.binary || !msg.text.starts_with("{") { return false; }
+    let payload = parse_json(msg.text);
+    if payload.type in ["ping", "pong"] { return false; }
+    if !("types" in this) { this.types = #{}; this.prices = 0.0; }
+    this.types[payload.type] = (this.types[payload.type] ?? 0) + 1;
+    if payload.type != "quote" { return false; }
+    this.prices += payload.price;
+}
+
+fn report() {
+    #{ "count by type": this.types, "sum of prices": this.prices }
+}
+"#;
+
+const GRAPHQL_WS_SCENARIO: &str = "\
+# Test server of GraphQL subscriptions over graphql-transport-ws, run with
+# --with-test-server --test-script <this file>. The proxy doesn't negotiate subprotocols,
+# so clients must not insist on the server accepting graphql-transport-ws.
+{\"type\":\"connection_init\"} => {\"type\":\"connection_ack\"}
+{\"type\":\"ping\"} => {\"type\":\"pong\"}
+{\"id\":\"{id}\",\"payload\":{payload},\"type\":\"subscribe\"} => {\"id\":\"{id}\",\"payload\":{\"data\":{\"ticker\":{\"price\":101.5,\"symbol\":\"ACME\"}}},\"type\":\"next\"}
+{\"id\":\"{id}\",\"payload\":{payload},\"type\":\"subscribe\"} => {\"id\":\"{id}\",\"type\":\"complete\"}
+* => {\"payload\":[{\"message\":\"unknown message\"}],\"type\":\"error\"}
+";
+
+const GRAPHQL_WS_SCRIPT: &str = r#"// Run with: ws-proxy process <session> --script <this file>
+// Keeps the data of subscriptions, counting messages by type and data by subscription.
+fn count(name, key) {
+    if !(name in this) { this[name] = #{}; }
+    this[name][key] = (this[name][key] ?? 0) + 1;
+}
+
+fn on_client_message(msg) {
+    this.handle(msg)
+}
+
+fn on_server_message(msg) {
+    this.handle(msg)
+}
+
+fn handle(msg) {
+    if msg.binary || !msg.text.starts_with("{") { return false; }
+    let payload = parse_json(msg.text);
+    if payload.type in ["ping", "pong"] { return false; }
+    this.count("count by type", payload.type);
+    if payload.type != "next" { return false; }
+    this.count("data by subscription", payload.id);
+}
+
+fn report() {
+    this
+}
+"#;
+
+const SOCKET_IO_SCENARIO: &str = "\
+# Test server of Socket.IO 4 over Engine.IO 4, run with --with-test-server --test-script <this file>.
+# The client is told there are no upgrades, so it stays on this websocket.
+on-open 0{\"sid\":\"scaffold\",\"upgrades\":[],\"pingInterval\":25000,\"pingTimeout\":20000,\"maxPayload\":1000000}
+40 => 40{\"sid\":\"scaffold-socket\"}
+every 25000 2
+42[\"chat\",{payload}] => 42[\"chat\",{payload}]
+";
+
+const SOCKET_IO_SCRIPT: &str = r#"// Run with: ws-proxy process <session> --script <this file>
+// Engine.IO pings (2) and pongs (3) are noise, events are 42["<name>",...] and kept alone.
+fn on_client_message(msg) {
+    !msg.binary && msg.text.starts_with("42")
+}
+
+fn on_server_message(msg) {
+    !msg.binary && msg.text.starts_with("42")
+}
+"#;
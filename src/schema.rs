@@ -0,0 +1,127 @@
+use serde_json::{json, Map, Value};
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Fields naming the kind of a JSON message, the first one found is used.
+pub const KIND_FIELDS: [&str; 6] = ["type", "event", "op", "method", "action", "kind"];
+
+/// Value of the first kind field of a JSON message.
+pub fn kind(value: &Value) -> Option<String> {
+    KIND_FIELDS.iter()
+        .filter_map(|field| value.get(field))
+        .find_map(|found| match found {
+            Value::String(name) => Some(name.clone()),
+            Value::Number(number) => Some(number.to_string()),
+            _ => None,
+        })
+}
+
+/// Shape of sample values, turned into a JSON Schema: the types seen, properties
+/// of objects with those present in every object required, and items of arrays.
+#[derive(Default)]
+pub struct Shape {
+    types: BTreeSet<&'static str>,
+    objects: usize,
+    properties: BTreeMap<String, (usize, Shape)>,
+    items: Option<Box<Shape>>,
+}
+
+impl Shape {
+    pub fn add(&mut self, value: &Value) {
+        self.types.insert(type_name(value));
+        match value {
+            Value::Object(object) => {
+                self.objects += 1;
+                for (key, value) in object.iter() {
+                    let (present, shape) = self.properties.entry(key.clone()).or_default();
+                    *present += 1;
+                    shape.add(value);
+                }
+            },
+            Value::Array(array) => {
+                let items = self.items.get_or_insert_with(Box::default);
+                for item in array.iter() {
+                    items.add(item);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Types seen, where integers are numbers as well once fractions are seen.
+    pub fn types(&self) -> Vec<&'static str> {
+        let mut types: Vec<&str> = self.types.iter().copied().collect();
+        if types.contains(&"number") {
+            types.retain(|name| *name != "integer");
+        }
+        types
+    }
+
+    /// All fields below the value, like `$.user.name` or `$.tags[*]`.
+    pub fn fields(&self) -> BTreeMap<String, Field> {
+        let mut fields = BTreeMap::new();
+        self.collect("$", &mut fields);
+        fields
+    }
+
+    fn collect(&self, path: &str, fields: &mut BTreeMap<String, Field>) {
+        for (key, (present, shape)) in self.properties.iter() {
+            let path = format!("{}.{}", path, key);
+            fields.insert(path.clone(), Field { types: shape.types(), optional: *present < self.objects });
+            shape.collect(&path, fields);
+        }
+        if let Some(items) = &self.items {
+            let path = format!("{}[*]", path);
+            fields.insert(path.clone(), Field { types: items.types(), optional: false });
+            items.collect(&path, fields);
+        }
+    }
+
+    pub fn schema(&self) -> Value {
+        let types = self.types();
+        let mut schema = Map::new();
+        match types.as_slice() {
+            [] => {},
+            [single] => { schema.insert("type".to_string(), json!(single)); },
+            _ => { schema.insert("type".to_string(), json!(types)); },
+        }
+        if self.objects > 0 {
+            let properties: Map<String, Value> = self.properties.iter()
+                .map(|(key, (_, shape))| (key.clone(), shape.schema()))
+                .collect();
+            let required: Vec<&String> = self.properties.iter()
+                .filter(|(_, (present, _))| *present == self.objects)
+                .map(|(key, _)| key)
+                .collect();
+            schema.insert("properties".to_string(), Value::Object(properties));
+            if !required.is_empty() {
+                schema.insert("required".to_string(), json!(required));
+            }
+        }
+        if let Some(items) = &self.items {
+            schema.insert("items".to_string(), items.schema());
+        }
+        Value::Object(schema)
+    }
+}
+
+/// Field of JSON values with the types seen in it.
+#[derive(Debug, PartialEq)]
+pub struct Field {
+    pub types: Vec<&'static str>,
+    /// Missing in some of the objects.
+    pub optional: bool,
+}
+
+/// Name of the JSON Schema type of the value.
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
@@ -0,0 +1,75 @@
+use std::cell::Cell;
+use std::fmt;
+
+use crate::condition::{Condition, Facts};
+
+/// Rule marking the boundary of a logical session in the traffic, where the capture
+/// is split: a matching message starts a new segment, a matching close ends one.
+#[derive(Clone)]
+pub enum SplitRule {
+    Message(Condition),
+    Close(u16),
+}
+
+impl SplitRule {
+    /// Parses `message=<condition>` or `close=<code>`.
+    pub fn parse(rule: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("Split rule {} is not message=<condition> or close=<code>", rule);
+        match rule.split_once('=').ok_or_else(invalid)? {
+            ("message", condition) => Condition::parse(condition).map(SplitRule::Message)
+                .map_err(|e| format!("Invalid split rule {}: {}", rule, e)),
+            ("close", code) => code.parse().map(SplitRule::Close).map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for SplitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitRule::Message(condition) => write!(f, "message={}", condition),
+            SplitRule::Close(code) => write!(f, "close={}", code),
+        }
+    }
+}
+
+/// Segments of the capture, numbered from 1. A boundary found before anything
+/// is captured in the current segment starts no new one, so a session beginning
+/// with a login message is not preceded by an empty segment.
+pub struct Segments {
+    rules: Vec<SplitRule>,
+    current: Cell<u32>,
+    captured: Cell<u64>,
+}
+
+impl Segments {
+    pub fn new(rules: Vec<SplitRule>) -> Self {
+        Segments { rules, current: Cell::new(1), captured: Cell::new(0) }
+    }
+
+    /// Rule by which the message starts a new segment.
+    pub fn starts(&self, facts: &Facts) -> Option<&SplitRule> {
+        self.rules.iter().filter(|_| self.captured.get() > 0).find(|rule| match rule {
+            SplitRule::Message(condition) => condition.matches(facts),
+            SplitRule::Close(_) => false,
+        })
+    }
+
+    /// Rule by which a connection closed with the code ends the segment.
+    pub fn ends(&self, code: u16) -> Option<&SplitRule> {
+        self.rules.iter().filter(|_| self.captured.get() > 0)
+            .find(|rule| matches!(rule, SplitRule::Close(closed) if *closed == code))
+    }
+
+    /// Starts the next segment, returns its number.
+    pub fn next(&self) -> u32 {
+        self.current.set(self.current.get() + 1);
+        self.captured.set(0);
+        self.current.get()
+    }
+
+    /// Counts a message written into the current segment.
+    pub fn captured(&self) {
+        self.captured.set(self.captured.get() + 1);
+    }
+}
@@ -0,0 +1,227 @@
+use sha2::{Digest, Sha256};
+use ws::{Frame, Message, OpCode};
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+
+use log::{info, error};
+
+use crate::session::MessageId;
+
+/// Verification that the proxy itself forwards messages in order and without losses.
+/// Every message is tagged with a sequence number and a digest of its payload as soon as
+/// it's received, before anything of the proxy touches it, for every connection it's
+/// routed to, and the tag is checked when a frame is written to the connection. What
+/// the user enabled to change the traffic, like plugins, faults and rules dropping
+/// messages, tells the check what the message became, and messages the proxy makes up,
+/// like notices, are registered as they are sent. Ordering is only guaranteed between
+/// two connections, since delays apply to each way separately. Digests are SHA-256,
+/// so that a corrupted message can't pass for the original one.
+#[derive(Default)]
+pub struct SelfCheck {
+    queues: HashMap<u32, Queue>,
+    violations: u64,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: VecDeque<Pending>,
+    next_seq: u64,
+    verified: u64,
+    reordered: u64,
+    unexpected: u64,
+    lost: u64,
+}
+
+struct Pending {
+    seq: u64,
+    /// Message received, none for those made up by the proxy.
+    id: Option<MessageId>,
+    digest: [u8; 32],
+}
+
+impl Pending {
+    /// Connection the message came from, messages made up by the proxy are told apart too.
+    fn source(&self) -> Option<u32> {
+        self.id.map(|id| id.connection_id)
+    }
+}
+
+impl Queue {
+    fn push(&mut self, id: Option<MessageId>, msg: &Message) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.pending.push_back(Pending { seq, id, digest: digest(payload(msg)) });
+    }
+}
+
+impl SelfCheck {
+    pub fn new() -> Self {
+        SelfCheck::default()
+    }
+
+    /// Registers a message just received, which is routed to the `destinations` connections.
+    pub fn received(&mut self, id: MessageId, destinations: &[u32], msg: &Message) {
+        for destination in destinations {
+            self.queues.entry(*destination).or_default().push(Some(id), msg);
+        }
+    }
+
+    /// Registers what a change the user enabled made of a received message, none if it's
+    /// dropped, or the message it's replaced with, or several if it's duplicated.
+    pub fn replaced(&mut self, id: MessageId, forwarded: &[Message]) {
+        for queue in self.queues.values_mut() {
+            let position = match queue.pending.iter().position(|pending| pending.id == Some(id)) {
+                Some(position) => position,
+                None => continue,
+            };
+            queue.pending.retain(|pending| pending.id != Some(id));
+            for (offset, msg) in forwarded.iter().enumerate() {
+                let seq = queue.next_seq;
+                queue.next_seq += 1;
+                queue.pending.insert(position + offset, Pending { seq, id: Some(id), digest: digest(payload(msg)) });
+            }
+        }
+    }
+
+    /// Registers that a rule the user enabled doesn't send the message to the `destination`.
+    pub fn skipped(&mut self, id: MessageId, destination: u32) {
+        if let Some(queue) = self.queues.get_mut(&destination) {
+            queue.pending.retain(|pending| pending.id != Some(id));
+        }
+    }
+
+    /// Registers a message made up by the proxy, which is about to be sent to the `destination`.
+    pub fn synthesized(&mut self, destination: u32, msg: &Message) {
+        self.queues.entry(destination).or_default().push(None, msg);
+    }
+
+    /// Reports a received message which the proxy drops itself instead of sending it to the `destination`.
+    pub fn dropped(&mut self, id: MessageId, destination: u32, why: &str) {
+        let position = self.queues.get(&destination)
+            .and_then(|queue| queue.pending.iter().position(|pending| pending.id == Some(id)));
+        if let Some(position) = position {
+            self.lose(destination, position..position + 1, why);
+        }
+    }
+
+    /// Reports the oldest messages which the proxy drops itself instead of sending them to the `destination`.
+    pub fn dropped_oldest(&mut self, destination: u32, count: usize, why: &str) {
+        let pending = self.queues.get(&destination).map(|queue| queue.pending.len()).unwrap_or_default();
+        self.lose(destination, 0..count.min(pending), why);
+    }
+
+    fn lose(&mut self, destination: u32, range: Range<usize>, why: &str) {
+        let queue = match self.queues.get_mut(&destination) {
+            Some(queue) if !range.is_empty() => queue,
+            _ => return,
+        };
+        let seqs: Vec<String> = queue.pending.drain(range)
+            .map(|pending| format!("#{}", pending.seq))
+            .collect();
+        queue.lost += seqs.len() as u64;
+        self.violations += 1;
+        error!("Self-check: {} messages to connection {} are dropped, {}: {}",
+            seqs.len(), destination, why, seqs.join(", "));
+    }
+
+    /// Forgets the last messages of the closed `source` connection, which the proxy held
+    /// back and doesn't send to the `destination` once their client is gone.
+    pub fn withdrawn(&mut self, destination: u32, source: u32, count: usize) {
+        if let Some(queue) = self.queues.get_mut(&destination) {
+            let mut left = count;
+            for position in (0..queue.pending.len()).rev() {
+                if left == 0 {
+                    break;
+                }
+                if queue.pending[position].source() == Some(source) {
+                    queue.pending.remove(position);
+                    left -= 1;
+                }
+            }
+        }
+    }
+
+    /// Checks a frame which is being written to the `destination` connection.
+    /// Returns what is wrong with it if it is out of order or not forwarded by the proxy.
+    pub fn egress(&mut self, destination: u32, frame: &Frame) -> std::result::Result<(), String> {
+        match frame.opcode() {
+            OpCode::Text | OpCode::Binary => {},
+            _ => return Ok(())
+        }
+
+        let queue = self.queues.entry(destination).or_default();
+        let digest = digest(frame.payload());
+
+        // Messages of other connections may be the same, the next one of a connection is expected
+        let next = |position: usize, pending: &Pending| {
+            !queue.pending.iter().take(position).any(|earlier| earlier.source() == pending.source())
+        };
+        let in_order = queue.pending.iter().enumerate()
+            .position(|(position, pending)| pending.digest == digest && next(position, pending));
+        let any = queue.pending.iter().position(|pending| pending.digest == digest);
+
+        let checked = match (in_order, any) {
+            (Some(position), _) => {
+                queue.pending.remove(position);
+                queue.verified += 1;
+                Ok(())
+            },
+            (None, Some(position)) => {
+                let pending = queue.pending.remove(position).unwrap();
+                let expected = queue.pending.iter().find(|earlier| earlier.source() == pending.source())
+                    .map(|earlier| earlier.seq)
+                    .unwrap_or_default();
+                queue.reordered += 1;
+                self.violations += 1;
+                Err(format!("connection {} received message #{} while #{} was expected",
+                    destination, pending.seq, expected))
+            },
+            (None, None) => {
+                queue.unexpected += 1;
+                self.violations += 1;
+                Err(format!("connection {} received a message which was not forwarded \
+                    by the proxy or was corrupted ({} bytes, SHA-256 {})",
+                    destination, frame.payload().len(), hex::encode(digest)))
+            }
+        };
+        if let Err(e) = &checked {
+            error!("Self-check: {}", e);
+        }
+        checked
+    }
+
+    /// Reports messages which were never delivered to the closed connection.
+    pub fn closed(&mut self, destination: u32) {
+        if let Some(queue) = self.queues.remove(&destination) {
+            if !queue.pending.is_empty() {
+                let seqs: Vec<String> = queue.pending.iter()
+                    .map(|pending| format!("#{}", pending.seq))
+                    .collect();
+                error!("Self-check: {} messages were not delivered to connection {}: {}",
+                    seqs.len(), destination, seqs.join(", "));
+            }
+
+            info!("Self-check for connection {}: forwarded {}, verified {}, reordered {}, \
+                unexpected {}, dropped {}", destination, queue.next_seq, queue.verified,
+                queue.reordered, queue.unexpected, queue.lost + queue.pending.len() as u64);
+        }
+    }
+
+
+    /// Messages delivered out of order, corrupted, made up or dropped since the start.
+    pub fn violations(&self) -> u64 {
+        self.violations
+    }
+}
+
+fn payload(msg: &Message) -> &[u8] {
+    match msg {
+        Message::Text(text) => text.as_bytes(),
+        Message::Binary(bytes) => bytes,
+    }
+}
+
+fn digest(payload: &[u8]) -> [u8; 32] {
+    Sha256::digest(payload).into()
+}
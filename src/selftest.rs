@@ -0,0 +1,202 @@
+use url::Url;
+use ws::{CloseCode, Handshake, Message, Result, Sender};
+
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::process::ExitCode;
+
+use ws_proxy::proxy::{Options, ProxyBuilder};
+use ws_proxy::render::Renderer;
+use ws_proxy::testserver::{self, Script};
+use ws_proxy::upstreamqueue::DEFAULT_QUEUE_SIZE;
+
+use crate::cli::SelftestArgs;
+use crate::{Outcome, DEFAULT_NO_FILES_LIMIT};
+
+const WINDOW: usize = 64;
+
+pub fn run(args: SelftestArgs) -> Outcome {
+    let SelftestArgs { bench, messages, size, pretty_jsons } = args;
+    // Logs and the capture are kept in memory, the run leaves nothing behind
+    let mut options = Options::default();
+    options.no_files = Some(DEFAULT_NO_FILES_LIMIT);
+    // The first messages are sent before the proxy has connected to the echo server
+    options.upstream_queue = Some(DEFAULT_QUEUE_SIZE);
+    if pretty_jsons {
+        options.renderers.enable(Renderer::Json);
+    }
+
+    let echo = testserver::spawn(Script::echo())?;
+    let echo_url = Url::parse(&format!("ws://{}", echo)).unwrap();
+
+    let proxy = ProxyBuilder::new(echo_url).options(options).start()?;
+    let proxy_url = Url::parse(&format!("ws://{}", proxy.address())).unwrap();
+
+    println!("Self-test: {} messages of {} bytes through {}", messages, size, proxy_url);
+
+    let check = measure(&proxy_url, Mode::Throughput, messages, size)?;
+    if check.mismatches > 0 {
+        println!("FAILED: {} of {} messages came back reordered or corrupted",
+            check.mismatches, messages);
+        return Ok(ExitCode::from(255));
+    }
+    println!("OK: all messages came back unchanged and in order");
+
+    if !bench {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let direct_url = Url::parse(&format!("ws://{}", echo)).unwrap();
+    let direct_latency = measure(&direct_url, Mode::Latency, messages.min(1_000), size)?;
+    let proxy_latency = measure(&proxy_url, Mode::Latency, messages.min(1_000), size)?;
+    let direct_throughput = measure(&direct_url, Mode::Throughput, messages, size)?;
+    let proxy_throughput = check;
+
+    println!();
+    println!("{:<20}{:>16}{:>16}", "", "direct", "via proxy");
+    for percentile in [50.0, 90.0, 99.0].iter() {
+        println!("{:<20}{:>13.3} ms{:>13.3} ms", format!("latency p{}", percentile),
+            millis(direct_latency.percentile(*percentile)),
+            millis(proxy_latency.percentile(*percentile)));
+    }
+    println!("{:<20}{:>12.0} m/s{:>12.0} m/s", "throughput",
+        direct_throughput.rate(), proxy_throughput.rate());
+    println!("{:<20}{:>11.2} MB/s{:>11.2} MB/s", "",
+        direct_throughput.rate() * size as f64 / 1e6, proxy_throughput.rate() * size as f64 / 1e6);
+    println!();
+    println!("The proxy adds {:.3} ms per round trip (p50) and becomes the bottleneck \
+        at about {:.0} messages per second in each direction.",
+        millis(proxy_latency.percentile(50.0)) - millis(direct_latency.percentile(50.0)),
+        proxy_throughput.rate());
+    Ok(ExitCode::SUCCESS)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// One message in flight, every round trip is timed.
+    Latency,
+    /// Up to `WINDOW` messages in flight, the whole run is timed.
+    Throughput,
+}
+
+struct Report {
+    round_trips: Vec<Duration>,
+    elapsed: Duration,
+    received: usize,
+    mismatches: usize,
+}
+
+impl Report {
+    fn percentile(&self, percentile: f64) -> Duration {
+        if self.round_trips.is_empty() {
+            return Duration::default();
+        }
+        let mut sorted = self.round_trips.clone();
+        sorted.sort();
+        let index = ((sorted.len() - 1) as f64 * percentile / 100.0).round() as usize;
+        sorted[index]
+    }
+
+    fn rate(&self) -> f64 {
+        self.received as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+fn measure(url: &Url, mode: Mode, messages: usize, size: usize) -> std::result::Result<Report, String> {
+    let (report_tx, report_rx) = mpsc::channel();
+
+    ws::connect(url.to_string(), |out: Sender| Client {
+        out,
+        mode,
+        messages,
+        size,
+        sent: 0,
+        sent_at: vec![],
+        report: Report {
+            round_trips: vec![],
+            elapsed: Duration::default(),
+            received: 0,
+            mismatches: 0,
+        },
+        started: Instant::now(),
+        result: report_tx.clone(),
+    }).map_err(|e| format!("Can't connect to {}: {}", url, e))?;
+
+    report_rx.recv().map_err(|_| format!("Connection to {} was closed", url))
+}
+
+struct Client {
+    out: Sender,
+    mode: Mode,
+    messages: usize,
+    size: usize,
+    sent: usize,
+    sent_at: Vec<Instant>,
+    report: Report,
+    started: Instant,
+    result: mpsc::Sender<Report>,
+}
+
+impl Client {
+    fn next_message(&mut self) -> String {
+        let text = payload(self.sent, self.size);
+        self.sent_at.push(Instant::now());
+        self.sent += 1;
+        text
+    }
+}
+
+impl ws::Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.started = Instant::now();
+        let window = if self.mode == Mode::Latency { 1 } else { WINDOW };
+        while self.sent < self.messages.min(window) {
+            let text = self.next_message();
+            self.out.send(text)?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let expected = payload(self.report.received, self.size);
+        if msg.as_text().map(|text| text != expected).unwrap_or(true) {
+            self.report.mismatches += 1;
+        }
+
+        if self.mode == Mode::Latency {
+            self.report.round_trips.push(self.sent_at[self.report.received].elapsed());
+        }
+        self.report.received += 1;
+
+        if self.sent < self.messages {
+            let text = self.next_message();
+            self.out.send(text)
+        } else if self.report.received == self.messages {
+            self.report.elapsed = self.started.elapsed();
+            self.out.close(CloseCode::Normal)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        let report = Report {
+            round_trips: std::mem::take(&mut self.report.round_trips),
+            ..self.report
+        };
+        self.result.send(report).ok();
+    }
+}
+
+fn payload(seq: usize, size: usize) -> String {
+    let mut text = format!("{{\"seq\":{},\"data\":\"", seq);
+    while text.len() + 2 < size {
+        text.push('x');
+    }
+    text.push_str("\"}");
+    text
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,428 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use ws::{Frame, Message, OpCode, Request, Response};
+
+use crate::closecodes::{self, Initiator, Leg};
+use crate::encryption::Sink;
+use crate::flood::Flood;
+use crate::probe::ProbeKind;
+use crate::sampling::Sampling;
+use crate::storage::Storage;
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::error;
+
+/// Directory where every run of the proxy gets its own session directory.
+pub const WORKSPACE: &str = "ws-proxy.sessions";
+
+/// Connections and other events of the session.
+pub const INDEX: &str = "index.jsonl";
+/// Every forwarded message, one record per line.
+pub const CAPTURE: &str = "capture.jsonl";
+/// Command line the session was started with.
+pub const CONFIG: &str = "config.json";
+/// Close codes counted per side and initiator.
+pub const CLOSE_CODES: &str = "close-codes.txt";
+
+/// Stable id of a captured message within a session, like `c3:1842`: the connection
+/// and the number of the message on it, counted from 1. The server is connection 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageId {
+    pub connection_id: u32,
+    pub sequence: u64,
+}
+
+impl MessageId {
+    /// Parses `c<connection>:<sequence>`.
+    pub fn parse(id: &str) -> std::result::Result<Self, String> {
+        id.strip_prefix('c')
+            .and_then(|id| id.split_once(':'))
+            .and_then(|(connection_id, sequence)| Some(MessageId {
+                connection_id: connection_id.parse().ok()?,
+                sequence: sequence.parse().ok()?,
+            }))
+            .ok_or_else(|| format!("Message id {} is invalid, expected one like c3:1842", id))
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "c{}:{}", self.connection_id, self.sequence)
+    }
+}
+
+/// One run of the proxy. Its directory keeps the metadata needed to interpret
+/// the capture later, most importantly the index of connections.
+pub struct Session {
+    id: String,
+    dir: PathBuf,
+    index: Sink,
+    storage: Storage,
+}
+
+impl Session {
+    /// Creates the session directory in the storage, where the index is written as well.
+    pub fn start(workspace: &Path, started: DateTime<Utc>, proxy_port: u16,
+                 storage: &Storage) -> io::Result<Self> {
+        let id = format!("{}-{}", started.format("%Y%m%d-%H%M%S"), proxy_port);
+        let dir = workspace.join(&id);
+        storage.create_dir(&dir)?;
+
+        let index = storage.open(&dir.join(INDEX))?;
+
+        Ok(Session { id, dir, index, storage: storage.clone() })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn capture_path(&self) -> PathBuf {
+        self.dir.join(CAPTURE)
+    }
+
+    /// Capture file of a segment, the first one is the capture itself.
+    pub fn segment_path(&self, segment: u32) -> PathBuf {
+        match segment {
+            1 => self.capture_path(),
+            _ => self.dir.join(format!("capture.{}.jsonl", segment)),
+        }
+    }
+
+    /// Replaces a report file in the session directory.
+    pub fn write_file(&self, name: &str, contents: &str) {
+        self.storage.write(&self.dir.join(name), contents.as_bytes()).unwrap_or_else(|e| {
+            error!("Error: {}", e);
+        })
+    }
+
+    /// Appends a record to the index. Every record is a single line,
+    /// so an interrupted run leaves at most the last record incomplete.
+    pub fn record(&mut self, record: Value) {
+        let line = format!("{}\n", record);
+        self.index.write_all(line.as_bytes()).unwrap_or_else(|e| {
+            error!("Error: {}", e);
+        })
+    }
+}
+
+/// Index record describing the session itself.
+pub fn session_record(id: &str, upstream: &str, proxy_port: u16, labels: &[(String, String)],
+                      hop: Option<&str>) -> Value {
+    json!({
+        "event": "session",
+        "session": id,
+        "hop": hop,
+        "time": Utc::now().to_rfc3339(),
+        "upstream": upstream,
+        "proxy_port": proxy_port,
+        "labels": labels_object(labels),
+    })
+}
+
+/// Index record of the state restored from a snapshot of a previous run.
+pub fn restored_record(previous_session: &str, snapshot: &str, undelivered: usize) -> Value {
+    json!({
+        "event": "restored",
+        "time": Utc::now().to_rfc3339(),
+        "previous_session": previous_session,
+        "snapshot": snapshot,
+        "undelivered": undelivered,
+    })
+}
+
+/// Index record of an opened connection with its handshake metadata.
+pub fn open_record(connection_id: u32, role: &str, peer: Option<String>, resource: &str,
+                   request_headers: &[(String, Vec<u8>)], response_headers: &[(String, Vec<u8>)],
+                   labels: &[(String, String)]) -> Value {
+    json!({
+        "event": "open",
+        "connection_id": connection_id,
+        "role": role,
+        "time": Utc::now().to_rfc3339(),
+        "peer": peer,
+        "resource": resource,
+        "request_headers": headers_object(request_headers),
+        "response_headers": headers_object(response_headers),
+        "labels": labels_object(labels),
+    })
+}
+
+/// Index record of a closed connection.
+pub fn close_record(connection_id: u32, code: u16, reason: &str, initiator: Initiator) -> Value {
+    json!({
+        "event": "close",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "code": code,
+        "meaning": closecodes::describe(code),
+        "reason": reason,
+        "initiator": initiator.to_string(),
+    })
+}
+
+/// Record of a forwarded message. Text is kept as is, binary data is encoded with base64.
+pub fn message_record(id: MessageId, from: Leg, message: &Message) -> Value {
+    let (kind, data) = match message {
+        Message::Text(text) => ("text", text.clone()),
+        Message::Binary(data) => ("binary", BASE64.encode(data)),
+    };
+    json!({
+        "event": "message",
+        "id": id.to_string(),
+        "connection_id": id.connection_id,
+        "sequence": id.sequence,
+        "time": Utc::now().to_rfc3339(),
+        "from": from.to_string(),
+        "type": kind,
+        "data": data,
+    })
+}
+
+/// Line of a log in the NDJSON format. Binary payloads are encoded with base64,
+/// the size is of the message before it was truncated.
+pub fn log_record(id: MessageId, from: Leg, message: &Message, size: usize) -> Value {
+    let (opcode, payload) = match message {
+        Message::Text(text) => ("text", text.clone()),
+        Message::Binary(data) => ("binary", BASE64.encode(data)),
+    };
+    let direction = match from {
+        Leg::Client => "client_to_server",
+        Leg::Server => "server_to_client",
+    };
+    json!({
+        "ts": Utc::now().to_rfc3339(),
+        "direction": direction,
+        "connection_id": id.connection_id,
+        "id": id.to_string(),
+        "opcode": opcode,
+        "payload": payload,
+        "size": size,
+    })
+}
+
+/// Capture record of a control frame received from a peer. Pings and pongs are answered
+/// by the proxy itself, so they are recorded without being forwarded.
+pub fn frame_record(connection_id: u32, from: Leg, frame: &Frame) -> Value {
+    let payload = frame.payload();
+    let mut record = json!({
+        "event": "frame",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "from": from.to_string(),
+        "opcode": frame.opcode().to_string().to_lowercase(),
+        "data": BASE64.encode(payload),
+    });
+    if frame.opcode() == OpCode::Close && payload.len() >= 2 {
+        record["code"] = json!(u16::from_be_bytes([payload[0], payload[1]]));
+        record["reason"] = json!(String::from_utf8_lossy(&payload[2..]));
+    }
+    record
+}
+
+/// Index record of a connection which isn't a WebSocket client, kept apart from the
+/// open and close records of clients. The request and the response are there for HTTP.
+pub fn probe_record(connection_id: u32, kind: ProbeKind, request: Option<&Request>,
+                    response: Option<&Response>, error: Option<String>) -> Value {
+    let mut record = json!({
+        "event": "probe",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "kind": kind.name(),
+    });
+    if let Some(request) = request {
+        let header = |name: &str| request.header(name).map(|value| String::from_utf8_lossy(value).into_owned());
+        record["method"] = json!(request.method());
+        record["resource"] = json!(request.resource());
+        record["user_agent"] = json!(header("user-agent"));
+        record["forwarded_for"] = json!(request.client_addr().ok().flatten());
+    }
+    if let Some(response) = response {
+        record["status"] = json!(response.status());
+    }
+    if let Some(error) = error {
+        record["error"] = json!(error);
+    }
+    record
+}
+
+/// Index record of a shutdown sequence performed by the proxy on purpose.
+pub fn shutdown_record(connection_id: u32, sequence: &str) -> Value {
+    json!({
+        "event": "shutdown",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "sequence": sequence,
+    })
+}
+
+/// Index record of a message which failed the integrity check of strict passthrough.
+pub fn integrity_record(connection_id: u32, violation: &str) -> Value {
+    json!({
+        "event": "integrity",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "violation": violation,
+    })
+}
+
+/// Index record of a connection where fragmented messages get frames interleaved.
+pub fn interleave_record(connection_id: u32, frames: &[&str]) -> Value {
+    json!({
+        "event": "interleave",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "frames": frames,
+    })
+}
+
+/// Index record summarizing the flooding of a connection.
+pub fn flood_record(connection_id: u32, flood: &Flood) -> Value {
+    let plan = flood.plan();
+    let (pongs, pings) = flood.pongs();
+    json!({
+        "event": "flood",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "payload": plan.payload.name(),
+        "size": plan.size,
+        "per_second": plan.per_second,
+        "sent": flood.sent(),
+        "duration_ms": flood.duration().as_millis() as u64,
+        "pings": pings,
+        "pongs": pongs,
+        "max_rtt_ms": flood.max_rtt().map(|rtt| rtt.as_secs_f64() * 1000.0),
+    })
+}
+
+/// Index record of the client connections left out of the capture by sampling.
+pub fn sampling_record(sampling: &Sampling) -> Value {
+    let (connections, messages, bytes) = sampling.skipped_counts();
+    json!({
+        "event": "sampling",
+        "time": Utc::now().to_rfc3339(),
+        "rate": sampling.rate().to_string(),
+        "skipped_connections": connections,
+        "skipped_messages": messages,
+        "skipped_bytes": bytes,
+    })
+}
+
+/// Index record of a new segment of the capture started at a session boundary.
+pub fn segment_record(segment: u32, file: &str, rule: &str, message: Option<MessageId>) -> Value {
+    json!({
+        "event": "segment",
+        "segment": segment,
+        "time": Utc::now().to_rfc3339(),
+        "file": file,
+        "rule": rule,
+        "message": message.map(|id| id.to_string()),
+    })
+}
+
+/// Capture record linking traffic the proxy dropped, delayed or generated on its own
+/// to the rule which caused it and to the original message, so that it can be told
+/// apart from the real traffic.
+pub fn provenance_record(connection_id: u32, rule: &str, action: &str, original: Option<String>,
+                         diff: Value) -> Value {
+    json!({
+        "event": "provenance",
+        "connection_id": connection_id,
+        "time": Utc::now().to_rfc3339(),
+        "rule": rule,
+        "action": action,
+        "original": original,
+        "diff": diff,
+    })
+}
+
+/// Capture record of an alert raised by the proxy.
+pub fn alert_record(rule: &str, message: Option<MessageId>, details: &str) -> Value {
+    json!({
+        "event": "alert",
+        "connection_id": message.map(|id| id.connection_id),
+        "message": message.map(|id| id.to_string()),
+        "time": Utc::now().to_rfc3339(),
+        "rule": rule,
+        "details": details,
+    })
+}
+
+/// Capture record of a message violating the contract, or of the channel not being in it.
+pub fn violation_record(message: Option<MessageId>, from: Option<Leg>, violation: &str) -> Value {
+    json!({
+        "event": "violation",
+        "connection_id": message.map(|id| id.connection_id),
+        "message": message.map(|id| id.to_string()),
+        "time": Utc::now().to_rfc3339(),
+        "from": from.map(|from| from.to_string()),
+        "violation": violation,
+    })
+}
+
+/// Capture record of a direction going silent for longer than the threshold.
+pub fn outage_record(from: Leg, since: DateTime<Utc>, threshold: Duration, last_message: &str) -> Value {
+    json!({
+        "event": "outage",
+        "time": Utc::now().to_rfc3339(),
+        "from": from.to_string(),
+        "silent_since": since.to_rfc3339(),
+        "threshold_ms": threshold.as_millis() as u64,
+        "last_message": last_message,
+    })
+}
+
+/// Capture record of messages coming again after an outage.
+pub fn resumed_record(message: MessageId, from: Leg, since: DateTime<Utc>, silent: Duration,
+                      first_message: &str) -> Value {
+    json!({
+        "event": "resumed",
+        "connection_id": message.connection_id,
+        "message": message.to_string(),
+        "time": Utc::now().to_rfc3339(),
+        "from": from.to_string(),
+        "silent_since": since.to_rfc3339(),
+        "silent_ms": silent.as_millis() as u64,
+        "summary": format!("resumed after {:.1}s", silent.as_secs_f64()),
+        "first_message": first_message,
+    })
+}
+
+fn headers_object(headers: &[(String, Vec<u8>)]) -> Value {
+    let mut object = Map::new();
+    for (name, value) in headers {
+        object.insert(name.to_ascii_lowercase(),
+            Value::String(String::from_utf8_lossy(value).into_owned()));
+    }
+    Value::Object(object)
+}
+
+fn labels_object(labels: &[(String, String)]) -> Value {
+    let mut object = Map::new();
+    for (key, value) in labels {
+        object.insert(key.clone(), Value::String(value.clone()));
+    }
+    Value::Object(object)
+}
+
+/// Capture record of tags added to and removed from a captured message by hand.
+pub fn tag_record(message: &str, added: &[String], removed: &[String]) -> Value {
+    json!({
+        "event": "tag",
+        "message": message,
+        "time": Utc::now().to_rfc3339(),
+        "added": added,
+        "removed": removed,
+    })
+}
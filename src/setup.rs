@@ -0,0 +1,129 @@
+use openssl::ex_data::Index;
+use openssl::ssl::{Ssl, SslConnectorBuilder};
+use serde_json::{json, Map, Value};
+
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Longest wait for the connection to the server to be set up, until its upgrade response.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Labels of the key log lines of the traffic keys of the client, with TLS 1.3 and TLS 1.2.
+const CLIENT_KEYS: [&str; 2] = ["CLIENT_TRAFFIC_SECRET_0 ", "CLIENT_RANDOM "];
+
+/// Timing of setting up a connection, from its request or acceptance to the end of
+/// the websocket upgrade, added to its open record. The credentials and the address of
+/// the server are obtained on a helper thread before ws is asked to connect, which also
+/// times a TCP connection to the address, since ws connects on its event loop without
+/// telling handlers. The connection of ws is timed from being asked for, through the
+/// TLS handshake which OpenSSL tells the end of, to the upgrade.
+#[derive(Clone, Debug)]
+pub struct Setup {
+    started: Instant,
+    /// Ends of the phases which took place.
+    authorized: Option<Instant>,
+    resolved: Option<Instant>,
+    connected: Option<Instant>,
+    /// Start of the connection of ws, after the phases of the helper thread.
+    dialed: Option<Instant>,
+    encrypted: Option<Instant>,
+}
+
+impl Setup {
+    pub fn start() -> Self {
+        Setup {
+            started: Instant::now(),
+            authorized: None,
+            resolved: None,
+            connected: None,
+            dialed: None,
+            encrypted: None,
+        }
+    }
+
+    pub fn authorized(&mut self) {
+        self.authorized = Some(Instant::now());
+    }
+
+    pub fn resolved(&mut self) {
+        self.resolved = Some(Instant::now());
+    }
+
+    /// Times a TCP connection to the address of the server, closed right away. It isn't
+    /// timed if it fails, the connection of ws tells why.
+    pub(crate) fn connect(&mut self, address: SocketAddr) {
+        if TcpStream::connect_timeout(&address, HANDSHAKE_TIMEOUT).is_ok() {
+            self.connected = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn dialed(&mut self) {
+        self.dialed = Some(Instant::now());
+    }
+
+    /// Takes the end of the TLS handshake from its progress.
+    pub(crate) fn encrypted(&mut self, progress: &TlsProgress) {
+        self.encrypted = *progress.0.lock().unwrap();
+    }
+
+    /// Phases in milliseconds, like `{"dns_ms":1.2,"tcp_ms":20.5,"tls_ms":61.0,"upgrade_ms":21.3,"total_ms":104.0}`,
+    /// with `auth_ms` first when credentials are asked for. The TLS handshake includes the TCP connection
+    /// of ws, and so does the upgrade without TLS.
+    pub fn record(&self, opened: Instant) -> Value {
+        let mut record = Map::new();
+        let mut ready = self.started;
+        let phases = [("auth_ms", self.authorized), ("dns_ms", self.resolved), ("tcp_ms", self.connected)];
+        for (name, end) in phases.iter() {
+            if let Some(end) = end {
+                record.insert(name.to_string(), json!(millis(end.saturating_duration_since(ready))));
+                ready = *end;
+            }
+        }
+        // The connection of ws is timed from being asked for, not from the end of the helper thread
+        ready = self.dialed.unwrap_or(ready);
+        if let Some(encrypted) = self.encrypted {
+            record.insert("tls_ms".to_string(), json!(millis(encrypted.saturating_duration_since(ready))));
+            ready = encrypted;
+        }
+        record.insert("upgrade_ms".to_string(), json!(millis(opened.saturating_duration_since(ready))));
+        record.insert("total_ms".to_string(), json!(millis(opened.saturating_duration_since(self.started))));
+        Value::Object(record)
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 10_000.0).round() / 10.0
+}
+
+/// When the TLS handshake with the server was done, told by OpenSSL deriving the traffic keys
+/// of the client. With TLS 1.3 they are derived once the server finished its handshake, while
+/// with TLS 1.2 the master secret is derived a round trip before, which is counted in the upgrade.
+#[derive(Clone, Default)]
+pub(crate) struct TlsProgress(Arc<Mutex<Option<Instant>>>);
+
+impl TlsProgress {
+    /// Keeps the progress of the handshake of the connection, told by the connector.
+    pub(crate) fn watch(ssl: &mut Ssl) -> Self {
+        let progress = TlsProgress::default();
+        ssl.set_ex_data(progress_index(), progress.clone());
+        progress
+    }
+}
+
+/// Has the connector tell the progress of handshakes of the connections watched.
+pub(crate) fn watch_handshakes(connector: &mut SslConnectorBuilder) {
+    connector.set_keylog_callback(|ssl, line| {
+        if !CLIENT_KEYS.iter().any(|label| line.starts_with(label)) {
+            return;
+        }
+        if let Some(Ok(mut progress)) = ssl.ex_data(progress_index()).map(|progress| progress.0.lock()) {
+            progress.get_or_insert_with(Instant::now);
+        }
+    });
+}
+
+fn progress_index() -> Index<Ssl, TlsProgress> {
+    static INDEX: OnceLock<Index<Ssl, TlsProgress>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("OpenSSL has no room for data of connections"))
+}
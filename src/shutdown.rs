@@ -0,0 +1,128 @@
+use ws::{CloseCode, Frame, OpCode};
+
+use crate::closecodes::Leg;
+
+/// Unusual ways to end a connection, used to probe close handling of a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sequence {
+    /// A close frame is sent, but nothing follows it: no response to the peer's
+    /// close frame and no TCP shutdown, the socket stays open until the peer drops it.
+    CloseKeepOpen,
+    /// TCP connection is shut down without any close frame.
+    Fin,
+    /// First half of a message is sent as a non-final fragment, then a close frame.
+    CloseMidFragment,
+}
+
+impl Sequence {
+    fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "close-keep-open" => Ok(Sequence::CloseKeepOpen),
+            "fin" => Ok(Sequence::Fin),
+            "close-mid-fragment" => Ok(Sequence::CloseMidFragment),
+            _ => Err(format!("Unknown shutdown sequence {}, expected \
+                close-keep-open, fin or close-mid-fragment", value)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sequence::CloseKeepOpen => "close-keep-open",
+            Sequence::Fin => "fin",
+            Sequence::CloseMidFragment => "close-mid-fragment",
+        }
+    }
+}
+
+/// Shutdown sequence to perform toward one side of the proxy,
+/// instead of delivering the n-th message to each connection of that side.
+#[derive(Clone, Copy, Debug)]
+pub struct ShutdownPlan {
+    pub leg: Leg,
+    pub sequence: Sequence,
+    pub message: u64,
+}
+
+impl ShutdownPlan {
+    /// Parses `<client|server>:<sequence>[@<n>]`, by default the first message is used.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (target, message) = match spec.find('@') {
+            Some(index) => {
+                let message = spec[index + 1..].parse::<u64>()
+                    .map_err(|e| format!("Invalid message number in {}: {}", spec, e))?;
+                (&spec[..index], message.max(1))
+            },
+            None => (spec, 1)
+        };
+
+        let mut parts = target.splitn(2, ':');
+        let leg = Leg::parse(parts.next().unwrap_or(""))?;
+        let sequence = Sequence::parse(parts.next().unwrap_or(""))?;
+
+        Ok(ShutdownPlan { leg, sequence, message })
+    }
+}
+
+/// What to do with an outgoing frame.
+pub enum Action {
+    Send(Frame),
+    Suppress,
+    /// Drop the TCP connection without sending anything.
+    Disconnect,
+    /// Send the frame, then start the closing handshake.
+    SendAndClose(Frame),
+}
+
+/// Progress of a shutdown plan on a single connection.
+pub struct Shutdown {
+    plan: ShutdownPlan,
+    delivered: u64,
+    silenced: bool,
+}
+
+impl Shutdown {
+    pub fn new(plan: ShutdownPlan) -> Self {
+        Shutdown { plan, delivered: 0, silenced: false }
+    }
+
+    pub fn sequence(&self) -> Sequence {
+        self.plan.sequence
+    }
+
+    /// Returns true if the sequence is performed with this frame.
+    pub fn is_due(&self, frame: &Frame) -> bool {
+        is_data(frame) && !self.silenced && self.delivered + 1 == self.plan.message
+    }
+
+    pub fn apply(&mut self, mut frame: Frame) -> Action {
+        if self.silenced {
+            return Action::Suppress;
+        }
+        if !is_data(&frame) {
+            return Action::Send(frame);
+        }
+
+        self.delivered += 1;
+        if self.delivered != self.plan.message {
+            return Action::Send(frame);
+        }
+
+        match self.plan.sequence {
+            Sequence::CloseKeepOpen => {
+                self.silenced = true;
+                Action::Send(Frame::close(CloseCode::Normal, ""))
+            },
+            Sequence::Fin => Action::Disconnect,
+            Sequence::CloseMidFragment => {
+                let half = frame.payload().len() / 2;
+                frame.payload_mut().truncate(half);
+                frame.set_final(false);
+                Action::SendAndClose(frame)
+            }
+        }
+    }
+}
+
+fn is_data(frame: &Frame) -> bool {
+    matches!(frame.opcode(), OpCode::Text | OpCode::Binary)
+}
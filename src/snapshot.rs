@@ -0,0 +1,178 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chrono::Utc;
+use serde_json::{json, Value};
+use ws::Message;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+
+use crate::closecodes::{CloseStats, Initiator, Leg};
+use crate::session::MessageId;
+
+/// Messages from the server kept while no client is connected, beyond that the oldest are dropped.
+const MAX_UNDELIVERED: usize = 1000;
+
+/// Snapshots are written at most this often while the state changes, and when the proxy stops.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runtime state of the proxy which outlives a restart: the command line with all rules,
+/// labels, close code counters and messages from the server not delivered to any client yet,
+/// together with their ids qualified by the session they were captured in.
+pub struct Snapshot {
+    pub args: Vec<String>,
+    pub session: String,
+    pub labels: Vec<(String, String)>,
+    pub close_codes: Vec<(Leg, u16, Initiator, u64)>,
+    pub undelivered: Vec<(Option<String>, Message)>,
+}
+
+impl Snapshot {
+    pub fn load(path: &Path) -> std::result::Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read snapshot {}: {}", path.display(), e))?;
+        let value: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Snapshot {} is damaged: {}", path.display(), e))?;
+
+        let strings = |value: &Value| -> Vec<String> {
+            value.as_array().into_iter().flatten()
+                .filter_map(|item| item.as_str().map(String::from))
+                .collect()
+        };
+        let labels = value["labels"].as_object().into_iter().flatten()
+            .map(|(key, value)| (key.clone(), value.as_str().unwrap_or_default().to_string()))
+            .collect();
+        let close_codes = value["close_codes"].as_array().into_iter().flatten()
+            .map(|entry| Ok((
+                Leg::parse(entry["leg"].as_str().unwrap_or_default())?,
+                entry["code"].as_u64().unwrap_or_default() as u16,
+                Initiator::parse(entry["initiator"].as_str().unwrap_or_default())?,
+                entry["count"].as_u64().unwrap_or_default(),
+            )))
+            .collect::<std::result::Result<Vec<_>, String>>()
+            .map_err(|e| format!("Snapshot {} is damaged: {}", path.display(), e))?;
+        let undelivered = value["undelivered"].as_array().into_iter().flatten()
+            .filter_map(message)
+            .collect();
+
+        Ok(Snapshot {
+            args: strings(&value["args"]),
+            session: value["session"].as_str().unwrap_or_default().to_string(),
+            labels,
+            close_codes,
+            undelivered,
+        })
+    }
+
+    /// Writes the snapshot into a temporary file first, so that a crash never leaves a partial one.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let labels: serde_json::Map<String, Value> = self.labels.iter()
+            .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+            .collect();
+        let close_codes: Vec<Value> = self.close_codes.iter()
+            .map(|(leg, code, initiator, count)| json!({
+                "leg": leg.to_string(),
+                "code": code,
+                "initiator": initiator.to_string(),
+                "count": count,
+            }))
+            .collect();
+        let undelivered: Vec<Value> = self.undelivered.iter()
+            .map(|(id, message)| match message {
+                Message::Text(text) => json!({ "id": id, "type": "text", "data": text }),
+                Message::Binary(data) => json!({ "id": id, "type": "binary", "data": BASE64.encode(data) }),
+            })
+            .collect();
+        let snapshot = json!({
+            "time": Utc::now().to_rfc3339(),
+            "args": self.args,
+            "session": self.session,
+            "labels": labels,
+            "close_codes": close_codes,
+            "undelivered": undelivered,
+        });
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(&snapshot)?)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+fn message(value: &Value) -> Option<(Option<String>, Message)> {
+    let id = value["id"].as_str().map(String::from);
+    let data = value["data"].as_str()?;
+    let message = match value["type"].as_str()? {
+        "text" => Message::text(data),
+        "binary" => Message::binary(BASE64.decode(data).ok()?),
+        _ => return None
+    };
+    Some((id, message))
+}
+
+/// Keeps the snapshot file of a running proxy up to date.
+pub struct StateFile {
+    path: PathBuf,
+    args: Vec<String>,
+    session: String,
+    labels: Vec<(String, String)>,
+    undelivered: RefCell<VecDeque<(Option<String>, Message)>>,
+    saved: Cell<Option<Instant>>,
+}
+
+impl StateFile {
+    pub fn new(path: &Path, args: Vec<String>, session: &str, labels: Vec<(String, String)>,
+               undelivered: Vec<(Option<String>, Message)>) -> Self {
+        StateFile {
+            path: path.to_path_buf(),
+            args,
+            session: session.to_string(),
+            labels,
+            undelivered: RefCell::new(undelivered.into()),
+            saved: Cell::new(None),
+        }
+    }
+
+    /// Keeps a message from the server which no client received.
+    pub fn undelivered(&self, id: MessageId, message: Message) {
+        let mut undelivered = self.undelivered.borrow_mut();
+        if undelivered.len() == MAX_UNDELIVERED {
+            warn!("Too many undelivered messages, the oldest one is dropped");
+            undelivered.pop_front();
+        }
+        undelivered.push_back((Some(format!("{}/{}", self.session, id)), message));
+    }
+
+    /// Takes the kept messages with their ids to deliver them to a connected client.
+    pub fn take_undelivered(&self) -> Vec<(Option<String>, Message)> {
+        self.undelivered.borrow_mut().drain(..).collect()
+    }
+
+    /// Saves the state unless it was saved very recently.
+    pub fn changed(&self, close_stats: &CloseStats) {
+        let recently = self.saved.get().map(|saved| saved.elapsed() < SAVE_INTERVAL).unwrap_or(false);
+        if !recently {
+            self.save(close_stats);
+        }
+    }
+
+    pub fn save(&self, close_stats: &CloseStats) {
+        let snapshot = Snapshot {
+            args: self.args.clone(),
+            session: self.session.clone(),
+            labels: self.labels.clone(),
+            close_codes: close_stats.counts().collect(),
+            undelivered: self.undelivered.borrow().iter().cloned().collect(),
+        };
+        if let Err(e) = snapshot.save(&self.path) {
+            error!("Error: can't save snapshot {}: {}", self.path.display(), e);
+        }
+        self.saved.set(Some(Instant::now()));
+    }
+}
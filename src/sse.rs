@@ -0,0 +1,367 @@
+use openssl::ssl::SslConnector;
+use serde_json::{json, Value};
+use url::Url;
+use ws::Message;
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use log::{debug, info};
+
+use crate::closecodes::{Initiator, Leg};
+use crate::proxy::Options;
+use crate::recorder::{Connection, Recorder};
+use crate::session;
+use crate::tls;
+
+/// Longest request or response head accepted.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Bytes read from the server at once.
+const CHUNK: usize = 16 * 1024;
+
+/// Close codes the end of a stream is recorded with, as if it was a websocket.
+const ENDED: u16 = 1000;
+const GONE: u16 = 1001;
+const BAD_GATEWAY: u16 = 1014;
+
+/// Proxy of Server-Sent Events: every request of a client is forwarded to the server
+/// with its own path under the path of the server url, and the response is streamed
+/// back as it arrives. Events of the stream are recorded into the capture as messages
+/// of the server on the connection of the client, so that the session is logged, tagged,
+/// viewed and replayed like one of websockets.
+struct Sse {
+    upstream: Url,
+    connector: Option<SslConnector>,
+}
+
+/// Runs the proxy of event streams until SIGINT or SIGTERM.
+pub fn serve(address: SocketAddr, upstream: Url, mut options: Options) -> std::result::Result<(), String> {
+    if upstream.scheme() != "http" && upstream.scheme() != "https" {
+        return Err(format!("Event streams are proxied from http:// or https:// urls, not {}", upstream));
+    }
+    let connector = match (upstream.scheme(), options.upstream_tls.take()) {
+        ("https", Some(connector)) => Some(connector),
+        ("https", None) => Some(tls::connector(None, None, false)?),
+        (_, Some(_)) => return Err("--upstream-ca, --upstream-cert and --insecure apply only to https:// servers".to_string()),
+        (_, None) => None,
+    };
+
+    let listener = TcpListener::bind(address).map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    let recorder = Recorder::start("--sse", address, upstream.as_str(), options)?;
+    info!("Listening on {}, proxying event streams of {}", address, upstream);
+    let sse = Sse { upstream, connector };
+    recorder.run(listener, move |recorder, connection_id, client| {
+        match sse.proxy(recorder, connection_id, client) {
+            Ok(closed) => closed,
+            Err(e) => {
+                debug!("Stream of connection {} failed: {}", connection_id, e);
+                (BAD_GATEWAY, e.to_string(), Initiator::Proxy)
+            },
+        }
+    })
+}
+
+impl Sse {
+    /// Forwards one request and streams its response, returns how it ended.
+    fn proxy(&self, recorder: &Recorder, connection_id: u32, client: TcpStream) -> io::Result<(u16, String, Initiator)> {
+        let peer = client.peer_addr().ok();
+        let mut from_client = BufReader::new(client.try_clone()?);
+        let mut to_client = client;
+        let (request_line, request_headers) = read_head(&mut from_client)?;
+        let (method, target) = match request_line.split(' ').collect::<Vec<&str>>().as_slice() {
+            [method, target, _] => (method.to_string(), target.to_string()),
+            _ => {
+                to_client.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n").ok();
+                return Ok((ENDED, "Bad request".to_string(), Initiator::Proxy));
+            }
+        };
+
+        let mut upstream = match self.connect(recorder, connection_id) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                to_client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n").ok();
+                return Err(e);
+            }
+        };
+        upstream.write_all(self.request(&method, &target, &request_headers).as_bytes())?;
+        let length = header(&request_headers, "content-length").and_then(|length| length.trim().parse::<u64>().ok());
+        if let Some(length) = length {
+            io::copy(&mut (&mut from_client).take(length), &mut upstream)?;
+        }
+
+        let mut from_server = BufReader::new(upstream);
+        let (status_line, response_headers) = read_head(&mut from_server)?;
+        let mut head = format!("{}\r\n", status_line);
+        for (name, value) in response_headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value)));
+        }
+        head.push_str("\r\n");
+        to_client.write_all(head.as_bytes())?;
+
+        let mut record = session::open_record(connection_id, "client", peer.map(|peer| peer.to_string()),
+            &target, &request_headers, &response_headers, recorder.labels());
+        record["sse"] = json!({ "method": method, "status": status_line });
+        recorder.opened(connection_id, record);
+        recorder.note(connection_id, &format!("{} {}", method, target));
+
+        let events = header(&response_headers, "content-type")
+            .map(|kind| kind.trim().starts_with("text/event-stream"))
+            .unwrap_or(false);
+        if !events {
+            info!("Response {} to connection {} is not an event stream, it is forwarded as is", status_line, connection_id);
+        }
+        let mut chunks = header(&response_headers, "transfer-encoding")
+            .filter(|encoding| encoding.to_ascii_lowercase().contains("chunked"))
+            .map(|_| Chunks::default());
+        let headers: Vec<(String, String)> = response_headers.iter()
+            .map(|(name, value)| (name.to_lowercase(), String::from_utf8_lossy(value).into_owned()))
+            .collect();
+        let connection = Connection::new(connection_id, headers);
+        let mut parser = Parser::default();
+
+        let mut buffer = vec![0; CHUNK];
+        loop {
+            let read = match from_server.read(&mut buffer) {
+                Ok(0) => return Ok((ENDED, "The server ended the stream".to_string(), Initiator::Peer)),
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if to_client.write_all(&buffer[..read]).and_then(|_| to_client.flush()).is_err() {
+                return Ok((GONE, "The client went away".to_string(), Initiator::Peer));
+            }
+            if !events {
+                continue;
+            }
+            let body = match &mut chunks {
+                Some(chunks) => chunks.decode(&buffer[..read]),
+                None => buffer[..read].to_vec(),
+            };
+            for mut event in parser.feed(&body) {
+                // Events are recorded like messages of the server
+                let message = Message::text(std::mem::take(&mut event.data));
+                recorder.message(&connection, Leg::Server, message, Some(("sse", event.fields())), event.event.as_deref());
+            }
+        }
+    }
+
+    fn connect(&self, recorder: &Recorder, connection_id: u32) -> io::Result<Box<dyn Upstream>> {
+        let host = self.upstream.host_str().unwrap_or("localhost");
+        let port = self.upstream.port_or_known_default().unwrap_or(80);
+        let socket = TcpStream::connect((host, port))?;
+        recorder.watch(connection_id, socket.try_clone()?);
+        match &self.connector {
+            Some(connector) => {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let stream = connector.connect(host, socket).map_err(|e| io::Error::other(e.to_string()))?;
+                Ok(Box::new(stream))
+            },
+            None => Ok(Box::new(socket)),
+        }
+    }
+
+    /// Request of the client for the server, which must not compress the stream
+    /// and closes the connection when the stream ends.
+    fn request(&self, method: &str, target: &str, headers: &[(String, Vec<u8>)]) -> String {
+        let host = match self.upstream.port() {
+            Some(port) => format!("{}:{}", self.upstream.host_str().unwrap_or_default(), port),
+            None => self.upstream.host_str().unwrap_or_default().to_string(),
+        };
+        let mut request = format!("{} {}{} HTTP/1.1\r\nHost: {}\r\n",
+            method, self.upstream.path().trim_end_matches('/'), target, host);
+        for (name, value) in headers.iter() {
+            if !["host", "connection", "accept-encoding", "keep-alive"].contains(&name.to_ascii_lowercase().as_str()) {
+                request.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value)));
+            }
+        }
+        request.push_str("Accept-Encoding: identity\r\nConnection: close\r\n\r\n");
+        request
+    }
+}
+
+/// Connection to the server, plain or over TLS.
+trait Upstream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Upstream for T {}
+
+/// Headers of a request or response, as ws keeps those of handshakes.
+type Headers = Vec<(String, Vec<u8>)>;
+
+/// Reads the request or status line and the headers up to the empty line.
+pub(crate) fn read_head(reader: &mut impl BufRead) -> io::Result<(String, Headers)> {
+    let mut lines = vec![];
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line)?;
+        size += read;
+        if read == 0 || size > MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Incomplete or too large HTTP head"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let mut lines = lines.into_iter();
+    let first = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(':').map(|(name, value)| (name.trim().to_string(), value.trim().as_bytes().to_vec())))
+        .collect();
+    Ok((first, headers))
+}
+
+pub(crate) fn header(headers: &[(String, Vec<u8>)], name: &str) -> Option<String> {
+    headers.iter()
+        .find(|(found, _)| found.eq_ignore_ascii_case(name))
+        .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+}
+
+/// Decoder of a chunked body as it arrives in pieces.
+#[derive(Default)]
+struct Chunks {
+    state: Chunk,
+}
+
+enum Chunk {
+    Size(String),
+    Data(usize),
+    /// Line end after the data of a chunk.
+    End,
+    /// After the last chunk, trailers are ignored.
+    Done,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::Size(String::new())
+    }
+}
+
+impl Chunks {
+    fn decode(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        let mut position = 0;
+        while position < bytes.len() {
+            match &mut self.state {
+                Chunk::Size(line) => {
+                    match bytes[position] {
+                        b'\n' => {
+                            let size = line.split(';').next().unwrap_or_default().trim();
+                            self.state = match usize::from_str_radix(size, 16) {
+                                Ok(0) | Err(_) => Chunk::Done,
+                                Ok(size) => Chunk::Data(size),
+                            };
+                        },
+                        byte => line.push(byte as char),
+                    }
+                    position += 1;
+                },
+                Chunk::Data(left) => {
+                    let taken = (*left).min(bytes.len() - position);
+                    body.extend_from_slice(&bytes[position..position + taken]);
+                    position += taken;
+                    *left -= taken;
+                    if *left == 0 {
+                        self.state = Chunk::End;
+                    }
+                },
+                Chunk::End => {
+                    if bytes[position] == b'\n' {
+                        self.state = Chunk::default();
+                    }
+                    position += 1;
+                },
+                Chunk::Done => break,
+            }
+        }
+        body
+    }
+}
+
+/// Event of a stream, with the fields given for it.
+struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+    data: String,
+}
+
+impl Event {
+    fn fields(&self) -> Value {
+        let mut fields = json!({});
+        if let Some(event) = &self.event {
+            fields["event"] = json!(event);
+        }
+        if let Some(id) = &self.id {
+            fields["id"] = json!(id);
+        }
+        if let Some(retry) = self.retry {
+            fields["retry"] = json!(retry);
+        }
+        fields
+    }
+}
+
+/// Parser of the event stream format: lines of fields, events end with an empty line.
+/// Lines end with CRLF, LF or CR, comments (like heartbeats) are skipped.
+#[derive(Default)]
+struct Parser {
+    line: Vec<u8>,
+    /// A CR ended the last line, a LF right after it ends nothing.
+    after_cr: bool,
+    data: Vec<String>,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl Parser {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Event> {
+        let mut events = vec![];
+        for byte in bytes.iter().copied() {
+            let after_cr = std::mem::replace(&mut self.after_cr, byte == b'\r');
+            match byte {
+                b'\n' if after_cr => {},
+                b'\r' | b'\n' => {
+                    let line = String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+                    events.extend(self.line(&line));
+                },
+                byte => self.line.push(byte),
+            }
+        }
+        events
+    }
+
+    fn line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            // Events without data are not dispatched
+            let dispatched = !self.data.is_empty();
+            let event = Event {
+                event: self.event.take(),
+                id: self.id.take(),
+                retry: self.retry.take(),
+                data: std::mem::take(&mut self.data).join("\n"),
+            };
+            return Some(event).filter(|_| dispatched);
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "data" => self.data.push(value.to_string()),
+            "event" => self.event = Some(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            "retry" => self.retry = value.parse().ok(),
+            _ => {}
+        }
+        None
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::encryption::{self, Encryption, Sink};
+
+/// Where the session files are written: to the disk, encrypted or not,
+/// or only into memory until they are flushed explicitly.
+#[derive(Clone)]
+pub enum Storage {
+    Disk(Option<Encryption>),
+    Memory(MemoryStore),
+}
+
+impl Storage {
+    /// Opens a file to append to.
+    pub fn open(&self, path: &Path) -> io::Result<Sink> {
+        match self {
+            Storage::Disk(encryption) => encryption::open(path, encryption.as_ref()),
+            Storage::Memory(store) => Ok(Sink::Memory(MemoryFile { store: store.clone(), path: path.to_path_buf() })),
+        }
+    }
+
+    /// Replaces a file with the contents.
+    pub fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        match self {
+            Storage::Disk(_) => fs::write(path, contents),
+            Storage::Memory(store) => {
+                store.inner.lock().unwrap().replaced.insert(path.to_path_buf(), contents.to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn create_dir(&self, dir: &Path) -> io::Result<()> {
+        match self {
+            Storage::Disk(_) => fs::create_dir_all(dir),
+            Storage::Memory(_) => Ok(()),
+        }
+    }
+
+    /// Path on the disk where a file is written, none for files in memory.
+    pub fn disk_path(&self, path: &Path) -> Option<PathBuf> {
+        match self {
+            Storage::Disk(None) => Some(path.to_path_buf()),
+            Storage::Disk(Some(_)) => Some(Encryption::path(path)),
+            Storage::Memory(_) => None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self, Storage::Disk(Some(_)))
+    }
+}
+
+/// Files kept in memory, bounded in total size: when the limit is exceeded,
+/// the oldest entries written are dropped. Flushing appends the entries to the files
+/// on the disk and empties the store.
+#[derive(Clone)]
+pub struct MemoryStore {
+    inner: Arc<Mutex<Entries>>,
+}
+
+#[derive(Default)]
+struct Entries {
+    appended: VecDeque<(PathBuf, Vec<u8>)>,
+    replaced: BTreeMap<PathBuf, Vec<u8>>,
+    size: usize,
+    max_bytes: usize,
+    dropped: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_bytes: usize) -> Self {
+        MemoryStore { inner: Arc::new(Mutex::new(Entries { max_bytes, ..Entries::default() })) }
+    }
+
+    fn append(&self, path: &Path, data: &[u8]) {
+        let mut entries = self.inner.lock().unwrap();
+        entries.size += data.len();
+        entries.appended.push_back((path.to_path_buf(), data.to_vec()));
+        while entries.size > entries.max_bytes {
+            match entries.appended.pop_front() {
+                Some((_, data)) => {
+                    entries.size -= data.len();
+                    entries.dropped += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// Size of the kept entries and the number of entries dropped because of the limit.
+    pub fn usage(&self) -> (usize, usize) {
+        let entries = self.inner.lock().unwrap();
+        (entries.size, entries.dropped)
+    }
+
+    /// Writes all kept entries to the disk, returns the files written.
+    pub fn flush(&self) -> io::Result<Vec<PathBuf>> {
+        let (appended, replaced) = {
+            let mut entries = self.inner.lock().unwrap();
+            entries.size = 0;
+            (std::mem::take(&mut entries.appended), std::mem::take(&mut entries.replaced))
+        };
+
+        let mut written: Vec<PathBuf> = vec![];
+        for (path, data) in appended.iter() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            OpenOptions::new().create(true).append(true).open(path)?.write_all(data)?;
+            if !written.contains(path) {
+                written.push(path.clone());
+            }
+        }
+        for (path, contents) in replaced.iter() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, contents)?;
+            if !written.contains(path) {
+                written.push(path.clone());
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// File being written into a `MemoryStore`.
+pub struct MemoryFile {
+    store: MemoryStore,
+    path: PathBuf,
+}
+
+impl MemoryFile {
+    pub fn append(&self, data: &[u8]) {
+        self.store.append(&self.path, data)
+    }
+}
@@ -0,0 +1,258 @@
+use url::Url;
+
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::process::ExitCode;
+
+use log::debug;
+
+use crate::cli::Stress;
+use crate::Outcome;
+
+const FILLER: &[u8] = b"X-Slow: 1\r\n";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn run(test: Stress) -> Outcome {
+    let Stress::Handshake { server_url: url, connections, interval, duration } = test;
+    if url.scheme() != "ws" {
+        return Err("Only ws:// urls are supported".to_string());
+    }
+    let address = url.socket_addrs(|| Some(80)).ok()
+        .and_then(|addresses| addresses.into_iter().next())
+        .ok_or_else(|| format!("Can't resolve {}", url))?;
+
+    println!("Handshake stress on {}: {} connections, one byte every {} ms for {} s",
+        address, connections, interval, duration);
+
+    let deadline = Instant::now() + Duration::from_secs(duration);
+    let probes = spawn_probes(address, upgrade_request(&url), deadline);
+    let report = trickle(address, upgrade_request(&url), connections,
+        Duration::from_millis(interval), deadline);
+    let probes: Vec<Probe> = probes.iter().collect();
+
+    report.print();
+    print_probes(&probes);
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Upgrade request without the final empty line, so that it is never complete.
+fn upgrade_request(url: &Url) -> Vec<u8> {
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    };
+    let resource = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    format!("GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+        resource, host).into_bytes()
+}
+
+struct Slow {
+    stream: TcpStream,
+    written: usize,
+    opened: Instant,
+}
+
+impl Slow {
+    /// Next byte of the request, endless header lines follow the request itself.
+    fn next_byte(&self, request: &[u8]) -> u8 {
+        match request.get(self.written) {
+            Some(byte) => *byte,
+            None => FILLER[(self.written - request.len()) % FILLER.len()]
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    connected: usize,
+    failed: BTreeMap<String, usize>,
+    /// How long closed connections lasted.
+    closed: Vec<Duration>,
+    reset: usize,
+    responses: BTreeMap<String, usize>,
+    open: usize,
+    bytes: usize,
+}
+
+fn trickle(address: SocketAddr, request: Vec<u8>, connections: usize,
+           interval: Duration, deadline: Instant) -> Report {
+    let mut report = Report::default();
+    let mut slow = vec![];
+
+    for _ in 0..connections {
+        if Instant::now() >= deadline {
+            break;
+        }
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+            .and_then(|stream| stream.set_nonblocking(true).map(|_| stream)) {
+            Ok(stream) => {
+                report.connected += 1;
+                slow.push(Slow { stream, written: 0, opened: Instant::now() });
+            },
+            Err(e) => {
+                debug!("Connection failed: {}", e);
+                *report.failed.entry(e.kind().to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    while Instant::now() < deadline && !slow.is_empty() {
+        let mut buffer = [0; 1024];
+        slow.retain_mut(|connection| {
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => {
+                    report.closed.push(connection.opened.elapsed());
+                    return false;
+                },
+                Ok(n) => {
+                    let response = String::from_utf8_lossy(&buffer[..n]);
+                    let status = response.lines().next().unwrap_or_default().to_string();
+                    *report.responses.entry(status).or_insert(0) += 1;
+                    report.closed.push(connection.opened.elapsed());
+                    return false;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(_) => {
+                    report.reset += 1;
+                    return false;
+                }
+            }
+
+            let byte = connection.next_byte(&request);
+            match connection.stream.write(&[byte]) {
+                Ok(_) => {
+                    connection.written += 1;
+                    true
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => true,
+                Err(_) => {
+                    report.reset += 1;
+                    false
+                }
+            }
+        });
+        report.bytes += slow.len();
+
+        thread::sleep(interval.min(deadline.saturating_duration_since(Instant::now())));
+    }
+
+    report.open = slow.len();
+    report
+}
+
+impl Report {
+    fn print(&self) {
+        let failed = self.failed.iter()
+            .map(|(kind, count)| format!("{} x{}", kind, count))
+            .collect::<Vec<_>>();
+        println!("  {:<28}{:>8}{}", "connected", self.connected,
+            if failed.is_empty() { String::new() } else { format!("  (failed: {})", failed.join(", ")) });
+
+        let mut closed = self.closed.clone();
+        closed.sort();
+        let median = closed.get(closed.len() / 2)
+            .map(|lasted| format!("  (median after {:.1} s)", lasted.as_secs_f64()))
+            .unwrap_or_default();
+        println!("  {:<28}{:>8}{}", "closed by server", self.closed.len(), median);
+        for (status, count) in self.responses.iter() {
+            println!("    {:<26}{:>8}", status, count);
+        }
+        println!("  {:<28}{:>8}", "reset", self.reset);
+        println!("  {:<28}{:>8}", "still open at the end", self.open);
+        println!("  {:<28}{:>8}", "bytes trickled", self.bytes);
+    }
+}
+
+enum Probe {
+    Upgraded(Duration),
+    Refused(String),
+    Failed(String),
+}
+
+/// Attempts a regular handshake every second until the deadline.
+fn spawn_probes(address: SocketAddr, request: Vec<u8>, deadline: Instant) -> mpsc::Receiver<Probe> {
+    let (probes, results) = mpsc::channel();
+    let mut request = request;
+    request.extend_from_slice(b"\r\n");
+
+    thread::spawn(move || {
+        while Instant::now() < deadline {
+            let started = Instant::now();
+            probes.send(probe(address, &request)).ok();
+            thread::sleep(Duration::from_secs(1).saturating_sub(started.elapsed()));
+        }
+    });
+    results
+}
+
+fn probe(address: SocketAddr, request: &[u8]) -> Probe {
+    let started = Instant::now();
+    let response = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).and_then(|mut stream| {
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        stream.write_all(request)?;
+
+        let mut response = vec![];
+        let mut buffer = [0; 1024];
+        while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+            let n = stream.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            response.extend_from_slice(&buffer[..n]);
+        }
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    });
+
+    match response {
+        Ok(response) => {
+            let status = response.lines().next().unwrap_or_default().to_string();
+            if status.split_whitespace().nth(1) == Some("101") {
+                Probe::Upgraded(started.elapsed())
+            } else if status.is_empty() {
+                Probe::Failed("closed without response".to_string())
+            } else {
+                Probe::Refused(status)
+            }
+        },
+        Err(e) => Probe::Failed(e.kind().to_string())
+    }
+}
+
+fn print_probes(probes: &[Probe]) {
+    let mut upgraded: Vec<Duration> = probes.iter()
+        .filter_map(|probe| match probe {
+            Probe::Upgraded(duration) => Some(*duration),
+            _ => None
+        })
+        .collect();
+    upgraded.sort();
+
+    let latency = match (upgraded.get(upgraded.len() / 2), upgraded.last()) {
+        (Some(median), Some(max)) => format!("  (p50 {:.1} ms, max {:.1} ms)",
+            median.as_secs_f64() * 1000.0, max.as_secs_f64() * 1000.0),
+        _ => String::new()
+    };
+    println!("  {:<28}{:>8}{}", "regular handshakes",
+        format!("{}/{}", upgraded.len(), probes.len()), latency);
+
+    let mut problems: BTreeMap<String, usize> = BTreeMap::new();
+    for probe in probes {
+        match probe {
+            Probe::Refused(status) => *problems.entry(status.clone()).or_insert(0) += 1,
+            Probe::Failed(reason) => *problems.entry(reason.clone()).or_insert(0) += 1,
+            Probe::Upgraded(_) => {}
+        }
+    }
+    for (problem, count) in problems {
+        println!("    {:<26}{:>8}", problem, count);
+    }
+}
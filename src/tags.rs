@@ -0,0 +1,73 @@
+use serde_json::Value;
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::condition::{Condition, Facts};
+
+/// Rule tagging messages automatically when they are captured.
+#[derive(Clone)]
+pub struct TagRule {
+    tag: String,
+    condition: Condition,
+}
+
+impl TagRule {
+    /// Parses `<tag>=<condition>`.
+    pub fn parse(rule: &str) -> std::result::Result<Self, String> {
+        let (tag, condition) = rule.split_once('=')
+            .filter(|(tag, _)| valid(tag))
+            .ok_or_else(|| format!("Tag rule {} is not <tag>=<condition>", rule))?;
+        let condition = Condition::parse(condition).map_err(|e| format!("Invalid tag rule {}: {}", rule, e))?;
+        Ok(TagRule { tag: tag.to_string(), condition })
+    }
+}
+
+/// Tags are words like `relevant`, `noise` or `auth-flow`.
+pub fn valid(tag: &str) -> bool {
+    !tag.is_empty() && tag.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Tags of all rules matching the message.
+pub fn apply(rules: &[TagRule], facts: &Facts) -> Vec<String> {
+    let mut tags: Vec<String> = rules.iter()
+        .filter(|rule| rule.condition.matches(facts))
+        .map(|rule| rule.tag.clone())
+        .collect();
+    tags.dedup();
+    tags
+}
+
+/// Message records of a capture with their tags: those given when they were captured,
+/// changed by tag events appended later in order.
+pub fn tagged(capture: &str) -> Vec<(Value, BTreeSet<String>)> {
+    let mut messages: Vec<(Value, BTreeSet<String>)> = vec![];
+    let mut by_id: HashMap<String, usize> = HashMap::new();
+    for record in capture.lines().filter_map(|line| serde_json::from_str::<Value>(line).ok()) {
+        match record["event"].as_str() {
+            Some("message") => {
+                let tags = strings(&record["tags"]).collect();
+                if let Some(id) = record["id"].as_str() {
+                    by_id.insert(id.to_string(), messages.len());
+                }
+                messages.push((record, tags));
+            },
+            Some("tag") => {
+                let index = match record["message"].as_str().and_then(|id| by_id.get(id)) {
+                    Some(index) => *index,
+                    None => continue,
+                };
+                let tags = &mut messages[index].1;
+                tags.extend(strings(&record["added"]));
+                for removed in strings(&record["removed"]) {
+                    tags.remove(&removed);
+                }
+            },
+            _ => {}
+        }
+    }
+    messages
+}
+
+fn strings(value: &Value) -> impl Iterator<Item = String> + '_ {
+    value.as_array().into_iter().flatten().filter_map(|tag| tag.as_str().map(String::from))
+}
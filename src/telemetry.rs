@@ -0,0 +1,144 @@
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState, TracerProvider};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::trace::{SdkTracerProvider, SpanData};
+use opentelemetry_sdk::Resource;
+use tracing::dispatcher::{self, DefaultGuard};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+use url::Url;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::closecodes::Leg;
+
+/// Path of the OTLP/HTTP traces endpoint of a collector given without one.
+const TRACES_PATH: &str = "/v1/traces";
+/// Longest wait of a collector to take a batch of spans.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Span of a connection of the proxy, the parent of the spans of its messages. A client's span is
+/// a child of the span in the W3C traceparent header of its handshake, so that its messages join
+/// the trace of the client; the header returned is sent to the server so that the messages join
+/// the traces of the backend too.
+pub fn connection_span(leg: Leg, connection_id: u32, traceparent: Option<&[u8]>) -> (Span, String) {
+    let kind = match leg {
+        Leg::Client => "server",
+        Leg::Server => "client",
+    };
+    let span = tracing::info_span!("connection", otel.kind = kind, side = %leg, connection_id);
+    if let Some(remote) = traceparent.and_then(parse_traceparent) {
+        span.set_parent(Context::new().with_remote_span_context(remote)).ok();
+    }
+    let context = span.context();
+    let own = context.span().span_context().clone();
+    let traceparent = format!("00-{}-{}-{:02x}", own.trace_id(), own.span_id(), own.trace_flags().to_u8());
+    (span, traceparent)
+}
+
+/// Span of a traceparent header, version 00 and those after it alike. Its spans are exported
+/// whatever the sampled flag of the header is.
+fn parse_traceparent(value: &[u8]) -> Option<SpanContext> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let mut parts = value.split('-');
+    let (version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+    let hex = |id: &str, len: usize| id.len() == len && id.bytes().all(|b| b.is_ascii_hexdigit());
+    if !hex(version, 2) || version == "ff" || !hex(trace_id, 32) || !hex(span_id, 16) {
+        return None;
+    }
+    let trace_id = TraceId::from_hex(trace_id).ok()?;
+    let span_id = SpanId::from_hex(span_id).ok()?;
+    // Ids of all zeros are invalid
+    let context = SpanContext::new(trace_id, span_id, TraceFlags::SAMPLED, true, TraceState::default());
+    Some(context).filter(SpanContext::is_valid)
+}
+
+/// Exports the spans of the proxy, given with --otlp-endpoint, to an OpenTelemetry collector
+/// as OTLP/HTTP protobuf. Spans of other crates are left out. The exporter is the subscriber of
+/// the thread which installs it only, so that it doesn't take over the spans of an application
+/// embedding the proxy. Dropping it exports the spans finished until then.
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+    _scope: DefaultGuard,
+}
+
+/// Exporter which reports failing exports, once until the collector takes spans again.
+#[derive(Debug)]
+struct Reporting {
+    exporter: SpanExporter,
+    endpoint: Url,
+    failing: AtomicBool,
+}
+
+impl Telemetry {
+    /// Installs the exporter as the subscriber of tracing on the current thread, the spans of the
+    /// session are marked with its id.
+    pub fn install(endpoint: &Url, session_id: &str) -> std::result::Result<Self, String> {
+        if endpoint.scheme() != "http" {
+            return Err(format!("OTLP endpoint {} is not an http:// url", endpoint));
+        }
+        let mut endpoint = endpoint.clone();
+        if endpoint.path() == "/" {
+            endpoint.set_path(TRACES_PATH);
+        }
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint.as_str())
+            .with_timeout(EXPORT_TIMEOUT)
+            .build()
+            .map_err(|e| format!("Spans can't be exported to {}: {}", endpoint, e))?;
+        let resource = Resource::builder()
+            .with_service_name("ws-proxy")
+            .with_attributes([
+                KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+                KeyValue::new("ws_proxy.session", session_id.to_string()),
+            ])
+            .build();
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(Reporting { exporter, endpoint: endpoint.clone(), failing: AtomicBool::new(false) })
+            .with_resource(resource)
+            .build();
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("ws-proxy"))
+            .with_filter(filter_fn(|metadata| metadata.is_span() && metadata.target().starts_with("ws_proxy")));
+        let scope = dispatcher::set_default(&tracing_subscriber::registry().with(layer).into());
+        info!("Spans of forwarded messages are exported to {}", endpoint);
+        Ok(Telemetry { provider, _scope: scope })
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        self.provider.shutdown().ok();
+    }
+}
+
+impl opentelemetry_sdk::trace::SpanExporter for Reporting {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let exported = self.exporter.export(batch).await;
+        match &exported {
+            Ok(()) => self.failing.store(false, Ordering::Relaxed),
+            Err(e) => {
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    error!("Spans are not exported to {}: {}", self.endpoint, e);
+                }
+            },
+        }
+        exported
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.exporter.shutdown_with_timeout(timeout)
+    }
+
+    fn set_resource(&mut self, resource: &Resource) {
+        self.exporter.set_resource(resource);
+    }
+}
@@ -0,0 +1,304 @@
+use regex::Regex;
+use serde_json::Value;
+use ws::{CloseCode, Handshake, Message, Result, Sender, Builder};
+use ws::util::Token;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+
+use log::{info, debug, error};
+
+/// Behaviour of the built-in test server.
+/// Without any rules it echoes every message back.
+///
+/// Script syntax, one rule per line (`#` starts a comment):
+///
+/// ```text
+/// on-open <message>          send the message right after a client connects
+/// every <ms> <message>       send the message periodically
+/// <request> => <reply>       reply to a text message equal to <request>
+/// * => <reply>               reply to any other text message
+/// close-on <request>         close the connection when a text message equal to <request> comes
+/// ```
+///
+/// In replies `{message}` is substituted with the received text. A request can have
+/// variables like `{id}` matching any text, which are substituted in the reply.
+/// JSON messages are matched in their compact form with fields sorted. Several rules
+/// with the same request send all their replies in order.
+///
+/// Replies and sent messages can have variables of the connection: `{connection}` (its id),
+/// `{path}` and `{query.<name>}` of the handshake request, `{headers.<name>}` of its headers
+/// and `{messages}`, the count of messages received on it. Missing ones are empty.
+#[derive(Default, Debug)]
+pub struct Script {
+    on_open: Vec<String>,
+    periodic: Vec<(u64, String)>,
+    closing: Vec<String>,
+    replies: Vec<Rule>,
+    fallback: Option<String>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    request: String,
+    /// Pattern of a request with variables.
+    pattern: Option<Regex>,
+    reply: String,
+}
+
+impl Rule {
+    fn new(request: String, reply: String) -> std::result::Result<Self, String> {
+        let variable = Regex::new(VARIABLE).unwrap();
+        let pattern = if variable.is_match(&request) {
+            let mut pattern = String::from("^");
+            let mut last = 0;
+            for found in variable.captures_iter(&request) {
+                let whole = found.get(0).unwrap();
+                pattern.push_str(&regex::escape(&request[last..whole.start()]));
+                pattern.push_str(&format!("(?P<{}>.+?)", &found[1]));
+                last = whole.end();
+            }
+            pattern.push_str(&regex::escape(&request[last..]));
+            pattern.push('$');
+            Some(Regex::new(&pattern).map_err(|e| format!("Invalid request {}: {}", request, e))?)
+        } else {
+            None
+        };
+        Ok(Rule { request, pattern, reply })
+    }
+
+    /// The reply with variables substituted, if the message matches the request.
+    fn reply(&self, message: &str, normalized: &str, peer: &Peer) -> Option<String> {
+        let found = match &self.pattern {
+            None if self.request == message || self.request == normalized => None,
+            None => return None,
+            Some(pattern) => Some(pattern.captures(normalized).or_else(|| pattern.captures(message))?),
+        };
+        Some(fill(&self.reply, |name| match (name, &found) {
+            ("message", _) => Some(message.to_string()),
+            (name, Some(found)) if found.name(name).is_some() => Some(found[name].to_string()),
+            (name, _) => peer.variable(name),
+        }))
+    }
+}
+
+/// Connection of a client to the test server, for variables of replies.
+#[derive(Default)]
+struct Peer {
+    connection: u32,
+    path: String,
+    query: Vec<(String, String)>,
+    /// Handshake headers with lowercase names.
+    headers: Vec<(String, String)>,
+    messages: u64,
+}
+
+impl Peer {
+    fn variable(&self, name: &str) -> Option<String> {
+        let value = |pairs: &[(String, String)], name: &str| pairs.iter().rev()
+            .find(|(given, _)| *given == name)
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default();
+        match name.split_once('.') {
+            Some(("query", parameter)) => Some(value(&self.query, parameter)),
+            Some(("headers", header)) => Some(value(&self.headers, &header.to_lowercase())),
+            Some(_) => None,
+            None => match name {
+                "connection" => Some(self.connection.to_string()),
+                "path" => Some(self.path.clone()),
+                "messages" => Some(self.messages.to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Text with every `{name}` the variable has a value for replaced by the value,
+/// in one pass so that values are never substituted again.
+pub(crate) fn fill(template: &str, variable: impl Fn(&str) -> Option<String>) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}').and_then(|end| variable(&after[..end]).map(|value| (end, value))) {
+            Some((end, value)) => {
+                filled.push_str(&value);
+                rest = &after[end + 1..];
+            },
+            None => {
+                filled.push('{');
+                rest = after;
+            },
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Variable of a request pattern, like `{id}`.
+pub(crate) const VARIABLE: &str = r"\{([A-Za-z_][A-Za-z0-9_]*)\}";
+
+impl Script {
+    pub fn echo() -> Self {
+        Script::default()
+    }
+
+    pub fn load(path: &str) -> std::result::Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path, e))?;
+        Script::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut script = Script::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(message) = line.strip_prefix("on-open ") {
+                script.on_open.push(message.trim().to_string());
+            } else if let Some(rest) = line.strip_prefix("every ") {
+                let mut parts = rest.trim().splitn(2, ' ');
+                let period = parts.next().unwrap_or("").parse::<u64>()
+                    .map_err(|e| format!("Line {}: invalid period: {}", number + 1, e))?;
+                let message = parts.next()
+                    .ok_or_else(|| format!("Line {}: message is missing", number + 1))?;
+                script.periodic.push((period.max(1), message.trim().to_string()));
+            } else if let Some(request) = line.strip_prefix("close-on ") {
+                script.closing.push(request.trim().to_string());
+            } else if let Some(index) = line.find("=>") {
+                let request = line[..index].trim().to_string();
+                let reply = line[index + 2..].trim().to_string();
+                if request == "*" {
+                    script.fallback = Some(reply);
+                } else {
+                    script.replies.push(Rule::new(request, reply)
+                        .map_err(|e| format!("Line {}: {}", number + 1, e))?);
+                }
+            } else {
+                return Err(format!("Line {}: can't parse rule \"{}\"", number + 1, line));
+            }
+        }
+
+        Ok(script)
+    }
+
+    fn is_echo(&self) -> bool {
+        self.replies.is_empty() && self.fallback.is_none()
+    }
+
+    fn replies(&self, request: &str, peer: &Peer) -> Vec<String> {
+        let normalized = serde_json::from_str::<Value>(request)
+            .map(|value| value.to_string())
+            .unwrap_or_else(|_| request.to_string());
+        let matched = self.replies.iter()
+            .find(|rule| rule.reply(request, &normalized, peer).is_some());
+        match matched {
+            Some(matched) => self.replies.iter()
+                .filter(|rule| rule.request == matched.request)
+                .filter_map(|rule| rule.reply(request, &normalized, peer))
+                .collect(),
+            None => self.fallback.iter()
+                .map(|reply| fill(reply, |name| match name {
+                    "message" => Some(request.to_string()),
+                    name => peer.variable(name),
+                }))
+                .collect()
+        }
+    }
+}
+
+/// Starts the test server on a random loopback port in a background thread.
+pub fn spawn(script: Script) -> std::result::Result<SocketAddr, String> {
+    let script = Arc::new(script);
+    let (address_tx, address_rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let connection = |out: Sender| Connection { out, script: script.clone(), peer: Peer::default() };
+        let ws = match Builder::new().build(connection) {
+            Ok(ws) => ws.bind(SocketAddr::from(([127,0,0,1], 0))),
+            Err(e) => Err(e)
+        };
+
+        let ws = match ws {
+            Ok(ws) => ws,
+            Err(e) => {
+                address_tx.send(Err(e.to_string())).unwrap();
+                return;
+            }
+        };
+
+        address_tx.send(ws.local_addr().map_err(|e| e.to_string())).unwrap();
+        if let Err(e) = ws.run() {
+            error!("Error: {}", e);
+        }
+    });
+
+    let address = address_rx.recv()
+        .map_err(|e| e.to_string())??;
+    info!("Test server is listening on {}", address);
+    Ok(address)
+}
+
+struct Connection {
+    out: Sender,
+    script: Arc<Script>,
+    peer: Peer,
+}
+
+impl ws::Handler for Connection {
+    fn on_open(&mut self, h: Handshake) -> Result<()> {
+        debug!("Test server accepted connection {}", self.out.connection_id());
+        let resource = h.request.resource();
+        let (path, query) = resource.split_once('?').unwrap_or((resource, ""));
+        self.peer = Peer {
+            connection: self.out.connection_id(),
+            path: path.to_string(),
+            query: url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+            headers: h.request.headers().iter()
+                .map(|(name, value)| (name.to_lowercase(), String::from_utf8_lossy(value).into_owned()))
+                .collect(),
+            messages: 0,
+        };
+        for message in self.script.on_open.iter() {
+            self.out.send(fill(message, |name| self.peer.variable(name)))?;
+        }
+        for (index, (period, _)) in self.script.periodic.iter().enumerate() {
+            self.out.timeout(*period, Token(index))?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.peer.messages += 1;
+        if let Message::Text(text) = &msg {
+            if self.script.closing.contains(text) {
+                // Like a server going away, to test what clients of it do then
+                return self.out.close(CloseCode::Away);
+            }
+        }
+        if self.script.is_echo() {
+            return self.out.send(msg);
+        }
+
+        if let Message::Text(text) = msg {
+            for reply in self.script.replies(&text, &self.peer) {
+                self.out.send(reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        let (period, message) = &self.script.periodic[event.0];
+        self.out.send(fill(message, |name| self.peer.variable(name)))?;
+        self.out.timeout(*period, event)
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::closecodes::Leg;
+use crate::latency;
+use crate::topology::Edge;
+
+/// Bytes a link sends at once after being idle, those of a tenth of a second.
+const BURST: f64 = 0.1;
+
+/// Bandwidth of the links of clients in a direction, or in both, given with --throttle.
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottlePlan {
+    /// Side the throttled messages come from.
+    pub from: Option<Leg>,
+    pub bytes_per_second: f64,
+}
+
+impl ThrottlePlan {
+    /// Parses `[<client->server|server->client>=]<rate>` with rates in bits per second,
+    /// like `64kbps`, `server->client=1.5mbps` or `9600bps`.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let (from, rate) = match spec.split_once('=') {
+            Some((direction, rate)) => (Some(latency::parse_direction(direction)?), rate),
+            None => (None, spec),
+        };
+        let (number, scale) = if let Some(number) = rate.strip_suffix("mbps") {
+            (number, 1_000_000.0)
+        } else if let Some(number) = rate.strip_suffix("kbps") {
+            (number, 1_000.0)
+        } else if let Some(number) = rate.strip_suffix("bps") {
+            (number, 1.0)
+        } else {
+            return Err(format!("Rate {} has no unit, expected bps, kbps or mbps", rate));
+        };
+        let bits_per_second = number.parse::<f64>().ok()
+            .map(|number| number * scale)
+            .filter(|bits| bits.is_finite() && *bits >= 8.0)
+            .ok_or_else(|| format!("Invalid rate {}, expected at least 8bps", rate))?;
+        Ok(ThrottlePlan { from, bytes_per_second: bits_per_second / 8.0 })
+    }
+}
+
+impl fmt::Display for ThrottlePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.from {
+            Some(Leg::Client) => write!(f, "client->server=")?,
+            Some(Leg::Server) => write!(f, "server->client=")?,
+            None => {},
+        }
+        write!(f, "{}bps", self.bytes_per_second * 8.0)
+    }
+}
+
+/// Tokens of a link, a byte each, filled at its rate up to the burst. Messages bigger
+/// than the tokens left take them into debt, which later messages wait out.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Paces the messages along every edge of the proxy with a token bucket of its own,
+/// as if each client was on a slow link.
+pub struct Throttle {
+    plans: Vec<ThrottlePlan>,
+    buckets: HashMap<Edge, Bucket>,
+}
+
+impl Throttle {
+    pub fn new(plans: Vec<ThrottlePlan>) -> Self {
+        Throttle { plans, buckets: HashMap::new() }
+    }
+
+    /// Adds a plan, which wins over those of its direction given before.
+    pub fn add(&mut self, plan: ThrottlePlan) {
+        self.plans.push(plan);
+    }
+
+    pub fn plans(&self) -> &[ThrottlePlan] {
+        &self.plans
+    }
+
+    /// Whether messages from the side are paced.
+    pub fn limits(&self, from: Leg) -> bool {
+        self.plan(from).is_some()
+    }
+
+    fn plan(&self, from: Leg) -> Option<&ThrottlePlan> {
+        self.plans.iter().rev().find(|plan| plan.from.is_none() || plan.from == Some(from))
+    }
+
+    /// How long a message of the size waits before it's sent along the edge, so that
+    /// the edge keeps to its rate. The plan given last for the direction wins.
+    pub fn wait(&mut self, edge: Edge, size: usize) -> Duration {
+        let rate = match self.plan(edge.from.leg()) {
+            Some(plan) => plan.bytes_per_second,
+            None => return Duration::ZERO,
+        };
+        let now = Instant::now();
+        let burst = rate * BURST;
+        let bucket = self.buckets.entry(edge).or_insert(Bucket { tokens: burst, updated: now });
+        let filled = (now - bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + filled).min(burst) - size as f64;
+        bucket.updated = now;
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+
+    /// Drops the buckets of the edges of a client which closed.
+    pub fn forget(&mut self, client: u32) {
+        self.buckets.retain(|edge, _| edge.client() != Some(client));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::topology::Party;
+
+    #[test]
+    fn rates_are_bits_per_second() {
+        let plan = ThrottlePlan::parse("server->client=1.5mbps").unwrap();
+        assert_eq!((plan.from, plan.bytes_per_second), (Some(Leg::Server), 187_500.0));
+        assert_eq!(plan.to_string(), "server->client=1500000bps");
+        assert_eq!(ThrottlePlan::parse("64kbps").unwrap().bytes_per_second, 8_000.0);
+        assert_eq!(ThrottlePlan::parse("9600").unwrap_err(), "Rate 9600 has no unit, expected bps, kbps or mbps");
+        assert_eq!(ThrottlePlan::parse("4bps").unwrap_err(), "Invalid rate 4bps, expected at least 8bps");
+    }
+
+    #[test]
+    fn edges_wait_out_bursts_on_their_own() {
+        // A thousand bytes a second, a hundred of them sent at once
+        let mut throttle = Throttle::new(vec![ThrottlePlan::parse("client->server=8kbps").unwrap()]);
+        let first = Edge::new(Party::Client(1), Party::Server);
+        let second = Edge::new(Party::Client(2), Party::Server);
+        assert_eq!(throttle.wait(first, 100), Duration::ZERO);
+        let wait = throttle.wait(first, 100);
+        assert!(wait > Duration::from_millis(90) && wait <= Duration::from_millis(100), "{:?}", wait);
+        assert_eq!(throttle.wait(second, 100), Duration::ZERO);
+        assert_eq!(throttle.wait(Edge::new(Party::Server, Party::Client(1)), 1000), Duration::ZERO);
+        assert!(!throttle.limits(Leg::Server));
+
+        throttle.forget(1);
+        assert_eq!(throttle.wait(first, 100), Duration::ZERO);
+        // The plan given last wins
+        throttle.add(ThrottlePlan::parse("800kbps").unwrap());
+        assert_eq!(throttle.wait(Edge::new(Party::Client(3), Party::Server), 9_000), Duration::ZERO);
+        assert!(throttle.limits(Leg::Server));
+    }
+}
@@ -0,0 +1,60 @@
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::X509;
+
+use std::fs;
+use std::path::Path;
+
+use crate::setup;
+
+/// TLS of the proxy port, so that clients can connect to `wss://`. Connections are
+/// decrypted in the proxy and messages are forwarded to the server as usual.
+/// The certificate chain and the private key are both in PEM.
+pub fn acceptor(cert: &Path, key: &Path) -> std::result::Result<SslAcceptor, String> {
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .map_err(|e| format!("Can't set up TLS: {}", e))?;
+    acceptor.set_certificate_chain_file(cert)
+        .map_err(|e| format!("Can't load certificate {}: {}", cert.display(), e))?;
+    acceptor.set_private_key_file(key, SslFiletype::PEM)
+        .map_err(|e| format!("Can't load private key {}: {}", key.display(), e))?;
+    acceptor.check_private_key()
+        .map_err(|e| format!("Private key {} doesn't match certificate {}: {}", key.display(), cert.display(), e))?;
+    Ok(acceptor.build())
+}
+
+/// TLS of the connection to a `wss://` server. The CA bundle in PEM replaces the system
+/// trust store, the client certificate and its key are presented to servers requiring
+/// mutual TLS. Insecure connections verify neither the certificate nor the host name,
+/// for self-signed development servers.
+pub fn connector(ca: Option<&Path>, identity: Option<(&Path, &Path)>, insecure: bool)
+    -> std::result::Result<SslConnector, String> {
+    let mut connector = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| format!("Can't set up TLS: {}", e))?;
+    if let Some(ca) = ca {
+        let mut store = X509StoreBuilder::new().map_err(|e| format!("Can't set up TLS: {}", e))?;
+        let pem = fs::read(ca).map_err(|e| format!("Can't read CA bundle {}: {}", ca.display(), e))?;
+        let certificates = X509::stack_from_pem(&pem)
+            .map_err(|e| format!("Can't load CA bundle {}: {}", ca.display(), e))?;
+        if certificates.is_empty() {
+            return Err(format!("There are no certificates in CA bundle {}", ca.display()));
+        }
+        for certificate in certificates {
+            store.add_cert(certificate).map_err(|e| format!("Can't load CA bundle {}: {}", ca.display(), e))?;
+        }
+        connector.set_verify_cert_store(store.build())
+            .map_err(|e| format!("Can't load CA bundle {}: {}", ca.display(), e))?;
+    }
+    if let Some((cert, key)) = identity {
+        connector.set_certificate_chain_file(cert)
+            .map_err(|e| format!("Can't load client certificate {}: {}", cert.display(), e))?;
+        connector.set_private_key_file(key, SslFiletype::PEM)
+            .map_err(|e| format!("Can't load client key {}: {}", key.display(), e))?;
+        connector.check_private_key()
+            .map_err(|e| format!("Client key {} doesn't match certificate {}: {}", key.display(), cert.display(), e))?;
+    }
+    if insecure {
+        connector.set_verify(SslVerifyMode::NONE);
+    }
+    setup::watch_handshakes(&mut connector);
+    Ok(connector.build())
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+use crate::closecodes::Leg;
+
+/// Connection id of the server, clients are numbered from 1 by the proxy.
+pub const SERVER_ID: u32 = 0;
+
+/// Endpoint of a connection of the proxy, which messages come from and go to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Party {
+    Server,
+    Client(u32),
+}
+
+impl Party {
+    /// Party of the connection with the id on the side.
+    pub fn new(leg: Leg, connection_id: u32) -> Self {
+        match leg {
+            Leg::Server => Party::Server,
+            Leg::Client => Party::Client(connection_id),
+        }
+    }
+
+    pub fn leg(&self) -> Leg {
+        match self {
+            Party::Server => Leg::Server,
+            Party::Client(_) => Leg::Client,
+        }
+    }
+
+    pub fn connection_id(&self) -> u32 {
+        match self {
+            Party::Server => SERVER_ID,
+            Party::Client(connection_id) => *connection_id,
+        }
+    }
+
+    /// Prefix of the messages of the party in the text logs.
+    pub fn prefix(&self) -> String {
+        match self {
+            Party::Server => "[server]".to_string(),
+            Party::Client(connection_id) => format!("[connection id: {}]", connection_id),
+        }
+    }
+}
+
+impl fmt::Display for Party {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Party::Server => f.write_str("server"),
+            Party::Client(connection_id) => write!(f, "client {}", connection_id),
+        }
+    }
+}
+
+/// Way of messages from one party to another. Every stage of forwarding, like delays
+/// and per-connection rules, applies to an edge rather than to a pair of connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Edge {
+    pub from: Party,
+    pub to: Party,
+}
+
+impl Edge {
+    pub fn new(from: Party, to: Party) -> Self {
+        Edge { from, to }
+    }
+
+    /// Client at an end of the edge, whose settings from the palette apply to it.
+    pub fn client(&self) -> Option<u32> {
+        match (self.from, self.to) {
+            (Party::Client(connection_id), _) | (_, Party::Client(connection_id)) => Some(connection_id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {}", self.from, self.to)
+    }
+}
@@ -0,0 +1,62 @@
+use chrono::Utc;
+use serde_json::Value;
+use serde_json_path::JsonPath;
+use ws::Message;
+
+use crate::closecodes::Leg;
+use crate::logqueue::LogFile;
+use crate::session::MessageId;
+
+/// Time series of tracked values, written into the session directory.
+pub const TRACK: &str = "track.csv";
+
+/// Extracts numeric values from JSON messages by JSONPath expressions
+/// into a CSV time series with one row per value.
+pub struct Tracker {
+    paths: Vec<(String, JsonPath)>,
+    file: LogFile,
+}
+
+impl Tracker {
+    pub fn new(expressions: &[String], file: LogFile) -> std::result::Result<Self, String> {
+        let paths = expressions.iter()
+            .map(|expression| Ok((expression.clone(), parse(expression)?)))
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        file.write("time,message,connection_id,from,path,value\n".to_string());
+        Ok(Tracker { paths, file })
+    }
+
+    /// Writes the values found in the message, and returns them with their expressions.
+    pub fn track(&self, id: MessageId, from: Leg, message: &Message) -> Vec<(&str, f64)> {
+        let value = match message {
+            Message::Text(text) => match serde_json::from_str::<Value>(text) {
+                Ok(value) => value,
+                Err(_) => return vec![],
+            },
+            Message::Binary(_) => return vec![],
+        };
+
+        let time = Utc::now().to_rfc3339();
+        let mut tracked = vec![];
+        for (expression, path) in self.paths.iter() {
+            for found in path.query(&value).all() {
+                let (number, parsed) = match found {
+                    Value::Number(number) => (number.to_string(), number.as_f64()),
+                    Value::String(text) => (text.trim().to_string(), text.trim().parse::<f64>().ok()),
+                    _ => continue,
+                };
+                if let Some(parsed) = parsed {
+                    self.file.write(format!("{},{},{},{},\"{}\",{}\n",
+                        time, id, id.connection_id, from, expression.replace('"', "\"\""), number));
+                    tracked.push((expression.as_str(), parsed));
+                }
+            }
+        }
+        tracked
+    }
+}
+
+/// Checks a JSONPath expression like `$.queue.depth`.
+pub fn parse(expression: &str) -> std::result::Result<JsonPath, String> {
+    JsonPath::parse(expression).map_err(|e| format!("Invalid JSONPath {}: {}", expression, e))
+}
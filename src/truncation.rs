@@ -0,0 +1,132 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use ws::Message;
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use log::error;
+
+use crate::encryption::Encryption;
+use crate::memory::format_size;
+use crate::storage::Storage;
+
+/// Directory of the session where full oversized payloads are kept.
+pub const BLOBS: &str = "blobs";
+
+/// Limit on the size of payloads written to logs and the capture.
+/// Larger payloads are cut, and optionally kept in full in the blob store.
+pub struct Truncation {
+    max_bytes: usize,
+    blobs: Option<Blobs>,
+}
+
+/// What is known about a payload after it is cut.
+pub struct Truncated {
+    pub size: usize,
+    pub blob: Option<String>,
+}
+
+impl Truncation {
+    pub fn new(max_bytes: usize, blobs: Option<Blobs>) -> Self {
+        Truncation { max_bytes, blobs }
+    }
+
+    /// Cuts the message if it exceeds the limit, storing the full payload if required.
+    pub fn apply(&self, message: Message) -> (Message, Option<Truncated>) {
+        if message.len() <= self.max_bytes {
+            return (message, None);
+        }
+
+        let size = message.len();
+        let blob = self.blobs.as_ref().map(|blobs| blobs.store(message.clone().into_data()));
+        let message = match message {
+            Message::Text(mut text) => {
+                let mut end = self.max_bytes;
+                while !text.is_char_boundary(end) {
+                    end -= 1;
+                }
+                text.truncate(end);
+                Message::Text(text)
+            },
+            Message::Binary(mut data) => {
+                data.truncate(self.max_bytes);
+                Message::Binary(data)
+            }
+        };
+        (message, Some(Truncated { size, blob }))
+    }
+}
+
+impl Truncated {
+    /// Description of the payload for the capture record.
+    pub fn to_value(&self) -> Value {
+        json!({
+            "size": self.size,
+            "blob": self.blob,
+        })
+    }
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[truncated, {} in total", format_size(self.size))?;
+        if let Some(blob) = &self.blob {
+            write!(f, ", full payload in {}/{}", BLOBS, blob)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// Store of full payloads named by their SHA-256, written in a background thread.
+/// Payloads seen before are not written again. Dropping the store waits for queued payloads.
+pub struct Blobs {
+    dir: PathBuf,
+    payloads: Option<mpsc::Sender<(PathBuf, Vec<u8>)>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Blobs {
+    /// Starts the store in the given session directory of the storage.
+    pub fn start(session_dir: &Path, storage: Storage) -> io::Result<Self> {
+        let dir = session_dir.join(BLOBS);
+        storage.create_dir(&dir)?;
+
+        let (payloads, queue) = mpsc::channel::<(PathBuf, Vec<u8>)>();
+        let writer = thread::spawn(move || {
+            for (path, data) in queue {
+                if path.exists() || Encryption::path(&path).exists() {
+                    continue;
+                }
+                let written = storage.open(&path)
+                    .and_then(|mut file| file.write_all(&data));
+                if let Err(e) = written {
+                    error!("Error: {}", e);
+                }
+            }
+        });
+
+        Ok(Blobs { dir, payloads: Some(payloads), writer: Some(writer) })
+    }
+
+    /// Queues the payload to be stored, returns its name in the store.
+    pub fn store(&self, data: Vec<u8>) -> String {
+        let name = hex::encode(Sha256::digest(&data));
+        if let Some(payloads) = &self.payloads {
+            payloads.send((self.dir.join(&name), data)).ok();
+        }
+        name
+    }
+}
+
+impl Drop for Blobs {
+    fn drop(&mut self) {
+        self.payloads.take();
+        if let Some(writer) = self.writer.take() {
+            writer.join().ok();
+        }
+    }
+}
@@ -0,0 +1,570 @@
+use chrono::Local;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Sparkline};
+use ratatui::prelude::CrosstermBackend;
+use ratatui::{Frame, Terminal};
+use regex::Regex;
+use signal_hook::consts::SIGINT;
+use ws::Message;
+
+use log::error;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::closecodes::Leg;
+use crate::condition::{Condition, Facts};
+use crate::memory::format_size;
+use crate::palette::Prompt;
+use crate::session::MessageId;
+
+/// Messages kept for each direction, the oldest are forgotten.
+const KEPT: usize = 2000;
+/// Characters of a message shown, the rest wouldn't fit on a line anyway.
+const SHOWN: usize = 1000;
+/// Closed connections still listed.
+const CLOSED_KEPT: usize = 50;
+/// Lines of the output pane kept, the oldest are forgotten.
+const OUTPUT_KEPT: usize = 500;
+/// Rows of the output pane, borders included.
+const OUTPUT_HEIGHT: u16 = 8;
+/// Values of each --track expression kept for its sparkline, the oldest are forgotten.
+const TRACKED_KEPT: usize = 500;
+/// Expressions whose sparklines are shown, the others are left out.
+const TRACKED_SHOWN: usize = 6;
+/// Columns of the labels of sparklines.
+const TRACKED_LABEL: u16 = 40;
+/// Time between redraws, and between checks for keys.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Live view of the traffic in the terminal, given with --tui instead of printing it:
+/// the messages of each direction in their own pane, the client connections and the rates
+/// of messages and bytes, with sparklines of the values of --track expressions. The terminal
+/// is drawn from a background thread until the proxy stops, q and Ctrl-C stop the proxy like
+/// SIGINT does. Lines typed after : are commands of the palette, and the logs and anything
+/// printed meanwhile are shown in an output pane.
+pub struct Tui {
+    inner: Arc<Mutex<State>>,
+    thread: Option<JoinHandle<()>>,
+    captured: Option<Captured>,
+}
+
+/// Standard output and error of the process as they were before the UI took them over, given
+/// back when it's dropped.
+struct Captured {
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+}
+
+/// The terminal the UI draws on, the standard output goes to the output pane meanwhile.
+type Screen = Terminal<CrosstermBackend<File>>;
+
+#[derive(Default)]
+struct State {
+    panes: [VecDeque<Entry>; 2],
+    /// Messages seen, numbering the entries so that a paused view leaves the newer ones out.
+    received: u64,
+    /// Messages and bytes of each direction since the start.
+    totals: [(u64, u64); 2],
+    connections: BTreeMap<u32, Connection>,
+    /// Values found by --track expressions, in the order the expressions found their first.
+    tracked: Vec<(String, VecDeque<f64>)>,
+    /// Lines printed and logged while the UI is shown.
+    output: VecDeque<String>,
+    stopped: bool,
+}
+
+struct Entry {
+    number: u64,
+    id: MessageId,
+    from: Leg,
+    time: String,
+    text: String,
+    /// Message as it was captured, for the filter.
+    message: Message,
+    /// Client connections the message came from or was forwarded to.
+    connections: Vec<u32>,
+}
+
+struct Connection {
+    peer: String,
+    resource: String,
+    messages: [u64; 2],
+    closed: bool,
+}
+
+impl Tui {
+    /// Takes over the terminal, which is given back when the handle is dropped. Lines typed on
+    /// the command line are run with the prompt.
+    pub fn start(prompt: Prompt) -> std::result::Result<Self, String> {
+        if !io::stdout().is_terminal() {
+            return Err("--tui needs the output to be a terminal".to_string());
+        }
+        ratatui::try_init().map_err(|e| format!("Can't open the terminal: {}", e))?;
+        let inner = Arc::new(Mutex::new(State::default()));
+        let (captured, terminal) = match Captured::start(inner.clone()).and_then(|(captured, screen)| {
+            Ok((captured, Terminal::new(CrosstermBackend::new(screen))?))
+        }) {
+            Ok(started) => started,
+            Err(e) => {
+                ratatui::restore();
+                return Err(format!("Can't take over the output of the terminal: {}", e));
+            },
+        };
+        let state = inner.clone();
+        let thread = thread::spawn(move || {
+            if let Err(e) = View::default().show(terminal, &state, &prompt) {
+                error!("The terminal UI failed: {}", e);
+            }
+        });
+        Ok(Tui { inner, thread: Some(thread), captured: Some(captured) })
+    }
+
+    pub fn opened(&self, connection_id: u32, peer: Option<String>, resource: &str) {
+        let mut state = self.inner.lock().unwrap();
+        state.connections.insert(connection_id, Connection {
+            peer: peer.unwrap_or_else(|| "unknown".to_string()),
+            resource: resource.to_string(),
+            messages: [0; 2],
+            closed: false,
+        });
+    }
+
+    pub fn closed(&self, connection_id: u32) {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(connection) = state.connections.get_mut(&connection_id) {
+            connection.closed = true;
+        }
+        let closed: Vec<u32> = state.connections.iter()
+            .filter(|(_, connection)| connection.closed)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in closed.iter().take(closed.len().saturating_sub(CLOSED_KEPT)) {
+            state.connections.remove(id);
+        }
+    }
+
+    /// Shows a message as it is printed without --tui, given the clients it went to or came from.
+    pub fn message(&self, id: MessageId, from: Leg, message: &Message, size: usize, tags: &[String], clients: &[u32]) {
+        let mut state = self.inner.lock().unwrap();
+        state.received += 1;
+        let totals = &mut state.totals[from as usize];
+        totals.0 += 1;
+        totals.1 += size as u64;
+        for client in clients {
+            if let Some(connection) = state.connections.get_mut(client) {
+                connection.messages[from as usize] += 1;
+            }
+        }
+        let mut text: String = match message {
+            Message::Text(text) => text.chars().take(SHOWN).map(|c| if c.is_control() { ' ' } else { c }).collect(),
+            Message::Binary(data) => format!("<{} bytes>", data.len()),
+        };
+        if !tags.is_empty() {
+            text = format!("[{}] {}", tags.join(","), text);
+        }
+        let entry = Entry {
+            number: state.received,
+            id,
+            from,
+            time: Local::now().format("%H:%M:%S%.3f").to_string(),
+            text,
+            message: message.clone(),
+            connections: clients.to_vec(),
+        };
+        let pane = &mut state.panes[from as usize];
+        if pane.len() == KEPT {
+            pane.pop_front();
+        }
+        pane.push_back(entry);
+    }
+
+    /// Adds a value found by the --track expression to its sparkline.
+    pub fn tracked(&self, expression: &str, value: f64) {
+        let mut state = self.inner.lock().unwrap();
+        let index = match state.tracked.iter().position(|(tracked, _)| tracked == expression) {
+            Some(index) => index,
+            None => {
+                state.tracked.push((expression.to_string(), VecDeque::new()));
+                state.tracked.len() - 1
+            },
+        };
+        let values = &mut state.tracked[index].1;
+        if values.len() == TRACKED_KEPT {
+            values.pop_front();
+        }
+        values.push_back(value);
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().stopped = true;
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        // The output goes to the terminal again before its mode is restored
+        drop(self.captured.take());
+        ratatui::restore();
+    }
+}
+
+impl Captured {
+    /// Sends the standard output and error to the output pane of the state, returning the
+    /// terminal they went to.
+    fn start(state: Arc<Mutex<State>>) -> io::Result<(Self, File)> {
+        let stdout = io::stdout().as_fd().try_clone_to_owned()?;
+        let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+        let screen = File::from(stdout.try_clone()?);
+        let (reader, writer) = io::pipe()?;
+        io::stdout().flush()?;
+        let captured = Captured { stdout, stderr };
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if unsafe { libc::dup2(writer.as_raw_fd(), fd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // The lines end once both are given back, or never with children printing still
+        thread::spawn(move || {
+            // Colors of the logs would be shown as garbage
+            let escapes = Regex::new("\x1b\\[[0-9;]*[A-Za-z]").unwrap();
+            for line in BufReader::new(reader).lines().map_while(std::result::Result::ok) {
+                let line = escapes.replace_all(&line, "").replace(|c: char| c.is_control(), " ");
+                let mut state = state.lock().unwrap();
+                if state.output.len() == OUTPUT_KEPT {
+                    state.output.pop_front();
+                }
+                state.output.push_back(line);
+            }
+        });
+        Ok((captured, screen))
+    }
+}
+
+impl Drop for Captured {
+    fn drop(&mut self) {
+        io::stdout().flush().ok();
+        unsafe {
+            libc::dup2(self.stdout.as_raw_fd(), libc::STDOUT_FILENO);
+            libc::dup2(self.stderr.as_raw_fd(), libc::STDERR_FILENO);
+        }
+    }
+}
+
+/// What the terminal shows, changed with the keys.
+#[derive(Default)]
+struct View {
+    /// Number of the last message shown while paused.
+    paused: Option<u64>,
+    /// Entries scrolled back from the newest ones shown.
+    scroll: usize,
+    filter: Option<Condition>,
+    /// Filter being typed after /, or command after :.
+    typing: Option<(Typing, String)>,
+    /// Connection whose messages are shown, all of them when none.
+    connection: Option<u32>,
+    /// Error of the last filter typed.
+    notice: Option<String>,
+    /// Commands run, recalled with the arrows on the command line.
+    commands: Vec<String>,
+    /// Command recalled, counted back from the last one run.
+    recalled: usize,
+    /// Messages and bytes per second of each direction, measured every second.
+    rates: [(f64, f64); 2],
+    measured: Option<(Instant, [(u64, u64); 2])>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Typing {
+    Filter,
+    Command,
+}
+
+impl View {
+    fn show(&mut self, mut terminal: Screen, state: &Mutex<State>, prompt: &Prompt)
+            -> std::result::Result<(), String> {
+        loop {
+            {
+                let state = state.lock().unwrap();
+                if state.stopped {
+                    return Ok(());
+                }
+                self.measure(&state.totals);
+                terminal.draw(|frame| self.draw(frame, &state)).map_err(|e| e.to_string())?;
+            }
+            if !event::poll(TICK).map_err(|e| e.to_string())? {
+                continue;
+            }
+            let key = match event::read().map_err(|e| e.to_string())? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            if let Some((typing, typed)) = &mut self.typing {
+                match key.code {
+                    KeyCode::Enter if *typing == Typing::Command => {
+                        let typed = std::mem::take(typed);
+                        self.typing = None;
+                        if !typed.trim().is_empty() {
+                            // Output of the command goes to the output pane
+                            prompt.run(&format!(":{}", typed));
+                            self.commands.retain(|command| *command != typed);
+                            self.commands.push(typed);
+                        }
+                    },
+                    KeyCode::Up | KeyCode::Down if *typing == Typing::Command => {
+                        self.recalled = match key.code {
+                            KeyCode::Up => (self.recalled + 1).min(self.commands.len()),
+                            _ => self.recalled.saturating_sub(1),
+                        };
+                        *typed = match self.recalled {
+                            0 => String::new(),
+                            back => self.commands[self.commands.len() - back].clone(),
+                        };
+                    },
+                    KeyCode::Enter => {
+                        let typed = self.typing.take().map(|(_, typed)| typed).unwrap_or_default();
+                        self.notice = None;
+                        self.filter = match typed.as_str() {
+                            "" => None,
+                            condition => match Condition::parse(condition) {
+                                Ok(filter) => Some(filter),
+                                Err(e) => {
+                                    self.notice = Some(format!("Invalid filter: {}", e));
+                                    self.filter.take()
+                                },
+                            },
+                        };
+                    },
+                    KeyCode::Esc => self.typing = None,
+                    KeyCode::Backspace => {
+                        typed.pop();
+                    },
+                    KeyCode::Char(c) => typed.push(c),
+                    _ => {}
+                }
+                continue;
+            }
+            let page = terminal.size().map(|size| size.height as usize / 4).unwrap_or(10);
+            match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => stop(),
+                KeyCode::Char('l') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    // Anything written to the terminal past the UI is gone once it's drawn from scratch
+                    terminal.clear().map_err(|e| e.to_string())?;
+                },
+                KeyCode::Char('q') => stop(),
+                KeyCode::Char('p') | KeyCode::Char(' ') => self.pause(state),
+                KeyCode::Char('/') => self.typing = Some((Typing::Filter, String::new())),
+                KeyCode::Char(':') => {
+                    self.typing = Some((Typing::Command, String::new()));
+                    self.recalled = 0;
+                },
+                KeyCode::Esc => {
+                    self.filter = None;
+                    self.notice = None;
+                },
+                KeyCode::Tab => self.select(state, true),
+                KeyCode::BackTab => self.select(state, false),
+                KeyCode::Up | KeyCode::Char('k') => self.scroll(state, 1),
+                KeyCode::Down | KeyCode::Char('j') => self.scroll(state, -1),
+                KeyCode::PageUp => self.scroll(state, page as isize),
+                KeyCode::PageDown => self.scroll(state, -(page as isize)),
+                KeyCode::End | KeyCode::Char('G') => {
+                    self.paused = None;
+                    self.scroll = 0;
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn pause(&mut self, state: &Mutex<State>) {
+        self.paused = match self.paused {
+            Some(_) => None,
+            None => Some(state.lock().unwrap().received),
+        };
+        self.scroll = 0;
+    }
+
+    /// Scrolls back by the number of entries, which pauses the view.
+    fn scroll(&mut self, state: &Mutex<State>, by: isize) {
+        if self.paused.is_none() {
+            self.pause(state);
+        }
+        self.scroll = (self.scroll as isize + by).clamp(0, KEPT as isize) as usize;
+    }
+
+    /// Selects the next or the previous connection, then all of them.
+    fn select(&mut self, state: &Mutex<State>, next: bool) {
+        let state = state.lock().unwrap();
+        let mut ids: Vec<Option<u32>> = vec![None];
+        ids.extend(state.connections.keys().copied().map(Some));
+        let current = ids.iter().position(|id| *id == self.connection).unwrap_or(0);
+        let selected = match next {
+            true => (current + 1) % ids.len(),
+            false => (current + ids.len() - 1) % ids.len(),
+        };
+        self.connection = ids[selected];
+        self.scroll = 0;
+    }
+
+    fn measure(&mut self, totals: &[(u64, u64); 2]) {
+        let now = Instant::now();
+        match self.measured {
+            Some((since, _)) if now - since < Duration::from_secs(1) => {},
+            Some((since, last)) => {
+                let seconds = (now - since).as_secs_f64();
+                for (rate, (total, last)) in self.rates.iter_mut().zip(totals.iter().zip(last.iter())) {
+                    *rate = ((total.0 - last.0) as f64 / seconds, (total.1 - last.1) as f64 / seconds);
+                }
+                self.measured = Some((now, *totals));
+            },
+            None => self.measured = Some((now, *totals)),
+        }
+    }
+
+    fn shown(&self, entry: &Entry) -> bool {
+        self.paused.is_none_or(|paused| entry.number <= paused)
+            && self.connection.is_none_or(|connection| entry.connections.contains(&connection))
+            && self.filter.as_ref().is_none_or(|filter| {
+                let facts = Facts { connection_id: entry.id.connection_id, ..Facts::of(entry.from, &entry.message) };
+                filter.matches(&facts)
+            })
+    }
+
+    fn draw(&self, frame: &mut Frame, state: &State) {
+        // The sparklines take a row each, and no room before a value is tracked
+        let tracked = match state.tracked.len().min(TRACKED_SHOWN) {
+            0 => 0,
+            shown => shown as u16 + 2,
+        };
+        let [body, sparklines, output, help] = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(6), Constraint::Length(tracked), Constraint::Length(OUTPUT_HEIGHT),
+                Constraint::Length(1)])
+            .areas(frame.area());
+        let [connections, panes] = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(36), Constraint::Min(20)])
+            .areas(body);
+        let [client, server] = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
+            .areas(panes);
+        self.render_connections(frame, connections, state);
+        for (leg, area) in [(Leg::Client, client), (Leg::Server, server)] {
+            self.render_pane(frame, area, state, leg);
+        }
+        if tracked > 0 {
+            self.render_tracked(frame, sparklines, state);
+        }
+        let height = output.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = state.output.iter()
+            .skip(state.output.len().saturating_sub(height))
+            .map(|line| Line::from(line.as_str()))
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Output ")),
+            output);
+
+        let line = match (&self.typing, &self.notice) {
+            (Some((Typing::Filter, typed)), _) => format!("Filter (condition, Enter applies, Esc cancels): {}", typed),
+            (Some((Typing::Command, typed)), _) => format!("Command (Enter runs, ↑↓ recall, Esc cancels): :{}", typed),
+            (None, Some(notice)) => notice.clone(),
+            (None, None) => {
+                let filter = self.filter.as_ref().map(|filter| format!("filter {}, ", filter)).unwrap_or_default();
+                format!("{}p pauses, ↑↓ PgUp PgDn scroll, End follows, / filters, Esc clears it, \
+                    Tab picks a connection, : runs a command, Ctrl-L redraws, q stops the proxy", filter)
+            },
+        };
+        frame.render_widget(Paragraph::new(line), help);
+    }
+
+    fn render_connections(&self, frame: &mut Frame, area: Rect, state: &State) {
+        let open = state.connections.values().filter(|connection| !connection.closed).count();
+        let mut items = vec![ListItem::new(format!("All ({} open)", open))];
+        let mut selected = 0;
+        for (index, (id, connection)) in state.connections.iter().enumerate() {
+            if self.connection == Some(*id) {
+                selected = index + 1;
+            }
+            let line = format!("#{} {} {} ↑{} ↓{}", id, connection.peer, connection.resource,
+                connection.messages[Leg::Client as usize], connection.messages[Leg::Server as usize]);
+            let style = match connection.closed {
+                true => Style::default().fg(Color::DarkGray).add_modifier(Modifier::CROSSED_OUT),
+                false => Style::default(),
+            };
+            items.push(ListItem::new(line).style(style));
+        }
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(" Connections "))
+            .highlight_style(Style::default().bg(Color::Blue).fg(Color::Black).add_modifier(Modifier::BOLD));
+        frame.render_stateful_widget(list, area, &mut ListState::default().with_selected(Some(selected)));
+    }
+
+    /// Sparklines of the newest values of each expression that fit, scaled between their minimum and maximum.
+    fn render_tracked(&self, frame: &mut Frame, area: Rect, state: &State) {
+        let block = Block::default().borders(Borders::ALL).title(" Tracked ");
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![Constraint::Length(1); state.tracked.len().min(TRACKED_SHOWN)])
+            .split(block.inner(area));
+        frame.render_widget(block, area);
+        for ((expression, values), row) in state.tracked.iter().zip(rows.iter()) {
+            let [label, sparkline] = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Length(TRACKED_LABEL), Constraint::Min(10)])
+                .areas(*row);
+            let shown: Vec<f64> = values.iter().skip(values.len().saturating_sub(sparkline.width as usize)).copied()
+                .collect();
+            let min = shown.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = shown.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let last = shown.last().copied().unwrap_or_default();
+            frame.render_widget(Paragraph::new(format!("{} {} ({} to {})", expression, last, min, max)), label);
+            // Values which don't change make a line halfway up
+            let bars: Vec<u64> = shown.iter()
+                .map(|value| match max > min {
+                    true => ((value - min) / (max - min) * 100.0).round() as u64,
+                    false => 50,
+                })
+                .collect();
+            frame.render_widget(Sparkline::default().data(&bars).max(100).style(Style::default().fg(Color::Green)),
+                sparkline);
+        }
+    }
+
+    fn render_pane(&self, frame: &mut Frame, area: Rect, state: &State, leg: Leg) {
+        let (arrow, color) = match leg {
+            Leg::Client => ("client → server", Color::Cyan),
+            Leg::Server => ("server → client", Color::Yellow),
+        };
+        let (messages, bytes) = self.rates[leg as usize];
+        let mut title = format!(" {}: {:.1} msg/s, {}/s, {} messages ", arrow, messages,
+            format_size(bytes as usize), state.totals[leg as usize].0);
+        if self.paused.is_some() {
+            title.push_str("PAUSED ");
+        }
+        let height = area.height.saturating_sub(2) as usize;
+        let mut lines: Vec<Line> = state.panes[leg as usize].iter().rev()
+            .filter(|entry| self.shown(entry))
+            .skip(self.scroll)
+            .take(height)
+            .map(|entry| Line::from(format!("{} {} {}", entry.time, entry.id, entry.text)))
+            .collect();
+        lines.reverse();
+        let pane = Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(title).border_style(Style::default().fg(color)));
+        frame.render_widget(pane, area);
+    }
+}
+
+/// Stops the proxy the way Ctrl-C does outside of the UI, which keeps the terminal in raw mode.
+fn stop() {
+    signal_hook::low_level::raise(SIGINT).ok();
+}
@@ -0,0 +1,365 @@
+use openssl::ssl::{SslConnector, SslStream};
+use serde_json::json;
+use url::Url;
+use ws::Message;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::closecodes::{Initiator, Leg};
+use crate::proxy::Options;
+use crate::recorder::{Connection, Recorder};
+use crate::session;
+use crate::tls;
+
+/// Bytes read at once.
+const CHUNK: usize = 16 * 1024;
+
+/// Longest frame decoded, a longer one is taken as a sign of the wrong framing.
+const MAX_FRAME: u64 = 16 * 1024 * 1024;
+
+/// How long a read of a TLS server holds the connection, which writes wait for, when
+/// the bytes which came are not yet a whole record.
+const POLL: Duration = Duration::from_millis(10);
+
+/// Close codes the end of a tunnel is recorded with, as if it was a websocket.
+const CLOSED: u16 = 1000;
+const ABNORMAL: u16 = 1006;
+const BAD_GATEWAY: u16 = 1014;
+
+/// How the bytes of a tunnel are cut into messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// Lines ended with LF, or CRLF, like newline-delimited JSON.
+    Lines,
+    /// Frames after their length of some bytes, which are big-endian unless `little`.
+    Length { bytes: usize, little: bool },
+    /// Whatever is read at once.
+    Raw,
+}
+
+impl Framing {
+    /// Parses `lines`, `length=<1|2|4|8>[le|be]` or `raw`.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        let error = || format!("Unknown framing {}, expected lines, length=<1|2|4|8>[le|be] or raw", value);
+        match value {
+            "lines" => Ok(Framing::Lines),
+            "raw" => Ok(Framing::Raw),
+            _ => {
+                let length = value.strip_prefix("length=").ok_or_else(error)?;
+                let (bytes, little) = match length.strip_suffix("le") {
+                    Some(bytes) => (bytes, true),
+                    None => (length.strip_suffix("be").unwrap_or(length), false),
+                };
+                match bytes.parse() {
+                    Ok(bytes @ (1 | 2 | 4 | 8)) => Ok(Framing::Length { bytes, little }),
+                    _ => Err(error()),
+                }
+            },
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Framing::Lines => write!(f, "lines"),
+            Framing::Length { bytes, little } => write!(f, "length={}{}", bytes, if *little { "le" } else { "be" }),
+            Framing::Raw => write!(f, "raw"),
+        }
+    }
+}
+
+/// Decoder of the frames of one direction as the bytes arrive in pieces.
+struct Decoder {
+    framing: Framing,
+    buffer: Vec<u8>,
+    /// A frame was too long, the rest of the direction is forwarded without decoding.
+    failed: bool,
+}
+
+impl Decoder {
+    fn new(framing: Framing) -> Self {
+        Decoder { framing, buffer: vec![], failed: false }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Vec<u8>> {
+        if self.failed {
+            return vec![];
+        }
+        if self.framing == Framing::Raw {
+            return vec![bytes.to_vec()];
+        }
+        self.buffer.extend_from_slice(bytes);
+        let mut frames = vec![];
+        let mut position = 0;
+        loop {
+            let rest = &self.buffer[position..];
+            match self.framing {
+                Framing::Lines => match rest.iter().position(|byte| *byte == b'\n') {
+                    Some(end) => {
+                        let line = &rest[..end];
+                        frames.push(line.strip_suffix(b"\r").unwrap_or(line).to_vec());
+                        position += end + 1;
+                    },
+                    None if rest.len() as u64 > MAX_FRAME => {
+                        self.fail();
+                        return frames;
+                    },
+                    None => break,
+                },
+                Framing::Length { bytes, little } => {
+                    if rest.len() < bytes {
+                        break;
+                    }
+                    let prefix = &rest[..bytes];
+                    let length = if little {
+                        prefix.iter().rev().fold(0, |length, byte| length << 8 | *byte as u64)
+                    } else {
+                        prefix.iter().fold(0, |length, byte| length << 8 | *byte as u64)
+                    };
+                    if length > MAX_FRAME {
+                        self.fail();
+                        return frames;
+                    }
+                    let end = bytes + length as usize;
+                    if rest.len() < end {
+                        break;
+                    }
+                    frames.push(rest[bytes..end].to_vec());
+                    position += end;
+                },
+                Framing::Raw => unreachable!("raw reads aren't buffered"),
+            }
+        }
+        self.buffer.drain(..position);
+        frames
+    }
+
+    fn fail(&mut self) {
+        warn!("A frame is longer than {} bytes, is the framing {} right? The rest is forwarded without decoding",
+            MAX_FRAME, self.framing);
+        self.failed = true;
+        self.buffer = vec![];
+    }
+}
+
+/// Proxy of a protocol over TCP other than websockets: each client gets a connection
+/// of its own to the server, and bytes are forwarded both ways unchanged. Frames of the
+/// bytes are recorded into the capture as messages on the connection of the client,
+/// so that the session is logged, tagged, viewed and replayed like one of websockets.
+struct Tunnel {
+    host: String,
+    port: u16,
+    connector: Option<SslConnector>,
+    framing: Framing,
+}
+
+/// Runs the proxy of a tcp:// or tls:// server until SIGINT or SIGTERM.
+pub fn serve(address: SocketAddr, upstream: Url, framing: Framing, mut options: Options) -> std::result::Result<(), String> {
+    let connector = match (upstream.scheme(), options.upstream_tls.take()) {
+        ("tls", Some(connector)) => Some(connector),
+        ("tls", None) => Some(tls::connector(None, None, false)?),
+        ("tcp", Some(_)) => return Err("--upstream-ca, --upstream-cert and --insecure apply only to tls:// servers".to_string()),
+        ("tcp", None) => None,
+        _ => return Err(format!("Tunnels are opened to tcp:// or tls:// urls, not {}", upstream)),
+    };
+    let (host, port) = match (upstream.host_str(), upstream.port()) {
+        (Some(host), Some(port)) => (host.trim_start_matches('[').trim_end_matches(']').to_string(), port),
+        _ => return Err(format!("The host and port of the server are required, like tcp://localhost:9000, not {}", upstream)),
+    };
+
+    let listener = TcpListener::bind(address).map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    let recorder = Recorder::start("--tunnel", address, upstream.as_str(), options)?;
+    info!("Listening on {}, tunneling to {} with {} framing", address, upstream, framing);
+    let tunnel = Tunnel { host, port, connector, framing };
+    recorder.run(listener, move |recorder, connection_id, client| {
+        match tunnel.proxy(recorder, connection_id, client) {
+            Ok(closed) => closed,
+            Err(e) => {
+                debug!("Tunnel of connection {} failed: {}", connection_id, e);
+                (BAD_GATEWAY, e.to_string(), Initiator::Proxy)
+            },
+        }
+    })
+}
+
+impl Tunnel {
+    /// Forwards the bytes of the client and the server both ways, returns how it ended.
+    fn proxy(&self, recorder: &Recorder, connection_id: u32, client: TcpStream) -> io::Result<(u16, String, Initiator)> {
+        let peer = client.peer_addr().ok();
+        let upstream = self.connect(recorder, connection_id)?;
+        let mut record = session::open_record(connection_id, "client", peer.map(|peer| peer.to_string()),
+            "", &[], &[], recorder.labels());
+        record["tunnel"] = json!({ "framing": self.framing.to_string() });
+        recorder.opened(connection_id, record);
+        recorder.note(connection_id, &format!("Tunnel to {}:{}", self.host, self.port));
+
+        let connection = Connection::new(connection_id, vec![]);
+        let ended = Mutex::new(None);
+        thread::scope(|scope| {
+            let (client, upstream) = (&client, &upstream);
+            let (connection, ended) = (&connection, &ended);
+            scope.spawn(move || {
+                let result = self.pump(recorder, connection, Leg::Server, upstream.try_clone(), client.try_clone());
+                end(ended, Leg::Server, result, client, upstream);
+            });
+            let result = self.pump(recorder, connection, Leg::Client, client.try_clone(), upstream.try_clone());
+            end(ended, Leg::Client, result, client, upstream);
+        });
+        let ended = ended.into_inner().unwrap();
+        Ok(ended.unwrap_or((ABNORMAL, "The tunnel ended".to_string(), Initiator::Peer)))
+    }
+
+    fn connect(&self, recorder: &Recorder, connection_id: u32) -> io::Result<Upstream> {
+        let socket = TcpStream::connect((self.host.as_str(), self.port))?;
+        recorder.watch(connection_id, socket.try_clone()?);
+        match &self.connector {
+            Some(connector) => {
+                let stream = connector.connect(&self.host, socket).map_err(|e| io::Error::other(e.to_string()))?;
+                stream.get_ref().set_read_timeout(Some(POLL))?;
+                Ok(Upstream::Tls(Arc::new(Mutex::new(stream))))
+            },
+            None => Ok(Upstream::Plain(socket)),
+        }
+    }
+
+    /// Forwards the bytes from the side until it closes, recording their frames.
+    fn pump(&self, recorder: &Recorder, connection: &Connection, from: Leg,
+        source: io::Result<impl Read>, sink: io::Result<impl Write>) -> io::Result<()> {
+        let (mut source, mut sink) = (source?, sink?);
+        let mut decoder = Decoder::new(self.framing);
+        let mut buffer = vec![0; CHUNK];
+        loop {
+            let read = match source.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            for frame in decoder.feed(&buffer[..read]) {
+                let message = match String::from_utf8(frame) {
+                    Ok(text) => Message::Text(text),
+                    Err(e) => Message::Binary(e.into_bytes()),
+                };
+                recorder.message(connection, from, message, None, None);
+            }
+            sink.write_all(&buffer[..read]).and_then(|_| sink.flush())?;
+        }
+    }
+}
+
+/// Notes how the first side to stop ended the tunnel. A side closing its connection
+/// is passed on to the other one, which may still reply, an error ends both.
+fn end(ended: &Mutex<Option<(u16, String, Initiator)>>, from: Leg, result: io::Result<()>,
+    client: &TcpStream, upstream: &Upstream) {
+    let outcome = match result {
+        Ok(()) => {
+            match from {
+                Leg::Client => upstream.shutdown(Shutdown::Write).ok(),
+                Leg::Server => client.shutdown(Shutdown::Write).ok(),
+            };
+            (CLOSED, format!("The {} closed the connection", from), Initiator::Peer)
+        },
+        Err(e) => {
+            client.shutdown(Shutdown::Both).ok();
+            upstream.shutdown(Shutdown::Both).ok();
+            (ABNORMAL, e.to_string(), Initiator::Peer)
+        },
+    };
+    ended.lock().unwrap().get_or_insert(outcome);
+}
+
+/// Connection to the server, plain or over TLS. Reading and writing TLS happens
+/// in turns, reads wait for the server to send something without holding the stream.
+enum Upstream {
+    Plain(TcpStream),
+    Tls(Arc<Mutex<SslStream<TcpStream>>>),
+}
+
+impl Upstream {
+    fn try_clone(&self) -> io::Result<Upstream> {
+        match self {
+            Upstream::Plain(socket) => socket.try_clone().map(Upstream::Plain),
+            Upstream::Tls(stream) => Ok(Upstream::Tls(stream.clone())),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Upstream::Plain(socket) => socket.shutdown(how),
+            Upstream::Tls(stream) => {
+                let mut stream = stream.lock().unwrap();
+                if how == Shutdown::Write {
+                    // Only close_notify, a reply can still be read
+                    stream.shutdown().map_err(|e| io::Error::other(e.to_string()))?;
+                    return Ok(());
+                }
+                stream.get_ref().shutdown(how)
+            },
+        }
+    }
+}
+
+impl Read for Upstream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Upstream::Plain(socket) => socket.read(buffer),
+            Upstream::Tls(stream) => loop {
+                let socket = {
+                    let mut stream = stream.lock().unwrap();
+                    // Bytes decrypted already aren't on the socket anymore
+                    if stream.ssl().pending() > 0 {
+                        return stream.read(buffer);
+                    }
+                    stream.get_ref().as_raw_fd()
+                };
+                readable(socket)?;
+                match stream.lock().unwrap().read(buffer) {
+                    // Part of a record, or one without data, the rest is waited for again
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                    read => return read,
+                }
+            },
+        }
+    }
+}
+
+impl Write for Upstream {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            Upstream::Plain(socket) => socket.write(bytes),
+            Upstream::Tls(stream) => stream.lock().unwrap().write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Upstream::Plain(socket) => socket.flush(),
+            Upstream::Tls(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
+
+/// Waits until the socket has bytes to read or is closed.
+fn readable(socket: RawFd) -> io::Result<()> {
+    let mut poll = libc::pollfd { fd: socket, events: libc::POLLIN, revents: 0 };
+    loop {
+        // Safety: the socket stays open as long as the stream it's read for
+        if unsafe { libc::poll(&mut poll, 1, -1) } >= 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use serde_json::{json, Value};
+use ws::{CloseCode, ErrorKind};
+
+/// Sent to clients when the server failed rather than closed with a code of its own.
+const BAD_GATEWAY: u16 = 1014;
+
+/// Longest reason of a close frame, its payload is at most 125 bytes with the code.
+const MAX_REASON: usize = 123;
+
+/// How clients learn that the connection to the server was lost, given with --on-upstream-loss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LossNotice {
+    /// A close frame with the structured reason as JSON.
+    Close,
+    /// A final JSON message with the whole reason before the close frame.
+    Json,
+}
+
+impl LossNotice {
+    /// Parses `close` or `json`.
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "close" => Ok(LossNotice::Close),
+            "json" => Ok(LossNotice::Json),
+            _ => Err(format!("Invalid notice {}, expected close or json", value)),
+        }
+    }
+}
+
+/// What the proxy made of the end of the connection to the server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Diagnosis {
+    /// Credentials for the server couldn't be obtained.
+    Auth,
+    /// The address of the server couldn't be resolved.
+    Dns,
+    /// The TCP connection failed or broke.
+    Tcp,
+    Tls,
+    /// The server refused the websocket handshake.
+    Handshake,
+    /// The server broke the websocket protocol.
+    Protocol,
+    /// The server closed the connection with a close frame.
+    Closed,
+    /// The proxy itself failed.
+    Proxy,
+}
+
+impl Diagnosis {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Diagnosis::Auth => "auth",
+            Diagnosis::Dns => "dns",
+            Diagnosis::Tcp => "tcp",
+            Diagnosis::Tls => "tls",
+            Diagnosis::Handshake => "handshake",
+            Diagnosis::Protocol => "protocol",
+            Diagnosis::Closed => "closed",
+            Diagnosis::Proxy => "proxy",
+        }
+    }
+}
+
+/// Events of the connection to the server, kept by its handler until ws drops it.
+#[derive(Default)]
+pub struct Loss {
+    opened: bool,
+    close: Option<(u16, String)>,
+    error: Option<(Diagnosis, String)>,
+}
+
+impl Loss {
+    pub fn opened(&mut self) {
+        self.opened = true;
+    }
+
+    pub fn closed(&mut self, code: u16, reason: &str) {
+        self.close = Some((code, reason.to_string()));
+    }
+
+    /// Keeps the first error, later ones are usually its consequences.
+    pub fn failed(&mut self, err: &ws::Error) {
+        if self.error.is_some() {
+            return;
+        }
+        let diagnosis = match (&err.kind, self.opened) {
+            (ErrorKind::Io(_), _) => Diagnosis::Tcp,
+            (ErrorKind::Ssl(_), _) | (ErrorKind::SslHandshake(_), _) => Diagnosis::Tls,
+            (ErrorKind::Protocol, false) | (ErrorKind::Http(_), false) => Diagnosis::Handshake,
+            (ErrorKind::Protocol, true) | (ErrorKind::Http(_), true) => Diagnosis::Protocol,
+            (ErrorKind::Encoding(_), _) => Diagnosis::Protocol,
+            _ => Diagnosis::Proxy,
+        };
+        self.error = Some((diagnosis, describe(err)));
+    }
+
+    /// Reason of the loss, like `{"diagnosis":"closed","upstream_code":1001,"upstream_reason":"restart"}`.
+    /// ws drops connections which it couldn't connect to without telling their handlers.
+    pub fn reason(&self) -> Value {
+        let diagnosis = match (&self.error, &self.close) {
+            (Some((diagnosis, _)), _) => *diagnosis,
+            (None, Some((code, _))) if *code != 1006 => Diagnosis::Closed,
+            (None, _) => Diagnosis::Tcp,
+        };
+        let mut reason = json!({ "diagnosis": diagnosis.name() });
+        if let Some((code, text)) = &self.close {
+            reason["upstream_code"] = json!(code);
+            reason["upstream_reason"] = json!(text);
+        }
+        if let Some((_, error)) = &self.error {
+            reason["error"] = json!(error);
+        }
+        reason
+    }
+}
+
+/// Reason of an attempt to connect which failed before ws was asked to connect.
+pub fn unreachable(diagnosis: Diagnosis, error: &str) -> Value {
+    json!({ "diagnosis": diagnosis.name(), "error": error })
+}
+
+/// Code of the close frame sent to clients for the reason: the code of the server
+/// if it's one an endpoint may send, 1014 (bad gateway) otherwise.
+pub fn close_code(reason: &Value) -> CloseCode {
+    let code = reason["upstream_code"].as_u64()
+        .map(|code| code as u16)
+        .filter(|code| matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999))
+        .unwrap_or(BAD_GATEWAY);
+    CloseCode::from(code)
+}
+
+/// The reason as compact JSON short enough for a close frame, its texts shortened if needed.
+pub fn close_reason(reason: &Value) -> String {
+    let mut reason = reason.clone();
+    loop {
+        let text = reason.to_string();
+        if text.len() <= MAX_REASON {
+            return text;
+        }
+        let longest = ["error", "upstream_reason"].iter()
+            .filter_map(|field| reason[*field].as_str().map(|text| (*field, text.chars().count())))
+            .max_by_key(|(_, length)| *length);
+        match longest {
+            Some((field, length)) if length > 0 => {
+                let shortened: String = reason[field].as_str().unwrap_or_default().chars().take(length - 1).collect();
+                reason[field] = json!(shortened);
+            },
+            _ => return json!({ "diagnosis": reason["diagnosis"] }).to_string(),
+        }
+    }
+}
+
+/// Text of the error, ws describes errors of its dependencies only by their kind.
+pub(crate) fn describe(err: &ws::Error) -> String {
+    match &err.kind {
+        ErrorKind::Io(e) => e.to_string(),
+        ErrorKind::Ssl(e) => e.to_string(),
+        ErrorKind::SslHandshake(e) => e.to_string(),
+        ErrorKind::Http(e) => format!("{}: {}", err.details, e),
+        _ => err.details.to_string(),
+    }
+}
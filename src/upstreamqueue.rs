@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+
+use ws::Message;
+
+/// Messages of clients held while the server isn't connected, 1MB by default.
+pub const DEFAULT_QUEUE_SIZE: usize = 1 << 20;
+
+/// What happens to a message of a client which doesn't fit into the queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Overflow {
+    /// The message is dropped, the queued ones stay.
+    #[default]
+    DropNewest,
+    /// Queued messages are dropped, the oldest first, until the message fits.
+    DropOldest,
+    /// The client is closed, so that it can retry later.
+    Close,
+}
+
+impl Overflow {
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        match value {
+            "drop-newest" => Ok(Overflow::DropNewest),
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "close" => Ok(Overflow::Close),
+            _ => Err(format!("Unknown overflow policy {}, expected drop-newest, drop-oldest or close", value)),
+        }
+    }
+}
+
+/// Messages of clients to the server in the order they came, bounded by their total size.
+pub struct UpstreamQueue {
+    max_bytes: usize,
+    overflow: Overflow,
+    messages: VecDeque<(u32, Message)>,
+    bytes: usize,
+}
+
+/// Outcome of queueing a message.
+#[derive(Debug, PartialEq)]
+pub enum Pushed {
+    /// The message is queued after dropping that many older ones.
+    Kept(usize),
+    /// The message doesn't fit and is dropped.
+    Dropped,
+    /// The message doesn't fit and its client is to be closed.
+    Refused,
+}
+
+impl UpstreamQueue {
+    pub fn new(max_bytes: usize, overflow: Overflow) -> Self {
+        UpstreamQueue { max_bytes, overflow, messages: VecDeque::new(), bytes: 0 }
+    }
+
+    pub fn push(&mut self, client: u32, msg: Message) -> Pushed {
+        let mut dropped = 0;
+        if self.bytes + msg.len() > self.max_bytes {
+            match self.overflow {
+                Overflow::DropNewest => return Pushed::Dropped,
+                Overflow::Close => return Pushed::Refused,
+                Overflow::DropOldest => {
+                    while self.bytes + msg.len() > self.max_bytes {
+                        match self.messages.pop_front() {
+                            Some((_, old)) => {
+                                self.bytes -= old.len();
+                                dropped += 1;
+                            },
+                            None => return Pushed::Dropped,
+                        }
+                    }
+                },
+            }
+        }
+        self.bytes += msg.len();
+        self.messages.push_back((client, msg));
+        Pushed::Kept(dropped)
+    }
+
+    /// Drops the messages of a client which closed, returns how many.
+    pub fn forget(&mut self, client: u32) -> usize {
+        let before = self.messages.len();
+        self.messages.retain(|(id, _)| *id != client);
+        self.bytes = self.messages.iter().map(|(_, msg)| msg.len()).sum();
+        before - self.messages.len()
+    }
+
+    /// All queued messages with their clients, leaving the queue empty.
+    pub fn take(&mut self) -> VecDeque<(u32, Message)> {
+        self.bytes = 0;
+        std::mem::take(&mut self.messages)
+    }
+}
@@ -0,0 +1,98 @@
+use env_logger::filter::Filter;
+use log::{LevelFilter, Log, Metadata, Record};
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::env;
+use std::sync::Mutex;
+
+/// Levels of the log of single client connections, set with `:on <connection> log <level>`.
+static LEVELS: Mutex<BTreeMap<u32, LevelFilter>> = Mutex::new(BTreeMap::new());
+/// Highest level of RUST_LOG once the logger is installed, the log crate doesn't make records
+/// above both it and the levels of connections.
+static BASE: Mutex<Option<LevelFilter>> = Mutex::new(None);
+
+thread_local! {
+    /// Client connection whose event the thread is handling.
+    static CONNECTION: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+/// Logger of the proxy, filtering with RUST_LOG except for records of the proxy logged while a
+/// client connection with a level of its own is handled, so that one client may be traced in
+/// detail while the rest stay quiet, or the other way round. Levels of connections take effect
+/// only with this logger installed.
+pub struct Logger {
+    filter: Filter,
+    writer: env_logger::Logger,
+}
+
+impl Logger {
+    /// Installs the logger as the global one.
+    pub fn init() {
+        let filter = env_logger::filter::Builder::from_env("RUST_LOG").build();
+        // Records are filtered before they get to the writer, which only formats them
+        let mut writer = env_logger::Builder::new();
+        writer.filter_level(LevelFilter::Trace);
+        if let Ok(style) = env::var("RUST_LOG_STYLE") {
+            writer.parse_write_style(&style);
+        }
+        let max_level = filter.filter();
+        log::set_boxed_logger(Box::new(Logger { filter, writer: writer.build() }))
+            .expect("the logger is installed once");
+        *BASE.lock().unwrap() = Some(max_level);
+        log::set_max_level(max_level);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match level() {
+            Some(level) if metadata.target().starts_with("ws_proxy") => metadata.level() <= level,
+            _ => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.writer.log(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Level of the connection being handled on the thread, if it has its own.
+fn level() -> Option<LevelFilter> {
+    let connection = CONNECTION.with(Cell::get)?;
+    LEVELS.lock().unwrap().get(&connection).copied()
+}
+
+/// Sets the level of the log of a client connection, or makes it follow RUST_LOG again.
+pub fn set(connection_id: u32, level: Option<LevelFilter>) {
+    let mut levels = LEVELS.lock().unwrap();
+    match level {
+        Some(level) => levels.insert(connection_id, level),
+        None => levels.remove(&connection_id),
+    };
+    // The level of another logger, of an application embedding the proxy, is left alone
+    if let Some(base) = *BASE.lock().unwrap() {
+        log::set_max_level(levels.values().copied().fold(base, Ord::max));
+    }
+}
+
+/// Marks the records logged on the thread as those of the client connection, until it's dropped.
+pub struct Scope {
+    outer: Option<u32>,
+}
+
+impl Scope {
+    pub fn enter(connection_id: Option<u32>) -> Self {
+        Scope { outer: CONNECTION.with(|connection| connection.replace(connection_id)) }
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CONNECTION.with(|connection| connection.set(self.outer));
+    }
+}
@@ -0,0 +1,178 @@
+use serde_json::{json, Map, Value};
+use serde_json_path::JsonPath;
+use ws::Message;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::closecodes::Leg;
+use crate::condition::{Condition, Facts};
+use crate::track;
+
+/// File keeping the saved views, next to the sessions.
+pub const VIEWS: &str = "ws-proxy.views.yaml";
+
+const HIGHLIGHT_START: &str = "\x1b[1;33m";
+const HIGHLIGHT_END: &str = "\x1b[0m";
+
+/// Saved way of looking at messages: which ones are shown, what is highlighted in them,
+/// and which of their fields are shown instead of the whole message.
+pub struct View {
+    pub name: String,
+    filter: Option<Condition>,
+    from: Option<Leg>,
+    tags: Vec<String>,
+    highlight: Vec<Condition>,
+    project: Vec<(String, JsonPath)>,
+}
+
+impl View {
+    /// Compiles a view saved as an object with optional fields `filter` (condition),
+    /// `from` (client or server), `tags`, `highlight` (conditions) and `project` (JSONPaths).
+    pub fn compile(name: &str, saved: &Value) -> std::result::Result<Self, String> {
+        let condition = |condition: &str| Condition::parse(condition).map_err(|e| format!("{} in view {}", e, name));
+        let strings = |field: &str| -> Vec<String> {
+            saved[field].as_array().into_iter().flatten()
+                .filter_map(|item| item.as_str().map(String::from))
+                .collect()
+        };
+
+        let filter = saved["filter"].as_str().map(condition).transpose()?;
+        let from = saved["from"].as_str().map(Leg::parse).transpose()?;
+        let highlight = strings("highlight").iter()
+            .map(|highlight| condition(highlight))
+            .collect::<std::result::Result<Vec<Condition>, String>>()?;
+        let project = strings("project").into_iter()
+            .map(|expression| Ok((expression.clone(), track::parse(&expression)?)))
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        Ok(View { name: name.to_string(), filter, from, tags: strings("tags"), highlight, project })
+    }
+
+    /// The message as the view shows it, unless the view hides it.
+    pub fn show(&self, facts: &Facts, tags: &[String]) -> Option<String> {
+        let shown = self.from.map(|leg| leg == facts.from).unwrap_or(true)
+            && self.tags.iter().all(|tag| tags.contains(tag))
+            && self.filter.as_ref().map(|filter| filter.matches(facts)).unwrap_or(true);
+        if !shown {
+            return None;
+        }
+
+        let data = data(facts.message);
+        let text = match serde_json::from_str::<Value>(&data) {
+            Ok(value) if !self.project.is_empty() => {
+                let fields: Vec<String> = self.project.iter()
+                    .map(|(expression, path)| {
+                        let found: Vec<String> = path.query(&value).all().iter().map(|found| found.to_string()).collect();
+                        format!("{}={}", expression, found.join(","))
+                    })
+                    .collect();
+                fields.join(" ")
+            },
+            _ => data,
+        };
+        Some(self.highlight.iter().fold(text, |text, highlight| match highlight {
+            // Patterns highlight what they match, expressions the whole message they hold for
+            Condition::Pattern { leg, pattern } if leg.is_none_or(|leg| leg == facts.from) => {
+                pattern.replace_all(&text, |found: &regex::Captures| {
+                    format!("{}{}{}", HIGHLIGHT_START, &found[0], HIGHLIGHT_END)
+                }).into_owned()
+            },
+            Condition::Expression(_) if highlight.matches(facts) => {
+                format!("{}{}{}", HIGHLIGHT_START, text, HIGHLIGHT_END)
+            },
+            _ => text,
+        }))
+    }
+}
+
+/// Text of the message as it is shown, binary ones by their size.
+fn data(message: &Message) -> String {
+    match message {
+        Message::Text(text) => text.clone(),
+        Message::Binary(data) => format!("<{} bytes>", data.len()),
+    }
+}
+
+/// Saved views by their names, none if the file doesn't exist yet.
+pub fn load(path: &Path) -> std::result::Result<Map<String, Value>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Map::new()),
+        Err(e) => return Err(format!("Can't read views {}: {}", path.display(), e)),
+    };
+    match serde_yaml::from_str::<Value>(&text) {
+        Ok(Value::Object(views)) => Ok(views),
+        Ok(Value::Null) => Ok(Map::new()),
+        Ok(_) => Err(format!("Views {} must be a map of names to views", path.display())),
+        Err(e) => Err(format!("Views {} are not YAML: {}", path.display(), e)),
+    }
+}
+
+pub fn save(path: &Path, views: &Map<String, Value>) -> std::result::Result<(), String> {
+    let text = serde_yaml::to_string(&json!(views)).map_err(|e| e.to_string())?;
+    fs::write(path, text).map_err(|e| format!("Can't write views {}: {}", path.display(), e))
+}
+
+/// Compiled view saved under the name.
+pub fn find(path: &Path, name: &str) -> std::result::Result<View, String> {
+    let views = load(path)?;
+    let saved = views.get(name).ok_or_else(|| format!("There is no view {} in {}", name, path.display()))?;
+    View::compile(name, saved)
+}
+
+/// What is printed of the live traffic.
+enum Showing {
+    Nothing,
+    All,
+    View(View),
+}
+
+/// View of the live traffic, switched while the proxy runs with `:view <name>`, `:view`
+/// to show everything, `:filter <condition>` or `:quiet`. Views are read anew on every switch.
+#[derive(Clone)]
+pub struct LiveView {
+    path: PathBuf,
+    current: Arc<Mutex<Showing>>,
+}
+
+impl LiveView {
+    /// Nothing is printed until a view is switched to, unless one is given.
+    pub fn new(path: &Path, initial: Option<View>) -> Self {
+        let showing = match initial {
+            Some(view) => Showing::View(view),
+            None => Showing::Nothing,
+        };
+        LiveView { path: path.to_path_buf(), current: Arc::new(Mutex::new(showing)) }
+    }
+
+    /// Switches to the saved view, or to all messages without a name.
+    pub fn switch(&self, name: Option<&str>) -> std::result::Result<(), String> {
+        let showing = match name {
+            Some(name) => Showing::View(find(&self.path, name)?),
+            None => Showing::All,
+        };
+        *self.current.lock().unwrap() = showing;
+        Ok(())
+    }
+
+    /// Shows the messages matching the condition.
+    pub fn filter(&self, condition: &str) -> std::result::Result<(), String> {
+        let view = View::compile("filter", &json!({ "filter": condition }))?;
+        *self.current.lock().unwrap() = Showing::View(view);
+        Ok(())
+    }
+
+    pub fn hide(&self) {
+        *self.current.lock().unwrap() = Showing::Nothing;
+    }
+
+    pub fn show(&self, facts: &Facts, tags: &[String]) -> Option<String> {
+        match &*self.current.lock().unwrap() {
+            Showing::Nothing => None,
+            Showing::All => Some(data(facts.message)),
+            Showing::View(view) => view.show(facts, tags),
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use log::error;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+use ws::Message;
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+use crate::closecodes::Leg;
+use crate::plugins::{self, Kind, Manifest};
+use crate::session::MessageId;
+
+/// Version of the interface between the proxy and WASM plugins. A module exports its
+/// memory and:
+///
+/// ```text
+/// ws_proxy_abi_version() -> i32
+/// ;; buffer of len bytes in the memory, where the proxy writes the message
+/// ws_proxy_alloc(len: i32) -> i32
+/// ;; from is 0 for the client and 1 for the server, binary 1 for binary messages;
+/// ;; returns 0 to forward the message, 1 to drop it, 2 to forward the replacement
+/// ws_proxy_on_message(from: i32, binary: i32, ptr: i32, len: i32) -> i32
+/// ```
+///
+/// and may import from the module `ws_proxy`:
+///
+/// ```text
+/// replace(ptr: i32, len: i32)  ;; replacement of the message, of the same type
+/// log(ptr: i32, len: i32)      ;; line printed with the id of the message
+/// ```
+pub const ABI_VERSION: u32 = 1;
+
+/// Instructions a plugin may run for one message before it is stopped.
+const FUEL: u64 = 10_000_000;
+/// Largest memory of a plugin.
+const MEMORY_LIMIT: usize = 64 << 20;
+
+const FORWARD: i32 = 0;
+const DROP: i32 = 1;
+const REPLACE: i32 = 2;
+
+/// What the plugins decided about a message.
+pub enum Verdict {
+    Forward,
+    /// Dropped by the named plugin.
+    Drop(String),
+    /// Replaced by the named plugins.
+    Replace(String, Message),
+}
+
+/// State of a plugin between the calls of the proxy and the imports it calls.
+struct Host {
+    limits: StoreLimits,
+    replacement: Option<Vec<u8>>,
+    lines: Vec<String>,
+}
+
+/// Message processor of a loaded WASM plugin, sandboxed in an interpreter with
+/// its own memory, which keeps its state across the messages of all connections.
+pub struct Processor {
+    pub manifest: Manifest,
+    store: RefCell<Store<Host>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_message: TypedFunc<(i32, i32, i32, i32), i32>,
+}
+
+impl Processor {
+    /// Instantiates the module of a verified plugin and checks it exports the interface.
+    pub fn load(manifest: Manifest) -> std::result::Result<Self, String> {
+        if manifest.kind != Kind::Wasm {
+            return Err(format!("{} is not a WASM plugin", manifest.name));
+        }
+        manifest.verify()?;
+        let library = manifest.library.display().to_string();
+        let wasm = fs::read(&manifest.library).map_err(|e| format!("Can't read {}: {}", library, e))?;
+
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, &wasm[..]).map_err(|e| format!("{} is not a WASM module: {}", library, e))?;
+        let limits = StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build();
+        let mut store = Store::new(&engine, Host { limits, replacement: None, lines: vec![] });
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(FUEL).map_err(|e| e.to_string())?;
+
+        let mut linker = <Linker<Host>>::new(&engine);
+        linker.func_wrap("ws_proxy", "replace", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let replacement = read(&caller, ptr, len)?;
+            caller.data_mut().replacement = Some(replacement);
+            Ok(())
+        }).map_err(|e| e.to_string())?;
+        linker.func_wrap("ws_proxy", "log", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+            let line = String::from_utf8_lossy(&read(&caller, ptr, len)?).into_owned();
+            caller.data_mut().lines.push(line);
+            Ok(())
+        }).map_err(|e| e.to_string())?;
+        let instance = linker.instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|e| format!("Can't instantiate {}: {}", library, e))?;
+
+        let memory = instance.get_memory(&store, "memory")
+            .ok_or_else(|| format!("{} doesn't export its memory", library))?;
+        let export = |name: &str| format!("{} doesn't export {} of the interface", library, name);
+        let version = instance.get_typed_func::<(), i32>(&store, "ws_proxy_abi_version")
+            .map_err(|_| export("ws_proxy_abi_version"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "ws_proxy_alloc")
+            .map_err(|_| export("ws_proxy_alloc"))?;
+        let on_message = instance.get_typed_func::<(i32, i32, i32, i32), i32>(&store, "ws_proxy_on_message")
+            .map_err(|_| export("ws_proxy_on_message"))?;
+        let abi = version.call(&mut store, ()).map_err(|e| format!("{} failed: {}", library, e))?;
+        if abi as u32 != ABI_VERSION {
+            return Err(format!("{} implements ABI {}, not {}", library, abi, ABI_VERSION));
+        }
+        Ok(Processor { manifest, store: RefCell::new(store), memory, alloc, on_message })
+    }
+
+    /// Calls the plugin with a message, returning its verdict and the lines it logged.
+    fn process(&self, from: Leg, message: &Message) -> std::result::Result<(Verdict, Vec<String>), String> {
+        let (data, binary): (&[u8], bool) = match message {
+            Message::Text(text) => (text.as_bytes(), false),
+            Message::Binary(data) => (data, true),
+        };
+        let mut store = self.store.borrow_mut();
+        store.set_fuel(FUEL).map_err(|e| e.to_string())?;
+        store.data_mut().replacement = None;
+        let len = i32::try_from(data.len()).map_err(|_| "Message is too big for the plugin".to_string())?;
+        let ptr = self.alloc.call(&mut *store, len).map_err(|e| e.to_string())?;
+        self.memory.write(&mut *store, ptr as u32 as usize, data)
+            .map_err(|_| format!("Buffer of {} bytes at {} is out of the memory", len, ptr))?;
+        let from = match from {
+            Leg::Client => 0,
+            Leg::Server => 1,
+        };
+        let code = self.on_message.call(&mut *store, (from, binary as i32, ptr, len)).map_err(|e| e.to_string());
+        let host = store.data_mut();
+        let lines = std::mem::take(&mut host.lines);
+        let name = self.manifest.name.clone();
+        let verdict = match code? {
+            FORWARD => Verdict::Forward,
+            DROP => Verdict::Drop(name),
+            REPLACE => {
+                let replacement = host.replacement.take().ok_or("Plugin replaced the message without calling replace")?;
+                let replaced = match binary {
+                    true => Message::Binary(replacement),
+                    false => Message::Text(String::from_utf8(replacement)
+                        .map_err(|_| "Replacement of a text message is not UTF-8")?),
+                };
+                Verdict::Replace(name, replaced)
+            },
+            other => return Err(format!("Plugin returned {}, expected 0, 1 or 2", other)),
+        };
+        Ok((verdict, lines))
+    }
+}
+
+/// Bytes at the pointer in the memory of the calling plugin.
+fn read(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> std::result::Result<Vec<u8>, wasmi::Error> {
+    let memory = caller.get_export("memory").and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("Plugin doesn't export its memory"))?;
+    let (start, len) = (ptr as u32 as usize, len as u32 as usize);
+    memory.data(caller).get(start..start + len)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmi::Error::new(format!("Buffer of {} bytes at {} is out of the memory", len, start)))
+}
+
+/// Processors of all valid WASM plugins in a directory, called in the order of their names.
+pub struct Processors {
+    processors: Vec<Processor>,
+}
+
+impl Processors {
+    /// Loads all WASM plugins, those failing to load are reported and skipped.
+    pub fn load(dir: &Path) -> std::result::Result<Self, String> {
+        let mut processors = vec![];
+        for (path, manifest) in plugins::discover(dir)? {
+            let manifest = match manifest {
+                Ok(manifest) if manifest.kind != Kind::Wasm => continue,
+                manifest => manifest,
+            };
+            match manifest.and_then(Processor::load) {
+                Ok(processor) => {
+                    println!("Loaded message processor {}", processor.manifest);
+                    processors.push(processor);
+                },
+                Err(e) => println!("Plugin {} is skipped: {}", path.display(), e),
+            }
+        }
+        Ok(Processors { processors })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    /// Passes the message through the plugins for it, each one getting the message as
+    /// the previous one replaced it, until one drops it. A plugin failing, like by running
+    /// out of fuel, leaves the message as it was given to it.
+    pub fn process(&self, id: MessageId, from: Leg, message: &Message) -> Verdict {
+        let mut replaced: Option<(Vec<&str>, Message)> = None;
+        for processor in self.processors.iter() {
+            let current = replaced.as_ref().map(|(_, message)| message).unwrap_or(message);
+            if !processor.manifest.applies(current) {
+                continue;
+            }
+            let name = processor.manifest.name.as_str();
+            let verdict = match processor.process(from, current) {
+                Ok((verdict, lines)) => {
+                    for line in lines {
+                        println!("Plugin {} on message {}: {}", name, id, line);
+                    }
+                    verdict
+                },
+                Err(e) => {
+                    error!("Plugin {} failed on message {}: {}", name, id, e);
+                    continue;
+                },
+            };
+            match verdict {
+                Verdict::Forward => {},
+                Verdict::Drop(plugin) => return Verdict::Drop(plugin),
+                Verdict::Replace(_, message) => {
+                    let mut names = replaced.map(|(names, _)| names).unwrap_or_default();
+                    names.push(name);
+                    replaced = Some((names, message));
+                },
+            }
+        }
+        match replaced {
+            Some((names, message)) => Verdict::Replace(names.join(", "), message),
+            None => Verdict::Forward,
+        }
+    }
+}
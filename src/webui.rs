@@ -0,0 +1,99 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::debug;
+
+/// Single page of the UI, served at / of the control port.
+pub const PAGE: &str = include_str!("webui.html");
+
+/// Hashed with the key of a client into the accept header of the handshake, by RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Events kept for viewers opening the page after the traffic started.
+const HISTORY: usize = 1000;
+/// Events waiting to be written to a viewer, a slower one misses the newer events.
+const BACKLOG: usize = 4 * HISTORY;
+
+/// Pages of the web UI streaming the traffic over a WebSocket at /events of the control port.
+///
+/// Every event is a text message of one JSON object: `opened` with the details the API lists
+/// for a connection, the message records of the capture with the `connections` of the clients
+/// they were forwarded to or came from, and `closed`. A page gets the recent events first,
+/// then live ones. Writing is left to a thread per page, so a slow page doesn't hold the proxy.
+#[derive(Clone, Default)]
+pub struct Viewers {
+    inner: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    history: VecDeque<String>,
+    viewers: Vec<mpsc::SyncSender<String>>,
+}
+
+impl Viewers {
+    pub fn publish(&self, event: &Value) {
+        let event = event.to_string();
+        let mut state = self.inner.lock().unwrap();
+        // Pages which went away are forgotten once their thread failed to write
+        state.viewers.retain(|viewer| {
+            !matches!(viewer.try_send(event.clone()), Err(mpsc::TrySendError::Disconnected(_)))
+        });
+        if state.history.len() == HISTORY {
+            state.history.pop_front();
+        }
+        state.history.push_back(event);
+    }
+
+    /// Completes the handshake of a page, given the key of its request, and starts streaming.
+    pub fn connect(&self, mut stream: TcpStream, key: &str) -> io::Result<()> {
+        let accept = BASE64.encode(Sha1::digest(format!("{}{}", key.trim(), GUID)));
+        write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Accept: {}\r\n\r\n", accept)?;
+        stream.set_read_timeout(None)?;
+        let (events_tx, events) = mpsc::sync_channel(BACKLOG);
+        {
+            let mut state = self.inner.lock().unwrap();
+            for event in state.history.iter() {
+                events_tx.try_send(event.clone()).ok();
+            }
+            state.viewers.push(events_tx);
+        }
+        let peer = stream.peer_addr().map(|peer| peer.to_string()).unwrap_or_default();
+        debug!("Web UI of {} connected", peer);
+        thread::spawn(move || {
+            for event in events {
+                if let Err(e) = stream.write_all(&text_frame(event.as_bytes())) {
+                    debug!("Web UI of {} disconnected: {}", peer, e);
+                    return;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Final text frame of a server, which isn't masked.
+fn text_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
@@ -0,0 +1,198 @@
+use url::Url;
+
+use std::env;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use crate::auth::SigV4;
+use crate::memory;
+use crate::track;
+
+/// Questions leading to a command line, for those who don't remember the flags.
+pub struct Wizard<R, W> {
+    input: R,
+    output: W,
+}
+
+/// Command line made by the wizard, and whether to start the proxy with it right away.
+pub struct Answers {
+    pub args: Vec<String>,
+    pub start: bool,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Wizard { input, output }
+    }
+
+    pub fn run(&mut self) -> std::result::Result<Answers, String> {
+        let mut args = vec![];
+
+        let upstream = self.ask("Server url (ws:// or wss://), empty for the built-in test server", "", |answer| {
+            if answer.is_empty() {
+                return Ok(());
+            }
+            let url = Url::parse(answer).map_err(|e| format!("{} is not a url: {}", answer, e))?;
+            match url.scheme() {
+                "ws" | "wss" => Ok(()),
+                scheme => Err(format!("The scheme is {}, not ws or wss", scheme)),
+            }
+        })?;
+        let port = self.ask("Port to listen on", "8080", |answer| {
+            answer.parse::<u16>().map(|_| ()).map_err(|e| format!("Port number {} is invalid: {}", answer, e))
+        })?;
+        if upstream.is_empty() {
+            args.push("--with-test-server".to_string());
+        } else {
+            args.push(upstream.clone());
+        }
+        args.push(port.clone());
+
+        if upstream.starts_with("wss://") {
+            self.say("The proxy connects to the server with TLS")?;
+            if self.confirm("Skip verification of the server certificate, for self-signed servers", false)? {
+                args.push("--insecure".to_string());
+            } else {
+                let ca = self.ask("CA bundle of the server in PEM, empty for the system ones", "", |answer| {
+                    if answer.is_empty() || Path::new(answer).is_file() {
+                        Ok(())
+                    } else {
+                        Err(format!("There is no file {}", answer))
+                    }
+                })?;
+                if !ca.is_empty() {
+                    args.extend(["--upstream-ca".to_string(), ca]);
+                }
+            }
+        }
+        if self.confirm("Serve wss:// to clients, with a certificate", false)? {
+            let exists = |answer: &str| if Path::new(answer).is_file() {
+                Ok(())
+            } else {
+                Err(format!("There is no file {}", answer))
+            };
+            let cert = self.ask("Certificate chain in PEM", "", exists)?;
+            let key = self.ask("Private key in PEM", "", exists)?;
+            args.extend(["--tls-cert".to_string(), cert, "--tls-key".to_string(), key]);
+        }
+        if !upstream.is_empty() {
+            let auth = self.ask("Authentication of the server: none or aws-sigv4", "none", |answer| {
+                match answer {
+                    "none" | "aws-sigv4" => Ok(()),
+                    _ => Err("Only AWS Signature Version 4 is supported, tokens can be given in the url".to_string()),
+                }
+            })?;
+            if auth == "aws-sigv4" {
+                // Without credentials the signer can't be made, they may be set before starting
+                let credentials = env::var_os("AWS_ACCESS_KEY_ID").is_some();
+                let spec = self.ask("AWS region and service, like eu-west-1:appsync", "us-east-1:execute-api",
+                    |answer| match credentials {
+                        true => SigV4::parse(answer).map(|_| ()),
+                        false if answer.starts_with(':') => Err(format!("Region is missing in {}", answer)),
+                        false => Ok(()),
+                    })?;
+                self.say("Credentials are taken from AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_SESSION_TOKEN")?;
+                if !credentials {
+                    self.say("They are not set yet, set them before starting the proxy")?;
+                }
+                args.extend(["--aws-sigv4".to_string(), spec]);
+            }
+        }
+
+        if self.confirm("Show messages live in the terminal instead of writing logs", false)? {
+            args.push("--console".to_string());
+        } else {
+            if self.confirm("Pretty-print messages in logs", true)? {
+                args.push("--pretty".to_string());
+            }
+            let limit = self.ask("Cut payloads larger than, like 64KB, empty for no limit", "", |answer| {
+                if answer.is_empty() { Ok(()) } else { memory::parse_size(answer).map(|_| ()) }
+            })?;
+            if !limit.is_empty() {
+                args.extend(["--log-max-payload".to_string(), limit]);
+            }
+        }
+        if self.confirm("Replace e-mails and IP addresses with pseudonyms", false)? {
+            args.extend(["--anonymize".to_string(), "email,ip".to_string()]);
+        }
+        loop {
+            let expression = self.ask("JSONPath of a number to track into track.csv, empty when done", "", |answer| {
+                if answer.is_empty() { Ok(()) } else { track::parse(answer).map(|_| ()) }
+            })?;
+            if expression.is_empty() {
+                break;
+            }
+            args.extend(["--track".to_string(), expression]);
+        }
+        let label = self.ask("Label of the session, like ticket=ABC-123, empty for none", "", |answer| {
+            if answer.is_empty() || answer.split_once('=').filter(|(key, _)| !key.is_empty()).is_some() {
+                Ok(())
+            } else {
+                Err(format!("Label {} is not <key>=<value>", answer))
+            }
+        })?;
+        if !label.is_empty() {
+            args.extend(["--label".to_string(), label]);
+        }
+
+        self.say(&format!("\nThe command line is:\n\n  {}\n", command_line(&args)))?;
+        let start = self.confirm("Start it now", true)?;
+        Ok(Answers { args, start })
+    }
+
+    fn say(&mut self, text: &str) -> std::result::Result<(), String> {
+        writeln!(self.output, "{}", text).map_err(|e| e.to_string())
+    }
+
+    /// Asks until the answer passes the check, the default is taken for an empty answer.
+    fn ask<F>(&mut self, question: &str, default: &str, check: F) -> std::result::Result<String, String>
+        where F: Fn(&str) -> std::result::Result<(), String> {
+        let prompt = match default {
+            "" => format!("{}: ", question),
+            default => format!("{} [{}]: ", question, default),
+        };
+        loop {
+            let answer = match self.read(&prompt)? {
+                answer if answer.is_empty() => default.to_string(),
+                answer => answer,
+            };
+            match check(&answer) {
+                Ok(()) => return Ok(answer),
+                Err(e) => self.say(&e)?,
+            }
+        }
+    }
+
+    fn confirm(&mut self, question: &str, default: bool) -> std::result::Result<bool, String> {
+        let prompt = format!("{} [{}]: ", question, if default { "Y/n" } else { "y/N" });
+        loop {
+            match self.read(&prompt)?.to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => self.say("Answer y or n")?,
+            }
+        }
+    }
+
+    fn read(&mut self, prompt: &str) -> std::result::Result<String, String> {
+        write!(self.output, "{}", prompt).and_then(|_| self.output.flush()).map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Err("The wizard is interrupted".to_string());
+        }
+        Ok(line.trim().to_string())
+    }
+}
+
+/// The command line as typed into a shell, with arguments quoted where needed.
+pub fn command_line(args: &[String]) -> String {
+    let quoted: Vec<String> = args.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty() && arg.chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_=.,:/@%+".contains(c));
+            if plain { arg.clone() } else { format!("'{}'", arg.replace('\'', r"'\''")) }
+        })
+        .collect();
+    format!("ws-proxy {}", quoted.join(" "))
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use chrono::Utc;
+use url::Url;
+use ws::Message;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ws_proxy::bundle::Recorded;
+use ws_proxy::clock::{Clock, SimulatedClock};
+use ws_proxy::closecodes::Leg;
+use ws_proxy::latency::Latency;
+use ws_proxy::normalize::Normalization;
+use ws_proxy::replay::{self, Comparison, Timing};
+
+use common::{Client, Event};
+
+#[test]
+fn manual_clock_sleeps_until_advanced() {
+    let clock = Arc::new(SimulatedClock::manual());
+    let sleeper = {
+        let clock = clock.clone();
+        thread::spawn(move || clock.sleep(Duration::from_secs(60)))
+    };
+    // The sleeper counts from the time it falls asleep
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(30));
+    thread::sleep(Duration::from_millis(50));
+    assert!(!sleeper.is_finished());
+    clock.advance(Duration::from_secs(30));
+    sleeper.join().unwrap();
+    assert_eq!(clock.elapsed(), Duration::from_secs(60));
+}
+
+#[test]
+fn instant_clock_advances_by_sleeping() {
+    let clock = SimulatedClock::instant();
+    let (start, start_utc) = (clock.now(), clock.utc());
+    clock.sleep(Duration::from_secs(3600));
+    assert_eq!(clock.now() - start, Duration::from_secs(3600));
+    assert_eq!(clock.utc() - start_utc, chrono::Duration::hours(1));
+}
+
+#[test]
+fn delays_pass_on_the_clock() {
+    let server = common::server("");
+    let clock = Arc::new(SimulatedClock::manual());
+    let mut options = common::options();
+    options.delays = vec![Latency::parse("client->server=10s").unwrap()];
+    let proxy = common::proxy_with(server, options, |builder| builder.clock(clock.clone()));
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    client.send("late");
+    assert_eq!(client.next(Duration::from_millis(300)), None);
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(client.next(Duration::from_millis(300)), None);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(client.next(common::PATIENCE), Some(Event::Message("late".to_string())));
+
+    client.close();
+    proxy.stop().unwrap();
+}
+
+#[test]
+fn replay_is_paced_on_the_clock() {
+    let server = common::server("");
+    let start = Utc::now();
+    let recorded = |from, text: &str, after_ms| Recorded {
+        id: None,
+        time: Some(start + chrono::Duration::milliseconds(after_ms)),
+        hop: None,
+        from,
+        message: Message::text(text),
+    };
+    let messages = vec![
+        recorded(Leg::Client, "first", 0),
+        recorded(Leg::Server, "first", 10),
+        recorded(Leg::Client, "second", 60_010),
+        recorded(Leg::Server, "second", 60_020),
+    ];
+    let clock = Arc::new(SimulatedClock::instant());
+    let url = Url::parse(&format!("ws://{}/", server)).unwrap();
+    let comparison = Comparison::new(&[], Normalization::new(vec![])).unwrap();
+
+    let report = replay::replay(messages, &url, comparison, common::PATIENCE, Timing::Scaled(0.5), clock.clone())
+        .unwrap();
+
+    assert_eq!(report.matched, 2);
+    assert!(report.divergences.is_empty());
+    assert_eq!(clock.elapsed(), Duration::from_secs(30));
+}
@@ -0,0 +1,151 @@
+#![allow(dead_code)]
+
+use url::Url;
+use ws::{CloseCode, Handshake, Message, Sender};
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use ws_proxy::closecodes::Leg;
+use ws_proxy::proxy::{ConnectionEvent, Options, Proxy, ProxyBuilder};
+use ws_proxy::testserver::{self, Script};
+
+/// Longest wait for anything expected to happen in a test.
+pub const PATIENCE: Duration = Duration::from_secs(5);
+
+/// What a client of a test got from the proxy.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Message(String),
+    Closed(u16, String),
+}
+
+/// Websocket client of a test, running its own event loop.
+pub struct Client {
+    out: Sender,
+    events: Receiver<Event>,
+}
+
+struct Collector {
+    out: Sender,
+    opened: mpsc::Sender<Sender>,
+    events: mpsc::Sender<Event>,
+}
+
+impl ws::Handler for Collector {
+    fn on_open(&mut self, _: Handshake) -> ws::Result<()> {
+        self.opened.send(self.out.clone()).ok();
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Binary(data) => format!("<{} bytes>", data.len()),
+        };
+        self.events.send(Event::Message(text)).ok();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.events.send(Event::Closed(code.into(), reason.to_string())).ok();
+    }
+}
+
+impl Client {
+    /// Connects to the proxy and waits until the handshake is done.
+    pub fn connect(address: SocketAddr) -> Client {
+        let (opened_tx, opened) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let url = format!("ws://{}/", address);
+        thread::spawn(move || {
+            ws::connect(url, |out| Collector { out, opened: opened_tx.clone(), events: events_tx.clone() }).ok();
+        });
+        let out = opened.recv_timeout(PATIENCE).expect("the proxy doesn't accept the client");
+        Client { out, events }
+    }
+
+    pub fn send(&self, text: &str) {
+        self.out.send(text).expect("the message can't be sent");
+    }
+
+    /// Next event, none if nothing happens in the time.
+    pub fn next(&self, timeout: Duration) -> Option<Event> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    /// Next message, panics unless it comes in time.
+    pub fn receive(&self) -> String {
+        match self.next(PATIENCE) {
+            Some(Event::Message(text)) => text,
+            other => panic!("a message is expected, got {:?}", other),
+        }
+    }
+
+    pub fn close(self) {
+        self.out.close(CloseCode::Normal).ok();
+    }
+}
+
+/// Echo server of the tests, or one following the script.
+pub fn server(script: &str) -> SocketAddr {
+    let script = match script {
+        "" => Script::echo(),
+        script => Script::parse(script).expect("the script of the server is invalid"),
+    };
+    testserver::spawn(script).expect("the test server doesn't start")
+}
+
+/// Options which keep the logs of the proxy in memory, so that tests leave no files behind.
+pub fn options() -> Options {
+    let mut options = Options::default();
+    options.no_files = Some(1 << 20);
+    options
+}
+
+/// Proxy of a test, telling when its connection to the server opens and closes.
+pub struct TestProxy {
+    proxy: Proxy,
+    upstream: Receiver<bool>,
+}
+
+impl TestProxy {
+    pub fn address(&self) -> SocketAddr {
+        self.proxy.address()
+    }
+
+    /// Waits until the connection to the server opens.
+    pub fn connected(&self) {
+        assert_eq!(self.upstream.recv_timeout(PATIENCE), Ok(true), "the server isn't connected");
+    }
+
+    /// Waits until the connection to the server closes.
+    pub fn disconnected(&self) {
+        assert_eq!(self.upstream.recv_timeout(PATIENCE), Ok(false), "the server isn't disconnected");
+    }
+
+    pub fn stop(self) -> Result<(), String> {
+        self.proxy.stop()
+    }
+}
+
+/// Proxy in front of the server, started with the options.
+pub fn proxy(server: SocketAddr, options: Options) -> TestProxy {
+    proxy_with(server, options, |builder| builder)
+}
+
+/// Proxy in front of the server, with the builder changed before it starts.
+pub fn proxy_with<F>(server: SocketAddr, options: Options, build: F) -> TestProxy
+    where F: FnOnce(ProxyBuilder) -> ProxyBuilder {
+    let url = Url::parse(&format!("ws://{}/", server)).unwrap();
+    let (upstream_tx, upstream) = mpsc::channel();
+    let builder = ProxyBuilder::new(url).options(options).on_connection(move |event| match event {
+        ConnectionEvent::Opened { leg: Leg::Server, .. } => drop(upstream_tx.send(true)),
+        ConnectionEvent::Closed { leg: Leg::Server, .. } => drop(upstream_tx.send(false)),
+        _ => (),
+    });
+    let proxy = build(builder).start().expect("the proxy doesn't start");
+    TestProxy { proxy, upstream }
+}
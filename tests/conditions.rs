@@ -0,0 +1,148 @@
+use serde_json::{json, Map, Value};
+use ws::Message;
+
+use std::time::Duration;
+
+use ws_proxy::closecodes::Leg;
+use ws_proxy::condition::{Condition, Environment, Facts};
+use ws_proxy::expr::Expression;
+use ws_proxy::views::View;
+
+fn variables(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(variables) => variables,
+        _ => panic!("variables are an object"),
+    }
+}
+
+fn evaluate(expression: &str, given: Value) -> Result<Value, String> {
+    Expression::parse(expression).unwrap().evaluate(&variables(given))
+}
+
+#[test]
+fn expressions_follow_precedence() {
+    let none = json!({});
+    assert_eq!(evaluate("1 + 2 * 3", none.clone()), Ok(json!(7)));
+    assert_eq!(evaluate("(1 + 2) * 3 % 4", none.clone()), Ok(json!(1)));
+    assert_eq!(evaluate("-2 - -3", none.clone()), Ok(json!(1)));
+    assert_eq!(evaluate("1 < 2 && 2 < 1 || !false", none.clone()), Ok(json!(true)));
+    assert_eq!(evaluate("1 == 1 ? 'yes' : 'no'", none.clone()), Ok(json!("yes")));
+    assert_eq!(evaluate("false ? 1 : true ? 2 : 3", none.clone()), Ok(json!(2)));
+    assert_eq!(evaluate("2 in [1, 2, 3]", none.clone()), Ok(json!(true)));
+    assert_eq!(evaluate("\"a\\\"b\" + 'c'", none), Ok(json!("a\"bc")));
+}
+
+#[test]
+fn expressions_read_variables() {
+    let given = json!({
+        "from": "server",
+        "payload": { "type": "error", "items": [10, 20], "code": "42" },
+        "headers": { "user-agent": "Mozilla/5.0" },
+    });
+    let holds = |expression: &str| Expression::parse(expression).unwrap().holds(&variables(given.clone()));
+    assert!(holds("from == \"server\" && payload.type == \"error\""));
+    assert!(holds("payload.items[1] == 20 && size(payload.items) == 2 && payload.items.size() == 2"));
+    assert!(holds("int(payload.code) + 1 == 43 && double(\"1.5\") == 1.5 && string(1) == \"1\""));
+    assert!(holds("headers[\"user-agent\"].startsWith(\"Mozilla\")"));
+    assert!(holds("headers[\"user-agent\"].matches(\"[0-9]\\\\.[0-9]\")"));
+    assert!(holds("has(payload.type) && !has(payload.missing)"));
+    assert!(holds("payload.type.lowerAscii().contains(\"err\") && payload.type.endsWith(\"or\")"));
+    // Errors don't hold, whatever is around them
+    assert!(!holds("payload.missing == 1"));
+    assert!(!holds("!(payload.missing == 1)"));
+    assert!(!holds("from + 1 == 2"));
+}
+
+#[test]
+fn expressions_report_errors() {
+    let error = |expression: &str| Expression::parse(expression).err().unwrap();
+    assert_eq!(error("1 +"), "Expression ends too early");
+    assert_eq!(error("(1"), "Expected ) at the end");
+    assert_eq!(error("[1 2]"), "Expected , instead of 2");
+    assert_eq!(error("1 2"), "Unexpected 2");
+    assert_eq!(error("payload.'type'"), "Expected a field or method name after .");
+    assert_eq!(error("'open"), "Unterminated string");
+    assert_eq!(error("1 # 2"), "Unexpected #");
+    assert!(error("text.matches('(')").starts_with("Invalid pattern ("));
+
+    assert_eq!(evaluate("missing", json!({})), Err("Unknown variable missing".to_string()));
+    assert_eq!(evaluate("payload.type", json!({ "payload": null })), Err("null has no field type".to_string()));
+    assert!(evaluate("nothing(1)", json!({})).unwrap_err().starts_with("Unknown function nothing"));
+}
+
+#[test]
+fn conditions_match_patterns_of_a_side() {
+    let text = Message::text("{\"type\":\"subscribe\"}");
+    let binary = Message::binary(b"subscribe".to_vec());
+
+    let any = Condition::parse("\"type\":\"subscribe\"").unwrap();
+    assert!(any.matches(&Facts::of(Leg::Client, &text)));
+    assert!(any.matches(&Facts::of(Leg::Server, &text)));
+    let client = Condition::parse("client:subscribe").unwrap();
+    assert!(client.matches(&Facts::of(Leg::Client, &text)));
+    assert!(!client.matches(&Facts::of(Leg::Server, &text)));
+    assert!(!client.matches(&Facts::of(Leg::Client, &binary)));
+    // A colon which doesn't follow a side is part of the pattern
+    let colon = Condition::parse("type\":\"sub").unwrap();
+    assert!(colon.matches(&Facts::of(Leg::Server, &text)));
+
+    assert_eq!(client.to_string(), "client:subscribe");
+    assert_eq!(colon.to_string(), "type\":\"sub");
+    assert!(Condition::parse("server:(").err().unwrap().starts_with("Invalid pattern ("));
+}
+
+#[test]
+fn conditions_hold_for_facts_of_messages() {
+    let message = Message::text("{\"type\":\"data\",\"id\":7}");
+    let headers = vec![("origin".to_string(), "https://example.com".to_string())];
+    let environment = Environment::new("a1b2".to_string(), "/feed?room=lobby", "ws://upstream/".to_string(),
+        vec![("env".to_string(), "staging".to_string())]);
+    let facts = Facts {
+        from: Leg::Server,
+        message: &message,
+        connection_id: 3,
+        headers: &headers,
+        gap: Duration::from_millis(1500),
+        age: Duration::from_secs(60),
+        messages: 12,
+        environment: &environment,
+    };
+    let holds = |condition: &str| Condition::parse(condition).unwrap().matches(&facts);
+
+    assert!(holds("expr:from == \"server\" && payload.type == \"data\" && payload.id > 5"));
+    assert!(holds("expr:connection == 3 && messages == 12 && gap_ms >= 1000 && age_ms == 60000"));
+    assert!(holds("expr:size == 22 && !binary && text.contains(\"data\")"));
+    assert!(holds("expr:headers.origin.endsWith(\"example.com\") && client == \"a1b2\""));
+    assert!(holds("expr:path == \"/feed\" && query.room == \"lobby\" && labels.env == \"staging\""));
+    assert!(holds("expr:upstream == \"ws://upstream/\""));
+    assert!(!holds("expr:payload.type == \"error\""));
+    // Binary messages have no payload
+    let binary = Message::binary(vec![0; 4]);
+    assert!(Condition::parse("expr:binary && size == 4 && payload == null").unwrap()
+        .matches(&Facts::of(Leg::Client, &binary)));
+
+    let condition = Condition::parse("expr:payload.id in [1, 7]").unwrap();
+    assert_eq!(condition.to_string(), "expr:payload.id in [1, 7]");
+    assert!(condition.matches(&facts));
+    assert!(Condition::parse("expr:payload.").err().unwrap().starts_with("Invalid expression payload.: "));
+}
+
+#[test]
+fn views_filter_and_highlight_with_conditions() {
+    let view = View::compile("errors", &json!({
+        "filter": "expr:payload.type == \"error\"",
+        "highlight": ["client:code", "server:[0-9]+", "expr:payload.code > 500"],
+    })).unwrap();
+    let error = Message::text("{\"type\":\"error\",\"code\":503}");
+    let data = Message::text("{\"type\":\"data\"}");
+
+    assert_eq!(view.show(&Facts::of(Leg::Server, &data), &[]), None);
+    // Patterns of the other side highlight nothing, expressions the whole message
+    assert_eq!(view.show(&Facts::of(Leg::Server, &error), &[]).unwrap(),
+        "\x1b[1;33m{\"type\":\"error\",\"code\":\x1b[1;33m503\x1b[0m}\x1b[0m");
+    assert_eq!(view.show(&Facts::of(Leg::Client, &error), &[]).unwrap(),
+        "\x1b[1;33m{\"type\":\"error\",\"\x1b[1;33mcode\x1b[0m\":503}\x1b[0m");
+
+    let invalid = View::compile("broken", &json!({ "highlight": ["("] })).err().unwrap();
+    assert!(invalid.starts_with("Invalid pattern (") && invalid.ends_with(" in view broken"), "{}", invalid);
+}
@@ -0,0 +1,39 @@
+mod common;
+
+use common::Client;
+
+#[test]
+fn messages_pass_both_ways() {
+    let server = common::server("ping => pong\n* => got {message} on {path}");
+    let proxy = common::proxy(server, common::options());
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    client.send("ping");
+    assert_eq!(client.receive(), "pong");
+    client.send("hello");
+    assert_eq!(client.receive(), "got hello on /");
+    client.send("{\"json\": [1, 2]}");
+    assert_eq!(client.receive(), "got {\"json\": [1, 2]} on /");
+
+    client.close();
+    proxy.stop().unwrap();
+}
+
+#[test]
+fn messages_keep_their_order() {
+    let server = common::server("");
+    let proxy = common::proxy(server, common::options());
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    for number in 0..100 {
+        client.send(&number.to_string());
+    }
+    for number in 0..100 {
+        assert_eq!(client.receive(), number.to_string());
+    }
+
+    client.close();
+    proxy.stop().unwrap();
+}
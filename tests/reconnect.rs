@@ -0,0 +1,84 @@
+mod common;
+
+use std::time::Duration;
+
+use ws_proxy::backoff::Backoff;
+use ws_proxy::condition::Condition;
+use ws_proxy::upstreamloss::LossNotice;
+
+use common::{Client, Event};
+
+/// Delays short enough for the tests.
+const BACKOFF: Backoff = Backoff { first: Duration::from_millis(50), max: Duration::from_millis(200) };
+
+#[test]
+fn clients_keep_working_after_the_server_reconnects() {
+    let server = common::server("close-on bye");
+    let mut options = common::options();
+    options.reconnect = Some(BACKOFF);
+    let proxy = common::proxy(server, options);
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    client.send("hello");
+    assert_eq!(client.receive(), "hello");
+    client.send("bye");
+    proxy.disconnected();
+    proxy.connected();
+    assert_eq!(client.next(Duration::from_millis(300)), None);
+    client.send("hello again");
+    assert_eq!(client.receive(), "hello again");
+
+    client.close();
+    proxy.stop().unwrap();
+}
+
+#[test]
+fn setup_messages_are_sent_again_after_reconnecting() {
+    let server = common::server("close-on bye");
+    let mut options = common::options();
+    options.reconnect = Some(BACKOFF);
+    options.resubscribe = vec![Condition::parse("^subscribe ").unwrap()];
+    let proxy = common::proxy(server, options);
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    client.send("subscribe prices");
+    assert_eq!(client.receive(), "subscribe prices");
+    client.send("hello");
+    assert_eq!(client.receive(), "hello");
+
+    // The server echoes the subscription sent again, the client stays connected meanwhile
+    client.send("bye");
+    proxy.disconnected();
+    proxy.connected();
+    assert_eq!(client.receive(), "subscribe prices");
+    assert_eq!(client.next(Duration::from_millis(300)), None);
+    client.send("hello again");
+    assert_eq!(client.receive(), "hello again");
+
+    client.close();
+    proxy.stop().unwrap();
+}
+
+#[test]
+fn server_going_away_closes_the_clients_with_its_code() {
+    let server = common::server("close-on bye");
+    let mut options = common::options();
+    options.on_upstream_loss = Some(LossNotice::Close);
+    let proxy = common::proxy(server, options);
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    client.send("bye");
+    proxy.disconnected();
+    match client.next(common::PATIENCE) {
+        Some(Event::Closed(code, reason)) => {
+            assert_eq!(code, 1001);
+            assert!(reason.contains("\"diagnosis\":\"closed\""), "unexpected reason {}", reason);
+        },
+        other => panic!("the client isn't closed, got {:?}", other),
+    }
+
+    proxy.stop().unwrap();
+}
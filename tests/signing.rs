@@ -0,0 +1,144 @@
+mod common;
+
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{TimeZone, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ws_proxy::auth::{AuthError, AuthProvider, HeadersOrQuery, SigV4};
+use ws_proxy::upstreamloss::LossNotice;
+
+use common::Client;
+
+const ACCESS_KEY: &str = "AKIDEXAMPLE";
+const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+
+/// Signer with the example credentials, the only ones of the tests in this file.
+fn signer(spec: &str) -> SigV4 {
+    env::set_var("AWS_ACCESS_KEY_ID", ACCESS_KEY);
+    env::set_var("AWS_SECRET_ACCESS_KEY", SECRET_KEY);
+    env::remove_var("AWS_SESSION_TOKEN");
+    SigV4::parse(spec).unwrap()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Signature of a presigned GET of the url as API Gateway computes it, from the parameters
+/// besides X-Amz-Signature, which have nothing to encode but the slashes of the credential.
+fn expected_signature(host: &str, parameters: &[(&str, &str)]) -> String {
+    let mut query: Vec<String> = parameters.iter()
+        .map(|(name, value)| format!("{}={}", name, value.replace('/', "%2F")))
+        .collect();
+    query.sort();
+    let canonical_request = format!("GET\n/\n{}\nhost:{}\n\nhost\n{}", query.join("&"), host,
+        hex::encode(Sha256::digest(b"")));
+    let date = parameters.iter().find(|(name, _)| *name == "X-Amz-Date").unwrap().1;
+    let scope = format!("{}/us-east-1/execute-api/aws4_request", &date[..8]);
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", date, scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes())));
+    let key = hmac(format!("AWS4{}", SECRET_KEY).as_bytes(), &date[..8]);
+    let key = hmac(&key, "us-east-1");
+    let key = hmac(&key, "execute-api");
+    let key = hmac(&key, "aws4_request");
+    hex::encode(hmac(&key, &string_to_sign))
+}
+
+#[test]
+fn handshake_to_the_server_is_presigned() {
+    let server = common::server("on-open {query.X-Amz-Credential} {query.X-Amz-Date} {query.X-Amz-Signature}");
+    let mut options = common::options();
+    options.auth = Some(Arc::new(signer("us-east-1")));
+    let proxy = common::proxy(server, options);
+
+    let client = Client::connect(proxy.address());
+    proxy.connected();
+    let received = client.receive();
+    let values: Vec<&str> = received.split(' ').collect();
+    let (credential, date, signature) = (values[0], values[1], values[2]);
+    assert_eq!(credential, format!("{}/{}/us-east-1/execute-api/aws4_request", ACCESS_KEY, &date[..8]));
+    let parameters = [
+        ("X-Amz-Algorithm", "AWS4-HMAC-SHA256"),
+        ("X-Amz-Credential", credential),
+        ("X-Amz-Date", date),
+        ("X-Amz-Expires", "300"),
+        ("X-Amz-SignedHeaders", "host"),
+    ];
+    assert_eq!(signature, expected_signature(&server.to_string(), &parameters));
+
+    client.close();
+    proxy.stop().unwrap();
+}
+
+#[test]
+fn appsync_handshake_has_a_signed_header() {
+    let signer = signer("eu-west-1:appsync");
+    let url = Url::parse("wss://example.appsync-realtime-api.eu-west-1.amazonaws.com/graphql").unwrap();
+    let now = Utc.with_ymd_and_hms(2026, 10, 16, 12, 30, 0).unwrap();
+
+    let parameters = signer.sign(&url, now);
+    assert_eq!(signer.subprotocol(), Some("graphql-ws"));
+    let names: Vec<&str> = parameters.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["header", "payload"]);
+    assert_eq!(BASE64.decode(&parameters[1].1).unwrap(), b"{}");
+    let header: Value = serde_json::from_slice(&BASE64.decode(&parameters[0].1).unwrap()).unwrap();
+    assert_eq!(header["host"], "example.appsync-api.eu-west-1.amazonaws.com");
+    assert_eq!(header["x-amz-date"], "20261016T123000Z");
+    let authorization = header["Authorization"].as_str().unwrap();
+    assert!(authorization.starts_with(
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20261016/eu-west-1/appsync/aws4_request, \
+        SignedHeaders=accept;content-encoding;content-type;host;x-amz-date, Signature="), "{}", authorization);
+
+    // Signed again at the same time, the header is the same
+    assert_eq!(signer.sign(&url, now), parameters);
+}
+
+/// Provider which fails in the ways a provider of an embedder might.
+enum Broken {
+    Panicking,
+    Hanging,
+}
+
+#[async_trait]
+impl AuthProvider for Broken {
+    async fn credentials(&self, _: &Url) -> Result<HeadersOrQuery, AuthError> {
+        match self {
+            Broken::Panicking => panic!("no token"),
+            Broken::Hanging => futures::future::pending().await,
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(200)
+    }
+}
+
+#[test]
+fn broken_providers_fail_the_attempt() {
+    for (provider, error) in [(Broken::Panicking, "panicked: no token"), (Broken::Hanging, "within 200ms")] {
+        let server = common::server("");
+        let mut options = common::options();
+        options.auth = Some(Arc::new(provider));
+        options.on_upstream_loss = Some(LossNotice::Json);
+        let proxy = common::proxy(server, options);
+
+        let client = Client::connect(proxy.address());
+        let notice: Value = serde_json::from_str(&client.receive()).unwrap();
+        let reason = &notice["ws-proxy"];
+        assert_eq!(reason["diagnosis"], "auth");
+        assert!(reason["error"].as_str().unwrap().contains(error), "unexpected reason {}", reason);
+
+        proxy.stop().unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+mod common;
+
+use url::Url;
+
+use ws_proxy::latency::Latency;
+use ws_proxy::proxy::{Options, ProxyBuilder};
+use ws_proxy::upstreamqueue::Overflow;
+
+use common::Client;
+
+/// Options of a strict proxy, which queues the messages of clients while the server isn't connected
+/// and closes those overflowing the queue.
+fn strict() -> Options {
+    let mut options = common::options();
+    options.strict = true;
+    options.upstream_queue = Some(1 << 20);
+    options.upstream_overflow = Overflow::Close;
+    options
+}
+
+#[test]
+fn strict_passthrough_forwards_messages_untouched() {
+    let server = common::server("ping => pong\nping => {\"type\":\"ack\",\"of\":\"{message}\"}");
+    let proxy = common::proxy(server, strict());
+
+    let client = Client::connect(proxy.address());
+    // Queued until the server is connected, and forwarded then
+    client.send("ping");
+    proxy.connected();
+    assert_eq!(client.receive(), "pong");
+    assert_eq!(client.receive(), "{\"type\":\"ack\",\"of\":\"ping\"}");
+    client.send(&"x".repeat(100_000));
+    client.send("ping");
+    assert_eq!(client.receive(), "pong");
+
+    client.close();
+    // Any message altered or lost on the way fails the integrity check
+    proxy.stop().unwrap();
+}
+
+#[test]
+fn strict_passthrough_refuses_changing_traffic() {
+    let server = common::server("");
+    let url = Url::parse(&format!("ws://{}/", server)).unwrap();
+    let refusal = |options: Options| match ProxyBuilder::new(url.clone()).options(options).start() {
+        Ok(proxy) => {
+            proxy.stop().ok();
+            panic!("the strict proxy starts");
+        },
+        Err(e) => e,
+    };
+
+    let mut delayed = strict();
+    delayed.delays = vec![Latency::parse("client->server=10ms").unwrap()];
+    assert_eq!(refusal(delayed), "--strict-passthrough can't be combined with --delay");
+
+    let mut dropping = strict();
+    dropping.upstream_queue = None;
+    dropping.notify_clients = true;
+    assert_eq!(refusal(dropping),
+        "--strict-passthrough can't be combined with --notify-clients, --upstream-queue 0");
+}